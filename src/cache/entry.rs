@@ -0,0 +1,230 @@
+//! On-disk format of cache entries
+//!
+//! Every cached chunk starts with a fixed header carrying the payload
+//! length and its BLAKE3 hash, so a file truncated by a crash or damaged on
+//! disk is detected when read instead of being served as chunk data.
+//!
+//! ```text
+//! magic "TGCC" | version (1) | reserved (3) | length (u64 LE) | BLAKE3 (32) | payload
+//! ```
+//!
+//! Entries written before the header existed are raw chunk data, which may
+//! start with anything, so a file is only read as headered when its magic,
+//! version and recorded length all agree with the file.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Identifies a cache entry file (distinct from the ciphertext envelope magic)
+const MAGIC: &[u8; 4] = b"TGCC";
+
+/// Current entry format version
+const VERSION: u8 = 1;
+
+/// Size of the entry header in bytes
+pub const HEADER_LEN: usize = 48;
+
+/// Why a cache entry was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Corruption {
+    /// Shorter than its header or recorded length
+    Truncated,
+    /// Longer than its recorded length
+    TrailingData,
+    /// Payload does not match its checksum
+    ChecksumMismatch,
+    /// Written in an unknown format
+    UnknownFormat,
+}
+
+impl std::fmt::Display for Corruption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Corruption::Truncated => write!(f, "truncated"),
+            Corruption::TrailingData => write!(f, "trailing data"),
+            Corruption::ChecksumMismatch => write!(f, "checksum mismatch"),
+            Corruption::UnknownFormat => write!(f, "unknown format"),
+        }
+    }
+}
+
+/// Build the header for a payload
+pub fn header(data: &[u8]) -> [u8; HEADER_LEN] {
+    let mut header = [0u8; HEADER_LEN];
+    header[..4].copy_from_slice(MAGIC);
+    header[4] = VERSION;
+    header[8..16].copy_from_slice(&(data.len() as u64).to_le_bytes());
+    header[16..].copy_from_slice(blake3::hash(data).as_bytes());
+    header
+}
+
+/// Whether a file starting with `header` and `file_len` bytes long is headered
+fn is_headered(header: &[u8], file_len: u64) -> bool {
+    header.len() >= HEADER_LEN
+        && &header[..4] == MAGIC
+        && header[4] == VERSION
+        && header[5..8] == [0; 3]
+        && u64::from_le_bytes(header[8..16].try_into().unwrap()) == file_len - HEADER_LEN as u64
+}
+
+/// Classify a file that is neither a valid headered entry nor legacy data
+fn classify(header: &[u8], file_len: u64) -> Corruption {
+    if header.len() < 16 || &header[..4] != MAGIC || header[4] != VERSION {
+        return Corruption::UnknownFormat;
+    }
+    let length = u64::from_le_bytes(header[8..16].try_into().unwrap());
+    match file_len.saturating_sub(HEADER_LEN as u64).cmp(&length) {
+        std::cmp::Ordering::Less => Corruption::Truncated,
+        std::cmp::Ordering::Greater => Corruption::TrailingData,
+        std::cmp::Ordering::Equal => Corruption::UnknownFormat,
+    }
+}
+
+/// Verify an entry file and return its payload
+///
+/// Files cached before entries had headers are accepted only if their
+/// content hashes to the chunk ID, as chunk IDs are plaintext hashes.
+pub fn verify<'a>(chunk_id: &str, file: &'a [u8]) -> Result<&'a [u8], Corruption> {
+    let file_len = file.len() as u64;
+    if !is_headered(file, file_len) {
+        return if blake3::hash(file).to_hex().as_str() == chunk_id {
+            Ok(file)
+        } else {
+            Err(classify(file, file_len))
+        };
+    }
+
+    let payload = &file[HEADER_LEN..];
+    if blake3::hash(payload).as_bytes() != &file[16..HEADER_LEN] {
+        return Err(Corruption::ChecksumMismatch);
+    }
+    Ok(payload)
+}
+
+/// Verify an entry file without loading it, returning where its payload starts
+///
+/// Used before serving ranges of an entry, so whole chunks need not be
+/// held in memory to check them.
+pub fn verify_file<R: Read + Seek>(
+    chunk_id: &str,
+    file: &mut R,
+) -> io::Result<Result<u64, Corruption>> {
+    let file_len = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(0))?;
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    file.by_ref().take(HEADER_LEN as u64).read_to_end(&mut header)?;
+
+    let mut hasher = blake3::Hasher::new();
+    if !is_headered(&header, file_len) {
+        hasher.update(&header);
+        hasher.update_reader(file)?;
+        return Ok(if hasher.finalize().to_hex().as_str() == chunk_id {
+            Ok(0)
+        } else {
+            Err(classify(&header, file_len))
+        });
+    }
+
+    hasher.update_reader(file)?;
+    if hasher.finalize().as_bytes() != &header[16..HEADER_LEN] {
+        return Ok(Err(Corruption::ChecksumMismatch));
+    }
+    Ok(Ok(HEADER_LEN as u64))
+}
+
+/// Payload size of an entry file
+///
+/// Files without a valid header (written before it existed) are all payload.
+pub fn payload_len(path: &Path) -> io::Result<u64> {
+    let file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    file.take(HEADER_LEN as u64).read_to_end(&mut header)?;
+    if is_headered(&header, file_len) {
+        Ok(file_len - HEADER_LEN as u64)
+    } else {
+        Ok(file_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(data: &[u8]) -> Vec<u8> {
+        let mut file = header(data).to_vec();
+        file.extend_from_slice(data);
+        file
+    }
+
+    #[test]
+    fn test_entry_roundtrip() {
+        let file = entry(b"chunk data");
+        assert_eq!(verify("id", &file), Ok(&b"chunk data"[..]));
+    }
+
+    #[test]
+    fn test_payload_len() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let current = temp.path().join("current");
+        std::fs::write(&current, entry(b"chunk data")).unwrap();
+        assert_eq!(payload_len(&current).unwrap(), 10);
+
+        let legacy = temp.path().join("legacy");
+        std::fs::write(&legacy, [7u8; 100]).unwrap();
+        assert_eq!(payload_len(&legacy).unwrap(), 100);
+
+        // Legacy data that happens to start with the magic is still all payload
+        let mut lookalike = header(b"chunk data").to_vec();
+        lookalike.extend_from_slice(&[7u8; 100]);
+        let lookalike_path = temp.path().join("lookalike");
+        std::fs::write(&lookalike_path, &lookalike).unwrap();
+        assert_eq!(payload_len(&lookalike_path).unwrap(), lookalike.len() as u64);
+    }
+
+    #[test]
+    fn test_entry_corruption() {
+        let file = entry(b"chunk data");
+        assert_eq!(verify("id", &file[..file.len() - 1]), Err(Corruption::Truncated));
+        assert_eq!(verify("id", &file[..20]), Err(Corruption::Truncated));
+        assert_eq!(verify("id", &file[..10]), Err(Corruption::UnknownFormat));
+
+        let mut longer = file.clone();
+        longer.push(0);
+        assert_eq!(verify("id", &longer), Err(Corruption::TrailingData));
+
+        let mut flipped = file.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert_eq!(verify("id", &flipped), Err(Corruption::ChecksumMismatch));
+    }
+
+    #[test]
+    fn test_verify_file() {
+        let file = entry(b"chunk data");
+        let check = |file: &[u8], id: &str| verify_file(id, &mut io::Cursor::new(file)).unwrap();
+        assert_eq!(check(&file, "id"), Ok(HEADER_LEN as u64));
+        assert_eq!(check(&file[..file.len() - 1], "id"), Err(Corruption::Truncated));
+
+        let mut flipped = file.clone();
+        *flipped.last_mut().unwrap() ^= 1;
+        assert_eq!(check(&flipped, "id"), Err(Corruption::ChecksumMismatch));
+
+        let legacy = b"written before headers";
+        assert_eq!(check(legacy, blake3::hash(legacy).to_hex().as_str()), Ok(0));
+    }
+
+    #[test]
+    fn test_legacy_entry() {
+        let data = b"written before headers";
+        let id = blake3::hash(data).to_hex().to_string();
+        assert_eq!(verify(&id, data), Ok(&data[..]));
+        assert_eq!(verify("other", data), Err(Corruption::UnknownFormat));
+
+        // Encrypted chunks cached raw start with the envelope magic
+        let mut envelope = b"TGCE".to_vec();
+        envelope.extend_from_slice(&[1u8; 60]);
+        let id = blake3::hash(&envelope).to_hex().to_string();
+        assert_eq!(verify(&id, &envelope), Ok(&envelope[..]));
+    }
+}
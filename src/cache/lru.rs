@@ -61,6 +61,11 @@ impl<K: Clone + Eq + std::hash::Hash> LruCache<K> {
         None
     }
 
+    /// Check if an item is tracked
+    pub fn contains(&self, key: &K) -> bool {
+        self.positions.contains_key(key)
+    }
+
     /// Check if empty
     pub fn is_empty(&self) -> bool {
         self.positions.is_empty()
@@ -71,6 +76,11 @@ impl<K: Clone + Eq + std::hash::Hash> LruCache<K> {
         self.positions.len()
     }
 
+    /// Get count of entries in the access order, stale ones included
+    pub fn order_len(&self) -> usize {
+        self.order.len()
+    }
+
     /// Clear all items
     pub fn clear(&mut self) {
         self.order.clear();
@@ -89,6 +99,16 @@ impl<K: Clone + Eq + std::hash::Hash> LruCache<K> {
             self.order.push_back((key, gen));
         }
     }
+
+    /// Compact once stale entries far outnumber the live ones
+    ///
+    /// Touches and removals leave stale entries behind in the order; call
+    /// this after them so a long-running mount does not grow it forever.
+    pub fn compact_stale(&mut self) {
+        if self.order.len() > 4 * self.positions.len() + 1024 {
+            self.compact();
+        }
+    }
 }
 
 impl<K: Clone + Eq + std::hash::Hash> Default for LruCache<K> {
@@ -0,0 +1,166 @@
+//! Bounded in-memory page cache
+//!
+//! Holds recently read pages of cached chunks so small random reads are
+//! served from memory instead of the chunk file. Pages are evicted
+//! least-recently-used once their total size exceeds the byte capacity.
+
+use super::LruCache;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Size of a cached page in bytes
+pub const PAGE_SIZE: u64 = 64 * 1024;
+
+/// A page of a chunk: chunk ID and page index
+type PageKey = (String, u64);
+
+/// Size-bounded LRU cache of chunk pages
+pub struct PageCache {
+    /// Cached pages
+    pages: HashMap<PageKey, Arc<Vec<u8>>>,
+    /// Recency order of cached pages
+    lru: LruCache<PageKey>,
+    /// Sum of page sizes
+    bytes: u64,
+    /// Byte capacity
+    capacity: u64,
+    /// Pages served from memory
+    hits: u64,
+}
+
+impl PageCache {
+    /// Create a cache holding at most `capacity` bytes of pages
+    pub fn new(capacity: u64) -> Self {
+        PageCache {
+            pages: HashMap::new(),
+            lru: LruCache::new(),
+            bytes: 0,
+            capacity,
+            hits: 0,
+        }
+    }
+
+    /// Get a cached page
+    pub fn get(&mut self, chunk_id: &str, page: u64) -> Option<Arc<Vec<u8>>> {
+        let key = (chunk_id.to_string(), page);
+        let data = self.pages.get(&key)?.clone();
+        self.hits += 1;
+        self.lru.touch(&key);
+        self.lru.compact_stale();
+        Some(data)
+    }
+
+    /// Cache a page, evicting older ones to stay under capacity
+    pub fn insert(&mut self, chunk_id: &str, page: u64, data: Arc<Vec<u8>>) {
+        let size = data.len() as u64;
+        if size > self.capacity {
+            return;
+        }
+        let key = (chunk_id.to_string(), page);
+        if let Some(old) = self.pages.insert(key.clone(), data) {
+            self.bytes -= old.len() as u64;
+            self.lru.remove(&key);
+        }
+        self.lru.insert(key);
+        self.lru.compact_stale();
+        self.bytes += size;
+
+        while self.bytes > self.capacity {
+            match self.lru.pop_oldest() {
+                Some(key) => {
+                    if let Some(old) = self.pages.remove(&key) {
+                        self.bytes -= old.len() as u64;
+                    }
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Drop every page of a chunk
+    pub fn remove_chunk(&mut self, chunk_id: &str) {
+        let lru = &mut self.lru;
+        let mut freed = 0;
+        self.pages.retain(|key, data| {
+            if key.0 == chunk_id {
+                lru.remove(key);
+                freed += data.len() as u64;
+                false
+            } else {
+                true
+            }
+        });
+        self.bytes -= freed;
+        self.lru.compact_stale();
+    }
+
+    /// Drop every page
+    pub fn clear(&mut self) {
+        self.pages.clear();
+        self.lru.clear();
+        self.bytes = 0;
+    }
+
+    /// Memory held by cached pages
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Pages served from memory
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(len: usize) -> Arc<Vec<u8>> {
+        Arc::new(vec![0u8; len])
+    }
+
+    #[test]
+    fn test_page_eviction() {
+        let mut cache = PageCache::new(300);
+        cache.insert("a", 0, page(100));
+        cache.insert("a", 1, page(100));
+        cache.insert("b", 0, page(100));
+        assert!(cache.get("a", 0).is_some());
+
+        // ("a", 1) is now the least recently used page
+        cache.insert("b", 1, page(100));
+        assert!(cache.get("a", 1).is_none());
+        assert!(cache.get("a", 0).is_some());
+        assert_eq!(cache.bytes(), 300);
+        assert_eq!(cache.hits(), 2);
+    }
+
+    #[test]
+    fn test_remove_chunk() {
+        let mut cache = PageCache::new(1000);
+        cache.insert("a", 0, page(100));
+        cache.insert("a", 1, page(50));
+        cache.insert("b", 0, page(100));
+
+        cache.remove_chunk("a");
+        assert!(cache.get("a", 0).is_none());
+        assert!(cache.get("b", 0).is_some());
+        assert_eq!(cache.bytes(), 100);
+
+        // Oversized pages are not cached
+        cache.insert("c", 0, page(2000));
+        assert!(cache.get("c", 0).is_none());
+    }
+
+    #[test]
+    fn test_order_compacted_after_removals() {
+        let mut cache = PageCache::new(1000);
+        for i in 0..10_000 {
+            let chunk = format!("chunk{}", i);
+            cache.insert(&chunk, 0, page(10));
+            cache.remove_chunk(&chunk);
+        }
+        assert!(cache.lru.order_len() <= 1024);
+    }
+}
@@ -1,23 +1,75 @@
 //! Local cache module
 //!
 //! Provides disk-based caching of decrypted chunks for fast local access.
-//! Implements LRU eviction and prefetching.
+//! Implements pluggable eviction (LRU, LFU, FIFO, ARC) and prefetching.
+//!
+//! Entries are written to a temporary file and renamed into place, and carry
+//! a checksummed header verified on every read; damaged entries are moved to
+//! `quarantine/`, which keeps the most recent few and counts against the
+//! size budget. The entry list is saved to an index on shutdown so a
+//! restart does not have to scan the whole directory. The index is removed
+//! while the cache is open, so after a crash the directory is scanned.
+//!
+//! Reads of part of a chunk go through [`ChunkCache::read`], which reads
+//! only the pages it needs from the entry file and keeps recently read
+//! pages in a bounded memory tier. The checksum covers the whole payload,
+//! so these reads check an entry once per open cache rather than per page.
+//!
+//! A cache opened with [`ChunkCache::shared`] keeps its entries in a key
+//! domain's subdirectory of a host-wide directory and shares one size
+//! budget with every other mount using it (see [`SharedDir`]).
 
+mod entry;
 mod lru;
+mod memory;
+mod policy;
+mod shared;
 
+pub use entry::Corruption;
 pub use lru::LruCache;
+pub use memory::{PageCache, PAGE_SIZE};
+pub use policy::{tracker_for, ArcTracker, EvictionTracker, FifoTracker, LfuTracker, LruTracker};
+pub use shared::SharedDir;
 
-use crate::config::CacheConfig;
+use crate::chunk::ChunkRef;
+use crate::config::{CacheConfig, EvictionPolicy};
 use crate::error::{Error, Result};
-use parking_lot::RwLock;
-use std::collections::{HashMap, VecDeque};
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs::{self, File};
-use std::io::{Read, Write};
-use std::path::PathBuf;
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, Ordering};
-use tracing::{debug, info};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tracing::{debug, info, warn};
+
+/// Entry list saved on shutdown
+const INDEX_FILE: &str = "index.bin";
+
+/// Directory damaged entries are moved to
+const QUARANTINE_DIR: &str = "quarantine";
+
+/// Damaged entries kept for inspection; older ones are deleted
+const QUARANTINE_KEEP: usize = 16;
+
+/// Extension of entries being written
+const TMP_EXTENSION: &str = "tmp";
+
+/// Current index format version
+const INDEX_VERSION: u8 = 1;
 
-/// Disk-based chunk cache with LRU eviction
+/// Persisted list of cached entries
+#[derive(Serialize, Deserialize)]
+struct CacheIndex {
+    version: u8,
+    /// Chunk ID and payload size
+    entries: Vec<(String, u64)>,
+}
+
+/// Disk-based chunk cache with configurable eviction
 pub struct ChunkCache {
     /// Cache directory
     cache_dir: PathBuf,
@@ -25,58 +77,199 @@ pub struct ChunkCache {
     max_size: u64,
     /// Current cache size
     current_size: AtomicU64,
-    /// LRU tracking
-    lru: RwLock<LruCache<String>>,
+    /// Eviction policy in use
+    policy: EvictionPolicy,
+    /// Eviction order tracking
+    tracker: RwLock<Box<dyn EvictionTracker>>,
     /// Chunk sizes (for accurate size tracking)
     sizes: RwLock<HashMap<String, u64>>,
+    /// Chunks exempt from eviction (not tracked by the policy)
+    pinned: RwLock<HashSet<String>>,
     /// Prefetch queue
-    prefetch_queue: RwLock<VecDeque<String>>,
+    prefetch_queue: RwLock<VecDeque<ChunkRef>>,
     /// Prefetch enabled
     prefetch_enabled: bool,
+    /// Reads served from the cache
+    hits: AtomicU64,
+    /// Reads that missed
+    misses: AtomicU64,
+    /// Chunks evicted to make room
+    evictions: AtomicU64,
+    /// Bytes returned by cache hits
+    bytes_served: AtomicU64,
+    /// Prefetched chunks not read yet
+    prefetched: Mutex<HashSet<String>>,
+    /// Chunks cached by the prefetcher
+    prefetch_count: AtomicU64,
+    /// Prefetched chunks later read
+    prefetch_used: AtomicU64,
+    /// Prefetched chunks dropped before being read
+    prefetch_wasted: AtomicU64,
+    /// Damaged entries moved aside
+    quarantined: AtomicU64,
+    /// Bytes held in quarantine
+    quarantine_size: AtomicU64,
+    /// Makes temporary file names unique
+    tmp_seq: AtomicU64,
+    /// Entries checked since opening: payload offset and length
+    verified: RwLock<HashMap<String, (u64, u64)>>,
+    /// Recently read pages
+    pages: Mutex<PageCache>,
+    /// Host-wide directory and budget (for a shared cache)
+    shared: Option<SharedDir>,
 }
 
 impl ChunkCache {
     /// Create a new chunk cache
     pub fn new(config: &CacheConfig) -> Result<Self> {
+        Self::open(config, None)
+    }
+
+    /// Open the host-wide cache directory for a key domain
+    ///
+    /// The configured `max_size` is the budget of every domain together.
+    pub fn shared(config: &CacheConfig, domain: &str) -> Result<Self> {
+        let shared = SharedDir::open(&config.cache_dir)?.with_domain(domain)?;
+        Self::open(config, Some(shared))
+    }
+
+    fn open(config: &CacheConfig, shared: Option<SharedDir>) -> Result<Self> {
+        let cache_dir = match &shared {
+            Some(shared) => shared.domain_dir(),
+            None => config.cache_dir.clone(),
+        };
         // Ensure cache directory exists
-        fs::create_dir_all(&config.cache_dir)?;
+        fs::create_dir_all(&cache_dir)?;
 
         let cache = ChunkCache {
-            cache_dir: config.cache_dir.clone(),
+            cache_dir,
             max_size: config.max_size,
             current_size: AtomicU64::new(0),
-            lru: RwLock::new(LruCache::new()),
+            policy: config.eviction_policy,
+            tracker: RwLock::new(tracker_for(config.eviction_policy)),
             sizes: RwLock::new(HashMap::new()),
+            pinned: RwLock::new(HashSet::new()),
             prefetch_queue: RwLock::new(VecDeque::new()),
             prefetch_enabled: config.prefetch_enabled,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+            bytes_served: AtomicU64::new(0),
+            prefetched: Mutex::new(HashSet::new()),
+            prefetch_count: AtomicU64::new(0),
+            prefetch_used: AtomicU64::new(0),
+            prefetch_wasted: AtomicU64::new(0),
+            quarantined: AtomicU64::new(0),
+            quarantine_size: AtomicU64::new(0),
+            tmp_seq: AtomicU64::new(0),
+            verified: RwLock::new(HashMap::new()),
+            pages: Mutex::new(PageCache::new(config.memory_cache_size)),
+            shared,
         };
 
-        // Scan existing cache
-        cache.scan_cache()?;
+        // Restore the entry list, or scan after an unclean shutdown
+        if !cache.load_index() {
+            cache.scan_cache()?;
+        }
+        cache.prune_quarantine(QUARANTINE_KEEP);
 
         info!(
-            "Cache initialized: {} bytes used of {} max",
+            "Cache initialized: {} bytes used of {} max ({:?} eviction)",
             cache.current_size.load(Ordering::Relaxed),
-            cache.max_size
+            cache.max_size,
+            cache.policy
         );
 
         Ok(cache)
     }
 
+    /// Load the index written at the last clean shutdown
+    ///
+    /// The index is deleted once loaded: it only describes the directory
+    /// until the cache changes again. A shared domain has no index, as
+    /// other mounts add and evict its entries too.
+    fn load_index(&self) -> bool {
+        if self.shared.is_some() {
+            return false;
+        }
+        let path = self.cache_dir.join(INDEX_FILE);
+        let Ok(data) = fs::read(&path) else {
+            return false;
+        };
+        let _ = fs::remove_file(&path);
+        let index = match bincode::deserialize::<CacheIndex>(&data) {
+            Ok(index) if index.version == INDEX_VERSION => index,
+            _ => {
+                warn!("Ignoring unreadable cache index");
+                return false;
+            }
+        };
+
+        let mut tracker = self.tracker.write();
+        let mut sizes = self.sizes.write();
+        let mut total_size = 0u64;
+        for (chunk_id, size) in index.entries {
+            total_size += size;
+            tracker.insert(&chunk_id);
+            sizes.insert(chunk_id, size);
+        }
+        self.current_size.store(total_size, Ordering::SeqCst);
+        debug!("Loaded cache index with {} entries", sizes.len());
+        true
+    }
+
+    /// Save the entry list so the next start can skip the directory scan
+    ///
+    /// Only the mounting process calls this, when it unmounts; a cache
+    /// opened by any other command must not overwrite the list. A shared
+    /// domain is always scanned instead.
+    pub fn save_index(&self) -> Result<()> {
+        if self.shared.is_some() {
+            return Ok(());
+        }
+        let index = CacheIndex {
+            version: INDEX_VERSION,
+            entries: self
+                .sizes
+                .read()
+                .iter()
+                .map(|(id, size)| (id.clone(), *size))
+                .collect(),
+        };
+        let path = self.cache_dir.join(INDEX_FILE);
+        let tmp = path.with_extension(TMP_EXTENSION);
+        fs::write(&tmp, bincode::serialize(&index)?)?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
     /// Scan existing cache on startup
+    ///
+    /// Temporary files left by interrupted writes are removed (those of
+    /// processes still writing to a shared directory are kept).
     fn scan_cache(&self) -> Result<()> {
         let mut total_size = 0u64;
-        let mut lru = self.lru.write();
+        let mut tracker = self.tracker.write();
         let mut sizes = self.sizes.write();
 
         if let Ok(entries) = fs::read_dir(&self.cache_dir) {
             for entry in entries.flatten() {
                 if let Ok(metadata) = entry.metadata() {
                     if metadata.is_file() {
+                        let path = entry.path();
+                        if path.extension().is_some_and(|ext| ext == TMP_EXTENSION) {
+                            if !tmp_writer_alive(&path) {
+                                let _ = fs::remove_file(&path);
+                            }
+                            continue;
+                        }
                         if let Some(name) = entry.file_name().to_str() {
-                            let size = metadata.len();
+                            if !shared::is_entry_name(name) {
+                                continue;
+                            }
+                            let size = entry::payload_len(&path).unwrap_or(metadata.len());
                             total_size += size;
-                            lru.insert(name.to_string());
+                            tracker.insert(name);
                             sizes.insert(name.to_string(), size);
                         }
                     }
@@ -99,48 +292,343 @@ impl ChunkCache {
     }
 
     /// Get a chunk from cache
+    ///
+    /// A damaged entry is quarantined and reported as a miss.
     pub fn get(&self, chunk_id: &str) -> Result<Option<Vec<u8>>> {
         let path = self.chunk_path(chunk_id);
 
-        if !path.exists() {
-            return Ok(None);
-        }
+        let mut file = match fs::read(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                self.forget(chunk_id);
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        };
 
-        // Update LRU
-        self.lru.write().touch(&chunk_id.to_string());
+        let payload_len = match entry::verify(chunk_id, &file) {
+            Ok(payload) => payload.len(),
+            Err(corruption) => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                self.quarantine(chunk_id, corruption);
+                return Ok(None);
+            }
+        };
+        let data = file.split_off(file.len() - payload_len);
+        self.verified.write().insert(
+            chunk_id.to_string(),
+            (file.len() as u64, payload_len as u64),
+        );
 
-        // Read file
-        let mut file = File::open(&path)?;
-        let mut data = Vec::new();
-        file.read_to_end(&mut data)?;
+        self.record_hit(chunk_id, data.len());
+        if !self.is_pinned(chunk_id) {
+            self.tracker.write().access(chunk_id);
+        }
+        self.touch(&path);
 
         debug!("Cache hit: {} ({} bytes)", chunk_id, data.len());
         Ok(Some(data))
     }
 
+    /// Move a damaged entry aside and stop tracking it
+    fn quarantine(&self, chunk_id: &str, corruption: Corruption) {
+        warn!("Quarantining damaged cache entry {}: {}", chunk_id, corruption);
+        let path = self.chunk_path(chunk_id);
+        let dir = self.cache_dir.join(QUARANTINE_DIR);
+        let target = dir.join(chunk_id);
+        let replaced = fs::metadata(&target).map(|m| m.len()).unwrap_or(0);
+        let mut moved = None;
+        let _ = self.delete_entry(&path, |path| {
+            let len = fs::metadata(path)?.len();
+            match fs::create_dir_all(&dir).and_then(|_| fs::rename(path, &target)) {
+                Ok(()) => moved = Some(len),
+                Err(_) => fs::remove_file(path)?,
+            }
+            Ok(())
+        });
+        if let Some(len) = moved {
+            // Age quarantined entries from when they were moved
+            let _ = File::open(&target).and_then(|file| file.set_modified(SystemTime::now()));
+            if let Some(shared) = &self.shared {
+                if let Ok(mut budget) = shared.lock() {
+                    budget.sub(replaced);
+                    budget.add(len);
+                }
+            }
+        }
+        self.forget(chunk_id);
+        self.quarantined.fetch_add(1, Ordering::Relaxed);
+        self.prune_quarantine(QUARANTINE_KEEP);
+    }
+
+    /// Delete all but the `keep` most recently quarantined entries
+    fn prune_quarantine(&self, keep: usize) {
+        let mut files: Vec<_> = fs::read_dir(self.cache_dir.join(QUARANTINE_DIR))
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter_map(|entry| {
+                let metadata = entry.metadata().ok().filter(|m| m.is_file())?;
+                let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                Some((modified, metadata.len(), entry.path()))
+            })
+            .collect();
+        files.sort_by_key(|&(modified, ..)| std::cmp::Reverse(modified));
+
+        let (mut kept, mut deleted) = (0u64, 0u64);
+        for (i, (_, len, path)) in files.into_iter().enumerate() {
+            if i >= keep && fs::remove_file(&path).is_ok() {
+                debug!("Deleted quarantined entry {}", path.display());
+                deleted += len;
+            } else {
+                kept += len;
+            }
+        }
+        self.quarantine_size.store(kept, Ordering::SeqCst);
+        if let (Some(shared), true) = (&self.shared, deleted > 0) {
+            if let Ok(mut budget) = shared.lock() {
+                budget.sub(deleted);
+            }
+        }
+    }
+
+    /// Read part of a cached chunk
+    ///
+    /// Only the pages overlapping the range are read from the entry file,
+    /// and recently read pages are served from memory. The entry is
+    /// verified in full the first time it is read after the cache is opened.
+    ///
+    /// Pages read from disk later are not checked again: the checksum covers
+    /// the whole payload, so damage to an entry after its first read goes
+    /// unnoticed here until [`ChunkCache::get`] reads it whole.
+    pub fn read(&self, chunk_id: &str, offset: u64, len: usize) -> Result<Option<Vec<u8>>> {
+        let Some((start, payload_len)) = self.verified_entry(chunk_id)? else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return Ok(None);
+        };
+
+        let end = payload_len.min(offset.saturating_add(len as u64));
+        let mut data = Vec::with_capacity(end.saturating_sub(offset) as usize);
+        let mut file = None;
+        let mut page = offset / PAGE_SIZE;
+        while page * PAGE_SIZE < end {
+            let page_start = page * PAGE_SIZE;
+            let cached = self.pages.lock().get(chunk_id, page);
+            let contents = match cached {
+                Some(contents) => contents,
+                None => {
+                    if file.is_none() {
+                        let path = self.chunk_path(chunk_id);
+                        file = match File::open(&path) {
+                            Ok(file) => {
+                                self.touch(&path);
+                                Some(file)
+                            }
+                            Err(e) if e.kind() == ErrorKind::NotFound => {
+                                self.misses.fetch_add(1, Ordering::Relaxed);
+                                self.forget(chunk_id);
+                                return Ok(None);
+                            }
+                            Err(e) => return Err(e.into()),
+                        };
+                    }
+                    let file = file.as_ref().unwrap();
+                    let mut buf = vec![0u8; PAGE_SIZE.min(payload_len - page_start) as usize];
+                    match file.read_exact_at(&mut buf, start + page_start) {
+                        Ok(()) => {}
+                        Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                            self.misses.fetch_add(1, Ordering::Relaxed);
+                            self.quarantine(chunk_id, Corruption::Truncated);
+                            return Ok(None);
+                        }
+                        Err(e) => return Err(e.into()),
+                    }
+                    let contents = Arc::new(buf);
+                    self.pages.lock().insert(chunk_id, page, contents.clone());
+                    contents
+                }
+            };
+
+            let from = offset.saturating_sub(page_start) as usize;
+            let to = (end - page_start).min(contents.len() as u64) as usize;
+            data.extend_from_slice(&contents[from..to]);
+            page += 1;
+        }
+
+        self.record_hit(chunk_id, data.len());
+        if !self.is_pinned(chunk_id) {
+            self.tracker.write().access(chunk_id);
+        }
+        Ok(Some(data))
+    }
+
+    /// Payload offset and length of an entry, verifying it on first use
+    fn verified_entry(&self, chunk_id: &str) -> Result<Option<(u64, u64)>> {
+        if let Some(extent) = self.verified.read().get(chunk_id) {
+            return Ok(Some(*extent));
+        }
+
+        let mut file = match File::open(self.chunk_path(chunk_id)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                self.forget(chunk_id);
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        match entry::verify_file(chunk_id, &mut file)? {
+            Ok(start) => {
+                let extent = (start, file.metadata()?.len() - start);
+                self.verified.write().insert(chunk_id.to_string(), extent);
+                Ok(Some(extent))
+            }
+            Err(corruption) => {
+                self.quarantine(chunk_id, corruption);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Stop tracking an entry that is no longer on disk
+    fn forget(&self, chunk_id: &str) {
+        self.drop_contents(chunk_id);
+        self.discard_prefetched(chunk_id);
+        if let Some(size) = self.sizes.write().remove(chunk_id) {
+            self.current_size.fetch_sub(size, Ordering::SeqCst);
+            self.tracker.write().remove(chunk_id);
+        }
+    }
+
+    /// Count a read served from the cache
+    fn record_hit(&self, chunk_id: &str, bytes: usize) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        self.bytes_served.fetch_add(bytes as u64, Ordering::Relaxed);
+        if self.prefetched.lock().remove(chunk_id) {
+            self.prefetch_used.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Note that the prefetcher cached a chunk ahead of its reader
+    pub fn mark_prefetched(&self, chunk_id: &str) {
+        if self.prefetched.lock().insert(chunk_id.to_string()) {
+            self.prefetch_count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Count a prefetched chunk leaving the cache unread
+    fn discard_prefetched(&self, chunk_id: &str) {
+        if self.prefetched.lock().remove(chunk_id) {
+            self.prefetch_wasted.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Mark an entry as recently used across a shared cache
+    ///
+    /// Mounts sharing a directory evict by modification time.
+    fn touch(&self, path: &Path) {
+        if self.shared.is_some() {
+            let _ = File::open(path).and_then(|file| file.set_modified(SystemTime::now()));
+        }
+    }
+
+    /// Delete (or move away) an entry file, updating a shared budget
+    fn delete_entry(&self, path: &Path, delete: impl FnOnce(&Path) -> std::io::Result<()>) -> Result<()> {
+        let Some(shared) = &self.shared else {
+            return Ok(delete(path)?);
+        };
+        let mut budget = shared.lock()?;
+        let size = match entry::payload_len(path) {
+            Ok(size) => size,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        delete(path)?;
+        budget.sub(size);
+        Ok(())
+    }
+
+    /// Drop what is known about an entry's contents
+    fn drop_contents(&self, chunk_id: &str) {
+        self.verified.write().remove(chunk_id);
+        self.pages.lock().remove_chunk(chunk_id);
+    }
+
     /// Put a chunk in cache
+    ///
+    /// The entry is written to a temporary file and renamed into place, so
+    /// a crash never leaves a partially written entry under the chunk ID.
     pub fn put(&self, chunk_id: &str, data: &[u8]) -> Result<()> {
         let size = data.len() as u64;
 
-        // Evict if necessary
-        self.ensure_space(size)?;
+        // Evict if necessary (a shared cache evicts as the entry is renamed in)
+        if self.shared.is_none() {
+            self.ensure_space(size)?;
+        }
 
         let path = self.chunk_path(chunk_id);
+        let seq = self.tmp_seq.fetch_add(1, Ordering::Relaxed);
+        let tmp = self.cache_dir.join(format!(
+            "{}.{}-{}.{}",
+            chunk_id,
+            std::process::id(),
+            seq,
+            TMP_EXTENSION
+        ));
 
         // Write file
-        let mut file = File::create(&path)?;
-        file.write_all(data)?;
-        file.sync_all()?;
+        let written = File::create(&tmp).and_then(|mut file| {
+            file.write_all(&entry::header(data))?;
+            file.write_all(data)?;
+            file.sync_all()
+        });
+        self.drop_contents(chunk_id);
+        let renamed = written
+            .map_err(Error::from)
+            .and_then(|_| self.commit(&tmp, &path, size));
+        if let Err(e) = renamed {
+            let _ = fs::remove_file(&tmp);
+            return Err(e);
+        }
+        self.verified
+            .write()
+            .insert(chunk_id.to_string(), (entry::HEADER_LEN as u64, size));
 
-        // Update tracking
-        self.lru.write().insert(chunk_id.to_string());
-        self.sizes.write().insert(chunk_id.to_string(), size);
+        // Update tracking (replacing an existing copy)
+        if !self.is_pinned(chunk_id) {
+            self.tracker.write().insert(chunk_id);
+        }
+        if let Some(old) = self.sizes.write().insert(chunk_id.to_string(), size) {
+            self.current_size.fetch_sub(old, Ordering::SeqCst);
+        }
         self.current_size.fetch_add(size, Ordering::SeqCst);
 
         debug!("Cached: {} ({} bytes)", chunk_id, size);
         Ok(())
     }
 
+    /// Rename a written entry into place
+    ///
+    /// In a shared cache, entries of any domain are evicted first if the
+    /// entry does not fit the host-wide budget.
+    fn commit(&self, tmp: &Path, path: &Path, size: u64) -> Result<()> {
+        let Some(shared) = &self.shared else {
+            return Ok(fs::rename(tmp, path)?);
+        };
+        let mut budget = shared.lock()?;
+        for evicted in budget.make_room(size, self.max_size)? {
+            if evicted.domain == shared.domain() {
+                self.forget(&evicted.chunk_id);
+            }
+            self.evictions.fetch_add(1, Ordering::Relaxed);
+        }
+        let replaced = entry::payload_len(path).ok();
+        fs::rename(tmp, path)?;
+        budget.sub(replaced.unwrap_or(0));
+        budget.add(size);
+        Ok(())
+    }
+
     /// Remove a chunk from cache
     pub fn remove(&self, chunk_id: &str) -> Result<()> {
         let path = self.chunk_path(chunk_id);
@@ -149,8 +637,10 @@ impl ChunkCache {
             if let Some(size) = self.sizes.write().remove(chunk_id) {
                 self.current_size.fetch_sub(size, Ordering::SeqCst);
             }
-            self.lru.write().remove(&chunk_id.to_string());
-            fs::remove_file(&path)?;
+            self.tracker.write().remove(chunk_id);
+            self.drop_contents(chunk_id);
+            self.discard_prefetched(chunk_id);
+            self.delete_entry(&path, |path| fs::remove_file(path))?;
             debug!("Removed from cache: {}", chunk_id);
         }
 
@@ -159,29 +649,30 @@ impl ChunkCache {
 
     /// Ensure we have space for new data
     fn ensure_space(&self, needed: u64) -> Result<()> {
-        let mut current = self.current_size.load(Ordering::SeqCst);
+        // Quarantined entries cannot be evicted but still take up room
+        let held = self.quarantine_size.load(Ordering::SeqCst);
+        let mut current = self.current_size.load(Ordering::SeqCst) + held;
 
         while current + needed > self.max_size {
-            // Evict oldest
-            let to_evict = {
-                let mut lru = self.lru.write();
-                lru.pop_oldest()
-            };
+            let to_evict = self.tracker.write().evict();
 
             match to_evict {
                 Some(chunk_id) => {
                     let size = self.sizes.write().remove(&chunk_id).unwrap_or(0);
+                    self.drop_contents(&chunk_id);
+                    self.discard_prefetched(&chunk_id);
                     let path = self.chunk_path(&chunk_id);
                     if path.exists() {
                         fs::remove_file(&path)?;
                     }
                     self.current_size.fetch_sub(size, Ordering::SeqCst);
-                    current = self.current_size.load(Ordering::SeqCst);
+                    self.evictions.fetch_add(1, Ordering::Relaxed);
+                    current = self.current_size.load(Ordering::SeqCst) + held;
                     debug!("Evicted from cache: {} ({} bytes)", chunk_id, size);
                 }
                 None => {
                     // Cache is empty but still can't fit
-                    if needed > self.max_size {
+                    if held + needed > self.max_size {
                         return Err(Error::CacheFull);
                     }
                     break;
@@ -192,22 +683,64 @@ impl ChunkCache {
         Ok(())
     }
 
-    /// Queue chunks for prefetching
-    pub fn queue_prefetch(&self, chunk_ids: Vec<String>) {
+    /// Exempt exactly these chunks from eviction
+    ///
+    /// Chunks no longer pinned become evictable again.
+    pub fn set_pinned(&self, chunk_ids: HashSet<String>) {
+        let mut pinned = self.pinned.write();
+        let mut tracker = self.tracker.write();
+        let sizes = self.sizes.read();
+        for id in pinned.difference(&chunk_ids) {
+            if sizes.contains_key(id) {
+                tracker.insert(id);
+            }
+        }
+        for id in chunk_ids.difference(&pinned) {
+            tracker.remove(id);
+        }
+        if let Some(shared) = &self.shared {
+            if let Err(e) = shared.set_pinned(&chunk_ids) {
+                warn!("Failed to publish pinned chunks: {}", e);
+            }
+        }
+        *pinned = chunk_ids;
+    }
+
+    /// Check if a chunk is exempt from eviction
+    pub fn is_pinned(&self, chunk_id: &str) -> bool {
+        self.pinned.read().contains(chunk_id)
+    }
+
+    /// Bytes held by cached pinned chunks
+    pub fn pinned_bytes(&self) -> u64 {
+        let pinned = self.pinned.read();
+        self.sizes
+            .read()
+            .iter()
+            .filter(|(id, _)| pinned.contains(*id))
+            .map(|(_, size)| size)
+            .sum()
+    }
+
+    /// Queue chunks for prefetching, returning how many were added
+    pub fn queue_prefetch(&self, chunks: Vec<ChunkRef>) -> usize {
         if !self.prefetch_enabled {
-            return;
+            return 0;
         }
 
         let mut queue = self.prefetch_queue.write();
-        for id in chunk_ids {
-            if !self.contains(&id) && !queue.contains(&id) {
-                queue.push_back(id);
+        let mut queued = 0;
+        for chunk in chunks {
+            if !self.contains(&chunk.id) && !queue.iter().any(|c| c.id == chunk.id) {
+                queue.push_back(chunk);
+                queued += 1;
             }
         }
+        queued
     }
 
     /// Get next chunk to prefetch
-    pub fn next_prefetch(&self) -> Option<String> {
+    pub fn next_prefetch(&self) -> Option<ChunkRef> {
         self.prefetch_queue.write().pop_front()
     }
 
@@ -223,28 +756,56 @@ impl ChunkCache {
 
     /// Get cache statistics
     pub fn stats(&self) -> CacheStats {
+        let (memory_size, page_hits) = {
+            let pages = self.pages.lock();
+            (pages.bytes(), pages.hits())
+        };
         CacheStats {
             current_size: self.size(),
             max_size: self.max_size,
             chunk_count: self.count(),
             prefetch_queue_len: self.prefetch_queue.read().len(),
+            pinned_size: self.pinned_bytes(),
+            policy: self.policy,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            bytes_served: self.bytes_served.load(Ordering::Relaxed),
+            prefetched: self.prefetch_count.load(Ordering::Relaxed),
+            prefetch_used: self.prefetch_used.load(Ordering::Relaxed),
+            prefetch_wasted: self.prefetch_wasted.load(Ordering::Relaxed),
+            quarantined: self.quarantined(),
+            quarantine_size: self.quarantine_size.load(Ordering::Relaxed),
+            memory_size,
+            page_hits,
+            shared_size: self.shared.as_ref().and_then(|s| s.usage().ok()),
         }
     }
 
+    /// Damaged entries found in the cache since it was opened
+    pub fn quarantined(&self) -> u64 {
+        self.quarantined.load(Ordering::Relaxed)
+    }
+
     /// Clear the entire cache
     pub fn clear(&self) -> Result<()> {
         // Remove all files
         if let Ok(entries) = fs::read_dir(&self.cache_dir) {
             for entry in entries.flatten() {
                 if entry.metadata().map(|m| m.is_file()).unwrap_or(false) {
-                    let _ = fs::remove_file(entry.path());
+                    let _ = self.delete_entry(&entry.path(), |path| fs::remove_file(path));
                 }
             }
         }
 
+        self.prune_quarantine(0);
+
         // Reset tracking
-        self.lru.write().clear();
+        self.tracker.write().clear();
         self.sizes.write().clear();
+        self.verified.write().clear();
+        self.pages.lock().clear();
+        self.prefetched.lock().clear();
         self.current_size.store(0, Ordering::SeqCst);
 
         info!("Cache cleared");
@@ -252,13 +813,50 @@ impl ChunkCache {
     }
 }
 
+/// Whether the process writing a temporary entry is still running
+///
+/// Temporary names end in `.<pid>-<seq>.tmp`; any other is left over.
+fn tmp_writer_alive(path: &Path) -> bool {
+    path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| stem.rsplit('.').next())
+        .and_then(|writer| writer.split_once('-'))
+        .and_then(|(pid, _)| pid.parse().ok())
+        .is_some_and(|pid: i32| pid != std::process::id() as i32 && shared::process_alive(pid))
+}
+
 /// Cache statistics
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CacheStats {
     pub current_size: u64,
     pub max_size: u64,
     pub chunk_count: usize,
     pub prefetch_queue_len: usize,
+    /// Bytes held by pinned chunks
+    pub pinned_size: u64,
+    /// Eviction policy the counters were collected under
+    pub policy: EvictionPolicy,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// Bytes returned by cache hits
+    pub bytes_served: u64,
+    /// Chunks cached by the prefetcher
+    pub prefetched: u64,
+    /// Prefetched chunks later read
+    pub prefetch_used: u64,
+    /// Prefetched chunks dropped before being read
+    pub prefetch_wasted: u64,
+    /// Damaged entries moved to quarantine
+    pub quarantined: u64,
+    /// Bytes held by quarantined entries
+    pub quarantine_size: u64,
+    /// Memory held by recently read pages
+    pub memory_size: u64,
+    /// Pages served from memory
+    pub page_hits: u64,
+    /// Bytes cached by every key domain (for a shared cache)
+    pub shared_size: Option<u64>,
 }
 
 impl CacheStats {
@@ -267,7 +865,26 @@ impl CacheStats {
         if self.max_size == 0 {
             0.0
         } else {
-            (self.current_size as f64 / self.max_size as f64) * 100.0
+            ((self.current_size + self.quarantine_size) as f64 / self.max_size as f64) * 100.0
+        }
+    }
+
+    /// Fraction of reads served from the cache
+    pub fn hit_ratio(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+
+    /// Fraction of prefetched chunks that were read
+    pub fn prefetch_effectiveness(&self) -> f64 {
+        if self.prefetched == 0 {
+            0.0
+        } else {
+            self.prefetch_used as f64 / self.prefetched as f64
         }
     }
 }
@@ -275,6 +892,7 @@ impl CacheStats {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::fs::testing::chunk_ref;
     use std::path::Path;
     use tempfile::TempDir;
 
@@ -284,7 +902,13 @@ mod tests {
             cache_dir: dir.to_path_buf(),
             prefetch_enabled: true,
             prefetch_count: 3,
+            prefetch_workers: crate::config::DEFAULT_PREFETCH_WORKERS,
             eviction_policy: crate::config::EvictionPolicy::Lru,
+            inode_cache_size: crate::config::DEFAULT_INODE_CACHE_SIZE,
+            negative_cache_entries: crate::config::DEFAULT_NEGATIVE_CACHE_ENTRIES,
+            memory_cache_size: crate::config::DEFAULT_MEMORY_CACHE_SIZE,
+            access_history_size: 0,
+            shared: false,
         }
     }
 
@@ -366,17 +990,257 @@ mod tests {
         assert!(cache.contains("chunk3"));
     }
 
+    #[test]
+    fn test_policy_stats() {
+        let temp = TempDir::new().unwrap();
+        let mut config = test_config(temp.path());
+        config.max_size = 100;
+        config.eviction_policy = EvictionPolicy::Arc;
+        let cache = ChunkCache::new(&config).unwrap();
+
+        cache.put("hot", &[0u8; 30]).unwrap();
+        cache.get("hot").unwrap();
+        // A scan of chunks read once does not displace the re-read one
+        for i in 0..5 {
+            cache.put(&format!("scan{}", i), &[0u8; 30]).unwrap();
+        }
+        assert!(cache.get("hot").unwrap().is_some());
+        assert!(cache.get("scan0").unwrap().is_none());
+
+        let stats = cache.stats();
+        assert_eq!(stats.policy, EvictionPolicy::Arc);
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (2, 1, 3));
+        assert!((stats.hit_ratio() - 2.0 / 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_prefetch_effectiveness() {
+        let temp = TempDir::new().unwrap();
+        let mut config = test_config(temp.path());
+        config.max_size = 100;
+        let cache = ChunkCache::new(&config).unwrap();
+
+        for id in ["next1", "next2"] {
+            cache.put(id, &[0u8; 40]).unwrap();
+            cache.mark_prefetched(id);
+        }
+        assert_eq!(cache.read("next1", 10, 20).unwrap().unwrap().len(), 20);
+        // next2 is evicted without ever being read
+        cache.put("other", &[0u8; 40]).unwrap();
+
+        let stats = cache.stats();
+        assert_eq!((stats.prefetched, stats.prefetch_used, stats.prefetch_wasted), (2, 1, 1));
+        assert_eq!(stats.bytes_served, 20);
+        assert!((stats.prefetch_effectiveness() - 0.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_pinned_chunks_not_evicted() {
+        let temp = TempDir::new().unwrap();
+        let mut config = test_config(temp.path());
+        config.max_size = 100;
+        let cache = ChunkCache::new(&config).unwrap();
+
+        cache.put("pinned", &[0u8; 40]).unwrap();
+        cache.set_pinned(["pinned".to_string()].into_iter().collect());
+        cache.put("chunk1", &[0u8; 40]).unwrap();
+        cache.put("chunk2", &[0u8; 40]).unwrap();
+
+        assert!(cache.contains("pinned"));
+        assert!(!cache.contains("chunk1"));
+        assert_eq!(cache.stats().pinned_size, 40);
+
+        // Unpinned chunks are evictable again
+        cache.set_pinned(HashSet::new());
+        cache.put("chunk3", &[0u8; 40]).unwrap();
+        cache.put("chunk4", &[0u8; 40]).unwrap();
+        assert!(!cache.contains("pinned"));
+        assert_eq!(cache.stats().pinned_size, 0);
+    }
+
+    #[test]
+    fn test_corrupt_entry_quarantined() {
+        let temp = TempDir::new().unwrap();
+        let config = test_config(temp.path());
+        let cache = ChunkCache::new(&config).unwrap();
+
+        cache.put("chunk1", b"hello world").unwrap();
+        let path = temp.path().join("chunk1");
+        let mut file = fs::read(&path).unwrap();
+        *file.last_mut().unwrap() ^= 1;
+        fs::write(&path, file).unwrap();
+
+        assert!(cache.get("chunk1").unwrap().is_none());
+        assert!(!cache.contains("chunk1"));
+        assert!(temp.path().join(QUARANTINE_DIR).join("chunk1").exists());
+        assert_eq!((cache.size(), cache.count(), cache.quarantined()), (0, 0, 1));
+        let held = (entry::HEADER_LEN + 11) as u64;
+        assert_eq!(cache.stats().quarantine_size, held);
+    }
+
+    #[test]
+    fn test_quarantine_bounded() {
+        let temp = TempDir::new().unwrap();
+        let config = test_config(temp.path());
+        let cache = ChunkCache::new(&config).unwrap();
+
+        for i in 0..QUARANTINE_KEEP + 4 {
+            let chunk_id = format!("chunk{}", i);
+            cache.put(&chunk_id, b"hello world").unwrap();
+            fs::write(temp.path().join(&chunk_id), b"damaged").unwrap();
+            assert!(cache.get(&chunk_id).unwrap().is_none());
+        }
+
+        let kept = fs::read_dir(temp.path().join(QUARANTINE_DIR)).unwrap().count();
+        assert_eq!(kept, QUARANTINE_KEEP);
+        assert_eq!(cache.stats().quarantine_size, (QUARANTINE_KEEP * 7) as u64);
+
+        cache.clear().unwrap();
+        assert_eq!(cache.stats().quarantine_size, 0);
+    }
+
+    #[test]
+    fn test_index_reload() {
+        let temp = TempDir::new().unwrap();
+        let config = test_config(temp.path());
+        {
+            let cache = ChunkCache::new(&config).unwrap();
+            cache.put("chunk1", b"hello").unwrap();
+            cache.put("chunk2", b"world!").unwrap();
+            cache.save_index().unwrap();
+        }
+        assert!(temp.path().join(INDEX_FILE).exists());
+
+        // The index is consumed while the cache is open
+        let cache = ChunkCache::new(&config).unwrap();
+        assert!(!temp.path().join(INDEX_FILE).exists());
+        assert_eq!((cache.size(), cache.count()), (11, 2));
+        assert_eq!(cache.get("chunk2").unwrap().unwrap(), b"world!");
+    }
+
+    #[test]
+    fn test_scan_after_crash() {
+        let temp = TempDir::new().unwrap();
+        let config = test_config(temp.path());
+        let cache = ChunkCache::new(&config).unwrap();
+        cache.put("chunk1", b"hello").unwrap();
+        // Only an unmount writes the index; leave an interrupted write behind
+        drop(cache);
+        assert!(!temp.path().join(INDEX_FILE).exists());
+        fs::write(temp.path().join("chunk2.0.tmp"), b"partial").unwrap();
+
+        let cache = ChunkCache::new(&config).unwrap();
+        assert_eq!((cache.size(), cache.count()), (5, 1));
+        assert!(!temp.path().join("chunk2.0.tmp").exists());
+        assert_eq!(cache.get("chunk1").unwrap().unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_shared_budget() {
+        let temp = TempDir::new().unwrap();
+        let mut config = test_config(temp.path());
+        config.max_size = 100;
+        let a = ChunkCache::shared(&config, "domain-a").unwrap();
+        let b = ChunkCache::shared(&config, "domain-b").unwrap();
+
+        // The same chunk is kept apart per key domain
+        a.put("chunk1", &[1u8; 40]).unwrap();
+        // Eviction order follows modification times
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        b.put("chunk1", &[2u8; 40]).unwrap();
+        assert_eq!(b.get("chunk1").unwrap().unwrap(), [2u8; 40]);
+        assert_eq!(a.stats().shared_size, Some(80));
+
+        // One budget: a write by one mount evicts the other's older entry
+        b.put("chunk2", &[2u8; 40]).unwrap();
+        assert!(a.get("chunk1").unwrap().is_none());
+        assert!(b.contains("chunk1"));
+        assert_eq!((a.size(), a.count()), (0, 0));
+        assert_eq!(b.stats().shared_size, Some(80));
+
+        b.remove("chunk2").unwrap();
+        assert_eq!(a.stats().shared_size, Some(40));
+    }
+
+    #[test]
+    fn test_shared_domain_rescanned() {
+        let temp = TempDir::new().unwrap();
+        let config = test_config(temp.path());
+
+        // Two mounts of one domain each know only their own writes
+        let first = ChunkCache::shared(&config, "domain").unwrap();
+        let second = ChunkCache::shared(&config, "domain").unwrap();
+        first.put("chunk1", b"hello").unwrap();
+        second.put("chunk2", b"world!").unwrap();
+        drop(first);
+        drop(second);
+
+        let cache = ChunkCache::shared(&config, "domain").unwrap();
+        assert!(!cache.cache_dir.join(INDEX_FILE).exists());
+        assert_eq!((cache.size(), cache.count()), (11, 2));
+    }
+
+    #[test]
+    fn test_range_read() {
+        let temp = TempDir::new().unwrap();
+        let mut config = test_config(temp.path());
+        config.max_size = 1 << 20;
+        let data: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+        {
+            let cache = ChunkCache::new(&config).unwrap();
+            cache.put("chunk1", &data).unwrap();
+        }
+
+        // Reopened, so the entry is verified on first read
+        let cache = ChunkCache::new(&config).unwrap();
+        let range = cache.read("chunk1", 65_000, 2_000).unwrap().unwrap();
+        assert_eq!(range, &data[65_000..67_000]);
+        assert_eq!(cache.stats().memory_size, 2 * PAGE_SIZE);
+
+        // Served from memory, clamped to the chunk
+        let tail = cache.read("chunk1", 199_000, 4096).unwrap().unwrap();
+        assert_eq!(tail, &data[199_000..]);
+        assert_eq!(cache.read("chunk1", 65_500, 10).unwrap().unwrap(), &data[65_500..65_510]);
+        assert_eq!(cache.stats().page_hits, 1);
+
+        cache.remove("chunk1").unwrap();
+        assert!(cache.read("chunk1", 0, 10).unwrap().is_none());
+        assert_eq!(cache.stats().memory_size, 0);
+    }
+
+    #[test]
+    fn test_range_read_corrupt() {
+        let temp = TempDir::new().unwrap();
+        let config = test_config(temp.path());
+        {
+            let cache = ChunkCache::new(&config).unwrap();
+            cache.put("chunk1", b"hello world").unwrap();
+        }
+        let path = temp.path().join("chunk1");
+        let mut file = fs::read(&path).unwrap();
+        file[entry::HEADER_LEN] ^= 1;
+        fs::write(&path, file).unwrap();
+
+        let cache = ChunkCache::new(&config).unwrap();
+        assert!(cache.read("chunk1", 6, 5).unwrap().is_none());
+        assert_eq!((cache.count(), cache.quarantined()), (0, 1));
+    }
+
     #[test]
     fn test_prefetch_queue() {
         let temp = TempDir::new().unwrap();
         let config = test_config(temp.path());
         let cache = ChunkCache::new(&config).unwrap();
 
-        cache.queue_prefetch(vec!["a".to_string(), "b".to_string(), "c".to_string()]);
+        let chunk = |id: &str| chunk_ref(id, 1);
+        cache.put("cached", b"data").unwrap();
+
+        let queued = cache.queue_prefetch(vec![chunk("a"), chunk("b"), chunk("a"), chunk("cached"), chunk("c")]);
+        assert_eq!(queued, 3);
 
-        assert_eq!(cache.next_prefetch(), Some("a".to_string()));
-        assert_eq!(cache.next_prefetch(), Some("b".to_string()));
-        assert_eq!(cache.next_prefetch(), Some("c".to_string()));
-        assert_eq!(cache.next_prefetch(), None);
+        assert_eq!(cache.next_prefetch().map(|c| c.id), Some("a".to_string()));
+        assert_eq!(cache.next_prefetch().map(|c| c.id), Some("b".to_string()));
+        assert_eq!(cache.next_prefetch().map(|c| c.id), Some("c".to_string()));
+        assert!(cache.next_prefetch().is_none());
     }
 }
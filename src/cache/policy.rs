@@ -0,0 +1,402 @@
+//! Eviction policies for the chunk cache
+//!
+//! The cache tracks keys only; it asks the policy which key to drop next
+//! until the new chunk fits. ARC (adaptive replacement) keeps chunks that
+//! were read more than once in a separate list, so a single large scan
+//! cannot flush the working set.
+
+use super::LruCache;
+use crate::config::EvictionPolicy;
+use std::collections::{BTreeSet, HashMap};
+
+/// Chooses which cached chunk to evict
+pub trait EvictionTracker: Send + Sync {
+    /// Track a newly cached key
+    fn insert(&mut self, key: &str);
+    /// Record a cache hit
+    fn access(&mut self, key: &str);
+    /// Stop tracking a key
+    fn remove(&mut self, key: &str);
+    /// Pick and stop tracking the next key to evict
+    fn evict(&mut self) -> Option<String>;
+    /// Number of tracked keys
+    fn len(&self) -> usize;
+    /// Check if nothing is tracked
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Forget everything
+    fn clear(&mut self);
+}
+
+/// Create the tracker for a policy
+pub fn tracker_for(policy: EvictionPolicy) -> Box<dyn EvictionTracker> {
+    match policy {
+        EvictionPolicy::Lru => Box::new(LruTracker::default()),
+        EvictionPolicy::Lfu => Box::new(LfuTracker::default()),
+        EvictionPolicy::Fifo => Box::new(FifoTracker::default()),
+        EvictionPolicy::Arc => Box::new(ArcTracker::default()),
+    }
+}
+
+/// Least recently used
+#[derive(Default)]
+pub struct LruTracker {
+    lru: LruCache<String>,
+}
+
+impl EvictionTracker for LruTracker {
+    fn insert(&mut self, key: &str) {
+        self.lru.insert(key.to_string());
+    }
+
+    fn access(&mut self, key: &str) {
+        self.lru.touch(&key.to_string());
+        self.lru.compact_stale();
+    }
+
+    fn remove(&mut self, key: &str) {
+        self.lru.remove(&key.to_string());
+        self.lru.compact_stale();
+    }
+
+    fn evict(&mut self) -> Option<String> {
+        self.lru.pop_oldest()
+    }
+
+    fn len(&self) -> usize {
+        self.lru.len()
+    }
+
+    fn clear(&mut self) {
+        self.lru.clear();
+    }
+}
+
+/// First in, first out (hits do not change the order)
+#[derive(Default)]
+pub struct FifoTracker {
+    order: LruCache<String>,
+}
+
+impl EvictionTracker for FifoTracker {
+    fn insert(&mut self, key: &str) {
+        self.order.insert(key.to_string());
+    }
+
+    fn access(&mut self, _key: &str) {}
+
+    fn remove(&mut self, key: &str) {
+        self.order.remove(&key.to_string());
+        self.order.compact_stale();
+    }
+
+    fn evict(&mut self) -> Option<String> {
+        self.order.pop_oldest()
+    }
+
+    fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    fn clear(&mut self) {
+        self.order.clear();
+    }
+}
+
+/// Least frequently used (ties go to the least recently used)
+#[derive(Default)]
+pub struct LfuTracker {
+    /// Key -> (hit count, last use)
+    counts: HashMap<String, (u64, u64)>,
+    /// Keys ordered by (hit count, last use)
+    order: BTreeSet<(u64, u64, String)>,
+    /// Use counter
+    tick: u64,
+}
+
+impl LfuTracker {
+    fn set(&mut self, key: &str, count: u64) {
+        self.tick += 1;
+        if let Some((old_count, old_tick)) = self.counts.insert(key.to_string(), (count, self.tick)) {
+            self.order.remove(&(old_count, old_tick, key.to_string()));
+        }
+        self.order.insert((count, self.tick, key.to_string()));
+    }
+}
+
+impl EvictionTracker for LfuTracker {
+    fn insert(&mut self, key: &str) {
+        let count = self.counts.get(key).map_or(1, |(c, _)| c + 1);
+        self.set(key, count);
+    }
+
+    fn access(&mut self, key: &str) {
+        if let Some(&(count, _)) = self.counts.get(key) {
+            self.set(key, count + 1);
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some((count, tick)) = self.counts.remove(key) {
+            self.order.remove(&(count, tick, key.to_string()));
+        }
+    }
+
+    fn evict(&mut self) -> Option<String> {
+        let (_, _, key) = self.order.pop_first()?;
+        self.counts.remove(&key);
+        Some(key)
+    }
+
+    fn len(&self) -> usize {
+        self.counts.len()
+    }
+
+    fn clear(&mut self) {
+        self.counts.clear();
+        self.order.clear();
+    }
+}
+
+/// Adaptive replacement cache
+///
+/// Resident keys live in `t1` (seen once) or `t2` (seen again); evicted
+/// keys are remembered in the ghost lists `b1`/`b2`. A ghost hit moves the
+/// target size `p` of `t1` towards whichever list would have kept the key.
+/// Capacity is counted in entries and follows the number of resident keys,
+/// since the cache itself is bounded by bytes.
+#[derive(Default)]
+pub struct ArcTracker {
+    t1: LruCache<String>,
+    t2: LruCache<String>,
+    b1: LruCache<String>,
+    b2: LruCache<String>,
+    /// Target size of `t1`
+    p: usize,
+}
+
+impl ArcTracker {
+    fn contains(list: &LruCache<String>, key: &str) -> bool {
+        list.contains(&key.to_string())
+    }
+
+    /// Drop stale entries left in any list by moves between lists
+    fn compact(&mut self) {
+        for list in [&mut self.t1, &mut self.t2, &mut self.b1, &mut self.b2] {
+            list.compact_stale();
+        }
+    }
+
+    /// Keep the ghost lists no larger than the resident set
+    fn trim_ghosts(&mut self) {
+        let capacity = self.len().max(1);
+        while self.b1.len() + self.b2.len() > capacity {
+            if self.b1.len() > self.b2.len() {
+                self.b1.pop_oldest();
+            } else {
+                self.b2.pop_oldest();
+            }
+        }
+    }
+}
+
+impl EvictionTracker for ArcTracker {
+    fn insert(&mut self, key: &str) {
+        let owned = key.to_string();
+        if Self::contains(&self.t1, key) || Self::contains(&self.t2, key) {
+            self.access(key);
+            return;
+        }
+
+        let capacity = self.len().max(1);
+        if Self::contains(&self.b1, key) {
+            // Recently evicted after one use: favour recency
+            let delta = (self.b2.len() / self.b1.len()).max(1);
+            self.p = (self.p + delta).min(capacity);
+            self.b1.remove(&owned);
+            self.t2.insert(owned);
+        } else if Self::contains(&self.b2, key) {
+            // Evicted despite repeated use: favour frequency
+            let delta = (self.b1.len() / self.b2.len()).max(1);
+            self.p = self.p.saturating_sub(delta);
+            self.b2.remove(&owned);
+            self.t2.insert(owned);
+        } else {
+            self.t1.insert(owned);
+        }
+        self.trim_ghosts();
+        self.compact();
+    }
+
+    fn access(&mut self, key: &str) {
+        let owned = key.to_string();
+        if Self::contains(&self.t1, key) {
+            self.t1.remove(&owned);
+            self.t2.insert(owned);
+        } else if Self::contains(&self.t2, key) {
+            self.t2.touch(&owned);
+        }
+        self.compact();
+    }
+
+    fn remove(&mut self, key: &str) {
+        let owned = key.to_string();
+        self.t1.remove(&owned);
+        self.t2.remove(&owned);
+        self.compact();
+    }
+
+    fn evict(&mut self) -> Option<String> {
+        let from_t1 = !self.t1.is_empty() && (self.t1.len() > self.p || self.t2.is_empty());
+        let key = if from_t1 {
+            let key = self.t1.pop_oldest()?;
+            self.b1.insert(key.clone());
+            key
+        } else {
+            let key = self.t2.pop_oldest()?;
+            self.b2.insert(key.clone());
+            key
+        };
+        self.trim_ghosts();
+        self.compact();
+        Some(key)
+    }
+
+    fn len(&self) -> usize {
+        self.t1.len() + self.t2.len()
+    }
+
+    fn clear(&mut self) {
+        self.t1.clear();
+        self.t2.clear();
+        self.b1.clear();
+        self.b2.clear();
+        self.p = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run a workload through a tracker holding `capacity` entries
+    fn hit_ratio(policy: EvictionPolicy, capacity: usize, workload: &[String]) -> f64 {
+        let mut tracker = tracker_for(policy);
+        let mut resident = std::collections::HashSet::new();
+        let mut hits = 0;
+        for key in workload {
+            if resident.contains(key) {
+                tracker.access(key);
+                hits += 1;
+                continue;
+            }
+            while resident.len() >= capacity {
+                let evicted = tracker.evict().unwrap();
+                assert!(resident.remove(&evicted));
+            }
+            tracker.insert(key);
+            resident.insert(key.clone());
+        }
+        hits as f64 / workload.len() as f64
+    }
+
+    #[test]
+    fn test_lru_and_fifo_order() {
+        let mut lru = tracker_for(EvictionPolicy::Lru);
+        let mut fifo = tracker_for(EvictionPolicy::Fifo);
+        for tracker in [&mut lru, &mut fifo] {
+            tracker.insert("a");
+            tracker.insert("b");
+            tracker.access("a");
+        }
+        assert_eq!(lru.evict().as_deref(), Some("b"));
+        assert_eq!(fifo.evict().as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn test_lfu_evicts_least_frequent() {
+        let mut lfu = tracker_for(EvictionPolicy::Lfu);
+        lfu.insert("a");
+        lfu.insert("b");
+        lfu.insert("c");
+        lfu.access("a");
+        lfu.access("a");
+        lfu.access("c");
+
+        assert_eq!(lfu.evict().as_deref(), Some("b"));
+        assert_eq!(lfu.evict().as_deref(), Some("c"));
+        lfu.remove("a");
+        assert!(lfu.is_empty());
+        assert!(lfu.evict().is_none());
+    }
+
+    #[test]
+    fn test_arc_resists_scans() {
+        // A hot set of 4 chunks read repeatedly, interleaved with a long scan
+        let mut workload = Vec::new();
+        for round in 0..50 {
+            for hot in 0..4 {
+                workload.push(format!("hot{}", hot));
+            }
+            for scan in 0..4 {
+                workload.push(format!("scan{}", round * 4 + scan));
+            }
+        }
+
+        let lru = hit_ratio(EvictionPolicy::Lru, 6, &workload);
+        let arc = hit_ratio(EvictionPolicy::Arc, 6, &workload);
+        assert!(arc > 0.4, "ARC hit ratio {}", arc);
+        assert!(arc > lru, "ARC {} should beat LRU {}", arc, lru);
+    }
+
+    #[test]
+    fn test_order_stays_bounded() {
+        let keys = ["a", "b", "c"];
+        let bound = 4 * keys.len() + 1025;
+
+        let mut lru = LruTracker::default();
+        let mut fifo = FifoTracker::default();
+        let mut arc = ArcTracker::default();
+        for key in keys {
+            lru.insert(key);
+            fifo.insert(key);
+            arc.insert(key);
+        }
+        for i in 0..100_000 {
+            let key = keys[i % keys.len()];
+            lru.access(key);
+            arc.access(key);
+            // Removing and re-adding leaves stale entries too
+            fifo.remove(key);
+            fifo.insert(key);
+            if i % 7 == 0 {
+                arc.remove(key);
+                arc.insert(key);
+            }
+        }
+
+        assert!(lru.lru.order_len() <= bound);
+        assert!(fifo.order.order_len() <= bound);
+        for list in [&arc.t1, &arc.t2, &arc.b1, &arc.b2] {
+            assert!(list.order_len() <= bound);
+        }
+        assert_eq!((lru.len(), fifo.len(), arc.len()), (3, 3, 3));
+    }
+
+    #[test]
+    fn test_arc_ghost_hit_promotes() {
+        let mut arc = ArcTracker::default();
+        arc.insert("a");
+        arc.insert("b");
+        assert_eq!(arc.evict().as_deref(), Some("a"));
+
+        // "a" comes back while remembered: it is now frequent
+        arc.insert("a");
+        assert!(ArcTracker::contains(&arc.t2, "a"));
+        assert_eq!(arc.p, 1);
+        arc.insert("c");
+        assert_eq!(arc.evict().as_deref(), Some("b"));
+        assert_eq!(arc.len(), 2);
+    }
+}
@@ -0,0 +1,441 @@
+//! Host-wide shared cache directory
+//!
+//! Mounts on one host can share a cache directory and a single size budget.
+//! Entries live in one subdirectory per key domain (derived from the
+//! volume's master key), so chunks are only shared between mounts that
+//! could decrypt them anyway.
+//!
+//! ```text
+//! cache_dir/
+//!   lock                  flock'd while the budget changes
+//!   usage                 bytes cached across all domains
+//!   pins/<domain>.<pid>.<n>  chunks pinned by a running cache
+//!   <domain>/<chunk id>   entries
+//! ```
+//!
+//! Whoever creates or deletes an entry updates the usage ledger. When a
+//! write would exceed the budget, the least recently used entries of every
+//! domain (by modification time, bumped on reads) are evicted, except those
+//! pinned by a running mount. Other mounts notice that an entry is gone the
+//! next time they read it.
+
+use super::{entry, INDEX_FILE, QUARANTINE_DIR, TMP_EXTENSION};
+use crate::error::{Error, Result};
+use parking_lot::{Mutex, MutexGuard};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::ErrorKind;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+use tracing::{debug, warn};
+
+/// Lock file serializing budget changes
+const LOCK_FILE: &str = "lock";
+
+/// Ledger of bytes cached across all domains
+const USAGE_FILE: &str = "usage";
+
+/// Directory of per-cache pin lists
+const PINS_DIR: &str = "pins";
+
+/// Eviction frees space down to this fraction of the budget, so the
+/// directory is not rescanned on every write
+const LOW_WATERMARK: f64 = 0.9;
+
+/// Distinguishes pin lists of caches opened by the same process
+static INSTANCE_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// An entry removed to make room
+pub struct Evicted {
+    /// Key domain of the entry
+    pub domain: String,
+    /// Chunk ID of the entry
+    pub chunk_id: String,
+}
+
+/// Shared cache directory, seen from one key domain or the whole host
+pub struct SharedDir {
+    /// Shared cache directory
+    root: PathBuf,
+    /// This cache's key domain (empty for the host view)
+    domain: String,
+    /// Lock file, also serializing threads of this process
+    lock: Mutex<File>,
+    /// This cache's pin list
+    pins_file: Option<PathBuf>,
+}
+
+impl SharedDir {
+    /// Open the shared directory at `root`
+    pub fn open(root: &Path) -> Result<Self> {
+        fs::create_dir_all(root.join(PINS_DIR))?;
+        let lock = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(root.join(LOCK_FILE))?;
+
+        let shared = SharedDir {
+            root: root.to_path_buf(),
+            domain: String::new(),
+            lock: Mutex::new(lock),
+            pins_file: None,
+        };
+        // Recount if the ledger was lost
+        let mut locked = shared.lock()?;
+        if locked.usage.is_none() {
+            let total = shared.scan()?.iter().map(|e| e.size).sum();
+            locked.usage = Some(total);
+        }
+        drop(locked);
+        Ok(shared)
+    }
+
+    /// Keep entries in a key domain's subdirectory
+    pub fn with_domain(mut self, domain: &str) -> Result<Self> {
+        fs::create_dir_all(self.root.join(domain))?;
+        self.domain = domain.to_string();
+        self.pins_file = Some(self.root.join(PINS_DIR).join(format!(
+            "{}.{}.{}",
+            domain,
+            std::process::id(),
+            INSTANCE_SEQ.fetch_add(1, Ordering::Relaxed)
+        )));
+        Ok(self)
+    }
+
+    /// This cache's key domain
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    /// Directory holding this domain's entries
+    pub fn domain_dir(&self) -> PathBuf {
+        self.root.join(&self.domain)
+    }
+
+    /// Take the cross-process lock
+    pub fn lock(&self) -> Result<Budget<'_>> {
+        let file = self.lock.lock();
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let usage = match fs::read(self.root.join(USAGE_FILE)) {
+            Ok(data) if data.len() == 8 => Some(u64::from_le_bytes(data.try_into().unwrap())),
+            _ => None,
+        };
+        Ok(Budget {
+            file,
+            dir: self,
+            usage,
+        })
+    }
+
+    /// Bytes cached across all domains
+    pub fn usage(&self) -> Result<u64> {
+        Ok(self.lock()?.usage())
+    }
+
+    /// Number of key domains with entries
+    pub fn domains(&self) -> Result<usize> {
+        let domains: HashSet<String> = self.scan()?.into_iter().map(|e| e.domain).collect();
+        Ok(domains.len())
+    }
+
+    /// Delete every entry of every domain
+    pub fn clear(&self) -> Result<()> {
+        let mut budget = self.lock()?;
+        for e in self.scan()? {
+            let _ = fs::remove_file(e.path(&self.root));
+        }
+        budget.usage = Some(0);
+        Ok(())
+    }
+
+    /// Publish the chunks this cache must not have evicted
+    pub fn set_pinned(&self, chunk_ids: &HashSet<String>) -> Result<()> {
+        let Some(pins_file) = &self.pins_file else {
+            return Ok(());
+        };
+        let list: String = chunk_ids.iter().map(|id| format!("{}\n", id)).collect();
+        let mut tmp = pins_file.clone().into_os_string();
+        tmp.push(format!(".{}", TMP_EXTENSION));
+        fs::write(&tmp, list)?;
+        fs::rename(&tmp, pins_file)?;
+        Ok(())
+    }
+
+    /// Entries pinned by running caches, as (domain, chunk ID)
+    ///
+    /// Pin lists left by processes that have exited are removed.
+    fn pinned(&self) -> HashSet<(String, String)> {
+        let mut pinned = HashSet::new();
+        let Ok(lists) = fs::read_dir(self.root.join(PINS_DIR)) else {
+            return pinned;
+        };
+        for list in lists.flatten() {
+            let name = list.file_name().to_string_lossy().into_owned();
+            let mut parts = name.split('.');
+            let (Some(domain), Some(pid)) = (parts.next(), parts.next()) else {
+                continue;
+            };
+            if !pid.parse().is_ok_and(process_alive) {
+                let _ = fs::remove_file(list.path());
+                continue;
+            }
+            if let Ok(ids) = fs::read_to_string(list.path()) {
+                pinned.extend(ids.lines().map(|id| (domain.to_string(), id.to_string())));
+            }
+        }
+        pinned
+    }
+
+    /// Every entry of every domain
+    fn scan(&self) -> Result<Vec<SharedEntry>> {
+        let mut entries = Vec::new();
+        for domain in fs::read_dir(&self.root)?.flatten() {
+            let Some(domain_name) = domain.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            if domain_name == PINS_DIR || !domain.file_type().is_ok_and(|t| t.is_dir()) {
+                continue;
+            }
+            for file in fs::read_dir(domain.path())?.flatten() {
+                let Ok(metadata) = file.metadata() else {
+                    continue;
+                };
+                let Some(name) = file.file_name().to_str().map(str::to_string) else {
+                    continue;
+                };
+                if !metadata.is_file() || !is_entry_name(&name) {
+                    continue;
+                }
+                entries.push(SharedEntry {
+                    domain: domain_name.clone(),
+                    chunk_id: name,
+                    modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                    size: entry::payload_len(&file.path()).unwrap_or(metadata.len()),
+                    quarantined: false,
+                });
+            }
+            // Quarantined entries use up the budget too, and go first
+            let Ok(quarantine) = fs::read_dir(domain.path().join(QUARANTINE_DIR)) else {
+                continue;
+            };
+            for file in quarantine.flatten() {
+                let Ok(metadata) = file.metadata() else {
+                    continue;
+                };
+                let Some(name) = file.file_name().to_str().map(str::to_string) else {
+                    continue;
+                };
+                if metadata.is_file() {
+                    entries.push(SharedEntry {
+                        domain: domain_name.clone(),
+                        chunk_id: name,
+                        modified: SystemTime::UNIX_EPOCH,
+                        size: metadata.len(),
+                        quarantined: true,
+                    });
+                }
+            }
+        }
+        Ok(entries)
+    }
+}
+
+impl Drop for SharedDir {
+    fn drop(&mut self) {
+        if let Some(pins_file) = &self.pins_file {
+            let _ = fs::remove_file(pins_file);
+        }
+    }
+}
+
+/// An entry found while scanning the shared directory
+struct SharedEntry {
+    domain: String,
+    chunk_id: String,
+    modified: SystemTime,
+    size: u64,
+    /// Damaged entry kept in the domain's quarantine
+    quarantined: bool,
+}
+
+impl SharedEntry {
+    fn path(&self, root: &Path) -> PathBuf {
+        let dir = root.join(&self.domain);
+        if self.quarantined {
+            dir.join(QUARANTINE_DIR).join(&self.chunk_id)
+        } else {
+            dir.join(&self.chunk_id)
+        }
+    }
+}
+
+/// The shared budget, held under the cross-process lock
+///
+/// The ledger is written back and the lock released when dropped.
+pub struct Budget<'a> {
+    file: MutexGuard<'a, File>,
+    dir: &'a SharedDir,
+    /// Bytes cached across all domains (unknown if the ledger was lost)
+    usage: Option<u64>,
+}
+
+impl Budget<'_> {
+    /// Bytes cached across all domains
+    pub fn usage(&self) -> u64 {
+        self.usage.unwrap_or(0)
+    }
+
+    /// Account for an entry written
+    pub fn add(&mut self, size: u64) {
+        self.usage = Some(self.usage().saturating_add(size));
+    }
+
+    /// Account for an entry deleted
+    pub fn sub(&mut self, size: u64) {
+        self.usage = Some(self.usage().saturating_sub(size));
+    }
+
+    /// Evict entries until `needed` more bytes fit in `max_size`
+    ///
+    /// Least recently used entries go first, across every domain, and
+    /// eviction continues down to the low watermark. Pinned entries are
+    /// never evicted, so the entry may still go over budget.
+    pub fn make_room(&mut self, needed: u64, max_size: u64) -> Result<Vec<Evicted>> {
+        if needed > max_size {
+            return Err(Error::CacheFull);
+        }
+        if self.usage() + needed <= max_size {
+            return Ok(Vec::new());
+        }
+
+        let mut entries = self.dir.scan()?;
+        let mut total: u64 = entries.iter().map(|e| e.size).sum();
+        let target = ((max_size as f64 * LOW_WATERMARK) as u64).saturating_sub(needed);
+        let pinned = self.dir.pinned();
+        entries.sort_by_key(|e| e.modified);
+
+        let mut evicted = Vec::new();
+        for e in entries {
+            if total <= target {
+                break;
+            }
+            if !e.quarantined && pinned.contains(&(e.domain.clone(), e.chunk_id.clone())) {
+                continue;
+            }
+            match fs::remove_file(e.path(&self.dir.root)) {
+                Ok(()) => {}
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+            debug!("Evicted from shared cache: {}/{} ({} bytes)", e.domain, e.chunk_id, e.size);
+            total -= e.size;
+            if e.quarantined {
+                continue;
+            }
+            evicted.push(Evicted {
+                domain: e.domain,
+                chunk_id: e.chunk_id,
+            });
+        }
+
+        // The scan is exact, so it also corrects any drift in the ledger.
+        // Like the private cache, pinned entries may push it over budget.
+        self.usage = Some(total);
+        if total + needed > max_size {
+            debug!("Shared cache over budget, {} bytes are pinned", total);
+        }
+        Ok(evicted)
+    }
+}
+
+impl Drop for Budget<'_> {
+    fn drop(&mut self) {
+        if let Some(usage) = self.usage {
+            let path = self.dir.root.join(USAGE_FILE);
+            let tmp = path.with_extension(TMP_EXTENSION);
+            let written = fs::write(&tmp, usage.to_le_bytes()).and_then(|_| fs::rename(&tmp, &path));
+            if let Err(e) = written {
+                warn!("Failed to update shared cache usage: {}", e);
+            }
+        }
+        unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN) };
+    }
+}
+
+/// Whether a file in an entry directory is a cache entry
+pub(super) fn is_entry_name(name: &str) -> bool {
+    name != INDEX_FILE && name != QUARANTINE_DIR && !name.ends_with(&format!(".{}", TMP_EXTENSION))
+}
+
+/// Whether a process is still running
+pub(super) fn process_alive(pid: i32) -> bool {
+    pid > 0
+        && (unsafe { libc::kill(pid, 0) } == 0
+            || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn put(dir: &SharedDir, chunk_id: &str, size: usize, max_size: u64) -> Vec<Evicted> {
+        let data = vec![0u8; size];
+        let mut budget = dir.lock().unwrap();
+        let evicted = budget.make_room(size as u64, max_size).unwrap();
+        let mut contents = entry::header(&data).to_vec();
+        contents.extend_from_slice(&data);
+        fs::write(dir.domain_dir().join(chunk_id), contents).unwrap();
+        budget.add(size as u64);
+        evicted
+    }
+
+    #[test]
+    fn test_global_budget() {
+        let temp = TempDir::new().unwrap();
+        let a = SharedDir::open(temp.path()).unwrap().with_domain("a").unwrap();
+        let b = SharedDir::open(temp.path()).unwrap().with_domain("b").unwrap();
+
+        put(&a, "old", 400, 1000);
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        put(&b, "pinned", 300, 1000);
+        b.set_pinned(&HashSet::from(["pinned".to_string()])).unwrap();
+        assert_eq!(a.usage().unwrap(), 700);
+
+        // A write by either domain evicts the other's least recent entry
+        let evicted = put(&b, "new", 400, 1000);
+        assert_eq!(evicted.len(), 1);
+        assert_eq!((evicted[0].domain.as_str(), evicted[0].chunk_id.as_str()), ("a", "old"));
+        assert_eq!(a.usage().unwrap(), 700);
+
+        // Pinned entries are kept even when nothing else can go, and the
+        // write goes over budget instead of failing
+        put(&b, "large", 800, 1000);
+        assert!(b.domain_dir().join("pinned").exists());
+        assert!(!b.domain_dir().join("new").exists());
+        assert_eq!(a.usage().unwrap(), 1100);
+
+        let mut budget = b.lock().unwrap();
+        assert!(matches!(budget.make_room(1001, 1000), Err(Error::CacheFull)));
+    }
+
+    #[test]
+    fn test_ledger_recount() {
+        let temp = TempDir::new().unwrap();
+        let dir = SharedDir::open(temp.path()).unwrap().with_domain("a").unwrap();
+        put(&dir, "chunk", 100, 1000);
+        drop(dir);
+
+        fs::remove_file(temp.path().join(USAGE_FILE)).unwrap();
+        let host = SharedDir::open(temp.path()).unwrap();
+        assert_eq!((host.usage().unwrap(), host.domains().unwrap()), (100, 1));
+        host.clear().unwrap();
+        assert_eq!(host.usage().unwrap(), 0);
+    }
+}
@@ -11,6 +11,9 @@ pub use compression::{compress, compress_or_original, decompress};
 
 use serde::{Deserialize, Serialize};
 
+/// Message ID of a chunk written offline and not uploaded yet
+pub const PENDING_MESSAGE_ID: i32 = 0;
+
 /// Reference to a chunk stored remotely
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct ChunkRef {
@@ -28,7 +31,17 @@ pub struct ChunkRef {
     pub compressed: bool,
 }
 
+impl ChunkRef {
+    /// Whether the chunk is still waiting in the offline upload queue
+    pub fn is_pending(&self) -> bool {
+        self.message_id == PENDING_MESSAGE_ID
+    }
+}
+
 /// Manifest describing all chunks of a file
+///
+/// The chunks hold the start of the file; a file extended by truncate has a
+/// `total_size` past them, and the rest reads as zeros.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChunkManifest {
     /// File version this manifest represents
@@ -73,6 +86,49 @@ impl ChunkManifest {
         }
         None
     }
+
+    /// Get the number of bytes held by chunks; the rest of the file is zeros
+    pub fn data_size(&self) -> u64 {
+        self.chunks.iter().map(|c| c.original_size).sum()
+    }
+
+    /// Cut or extend the file to `size`
+    ///
+    /// Chunks past the new end are removed. If the end falls inside a chunk,
+    /// that chunk is removed too and its kept prefix must be stored again as
+    /// a new chunk. Extending only records the new size.
+    pub fn truncate(&mut self, size: u64) -> Truncation {
+        let mut truncation = Truncation::default();
+        let mut start = 0u64;
+        let mut keep = self.chunks.len();
+        for (idx, chunk) in self.chunks.iter().enumerate() {
+            if start >= size {
+                keep = idx;
+                break;
+            }
+            if start + chunk.original_size > size {
+                keep = idx;
+                truncation.kept_prefix = size - start;
+                break;
+            }
+            start += chunk.original_size;
+        }
+        truncation.removed = self.chunks.split_off(keep);
+        if !truncation.removed.is_empty() {
+            self.file_hash.clear();
+        }
+        self.total_size = size;
+        truncation
+    }
+}
+
+/// Chunks a truncate removed from a manifest
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Truncation {
+    /// Removed chunks, in file order
+    pub removed: Vec<ChunkRef>,
+    /// Bytes of the first removed chunk that are still part of the file
+    pub kept_prefix: u64,
 }
 
 /// Location of a single block within a stripe
@@ -174,3 +230,84 @@ impl ErasureChunkManifest {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(sizes: &[u64]) -> ChunkManifest {
+        let mut manifest = ChunkManifest::new(1);
+        for (idx, &size) in sizes.iter().enumerate() {
+            manifest.chunks.push(ChunkRef {
+                id: format!("c{}", idx),
+                size,
+                message_id: idx as i32 + 1,
+                offset: manifest.total_size,
+                original_size: size,
+                compressed: false,
+            });
+            manifest.total_size += size;
+        }
+        manifest.file_hash = "hash".to_string();
+        manifest
+    }
+
+    fn ids(chunks: &[ChunkRef]) -> Vec<&str> {
+        chunks.iter().map(|c| c.id.as_str()).collect()
+    }
+
+    #[test]
+    fn test_truncate_down() {
+        // Inside a chunk: it is removed and its prefix kept
+        let mut m = manifest(&[10, 10, 10]);
+        let cut = m.truncate(15);
+        assert_eq!(ids(&cut.removed), ["c1", "c2"]);
+        assert_eq!(cut.kept_prefix, 5);
+        assert_eq!(ids(&m.chunks), ["c0"]);
+        assert_eq!((m.total_size, m.data_size()), (15, 10));
+        assert!(m.file_hash.is_empty());
+
+        // On a chunk boundary nothing needs re-chunking
+        let mut m = manifest(&[10, 10, 10]);
+        let cut = m.truncate(20);
+        assert_eq!(ids(&cut.removed), ["c2"]);
+        assert_eq!(cut.kept_prefix, 0);
+        assert_eq!(m.data_size(), 20);
+
+        let cut = m.truncate(0);
+        assert_eq!(ids(&cut.removed), ["c0", "c1"]);
+        assert!(m.chunks.is_empty());
+        assert_eq!(m.total_size, 0);
+    }
+
+    #[test]
+    fn test_truncate_up() {
+        let mut m = manifest(&[10, 10]);
+        assert_eq!(m.truncate(100), Truncation::default());
+        assert_eq!(ids(&m.chunks), ["c0", "c1"]);
+        assert_eq!((m.total_size, m.data_size()), (100, 20));
+        assert_eq!(m.file_hash, "hash");
+        assert!(m.chunk_at_offset(50).is_none());
+    }
+
+    #[test]
+    fn test_shrink_then_grow() {
+        let mut m = manifest(&[10, 10]);
+        let cut = m.truncate(4);
+        assert_eq!(ids(&cut.removed), ["c0", "c1"]);
+        assert_eq!(cut.kept_prefix, 4);
+        m.chunks.push(ChunkRef {
+            id: "tail".to_string(),
+            size: 4,
+            message_id: 9,
+            offset: 0,
+            original_size: 4,
+            compressed: false,
+        });
+
+        // Growing keeps the re-chunked tail; the old bytes do not come back
+        assert_eq!(m.truncate(30), Truncation::default());
+        assert_eq!(ids(&m.chunks), ["tail"]);
+        assert_eq!((m.total_size, m.data_size()), (30, 4));
+    }
+}
@@ -1,7 +1,9 @@
 //! Configuration management for tgcryptfs
 
+use crate::crypto::{AeadSuite, DEFAULT_SEGMENT_SIZE};
 use crate::error::{Error, Result};
 use serde::{Deserialize, Serialize};
+use std::io::Write;
 use std::path::{Path, PathBuf};
 use uuid::Uuid;
 
@@ -34,6 +36,18 @@ pub const DEFAULT_CACHE_SIZE: u64 = 1024 * 1024 * 1024;
 /// Default prefetch count
 pub const DEFAULT_PREFETCH_COUNT: usize = 3;
 
+/// Default number of background prefetch workers
+pub const DEFAULT_PREFETCH_WORKERS: usize = 2;
+
+/// Default in-memory inode cache size: 64MB
+pub const DEFAULT_INODE_CACHE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Default in-memory chunk page cache size: 64MB
+pub const DEFAULT_MEMORY_CACHE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Default number of remembered failed lookups
+pub const DEFAULT_NEGATIVE_CACHE_ENTRIES: usize = 16384;
+
 /// Default sync interval for master-replica (seconds)
 pub const DEFAULT_MASTER_REPLICA_SYNC_INTERVAL: u64 = 60;
 
@@ -95,6 +109,26 @@ pub struct MasterReplicaConfig {
     /// Number of snapshots to retain
     #[serde(default = "default_snapshot_retention")]
     pub snapshot_retention: usize,
+
+    /// Master's Ed25519 public key (hex); replicas verify snapshot signatures against it
+    #[serde(default)]
+    pub master_public_key: Option<String>,
+}
+
+impl MasterReplicaConfig {
+    /// Decode the master's public key, if one is configured
+    pub fn master_public_key(&self) -> Result<Option<[u8; 32]>> {
+        let Some(hex_key) = &self.master_public_key else {
+            return Ok(None);
+        };
+        hex::decode(hex_key)
+            .ok()
+            .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+            .map(Some)
+            .ok_or_else(|| {
+                Error::InvalidConfig("master_public_key must be 32 bytes of hex".to_string())
+            })
+    }
 }
 
 /// Replica role
@@ -294,6 +328,24 @@ pub struct ConfigV2 {
     pub data_dir: PathBuf,
 }
 
+/// Replace a file so readers see either the old or the new contents
+///
+/// The permissions of an existing file are kept.
+pub fn write_atomic(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    {
+        let mut file = std::fs::File::create(&tmp_path)?;
+        if let Ok(metadata) = std::fs::metadata(path) {
+            file.set_permissions(metadata.permissions())?;
+        }
+        file.write_all(content)?;
+        file.sync_all()?;
+    }
+    std::fs::rename(&tmp_path, path)
+}
+
 fn default_version() -> u32 {
     2
 }
@@ -319,6 +371,14 @@ pub struct Config {
     /// Version control configuration
     pub versioning: VersioningConfig,
 
+    /// Cloud metadata journal configuration
+    #[serde(default)]
+    pub journal: JournalConfig,
+
+    /// Behaviour while the backend is unreachable
+    #[serde(default)]
+    pub offline: OfflineConfig,
+
     /// Path to the data directory
     pub data_dir: PathBuf,
 }
@@ -366,6 +426,31 @@ pub struct EncryptionConfig {
     /// Salt for key derivation (will be generated if not set)
     #[serde(with = "hex_serde")]
     pub salt: Vec<u8>,
+
+    /// AEAD suite used for newly written ciphertexts
+    #[serde(default)]
+    pub aead_suite: AeadSuite,
+
+    /// Keyfile combined with the password (volume requires one when set)
+    ///
+    /// Relative paths are also looked up in `$CREDENTIALS_DIRECTORY`.
+    #[serde(default)]
+    pub keyfile: Option<PathBuf>,
+
+    /// Reject chunks sealed without AAD binding
+    ///
+    /// Enable once every chunk of the volume was uploaded with binding;
+    /// legacy headerless chunks are rejected as well.
+    #[serde(default)]
+    pub require_bound_chunks: bool,
+
+    /// Reject metadata entries sealed without AAD binding
+    ///
+    /// Set for new volumes and by `tgcryptfs migrate-aad` once every entry
+    /// is bound. Kept here rather than in the store so that deleting a
+    /// database entry cannot turn the check off.
+    #[serde(default)]
+    pub require_bound_metadata: bool,
 }
 
 /// Cache configuration
@@ -383,8 +468,48 @@ pub struct CacheConfig {
     /// Number of chunks to prefetch
     pub prefetch_count: usize,
 
+    /// Number of concurrent background prefetch downloads
+    #[serde(default = "default_prefetch_workers")]
+    pub prefetch_workers: usize,
+
     /// Cache eviction policy
     pub eviction_policy: EvictionPolicy,
+
+    /// Maximum memory used by cached inodes in bytes
+    #[serde(default = "default_inode_cache_size")]
+    pub inode_cache_size: u64,
+
+    /// Maximum number of cached failed lookups (0 disables)
+    #[serde(default = "default_negative_cache_entries")]
+    pub negative_cache_entries: usize,
+
+    /// Maximum memory used by recently read chunk pages in bytes (0 disables)
+    #[serde(default = "default_memory_cache_size")]
+    pub memory_cache_size: u64,
+
+    /// Number of recently opened files remembered for re-warming (0 disables)
+    #[serde(default)]
+    pub access_history_size: usize,
+
+    /// Share `cache_dir` and `max_size` with the other mounts on this host
+    #[serde(default)]
+    pub shared: bool,
+}
+
+fn default_prefetch_workers() -> usize {
+    DEFAULT_PREFETCH_WORKERS
+}
+
+fn default_inode_cache_size() -> u64 {
+    DEFAULT_INODE_CACHE_SIZE
+}
+
+fn default_negative_cache_entries() -> usize {
+    DEFAULT_NEGATIVE_CACHE_ENTRIES
+}
+
+fn default_memory_cache_size() -> u64 {
+    DEFAULT_MEMORY_CACHE_SIZE
 }
 
 /// Chunk configuration
@@ -401,6 +526,16 @@ pub struct ChunkConfig {
 
     /// Enable content-based deduplication
     pub dedup_enabled: bool,
+
+    /// Plaintext bytes per independently decryptable segment (0 seals chunks whole)
+    ///
+    /// Segmented chunks that are not compressed can be read in part.
+    #[serde(default = "default_segment_size")]
+    pub segment_size: u32,
+}
+
+fn default_segment_size() -> u32 {
+    DEFAULT_SEGMENT_SIZE
 }
 
 /// Mount configuration
@@ -415,6 +550,14 @@ pub struct MountConfig {
     /// Allow root to access the mount
     pub allow_root: bool,
 
+    /// Let the kernel check permission bits before calling the filesystem
+    #[serde(default)]
+    pub default_permissions: bool,
+
+    /// Honour POSIX ACLs stored in `system.posix_acl_*` xattrs
+    #[serde(default)]
+    pub posix_acl: bool,
+
     /// Default file permissions
     pub default_file_mode: u32,
 
@@ -428,6 +571,68 @@ pub struct MountConfig {
     pub gid: u32,
 }
 
+/// Cloud metadata journal configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalConfig {
+    /// Stream metadata changes to the backend
+    pub enabled: bool,
+
+    /// Maximum operations per uploaded batch
+    pub batch_size: usize,
+
+    /// Upload pending operations at least this often (seconds)
+    pub flush_interval_secs: u64,
+
+    /// Upload a compacted checkpoint after this many batches
+    pub checkpoint_interval: u32,
+}
+
+/// Offline operation configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OfflineConfig {
+    /// Mount from local metadata and cache when the backend is unreachable
+    pub enabled: bool,
+
+    /// What happens to writes while offline
+    pub writes: OfflineWrites,
+
+    /// Error returned for reads of chunks that are not cached
+    pub uncached_read_error: UncachedReadError,
+
+    /// How often the connection is re-probed while offline (seconds)
+    pub probe_interval_secs: u64,
+}
+
+/// Handling of writes while offline
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OfflineWrites {
+    /// Spool encrypted chunks locally and upload them on reconnect
+    Queue,
+    /// Refuse modifications with EROFS
+    ReadOnly,
+}
+
+/// Errno for uncached reads while offline
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UncachedReadError {
+    /// EIO
+    Eio,
+    /// ENODATA
+    Enodata,
+}
+
+impl UncachedReadError {
+    /// The errno to return
+    pub fn errno(self) -> i32 {
+        match self {
+            UncachedReadError::Eio => libc::EIO,
+            UncachedReadError::Enodata => libc::ENODATA,
+        }
+    }
+}
+
 /// Versioning configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VersioningConfig {
@@ -456,6 +661,8 @@ pub enum EvictionPolicy {
     Lfu,
     /// First In First Out
     Fifo,
+    /// Adaptive Replacement Cache (scan resistant)
+    Arc,
 }
 
 impl Default for Config {
@@ -472,11 +679,19 @@ impl Default for Config {
                 cache_dir: data_dir.join("cache"),
                 prefetch_enabled: true,
                 prefetch_count: DEFAULT_PREFETCH_COUNT,
+                prefetch_workers: DEFAULT_PREFETCH_WORKERS,
                 eviction_policy: EvictionPolicy::Lru,
+                inode_cache_size: DEFAULT_INODE_CACHE_SIZE,
+                negative_cache_entries: DEFAULT_NEGATIVE_CACHE_ENTRIES,
+                memory_cache_size: DEFAULT_MEMORY_CACHE_SIZE,
+                access_history_size: 0,
+                shared: false,
             },
             chunk: ChunkConfig::default(),
             mount: MountConfig::default(),
             versioning: VersioningConfig::default(),
+            journal: JournalConfig::default(),
+            offline: OfflineConfig::default(),
             data_dir,
         }
     }
@@ -512,6 +727,10 @@ impl Default for EncryptionConfig {
             argon2_iterations: 3,
             argon2_parallelism: 4,
             salt: Vec::new(), // Will be generated on first use
+            aead_suite: AeadSuite::default(),
+            keyfile: None,
+            require_bound_chunks: false,
+            require_bound_metadata: true,
         }
     }
 }
@@ -523,6 +742,7 @@ impl Default for ChunkConfig {
             compression_enabled: true,
             compression_threshold: 1024, // Only compress if > 1KB
             dedup_enabled: true,
+            segment_size: DEFAULT_SEGMENT_SIZE,
         }
     }
 }
@@ -533,6 +753,8 @@ impl Default for MountConfig {
             mount_point: PathBuf::from("/mnt/tgcryptfs"),
             allow_other: false,
             allow_root: false,
+            default_permissions: false,
+            posix_acl: false,
             default_file_mode: 0o644,
             default_dir_mode: 0o755,
             uid: unsafe { libc::getuid() },
@@ -541,6 +763,28 @@ impl Default for MountConfig {
     }
 }
 
+impl Default for JournalConfig {
+    fn default() -> Self {
+        JournalConfig {
+            enabled: true,
+            batch_size: 256,
+            flush_interval_secs: 30,
+            checkpoint_interval: 64,
+        }
+    }
+}
+
+impl Default for OfflineConfig {
+    fn default() -> Self {
+        OfflineConfig {
+            enabled: true,
+            writes: OfflineWrites::Queue,
+            uncached_read_error: UncachedReadError::Eio,
+            probe_interval_secs: 30,
+        }
+    }
+}
+
 impl Default for VersioningConfig {
     fn default() -> Self {
         VersioningConfig {
@@ -649,11 +893,8 @@ impl Config {
             Error::Config(format!("Failed to serialize config: {}", e))
         })?;
 
-        std::fs::write(path.as_ref(), content).map_err(|e| {
-            Error::Config(format!("Failed to write config file: {}", e))
-        })?;
-
-        Ok(())
+        write_atomic(path.as_ref(), content.as_bytes())
+            .map_err(|e| Error::Config(format!("Failed to write config file: {}", e)))
     }
 
     /// Validate the configuration
@@ -734,7 +975,13 @@ impl Default for ConfigV2 {
                 cache_dir: data_dir.join("cache"),
                 prefetch_enabled: true,
                 prefetch_count: DEFAULT_PREFETCH_COUNT,
+                prefetch_workers: DEFAULT_PREFETCH_WORKERS,
                 eviction_policy: EvictionPolicy::Lru,
+                inode_cache_size: DEFAULT_INODE_CACHE_SIZE,
+                negative_cache_entries: DEFAULT_NEGATIVE_CACHE_ENTRIES,
+                memory_cache_size: DEFAULT_MEMORY_CACHE_SIZE,
+                access_history_size: 0,
+                shared: false,
             },
             logging: LoggingConfig::default(),
             pool: None,
@@ -821,11 +1068,8 @@ impl ConfigV2 {
             })?
         };
 
-        std::fs::write(path_ref, content).map_err(|e| {
-            Error::Config(format!("Failed to write config file: {}", e))
-        })?;
-
-        Ok(())
+        write_atomic(path_ref, content.as_bytes())
+            .map_err(|e| Error::Config(format!("Failed to write config file: {}", e)))
     }
 
     /// Validate the configuration
@@ -854,11 +1098,16 @@ impl ConfigV2 {
                         "cluster_id is required for master-replica mode".to_string(),
                     ));
                 }
-                if self.distribution.master_replica.is_none() {
-                    return Err(Error::InvalidConfig(
-                        "master_replica configuration is required for master-replica mode"
-                            .to_string(),
-                    ));
+                match &self.distribution.master_replica {
+                    Some(master_replica) => {
+                        master_replica.master_public_key()?;
+                    }
+                    None => {
+                        return Err(Error::InvalidConfig(
+                            "master_replica configuration is required for master-replica mode"
+                                .to_string(),
+                        ));
+                    }
                 }
             }
             DistributionMode::Distributed => {
@@ -1,19 +1,167 @@
-//! AES-256-GCM Encryption Implementation
+//! AES-256-GCM / XChaCha20-Poly1305 Encryption Implementation
 //!
-//! All data is encrypted using AES-256-GCM which provides:
+//! All data is encrypted using an AEAD which provides:
 //! - Confidentiality: Data is encrypted
 //! - Integrity: Any tampering is detected
 //! - Authentication: Verifies the data came from the key holder
+//!
+//! Ciphertexts are stored in a self-describing envelope:
+//!
+//! ```text
+//! magic "TGCE" (4) | version (1) | suite (1) | key epoch (4, BE) | nonce | ciphertext+tag
+//! ```
+//!
+//! The header is authenticated together with the caller's AAD, so the suite
+//! and key epoch cannot be swapped without failing decryption. Keys are not
+//! rotated yet, so every blob is sealed under epoch 0 and any other epoch is
+//! rejected. Blobs written before the envelope existed (bare
+//! `nonce || ciphertext`) are still accepted.
 
 use crate::crypto::{KEY_SIZE, NONCE_SIZE, TAG_SIZE};
 use crate::error::{Error, Result};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
 use rand::RngCore;
 use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM};
 use serde::{Deserialize, Serialize};
 
+/// Magic bytes identifying an enveloped ciphertext
+pub const ENVELOPE_MAGIC: [u8; 4] = *b"TGCE";
+
+/// Current envelope format version
+pub const ENVELOPE_VERSION: u8 = 1;
+
+/// Key epoch of every ciphertext, the only one there are keys for
+///
+/// The header field is reserved for key rotation: every blob is sealed
+/// under epoch 0 today, and `check_key_epoch` is where decryption will pick
+/// the key of an older epoch once rotation exists.
+pub const KEY_EPOCH: u32 = 0;
+
+/// Size of the fixed envelope header (magic + version + suite + key epoch)
+pub const ENVELOPE_HEADER_SIZE: usize = 10;
+
+/// Size of the XChaCha20-Poly1305 extended nonce in bytes
+pub const XNONCE_SIZE: usize = 24;
+
+/// AEAD algorithm used to seal a blob
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum AeadSuite {
+    /// AES-256-GCM with a 96-bit random nonce
+    #[default]
+    #[serde(rename = "aes-256-gcm")]
+    Aes256Gcm,
+    /// XChaCha20-Poly1305 with a 192-bit random nonce (safe for very large volumes)
+    #[serde(rename = "xchacha20-poly1305")]
+    XChaCha20Poly1305,
+}
+
+impl AeadSuite {
+    /// Identifier stored in the envelope header
+    pub fn id(&self) -> u8 {
+        match self {
+            AeadSuite::Aes256Gcm => 1,
+            AeadSuite::XChaCha20Poly1305 => 2,
+        }
+    }
+
+    /// Look up a suite by its header identifier
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(AeadSuite::Aes256Gcm),
+            2 => Some(AeadSuite::XChaCha20Poly1305),
+            _ => None,
+        }
+    }
+
+    /// Nonce length used by this suite
+    pub fn nonce_size(&self) -> usize {
+        match self {
+            AeadSuite::Aes256Gcm => NONCE_SIZE,
+            AeadSuite::XChaCha20Poly1305 => XNONCE_SIZE,
+        }
+    }
+
+    /// Size of `plaintext_len` bytes sealed whole in an envelope
+    pub fn sealed_size(&self, plaintext_len: u64) -> u64 {
+        (ENVELOPE_HEADER_SIZE + self.nonce_size() + TAG_SIZE) as u64 + plaintext_len
+    }
+}
+
+impl std::fmt::Display for AeadSuite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AeadSuite::Aes256Gcm => write!(f, "AES-256-GCM"),
+            AeadSuite::XChaCha20Poly1305 => write!(f, "XChaCha20-Poly1305"),
+        }
+    }
+}
+
+/// Self-describing header prepended to every new ciphertext
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EnvelopeHeader {
+    /// Envelope format version
+    pub version: u8,
+    /// AEAD suite used for this blob
+    pub suite: AeadSuite,
+    /// Key slot / epoch the blob was sealed under
+    pub key_epoch: u32,
+}
+
+impl EnvelopeHeader {
+    /// Create a header for the current envelope version
+    pub fn new(suite: AeadSuite) -> Self {
+        EnvelopeHeader {
+            version: ENVELOPE_VERSION,
+            suite,
+            key_epoch: KEY_EPOCH,
+        }
+    }
+
+    /// Encode the fixed-size header
+    pub fn to_bytes(&self) -> [u8; ENVELOPE_HEADER_SIZE] {
+        let mut bytes = [0u8; ENVELOPE_HEADER_SIZE];
+        bytes[..4].copy_from_slice(&ENVELOPE_MAGIC);
+        bytes[4] = self.version;
+        bytes[5] = self.suite.id();
+        bytes[6..10].copy_from_slice(&self.key_epoch.to_be_bytes());
+        bytes
+    }
+
+    /// Parse a header from the start of a blob
+    ///
+    /// Returns None if the blob does not start with a known envelope header.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < ENVELOPE_HEADER_SIZE || bytes[..4] != ENVELOPE_MAGIC {
+            return None;
+        }
+        if bytes[4] != ENVELOPE_VERSION {
+            return None;
+        }
+        let suite = AeadSuite::from_id(bytes[5])?;
+        let key_epoch = u32::from_be_bytes(bytes[6..10].try_into().unwrap());
+        Some(EnvelopeHeader {
+            version: bytes[4],
+            suite,
+            key_epoch,
+        })
+    }
+
+    /// Additional data actually fed to the AEAD: header followed by caller AAD
+    fn bound_aad(&self, aad: &[u8]) -> Vec<u8> {
+        let mut bound = Vec::with_capacity(ENVELOPE_HEADER_SIZE + aad.len());
+        bound.extend_from_slice(&self.to_bytes());
+        bound.extend_from_slice(aad);
+        bound
+    }
+}
+
 /// Encrypted data container with nonce and authentication tag
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EncryptedData {
+    /// Envelope header (None for legacy blobs)
+    #[serde(default)]
+    pub header: Option<EnvelopeHeader>,
     /// Nonce used for encryption (unique per encryption)
     #[serde(with = "serde_bytes")]
     pub nonce: Vec<u8>,
@@ -25,31 +173,74 @@ pub struct EncryptedData {
 impl EncryptedData {
     /// Get the total size of encrypted data
     pub fn size(&self) -> usize {
-        self.nonce.len() + self.ciphertext.len()
+        let header_len = if self.header.is_some() {
+            ENVELOPE_HEADER_SIZE
+        } else {
+            0
+        };
+        header_len + self.nonce.len() + self.ciphertext.len()
+    }
+
+    /// Check whether this blob predates the versioned envelope
+    pub fn is_legacy(&self) -> bool {
+        self.header.is_none()
+    }
+
+    /// AEAD suite of this blob (legacy blobs are always AES-256-GCM)
+    pub fn suite(&self) -> AeadSuite {
+        self.header.map(|h| h.suite).unwrap_or_default()
+    }
+
+    /// Key epoch of this blob (legacy blobs belong to epoch 0)
+    pub fn key_epoch(&self) -> u32 {
+        self.header.map(|h| h.key_epoch).unwrap_or(0)
     }
 
     /// Serialize to bytes for storage
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::with_capacity(NONCE_SIZE + self.ciphertext.len());
+        let mut bytes = Vec::with_capacity(self.size());
+        if let Some(header) = &self.header {
+            bytes.extend_from_slice(&header.to_bytes());
+        }
         bytes.extend_from_slice(&self.nonce);
         bytes.extend_from_slice(&self.ciphertext);
         bytes
     }
 
     /// Deserialize from bytes
+    ///
+    /// Enveloped blobs are recognised by their header; anything else is
+    /// treated as a legacy `nonce || ciphertext` blob.
     pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if let Some(header) = EnvelopeHeader::parse(bytes) {
+            let nonce_end = ENVELOPE_HEADER_SIZE + header.suite.nonce_size();
+            if bytes.len() >= nonce_end + TAG_SIZE {
+                return Ok(EncryptedData {
+                    header: Some(header),
+                    nonce: bytes[ENVELOPE_HEADER_SIZE..nonce_end].to_vec(),
+                    ciphertext: bytes[nonce_end..].to_vec(),
+                });
+            }
+        }
+
+        Self::from_legacy_bytes(bytes)
+    }
+
+    /// Parse bytes as a legacy `nonce || ciphertext` blob
+    fn from_legacy_bytes(bytes: &[u8]) -> Result<Self> {
         if bytes.len() < NONCE_SIZE + TAG_SIZE {
             return Err(Error::Decryption("Data too short".to_string()));
         }
 
         Ok(EncryptedData {
+            header: None,
             nonce: bytes[..NONCE_SIZE].to_vec(),
             ciphertext: bytes[NONCE_SIZE..].to_vec(),
         })
     }
 }
 
-/// Encrypt data using AES-256-GCM
+/// Encrypt data using the default suite (AES-256-GCM)
 ///
 /// # Arguments
 /// * `key` - 256-bit encryption key
@@ -57,16 +248,167 @@ impl EncryptedData {
 /// * `aad` - Additional authenticated data (optional, authenticated but not encrypted)
 ///
 /// # Returns
-/// EncryptedData containing nonce and ciphertext with auth tag
+/// EncryptedData containing envelope header, nonce and ciphertext with auth tag
 pub fn encrypt(key: &[u8; KEY_SIZE], plaintext: &[u8], aad: &[u8]) -> Result<EncryptedData> {
+    encrypt_with(key, plaintext, aad, AeadSuite::default())
+}
+
+/// Encrypt data with an explicit AEAD suite
+pub fn encrypt_with(
+    key: &[u8; KEY_SIZE],
+    plaintext: &[u8],
+    aad: &[u8],
+    suite: AeadSuite,
+) -> Result<EncryptedData> {
+    let header = EnvelopeHeader::new(suite);
+    let bound_aad = header.bound_aad(aad);
+
+    // Generate random nonce
+    let mut nonce = vec![0u8; suite.nonce_size()];
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let ciphertext = seal(suite, key, &nonce, plaintext, &bound_aad)?;
+
+    Ok(EncryptedData {
+        header: Some(header),
+        nonce,
+        ciphertext,
+    })
+}
+
+/// Decrypt data, dispatching on the envelope header
+///
+/// # Arguments
+/// * `key` - 256-bit encryption key
+/// * `encrypted` - Encrypted data container
+/// * `aad` - Additional authenticated data (must match encryption)
+///
+/// # Returns
+/// Decrypted plaintext
+pub fn decrypt(key: &[u8; KEY_SIZE], encrypted: &EncryptedData, aad: &[u8]) -> Result<Vec<u8>> {
+    match &encrypted.header {
+        Some(header) => open_envelope(key, header, encrypted, aad)
+            .or_else(|e| reopen_as_legacy(key, encrypted, aad).map_err(|_| e)),
+        None => open_aes_gcm(key, &encrypted.nonce, &encrypted.ciphertext, aad),
+    }
+}
+
+/// Decrypt an object bound to `aad`
+///
+/// `unbound_aad` is the AAD the object was sealed with before it was bound to
+/// its identity. When it is `Some`, such blobs (legacy or enveloped) are still
+/// accepted; when `None`, only blobs bound to `aad` authenticate.
+pub fn decrypt_bound(
+    key: &[u8; KEY_SIZE],
+    encrypted: &EncryptedData,
+    aad: &[u8],
+    unbound_aad: Option<&[u8]>,
+) -> Result<Vec<u8>> {
+    match (&encrypted.header, unbound_aad) {
+        (Some(header), None) => open_envelope(key, header, encrypted, aad),
+        (Some(header), Some(unbound)) => open_envelope(key, header, encrypted, aad).or_else(|e| {
+            open_envelope(key, header, encrypted, unbound)
+                .or_else(|_| reopen_as_legacy(key, encrypted, unbound))
+                .map_err(|_| e)
+        }),
+        (None, Some(unbound)) => open_aes_gcm(key, &encrypted.nonce, &encrypted.ciphertext, unbound),
+        (None, None) => Err(Error::Decryption(
+            "Unbound legacy ciphertext rejected".to_string(),
+        )),
+    }
+}
+
+/// Open an enveloped blob
+fn open_envelope(
+    key: &[u8; KEY_SIZE],
+    header: &EnvelopeHeader,
+    encrypted: &EncryptedData,
+    aad: &[u8],
+) -> Result<Vec<u8>> {
+    check_key_epoch(header.key_epoch)?;
+    let bound_aad = header.bound_aad(aad);
+    open(header.suite, key, &encrypted.nonce, &encrypted.ciphertext, &bound_aad)
+}
+
+/// Reject blobs sealed under a key epoch there is no key for
+///
+/// Until keys can be rotated only [`KEY_EPOCH`] has a key, so any other
+/// epoch means the blob was sealed by a newer version or tampered with.
+pub(super) fn check_key_epoch(key_epoch: u32) -> Result<()> {
+    if key_epoch != KEY_EPOCH {
+        return Err(Error::Decryption(format!(
+            "Sealed under unknown key epoch {}",
+            key_epoch
+        )));
+    }
+    Ok(())
+}
+
+/// Seal with the given suite
+pub(super) fn seal(
+    suite: AeadSuite,
+    key: &[u8; KEY_SIZE],
+    nonce: &[u8],
+    plaintext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>> {
+    match suite {
+        AeadSuite::Aes256Gcm => seal_aes_gcm(key, nonce, plaintext, aad),
+        AeadSuite::XChaCha20Poly1305 => {
+            let cipher = XChaCha20Poly1305::new(key.into());
+            cipher
+                .encrypt(XNonce::from_slice(nonce), Payload { msg: plaintext, aad })
+                .map_err(|_| Error::Encryption("Encryption failed".to_string()))
+        }
+    }
+}
+
+/// Open with the given suite
+pub(super) fn open(
+    suite: AeadSuite,
+    key: &[u8; KEY_SIZE],
+    nonce: &[u8],
+    ciphertext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>> {
+    match suite {
+        AeadSuite::Aes256Gcm => open_aes_gcm(key, nonce, ciphertext, aad),
+        AeadSuite::XChaCha20Poly1305 => {
+            if nonce.len() != XNONCE_SIZE {
+                return Err(Error::Decryption(format!(
+                    "Invalid nonce length: {}",
+                    nonce.len()
+                )));
+            }
+
+            let cipher = XChaCha20Poly1305::new(key.into());
+            cipher
+                .decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+                .map_err(|_| {
+                    Error::Decryption("Decryption failed - data corrupted or wrong key".to_string())
+                })
+        }
+    }
+}
+
+/// Retry a blob that parsed as an envelope as a legacy blob
+///
+/// A legacy blob's random nonce can begin with the envelope magic by chance,
+/// so an envelope that fails to authenticate gets one more try in the old layout.
+fn reopen_as_legacy(key: &[u8; KEY_SIZE], encrypted: &EncryptedData, aad: &[u8]) -> Result<Vec<u8>> {
+    let legacy = EncryptedData::from_legacy_bytes(&encrypted.to_bytes())?;
+    open_aes_gcm(key, &legacy.nonce, &legacy.ciphertext, aad)
+}
+
+/// Seal with AES-256-GCM
+fn seal_aes_gcm(key: &[u8; KEY_SIZE], nonce: &[u8], plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
     // Create the key
     let unbound_key = UnboundKey::new(&AES_256_GCM, key)
         .map_err(|_| Error::Encryption("Failed to create encryption key".to_string()))?;
     let sealing_key = LessSafeKey::new(unbound_key);
 
-    // Generate random nonce
     let mut nonce_bytes = [0u8; NONCE_SIZE];
-    rand::thread_rng().fill_bytes(&mut nonce_bytes);
+    nonce_bytes.copy_from_slice(nonce);
     let nonce = Nonce::assume_unique_for_key(nonce_bytes);
 
     // Prepare buffer: plaintext + space for tag
@@ -78,30 +420,19 @@ pub fn encrypt(key: &[u8; KEY_SIZE], plaintext: &[u8], aad: &[u8]) -> Result<Enc
         .seal_in_place_append_tag(nonce, Aad::from(aad), &mut in_out)
         .map_err(|_| Error::Encryption("Encryption failed".to_string()))?;
 
-    Ok(EncryptedData {
-        nonce: nonce_bytes.to_vec(),
-        ciphertext: in_out,
-    })
+    Ok(in_out)
 }
 
-/// Decrypt data using AES-256-GCM
-///
-/// # Arguments
-/// * `key` - 256-bit encryption key
-/// * `encrypted` - Encrypted data container
-/// * `aad` - Additional authenticated data (must match encryption)
-///
-/// # Returns
-/// Decrypted plaintext
-pub fn decrypt(key: &[u8; KEY_SIZE], encrypted: &EncryptedData, aad: &[u8]) -> Result<Vec<u8>> {
-    if encrypted.nonce.len() != NONCE_SIZE {
+/// Open with AES-256-GCM
+fn open_aes_gcm(key: &[u8; KEY_SIZE], nonce: &[u8], ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    if nonce.len() != NONCE_SIZE {
         return Err(Error::Decryption(format!(
             "Invalid nonce length: {}",
-            encrypted.nonce.len()
+            nonce.len()
         )));
     }
 
-    if encrypted.ciphertext.len() < TAG_SIZE {
+    if ciphertext.len() < TAG_SIZE {
         return Err(Error::Decryption("Ciphertext too short".to_string()));
     }
 
@@ -112,11 +443,11 @@ pub fn decrypt(key: &[u8; KEY_SIZE], encrypted: &EncryptedData, aad: &[u8]) -> R
 
     // Create nonce
     let mut nonce_bytes = [0u8; NONCE_SIZE];
-    nonce_bytes.copy_from_slice(&encrypted.nonce);
+    nonce_bytes.copy_from_slice(nonce);
     let nonce = Nonce::assume_unique_for_key(nonce_bytes);
 
     // Decrypt in place
-    let mut in_out = encrypted.ciphertext.clone();
+    let mut in_out = ciphertext.to_vec();
     let plaintext = opening_key
         .open_in_place(nonce, Aad::from(aad), &mut in_out)
         .map_err(|_| Error::Decryption("Decryption failed - data corrupted or wrong key".to_string()))?;
@@ -124,6 +455,75 @@ pub fn decrypt(key: &[u8; KEY_SIZE], encrypted: &EncryptedData, aad: &[u8]) -> R
     Ok(plaintext.to_vec())
 }
 
+/// Version of the object AAD layout
+pub const OBJECT_AAD_VERSION: u8 = 1;
+
+/// Kind of object a ciphertext belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectKind {
+    /// File content chunk
+    Chunk,
+    /// Inode record in the metadata store
+    Inode,
+    /// General metadata entry
+    Metadata,
+    /// Replicated metadata snapshot
+    Snapshot,
+    /// Local snapshot index
+    SnapshotIndex,
+    /// Telegram session state
+    Session,
+    /// Uploaded metadata journal batch or checkpoint
+    Journal,
+    /// Directory entry in the metadata store
+    DirEntry,
+    /// Exported metadata archive
+    Archive,
+}
+
+impl ObjectKind {
+    /// Label mixed into the AAD
+    fn label(&self) -> &'static [u8] {
+        match self {
+            ObjectKind::Chunk => b"chunk",
+            ObjectKind::Inode => b"inode",
+            ObjectKind::Metadata => b"metadata",
+            ObjectKind::Snapshot => b"snapshot",
+            ObjectKind::SnapshotIndex => b"snapshot-index",
+            ObjectKind::Session => b"session",
+            ObjectKind::Journal => b"journal",
+            ObjectKind::DirEntry => b"dirent",
+            ObjectKind::Archive => b"archive",
+        }
+    }
+}
+
+/// Build the AAD that binds a ciphertext to the object it belongs to
+///
+/// Layout: `"tgcryptfs-aad" | layout version (1) | kind len (1) | kind |
+/// namespace len (2, BE) | namespace | object version (8, BE) | id`.
+/// A blob sealed for one object will not authenticate as another.
+pub fn object_aad(kind: ObjectKind, namespace: Option<&str>, id: &[u8], version: u64) -> Vec<u8> {
+    let label = kind.label();
+    let namespace = namespace.unwrap_or_default().as_bytes();
+
+    let mut aad = Vec::with_capacity(13 + 1 + 1 + label.len() + 2 + namespace.len() + 8 + id.len());
+    aad.extend_from_slice(b"tgcryptfs-aad");
+    aad.push(OBJECT_AAD_VERSION);
+    aad.push(label.len() as u8);
+    aad.extend_from_slice(label);
+    aad.extend_from_slice(&(namespace.len() as u16).to_be_bytes());
+    aad.extend_from_slice(namespace);
+    aad.extend_from_slice(&version.to_be_bytes());
+    aad.extend_from_slice(id);
+    aad
+}
+
+/// Build the AAD that binds a chunk ciphertext to its identity
+pub fn chunk_aad(namespace: Option<&str>, chunk_id: &str) -> Vec<u8> {
+    object_aad(ObjectKind::Chunk, namespace, chunk_id.as_bytes(), 0)
+}
+
 /// Encrypt with empty AAD (convenience function)
 #[allow(dead_code)]
 pub fn encrypt_simple(key: &[u8; KEY_SIZE], plaintext: &[u8]) -> Result<EncryptedData> {
@@ -237,6 +637,17 @@ mod tests {
         assert_eq!(decrypted, plaintext);
     }
 
+    #[test]
+    fn test_sealed_size() {
+        let key = test_key();
+        for suite in [AeadSuite::Aes256Gcm, AeadSuite::XChaCha20Poly1305] {
+            for len in [0usize, 1, 1000] {
+                let encrypted = encrypt_with(&key, &vec![1u8; len], b"", suite).unwrap();
+                assert_eq!(suite.sealed_size(len as u64), encrypted.size() as u64);
+            }
+        }
+    }
+
     #[test]
     fn test_large_plaintext() {
         let key = test_key();
@@ -260,4 +671,137 @@ mod tests {
         let decrypted = decrypt_simple(&key, &restored).unwrap();
         assert_eq!(decrypted, plaintext);
     }
+
+    #[test]
+    fn test_envelope_header_roundtrip() {
+        let key = test_key();
+        let encrypted =
+            encrypt_with(&key, b"header", &[], AeadSuite::XChaCha20Poly1305).unwrap();
+        let bytes = encrypted.to_bytes();
+
+        assert_eq!(&bytes[..4], &ENVELOPE_MAGIC);
+        assert_eq!(bytes.len(), encrypted.size());
+
+        let restored = EncryptedData::from_bytes(&bytes).unwrap();
+        assert!(!restored.is_legacy());
+        assert_eq!(restored.suite(), AeadSuite::XChaCha20Poly1305);
+        assert_eq!(restored.key_epoch(), KEY_EPOCH);
+        assert_eq!(restored.nonce.len(), XNONCE_SIZE);
+    }
+
+    #[test]
+    fn test_xchacha_encrypt_decrypt() {
+        let key = test_key();
+        let aad = b"chunk:abc";
+
+        let encrypted =
+            encrypt_with(&key, b"Secret data", aad, AeadSuite::XChaCha20Poly1305).unwrap();
+        let restored = EncryptedData::from_bytes(&encrypted.to_bytes()).unwrap();
+
+        assert_eq!(decrypt(&key, &restored, aad).unwrap(), b"Secret data");
+        assert!(decrypt(&key, &restored, b"chunk:def").is_err());
+    }
+
+    #[test]
+    fn test_tampered_header_fails() {
+        let key = test_key();
+        let encrypted = encrypt_with(&key, b"Secret data", &[], AeadSuite::Aes256Gcm).unwrap();
+
+        // Rewrite the key epoch in the header
+        let mut bytes = encrypted.to_bytes();
+        bytes[9] ^= 0x01;
+        let tampered = EncryptedData::from_bytes(&bytes).unwrap();
+
+        assert!(decrypt(&key, &tampered, &[]).is_err());
+    }
+
+    #[test]
+    fn test_unknown_key_epoch_rejected() {
+        let key = test_key();
+        let mut encrypted = encrypt(&key, b"Secret data", &[]).unwrap();
+        encrypted.header.as_mut().unwrap().key_epoch = 1;
+
+        match decrypt(&key, &encrypted, &[]) {
+            Err(Error::Decryption(message)) => assert!(message.contains("key epoch 1")),
+            other => panic!("expected an unknown epoch error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_legacy_blob_accepted() {
+        let key = test_key();
+        let mut nonce = [0u8; NONCE_SIZE];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        // Legacy layout: bare nonce || ciphertext
+        let ciphertext = seal_aes_gcm(&key, &nonce, b"old data", &[]).unwrap();
+        let mut bytes = nonce.to_vec();
+        bytes.extend_from_slice(&ciphertext);
+
+        let restored = EncryptedData::from_bytes(&bytes).unwrap();
+        assert!(restored.is_legacy());
+        assert_eq!(restored.to_bytes(), bytes);
+        assert_eq!(decrypt_simple(&key, &restored).unwrap(), b"old data");
+    }
+
+    #[test]
+    fn test_legacy_blob_with_magic_prefix() {
+        let key = test_key();
+
+        // A legacy nonce that happens to look like an envelope header
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce[..4].copy_from_slice(&ENVELOPE_MAGIC);
+        nonce[4] = ENVELOPE_VERSION;
+        nonce[5] = AeadSuite::Aes256Gcm.id();
+        let ciphertext = seal_aes_gcm(&key, &nonce, b"unlucky", &[]).unwrap();
+        let mut bytes = nonce.to_vec();
+        bytes.extend_from_slice(&ciphertext);
+
+        let restored = EncryptedData::from_bytes(&bytes).unwrap();
+        assert_eq!(decrypt_simple(&key, &restored).unwrap(), b"unlucky");
+    }
+
+    #[test]
+    fn test_decrypt_bound() {
+        let key = test_key();
+        let aad = chunk_aad(Some("photos"), "chunk-1");
+
+        let encrypted = encrypt(&key, b"bound", &aad).unwrap();
+        assert_eq!(decrypt_bound(&key, &encrypted, &aad, None).unwrap(), b"bound");
+
+        // Bound to a different chunk or namespace must fail
+        assert!(decrypt_bound(&key, &encrypted, &chunk_aad(Some("photos"), "chunk-2"), Some(&[])).is_err());
+        assert!(decrypt_bound(&key, &encrypted, &chunk_aad(None, "chunk-1"), Some(&[])).is_err());
+
+        // Legacy blobs were sealed with empty AAD
+        let mut nonce = [0u8; NONCE_SIZE];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let legacy = EncryptedData {
+            header: None,
+            nonce: nonce.to_vec(),
+            ciphertext: seal_aes_gcm(&key, &nonce, b"legacy", &[]).unwrap(),
+        };
+        assert_eq!(decrypt_bound(&key, &legacy, &aad, Some(&[])).unwrap(), b"legacy");
+        assert!(decrypt_bound(&key, &legacy, &aad, None).is_err());
+
+        // Enveloped but unbound blobs are only accepted when allowed
+        let unbound = encrypt(&key, b"unbound", &[]).unwrap();
+        assert_eq!(decrypt_bound(&key, &unbound, &aad, Some(&[])).unwrap(), b"unbound");
+        assert!(decrypt_bound(&key, &unbound, &aad, None).is_err());
+    }
+
+    #[test]
+    fn test_object_aad_distinct() {
+        let inode_2 = object_aad(ObjectKind::Inode, None, &2u64.to_be_bytes(), 0);
+        let inode_3 = object_aad(ObjectKind::Inode, None, &3u64.to_be_bytes(), 0);
+        let meta_2 = object_aad(ObjectKind::Metadata, None, &2u64.to_be_bytes(), 0);
+        let ns_inode_2 = object_aad(ObjectKind::Inode, Some("ns"), &2u64.to_be_bytes(), 0);
+        let v1 = object_aad(ObjectKind::Snapshot, Some("ns"), b"snap", 1);
+        let v2 = object_aad(ObjectKind::Snapshot, Some("ns"), b"snap", 2);
+
+        assert_ne!(inode_2, inode_3);
+        assert_ne!(inode_2, meta_2);
+        assert_ne!(inode_2, ns_inode_2);
+        assert_ne!(v1, v2);
+    }
 }
@@ -104,6 +104,10 @@ mod tests {
             argon2_iterations: 1,
             argon2_parallelism: 1,
             salt: Vec::new(),
+            aead_suite: Default::default(),
+            keyfile: None,
+            require_bound_chunks: false,
+            require_bound_metadata: true,
         }
     }
 
@@ -0,0 +1,151 @@
+//! Keyfiles for two-factor key derivation
+//!
+//! A keyfile is 32 random bytes kept apart from the password, e.g. on
+//! removable media or in the systemd credential store. When one is used, the
+//! Argon2id output and the keyfile are combined through HKDF, so the master
+//! key cannot be derived without both.
+
+use crate::crypto::{KEY_SIZE, SALT_SIZE};
+use crate::error::{Error, Result};
+use rand::RngCore;
+use ring::hkdf::{Salt, HKDF_SHA256};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use zeroize::Zeroizing;
+
+/// Size of a keyfile in bytes
+pub const KEYFILE_SIZE: usize = 32;
+
+/// Environment variable set by systemd for `LoadCredential=`
+pub const CREDENTIALS_DIRECTORY_ENV: &str = "CREDENTIALS_DIRECTORY";
+
+/// HKDF info for combining the password key with a keyfile
+const KEYFILE_INFO: &[u8] = b"tgcryptfs-keyfile-v1";
+
+/// Secret keyfile contents (zeroized on drop)
+pub struct Keyfile {
+    bytes: Zeroizing<[u8; KEYFILE_SIZE]>,
+}
+
+impl Keyfile {
+    /// Generate a new random keyfile
+    pub fn generate() -> Self {
+        let mut bytes = Zeroizing::new([0u8; KEYFILE_SIZE]);
+        rand::thread_rng().fill_bytes(bytes.as_mut());
+        Keyfile { bytes }
+    }
+
+    /// Build a keyfile from raw bytes
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        if data.len() != KEYFILE_SIZE {
+            return Err(Error::InvalidKeyLength {
+                expected: KEYFILE_SIZE,
+                got: data.len(),
+            });
+        }
+        let mut bytes = Zeroizing::new([0u8; KEYFILE_SIZE]);
+        bytes.copy_from_slice(data);
+        Ok(Keyfile { bytes })
+    }
+
+    /// Load a keyfile, resolving relative paths against the credential store
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = Self::resolve(path.as_ref());
+        let data = Zeroizing::new(std::fs::read(&path).map_err(|e| {
+            Error::KeyDerivation(format!("Failed to read keyfile {:?}: {}", path, e))
+        })?);
+        Self::from_bytes(&data)
+    }
+
+    /// Write the keyfile with owner-only permissions, refusing to overwrite
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(path)?;
+        file.write_all(self.bytes.as_ref())?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Resolve a keyfile path
+    ///
+    /// A relative path that does not exist is looked up in
+    /// `$CREDENTIALS_DIRECTORY`, so a systemd credential can be named directly.
+    pub fn resolve(path: &Path) -> PathBuf {
+        if path.is_relative() && !path.exists() {
+            if let Some(dir) = std::env::var_os(CREDENTIALS_DIRECTORY_ENV) {
+                let candidate = Path::new(&dir).join(path);
+                if candidate.exists() {
+                    return candidate;
+                }
+            }
+        }
+        path.to_path_buf()
+    }
+
+    /// Combine a password-derived key with this keyfile
+    pub fn combine(
+        &self,
+        password_key: &[u8; KEY_SIZE],
+        salt: &[u8; SALT_SIZE],
+    ) -> Result<Zeroizing<[u8; KEY_SIZE]>> {
+        let mut ikm = Zeroizing::new([0u8; KEY_SIZE + KEYFILE_SIZE]);
+        ikm[..KEY_SIZE].copy_from_slice(password_key);
+        ikm[KEY_SIZE..].copy_from_slice(self.bytes.as_ref());
+
+        let prk = Salt::new(HKDF_SHA256, salt).extract(ikm.as_ref());
+        let mut output = Zeroizing::new([0u8; KEY_SIZE]);
+        prk.expand(&[KEYFILE_INFO], HKDF_SHA256)
+            .map_err(|_| Error::KeyDerivation("HKDF expansion failed".to_string()))?
+            .fill(output.as_mut())
+            .map_err(|_| Error::KeyDerivation("HKDF fill failed".to_string()))?;
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keyfile_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("volume.key");
+
+        let keyfile = Keyfile::generate();
+        keyfile.write(&path).unwrap();
+        assert!(keyfile.write(&path).is_err());
+
+        let loaded = Keyfile::load(&path).unwrap();
+        assert_eq!(*loaded.bytes, *keyfile.bytes);
+    }
+
+    #[test]
+    fn test_keyfile_wrong_size_rejected() {
+        assert!(Keyfile::from_bytes(&[0u8; 16]).is_err());
+    }
+
+    #[test]
+    fn test_combine_depends_on_keyfile() {
+        let password_key = [1u8; KEY_SIZE];
+        let salt = [2u8; SALT_SIZE];
+
+        let a = Keyfile::from_bytes(&[3u8; KEYFILE_SIZE]).unwrap();
+        let b = Keyfile::from_bytes(&[4u8; KEYFILE_SIZE]).unwrap();
+
+        let ka = a.combine(&password_key, &salt).unwrap();
+        assert_eq!(*ka, *a.combine(&password_key, &salt).unwrap());
+        assert_ne!(*ka, *b.combine(&password_key, &salt).unwrap());
+        assert_ne!(*ka, password_key);
+    }
+}
@@ -5,7 +5,7 @@
 //! - Metadata Key: Encrypts filesystem metadata
 //! - Chunk Keys: Per-chunk keys derived from master key + chunk ID
 
-use crate::crypto::{derive_key, KEY_SIZE, SALT_SIZE};
+use crate::crypto::{derive_key, AeadSuite, Keyfile, KEY_SIZE, SALT_SIZE};
 use crate::config::EncryptionConfig;
 use crate::error::{Error, Result};
 use ring::hkdf::{self, Salt, HKDF_SHA256};
@@ -47,6 +47,15 @@ impl MasterKey {
         })
     }
 
+    /// Combine the password-derived key with a keyfile
+    pub fn with_keyfile(self, keyfile: &Keyfile) -> Result<Self> {
+        let key = keyfile.combine(&self.key, &self.salt)?;
+        Ok(MasterKey {
+            key,
+            salt: self.salt,
+        })
+    }
+
     /// Get the raw key bytes
     pub fn key(&self) -> &[u8; KEY_SIZE] {
         &self.key
@@ -76,6 +85,11 @@ impl MasterKey {
         // Use new HKDF purpose string (data migrated from telegramfs-* to tgcryptfs-*)
         self.derive_subkey(b"tgcryptfs-metadata-v1")
     }
+
+    /// Derive the Telegram session encryption key
+    pub fn session_key(&self) -> Result<[u8; KEY_SIZE]> {
+        self.derive_subkey(b"tgcryptfs-session-v1")
+    }
 }
 
 impl Drop for MasterKey {
@@ -121,6 +135,8 @@ impl hkdf::KeyType for HkdfKeyType {
 pub struct KeyManager {
     master_key: Arc<MasterKey>,
     metadata_key: [u8; KEY_SIZE],
+    /// AEAD suite for newly written ciphertexts
+    aead_suite: AeadSuite,
 }
 
 impl KeyManager {
@@ -131,9 +147,21 @@ impl KeyManager {
         Ok(KeyManager {
             master_key: Arc::new(master_key),
             metadata_key,
+            aead_suite: AeadSuite::default(),
         })
     }
 
+    /// Use the AEAD suite from the encryption config
+    pub fn with_envelope(mut self, config: &EncryptionConfig) -> Self {
+        self.aead_suite = config.aead_suite;
+        self
+    }
+
+    /// Get the AEAD suite for new ciphertexts
+    pub fn aead_suite(&self) -> AeadSuite {
+        self.aead_suite
+    }
+
     /// Get the metadata encryption key
     pub fn metadata_key(&self) -> &[u8; KEY_SIZE] {
         &self.metadata_key
@@ -144,6 +172,20 @@ impl KeyManager {
         ChunkKey::derive(&self.master_key, chunk_id)
     }
 
+    /// Get the Telegram session encryption key
+    pub fn session_key(&self) -> Result<[u8; KEY_SIZE]> {
+        self.master_key.session_key()
+    }
+
+    /// Get the name of this key's domain in a shared cache
+    ///
+    /// Mounts with the same master key can share cached chunks; the name is
+    /// derived one way, so it reveals nothing about the key.
+    pub fn cache_domain(&self) -> Result<String> {
+        let subkey = Zeroizing::new(self.master_key.derive_subkey(b"tgcryptfs-cache-domain-v1")?);
+        Ok(hex::encode(&subkey[..16]))
+    }
+
     /// Get the salt (needed for config persistence)
     pub fn salt(&self) -> &[u8; SALT_SIZE] {
         self.master_key.salt()
@@ -166,6 +208,10 @@ mod tests {
             argon2_iterations: 1,
             argon2_parallelism: 1,
             salt: Vec::new(),
+            aead_suite: Default::default(),
+            keyfile: None,
+            require_bound_chunks: false,
+            require_bound_metadata: true,
         }
     }
 
@@ -216,6 +262,22 @@ mod tests {
         assert_eq!(chunk1.key(), chunk1_again.key());
     }
 
+    #[test]
+    fn test_master_key_with_keyfile() {
+        let mut config = test_config();
+        config.salt = vec![0u8; SALT_SIZE];
+
+        let plain = MasterKey::from_password(b"password", &config).unwrap();
+        let keyfile = Keyfile::generate();
+        let combined = MasterKey::from_password(b"password", &config)
+            .unwrap()
+            .with_keyfile(&keyfile)
+            .unwrap();
+
+        assert_ne!(plain.key(), combined.key());
+        assert_eq!(plain.salt(), combined.salt());
+    }
+
     #[test]
     fn test_key_manager() {
         let config = test_config();
@@ -1,15 +1,25 @@
 //! Cryptography module for tgcryptfs
 //!
-//! Provides AES-256-GCM encryption with Argon2id key derivation.
+//! Provides AES-256-GCM and XChaCha20-Poly1305 encryption with Argon2id key derivation.
 //! All data is encrypted before leaving the local system.
 
 mod encryption;
 mod kdf;
+mod keyfile;
 mod keys;
+mod segmented;
 
-pub use encryption::{decrypt, encrypt, EncryptedData};
+pub use encryption::{
+    chunk_aad, decrypt, decrypt_bound, encrypt, encrypt_with, object_aad, AeadSuite,
+    EncryptedData, EnvelopeHeader, ObjectKind, ENVELOPE_VERSION, OBJECT_AAD_VERSION,
+};
 pub use kdf::{derive_key, DerivedKey};
+pub use keyfile::{Keyfile, CREDENTIALS_DIRECTORY_ENV, KEYFILE_SIZE};
 pub use keys::{ChunkKey, KeyManager, MasterKey};
+pub use segmented::{
+    decrypt_segmented, decrypt_segments, encrypt_segmented, may_be_segmented, SegmentRange,
+    SegmentedHeader, DEFAULT_SEGMENT_SIZE, MAX_SEGMENTED_HEADER_SIZE, SEGMENTED_VERSION,
+};
 
 /// Size of AES-256 key in bytes
 pub const KEY_SIZE: usize = 32;
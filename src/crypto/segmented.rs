@@ -0,0 +1,341 @@
+//! Segmented chunk encryption
+//!
+//! Chunks are sealed as a sequence of independently authenticated segments,
+//! following the STREAM construction, so that a range of a chunk can be
+//! downloaded and decrypted without the rest of it:
+//!
+//! ```text
+//! magic "TGCE" | version (2) | suite (1) | key epoch (4, BE) | segment size (4, BE) | nonce prefix | segments
+//! ```
+//!
+//! Each segment holds up to `segment size` bytes of plaintext followed by
+//! its tag. Its nonce is the random prefix, the segment index (4, BE) and a
+//! flag set only on the final segment, so segments cannot be reordered or
+//! dropped from the end. The header is authenticated with every segment,
+//! together with the caller's AAD.
+
+use super::encryption::{
+    check_key_epoch, open, seal, ENVELOPE_HEADER_SIZE, ENVELOPE_MAGIC, KEY_EPOCH, XNONCE_SIZE,
+};
+use crate::crypto::{AeadSuite, KEY_SIZE, TAG_SIZE};
+use crate::error::{Error, Result};
+use rand::RngCore;
+
+/// Envelope version of segmented ciphertexts
+pub const SEGMENTED_VERSION: u8 = 2;
+
+/// Default plaintext bytes per segment
+pub const DEFAULT_SEGMENT_SIZE: u32 = 64 * 1024;
+
+/// Nonce bytes taken by the segment index and final flag
+const NONCE_SUFFIX_SIZE: usize = 5;
+
+/// Size of the header before the nonce prefix
+const FIXED_HEADER_SIZE: usize = ENVELOPE_HEADER_SIZE + 4;
+
+/// Largest possible segmented header, enough to parse any of them
+pub const MAX_SEGMENTED_HEADER_SIZE: usize = FIXED_HEADER_SIZE + XNONCE_SIZE - NONCE_SUFFIX_SIZE;
+
+/// Header of a segmented ciphertext
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentedHeader {
+    /// AEAD suite used for every segment
+    pub suite: AeadSuite,
+    /// Key slot / epoch the chunk was sealed under
+    pub key_epoch: u32,
+    /// Plaintext bytes per segment (the last may be shorter)
+    pub segment_size: u32,
+    /// Random nonce prefix shared by all segments
+    pub nonce_prefix: Vec<u8>,
+}
+
+/// Location of the segments covering a plaintext range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentRange {
+    /// Index of the first segment
+    pub first: u64,
+    /// Byte offset of the first segment in the ciphertext
+    pub start: u64,
+    /// Byte offset just past the last segment in the ciphertext
+    pub end: u64,
+    /// Plaintext bytes of the first segment before the range
+    pub skip: usize,
+}
+
+impl SegmentedHeader {
+    /// Create a header with a fresh nonce prefix
+    pub fn new(suite: AeadSuite, segment_size: u32) -> Self {
+        let mut nonce_prefix = vec![0u8; suite.nonce_size() - NONCE_SUFFIX_SIZE];
+        rand::thread_rng().fill_bytes(&mut nonce_prefix);
+        SegmentedHeader {
+            suite,
+            key_epoch: KEY_EPOCH,
+            segment_size,
+            nonce_prefix,
+        }
+    }
+
+    /// Parse a header from the start of a ciphertext
+    ///
+    /// Returns None if the bytes do not start with a segmented header.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < FIXED_HEADER_SIZE || bytes[..4] != ENVELOPE_MAGIC {
+            return None;
+        }
+        if bytes[4] != SEGMENTED_VERSION {
+            return None;
+        }
+        let suite = AeadSuite::from_id(bytes[5])?;
+        let key_epoch = u32::from_be_bytes(bytes[6..10].try_into().unwrap());
+        let segment_size = u32::from_be_bytes(bytes[10..14].try_into().unwrap());
+        let prefix_end = FIXED_HEADER_SIZE + suite.nonce_size() - NONCE_SUFFIX_SIZE;
+        if segment_size == 0 || bytes.len() < prefix_end {
+            return None;
+        }
+        Some(SegmentedHeader {
+            suite,
+            key_epoch,
+            segment_size,
+            nonce_prefix: bytes[FIXED_HEADER_SIZE..prefix_end].to_vec(),
+        })
+    }
+
+    /// Encode the header
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.size());
+        bytes.extend_from_slice(&ENVELOPE_MAGIC);
+        bytes.push(SEGMENTED_VERSION);
+        bytes.push(self.suite.id());
+        bytes.extend_from_slice(&self.key_epoch.to_be_bytes());
+        bytes.extend_from_slice(&self.segment_size.to_be_bytes());
+        bytes.extend_from_slice(&self.nonce_prefix);
+        bytes
+    }
+
+    /// Encoded header size in bytes
+    pub fn size(&self) -> usize {
+        FIXED_HEADER_SIZE + self.nonce_prefix.len()
+    }
+
+    /// Number of segments holding `plaintext_len` bytes (at least one)
+    pub fn segment_count(&self, plaintext_len: u64) -> u64 {
+        plaintext_len.div_ceil(self.segment_size as u64).max(1)
+    }
+
+    /// Ciphertext size of `plaintext_len` bytes of plaintext
+    pub fn sealed_size(&self, plaintext_len: u64) -> u64 {
+        self.size() as u64 + self.segment_count(plaintext_len) * TAG_SIZE as u64 + plaintext_len
+    }
+
+    /// Segments covering `len` bytes at `offset` of `plaintext_len` bytes
+    ///
+    /// Returns None if the range is empty.
+    pub fn segment_range(&self, plaintext_len: u64, offset: u64, len: u64) -> Option<SegmentRange> {
+        let end = plaintext_len.min(offset.saturating_add(len));
+        if offset >= end {
+            return None;
+        }
+        let segment_size = self.segment_size as u64;
+        let sealed_segment = segment_size + TAG_SIZE as u64;
+        let first = offset / segment_size;
+        let last = (end - 1) / segment_size;
+        let last_len = segment_size.min(plaintext_len - last * segment_size);
+        Some(SegmentRange {
+            first,
+            start: self.size() as u64 + first * sealed_segment,
+            end: self.size() as u64 + last * sealed_segment + last_len + TAG_SIZE as u64,
+            skip: (offset - first * segment_size) as usize,
+        })
+    }
+
+    /// Nonce of a segment
+    fn nonce(&self, index: u64, last: bool) -> Result<Vec<u8>> {
+        let index = u32::try_from(index)
+            .map_err(|_| Error::Encryption("Too many segments".to_string()))?;
+        let mut nonce = self.nonce_prefix.clone();
+        nonce.extend_from_slice(&index.to_be_bytes());
+        nonce.push(last as u8);
+        Ok(nonce)
+    }
+
+    /// Additional data fed to the AEAD for every segment
+    fn bound_aad(&self, aad: &[u8]) -> Vec<u8> {
+        let mut bound = self.to_bytes();
+        bound.extend_from_slice(aad);
+        bound
+    }
+}
+
+/// Check whether `sealed_len` bytes can be `plaintext_len` bytes sealed in segments
+///
+/// The overhead of a segmented ciphertext is its header plus one tag per
+/// segment, which no whole envelope's overhead matches, so a chunk sealed
+/// whole is told apart without fetching its header.
+pub fn may_be_segmented(sealed_len: u64, plaintext_len: u64) -> bool {
+    let Some(overhead) = sealed_len.checked_sub(plaintext_len) else {
+        return false;
+    };
+    [AeadSuite::Aes256Gcm, AeadSuite::XChaCha20Poly1305].iter().any(|suite| {
+        let header = (FIXED_HEADER_SIZE + suite.nonce_size() - NONCE_SUFFIX_SIZE) as u64;
+        overhead
+            .checked_sub(header)
+            .filter(|tags| tags % TAG_SIZE as u64 == 0)
+            .is_some_and(|tags| (1..=plaintext_len.max(1)).contains(&(tags / TAG_SIZE as u64)))
+    })
+}
+
+/// Encrypt data as a sequence of `segment_size` segments
+pub fn encrypt_segmented(
+    key: &[u8; KEY_SIZE],
+    plaintext: &[u8],
+    aad: &[u8],
+    suite: AeadSuite,
+    segment_size: u32,
+) -> Result<Vec<u8>> {
+    let header = SegmentedHeader::new(suite, segment_size);
+    let bound_aad = header.bound_aad(aad);
+    let count = header.segment_count(plaintext.len() as u64);
+
+    let mut sealed = Vec::with_capacity(header.sealed_size(plaintext.len() as u64) as usize);
+    sealed.extend_from_slice(&header.to_bytes());
+    for index in 0..count {
+        let start = (index * segment_size as u64) as usize;
+        let end = plaintext.len().min(start + segment_size as usize);
+        let nonce = header.nonce(index, index + 1 == count)?;
+        sealed.extend_from_slice(&seal(suite, key, &nonce, &plaintext[start..end], &bound_aad)?);
+    }
+    Ok(sealed)
+}
+
+/// Decrypt a whole segmented ciphertext
+pub fn decrypt_segmented(key: &[u8; KEY_SIZE], sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+    let header = SegmentedHeader::parse(sealed)
+        .ok_or_else(|| Error::Decryption("Not a segmented ciphertext".to_string()))?;
+    let body = (sealed.len() - header.size()) as u64;
+    let sealed_segment = header.segment_size as u64 + TAG_SIZE as u64;
+    let count = body.div_ceil(sealed_segment).max(1);
+    let plaintext_len = body
+        .checked_sub(count * TAG_SIZE as u64)
+        .ok_or_else(|| Error::Decryption("Ciphertext too short".to_string()))?;
+    decrypt_segments(key, &header, aad, 0, &sealed[header.size()..], plaintext_len)
+}
+
+/// Decrypt consecutive segments starting at segment `first`
+///
+/// `plaintext_len` is the size of the whole chunk, needed to recognise its
+/// final segment.
+pub fn decrypt_segments(
+    key: &[u8; KEY_SIZE],
+    header: &SegmentedHeader,
+    aad: &[u8],
+    first: u64,
+    sealed: &[u8],
+    plaintext_len: u64,
+) -> Result<Vec<u8>> {
+    check_key_epoch(header.key_epoch)?;
+    let bound_aad = header.bound_aad(aad);
+    let segment_size = header.segment_size as u64;
+    let count = header.segment_count(plaintext_len);
+
+    let mut plaintext = Vec::with_capacity(sealed.len());
+    let mut rest = sealed;
+    let mut index = first;
+    while !rest.is_empty() {
+        if index >= count {
+            return Err(Error::Decryption("Trailing data after final segment".to_string()));
+        }
+        let segment_len = segment_size.min(plaintext_len - index * segment_size) as usize + TAG_SIZE;
+        if rest.len() < segment_len {
+            return Err(Error::Decryption("Truncated segment".to_string()));
+        }
+        let nonce = header.nonce(index, index + 1 == count)?;
+        plaintext.extend_from_slice(&open(header.suite, key, &nonce, &rest[..segment_len], &bound_aad)?);
+        rest = &rest[segment_len..];
+        index += 1;
+    }
+    Ok(plaintext)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{encrypt_with, NONCE_SIZE};
+
+    fn test_key() -> [u8; KEY_SIZE] {
+        let mut key = [0u8; KEY_SIZE];
+        rand::thread_rng().fill_bytes(&mut key);
+        key
+    }
+
+    #[test]
+    fn test_segmented_roundtrip() {
+        let key = test_key();
+        for suite in [AeadSuite::Aes256Gcm, AeadSuite::XChaCha20Poly1305] {
+            for len in [0usize, 1, 100, 256, 1000] {
+                let plaintext: Vec<u8> = (0..len).map(|i| i as u8).collect();
+                let sealed = encrypt_segmented(&key, &plaintext, b"aad", suite, 100).unwrap();
+                let header = SegmentedHeader::parse(&sealed).unwrap();
+                assert_eq!((header.suite, header.key_epoch), (suite, KEY_EPOCH));
+                assert_eq!(header.sealed_size(len as u64), sealed.len() as u64);
+                assert!(may_be_segmented(sealed.len() as u64, len as u64));
+                assert_eq!(decrypt_segmented(&key, &sealed, b"aad").unwrap(), plaintext);
+                assert!(decrypt_segmented(&key, &sealed, b"other").is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn test_whole_envelopes_are_not_segmented() {
+        let key = test_key();
+        for len in [0usize, 1, 100, 1000] {
+            let plaintext = vec![7u8; len];
+            for suite in [AeadSuite::Aes256Gcm, AeadSuite::XChaCha20Poly1305] {
+                let sealed = encrypt_with(&key, &plaintext, b"", suite).unwrap();
+                assert!(!may_be_segmented(sealed.size() as u64, len as u64));
+            }
+            // Legacy nonce || ciphertext blobs
+            let legacy = (NONCE_SIZE + len + TAG_SIZE) as u64;
+            assert!(!may_be_segmented(legacy, len as u64));
+        }
+    }
+
+    #[test]
+    fn test_segment_range() {
+        let key = test_key();
+        let plaintext: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        let sealed =
+            encrypt_segmented(&key, &plaintext, b"", AeadSuite::Aes256Gcm, 100).unwrap();
+        let header = SegmentedHeader::parse(&sealed).unwrap();
+
+        for (offset, len) in [(0u64, 10u64), (150, 200), (990, 50), (0, 1000)] {
+            let range = header.segment_range(1000, offset, len).unwrap();
+            let segments = &sealed[range.start as usize..range.end as usize];
+            let decrypted = decrypt_segments(&key, &header, b"", range.first, segments, 1000).unwrap();
+            let end = 1000.min(offset + len) as usize;
+            assert_eq!(
+                &decrypted[range.skip..range.skip + end - offset as usize],
+                &plaintext[offset as usize..end]
+            );
+        }
+        assert!(header.segment_range(1000, 1000, 10).is_none());
+    }
+
+    #[test]
+    fn test_segments_bound_to_position() {
+        let key = test_key();
+        let plaintext = vec![7u8; 300];
+        let sealed =
+            encrypt_segmented(&key, &plaintext, b"", AeadSuite::Aes256Gcm, 100).unwrap();
+        let header = SegmentedHeader::parse(&sealed).unwrap();
+        let segment = |i: usize| {
+            let start = header.size() + i * (100 + TAG_SIZE);
+            &sealed[start..start + 100 + TAG_SIZE]
+        };
+
+        // A segment served in place of another does not authenticate
+        assert!(decrypt_segments(&key, &header, b"", 1, segment(0), 300).is_err());
+        // Dropping the final segment is detected
+        let truncated = &sealed[..sealed.len() - 100 - TAG_SIZE];
+        assert!(decrypt_segmented(&key, truncated, b"").is_err());
+    }
+}
@@ -111,8 +111,13 @@ impl MachineIdentity {
 
     /// Verify a signature using this machine's public key
     pub fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
+        Self::verify_with_key(&self.public_key, data, signature)
+    }
+
+    /// Verify a signature made by another machine, given its public key
+    pub fn verify_with_key(public_key: &[u8; 32], data: &[u8], signature: &[u8]) -> bool {
         use ring::signature::{UnparsedPublicKey, ED25519};
-        let public_key = UnparsedPublicKey::new(&ED25519, &self.public_key);
+        let public_key = UnparsedPublicKey::new(&ED25519, public_key);
         public_key.verify(data, signature).is_ok()
     }
 
@@ -269,6 +274,10 @@ mod tests {
             argon2_iterations: 1,
             argon2_parallelism: 1,
             salt: Vec::new(),
+            aead_suite: Default::default(),
+            keyfile: None,
+            require_bound_chunks: false,
+            require_bound_metadata: true,
         }
     }
 
@@ -21,7 +21,8 @@ pub mod identity;
 
 // Re-export master-replica types
 pub use replication::{
-    MetadataSnapshot, ReplicaEnforcer, ReplicationRole, SnapshotManager,
+    EpochState, MetadataSnapshot, ReplicaEnforcer, ReplicationRole, SignedSnapshot,
+    SnapshotManager,
 };
 pub use sync::{SyncConfig, SyncDaemon, SyncStatus};
 
@@ -5,8 +5,13 @@
 //! - Multiple replica nodes have read-only access
 //! - The master periodically creates snapshots and uploads to Telegram
 //! - Replicas periodically download and apply the latest snapshot
+//!
+//! Snapshots form a signed epoch chain: each one carries the hash of its
+//! predecessor and is signed by the master's machine identity. Clients
+//! remember the highest epoch they have seen and refuse older snapshots.
 
-use crate::crypto::{decrypt, encrypt, EncryptedData, KEY_SIZE};
+use crate::crypto::{decrypt_bound, encrypt, object_aad, EncryptedData, ObjectKind, KEY_SIZE};
+use crate::distributed::identity::MachineIdentity;
 use crate::error::{Error, Result};
 use crate::metadata::{Inode, MetadataStore};
 use crate::telegram::TelegramBackend;
@@ -57,9 +62,12 @@ pub struct MetadataSnapshot {
     /// Creation timestamp
     pub created_at: DateTime<Utc>,
 
-    /// Snapshot version number (monotonically increasing)
+    /// Snapshot version number (monotonically increasing epoch)
     pub version: u64,
 
+    /// Hash of the previous snapshot in the epoch chain
+    pub prev_hash: Option<[u8; 32]>,
+
     /// All inodes in the filesystem (ino -> inode)
     pub inodes: HashMap<u64, Inode>,
 
@@ -85,6 +93,7 @@ impl MetadataSnapshot {
             namespace_id,
             created_at: Utc::now(),
             version,
+            prev_hash: None,
             inodes,
             next_ino,
             description: None,
@@ -111,6 +120,121 @@ impl MetadataSnapshot {
         self.description = Some(description);
         self
     }
+
+    /// Link this snapshot to its predecessor in the epoch chain
+    pub fn with_prev_hash(mut self, prev_hash: [u8; 32]) -> Self {
+        self.prev_hash = Some(prev_hash);
+        self
+    }
+}
+
+/// A serialized snapshot signed by the machine that wrote it
+///
+/// The signature covers the exact serialized bytes, so verification does not
+/// depend on re-serializing the snapshot deterministically.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedSnapshot {
+    /// Serialized `MetadataSnapshot`
+    pub body: Vec<u8>,
+
+    /// Ed25519 public key of the signer
+    pub signer: [u8; 32],
+
+    /// Ed25519 signature over `body`
+    pub signature: Vec<u8>,
+}
+
+impl SignedSnapshot {
+    /// Serialize and sign a snapshot
+    pub fn sign(snapshot: &MetadataSnapshot, identity: &MachineIdentity) -> Result<Self> {
+        let body = snapshot.serialize()?;
+        let signature = identity.sign(&body)?;
+        Ok(Self {
+            body,
+            signer: identity.public_key,
+            signature,
+        })
+    }
+
+    /// Verify the signature against a trusted key and return the snapshot
+    pub fn verify(&self, trusted_key: &[u8; 32]) -> Result<MetadataSnapshot> {
+        if &self.signer != trusted_key {
+            return Err(Error::SnapshotVerification(format!(
+                "signed by untrusted key {}",
+                hex::encode(self.signer)
+            )));
+        }
+        if !MachineIdentity::verify_with_key(trusted_key, &self.body, &self.signature) {
+            return Err(Error::SnapshotVerification("invalid signature".to_string()));
+        }
+        MetadataSnapshot::deserialize(&self.body)
+    }
+
+    /// Hash identifying this snapshot in the epoch chain
+    pub fn hash(&self) -> [u8; 32] {
+        *blake3::hash(&self.body).as_bytes()
+    }
+}
+
+/// Highest snapshot epoch seen for a namespace
+///
+/// The state lives under a `local:` key, which is never journaled or
+/// replicated, so it only protects the machine that recorded it. A machine
+/// without it (a new replica, or one whose data directory was reset)
+/// trusts the first snapshot it downloads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EpochState {
+    /// Highest snapshot version seen
+    pub epoch: u64,
+
+    /// Hash of the snapshot at that epoch
+    pub hash: [u8; 32],
+}
+
+impl EpochState {
+    /// Local metadata key the epoch state is stored under
+    const KEY: &'static str = "local:snapshot_epoch";
+
+    /// Load the persisted epoch state, if any
+    pub fn load(store: &MetadataStore) -> Result<Option<Self>> {
+        match store.get_metadata(Self::KEY)? {
+            Some(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Persist the epoch state
+    pub fn save(&self, store: &MetadataStore) -> Result<()> {
+        store.save_metadata(Self::KEY, &bincode::serialize(self)?)?;
+        store.flush()
+    }
+
+    /// Check that a snapshot does not regress or fork the chain
+    pub fn check(&self, snapshot: &MetadataSnapshot, hash: &[u8; 32]) -> Result<()> {
+        if snapshot.version < self.epoch {
+            return Err(Error::SnapshotRollback {
+                epoch: snapshot.version,
+                highest: self.epoch,
+            });
+        }
+
+        if snapshot.version == self.epoch && hash != &self.hash {
+            return Err(Error::SnapshotVerification(format!(
+                "epoch {} was already seen with different contents",
+                self.epoch
+            )));
+        }
+
+        // Only a direct successor can be linked; replicas may skip epochs
+        if snapshot.version == self.epoch + 1 && snapshot.prev_hash != Some(self.hash) {
+            return Err(Error::SnapshotVerification(format!(
+                "epoch {} does not chain from epoch {}",
+                snapshot.version, self.epoch
+            )));
+        }
+
+        Ok(())
+    }
 }
 
 /// Snapshot metadata stored in the metadata store
@@ -155,6 +279,18 @@ pub struct SnapshotManager {
     /// Current version number
     current_version: Arc<RwLock<u64>>,
 
+    /// Highest epoch seen, with its hash
+    epoch_state: Arc<RwLock<Option<EpochState>>>,
+
+    /// Identity used to sign uploaded snapshots
+    identity: Option<MachineIdentity>,
+
+    /// Public key downloaded snapshots must be signed with
+    trusted_master: Option<[u8; 32]>,
+
+    /// Accept snapshots older than the highest epoch seen
+    allow_rollback: bool,
+
     /// Maximum snapshots to retain (TODO: implement retention policy)
     #[allow(dead_code)]
     max_snapshots: usize,
@@ -162,6 +298,8 @@ pub struct SnapshotManager {
 
 impl SnapshotManager {
     /// Create a new snapshot manager
+    ///
+    /// The highest epoch seen is restored from the metadata store.
     pub fn new(
         key: [u8; KEY_SIZE],
         telegram: Arc<TelegramBackend>,
@@ -169,16 +307,96 @@ impl SnapshotManager {
         machine_id: Uuid,
         namespace_id: String,
         max_snapshots: usize,
-    ) -> Self {
-        Self {
+    ) -> Result<Self> {
+        let epoch_state = EpochState::load(&metadata_store)?;
+        if epoch_state.is_none() {
+            warn!(
+                "No snapshot epoch recorded for namespace {}; the next snapshot is trusted without a rollback check",
+                namespace_id
+            );
+        }
+        let current_version = epoch_state.map(|s| s.epoch).unwrap_or(0);
+
+        Ok(Self {
             key,
             telegram,
             metadata_store,
             machine_id,
             namespace_id,
-            current_version: Arc::new(RwLock::new(0)),
+            current_version: Arc::new(RwLock::new(current_version)),
+            epoch_state: Arc::new(RwLock::new(epoch_state)),
+            identity: None,
+            trusted_master: None,
+            allow_rollback: false,
             max_snapshots,
+        })
+    }
+
+    /// Sign uploaded snapshots with this machine identity
+    pub fn with_identity(mut self, identity: MachineIdentity) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    /// Require downloaded snapshots to be signed by this master key
+    pub fn with_trusted_master(mut self, public_key: [u8; 32]) -> Self {
+        self.trusted_master = Some(public_key);
+        self
+    }
+
+    /// Accept snapshots older than the highest epoch seen
+    pub fn with_allow_rollback(mut self, allow_rollback: bool) -> Self {
+        self.allow_rollback = allow_rollback;
+        self
+    }
+
+    /// Key downloaded snapshots are verified against
+    ///
+    /// Replicas trust the configured master key; a master without one
+    /// trusts its own identity.
+    fn trusted_key(&self) -> Result<[u8; 32]> {
+        self.trusted_master
+            .or_else(|| self.identity.as_ref().map(|i| i.public_key))
+            .ok_or_else(|| {
+                Error::SnapshotVerification("no trusted master public key configured".to_string())
+            })
+    }
+
+    /// Check a verified snapshot against the epoch chain and remember it
+    async fn accept_epoch(&self, snapshot: &MetadataSnapshot, hash: [u8; 32]) -> Result<()> {
+        let mut state = self.epoch_state.write().await;
+
+        if let Some(current) = state.as_ref() {
+            if let Err(e) = current.check(snapshot, &hash) {
+                if !self.allow_rollback {
+                    return Err(e);
+                }
+                warn!("{}; accepting because --allow-rollback is set", e);
+                // The override is for this snapshot only; the chain keeps
+                // checking against the highest epoch seen
+                if snapshot.version <= current.epoch {
+                    return Ok(());
+                }
+            }
         }
+
+        let next = EpochState {
+            epoch: snapshot.version,
+            hash,
+        };
+        next.save(&self.metadata_store)?;
+        *state = Some(next);
+        Ok(())
+    }
+
+    /// AAD binding a snapshot blob to its namespace, ID and version
+    fn snapshot_aad(&self, snapshot_id: &str, version: u64) -> Vec<u8> {
+        object_aad(
+            ObjectKind::Snapshot,
+            Some(&self.namespace_id),
+            snapshot_id.as_bytes(),
+            version,
+        )
     }
 
     /// Create a snapshot of the current metadata state
@@ -204,8 +422,8 @@ impl SnapshotManager {
 
                 // If it's a directory, add children to visit list
                 if inode.is_dir() {
-                    for child_ino in &inode.children {
-                        to_visit.push(*child_ino);
+                    for child in self.metadata_store.get_children(ino)? {
+                        to_visit.push(child.ino);
                     }
                 }
 
@@ -221,7 +439,7 @@ impl SnapshotManager {
         let snapshot_version = *version;
         drop(version);
 
-        let snapshot = MetadataSnapshot::new(
+        let mut snapshot = MetadataSnapshot::new(
             self.machine_id,
             self.namespace_id.clone(),
             snapshot_version,
@@ -229,6 +447,11 @@ impl SnapshotManager {
             next_ino,
         );
 
+        // Chain to the last snapshot we uploaded or accepted
+        if let Some(state) = self.epoch_state.read().await.as_ref() {
+            snapshot = snapshot.with_prev_hash(state.hash);
+        }
+
         info!(
             "Created snapshot {} with {} inodes (version {})",
             snapshot.id,
@@ -243,12 +466,17 @@ impl SnapshotManager {
     pub async fn upload_snapshot(&self, snapshot: &MetadataSnapshot) -> Result<i32> {
         info!("Uploading snapshot {} to Telegram", snapshot.id);
 
-        // Serialize the snapshot
-        let data = snapshot.serialize()?;
+        // Serialize and sign the snapshot
+        let identity = self.identity.as_ref().ok_or_else(|| {
+            Error::SnapshotVerification("signing snapshots requires a machine identity".to_string())
+        })?;
+        let signed = SignedSnapshot::sign(snapshot, identity)?;
+        let data = bincode::serialize(&signed)?;
         debug!("Snapshot serialized to {} bytes", data.len());
 
-        // Encrypt the data
-        let encrypted = encrypt(&self.key, &data, &[])?;
+        // Encrypt the data, bound to this snapshot's identity and version
+        let aad = self.snapshot_aad(&snapshot.id, snapshot.version);
+        let encrypted = encrypt(&self.key, &data, &aad)?;
         let encrypted_bytes = encrypted.to_bytes();
         debug!("Snapshot encrypted to {} bytes", encrypted_bytes.len());
 
@@ -270,6 +498,9 @@ impl SnapshotManager {
         let metadata_bytes = bincode::serialize(&metadata)?;
         self.metadata_store.save_metadata(&metadata_key, &metadata_bytes)?;
 
+        // This snapshot is now the head of the epoch chain
+        self.accept_epoch(snapshot, signed.hash()).await?;
+
         info!(
             "Snapshot {} uploaded as message {} ({} bytes)",
             snapshot.id, message_id, encrypted_bytes.len()
@@ -292,13 +523,34 @@ impl SnapshotManager {
         let encrypted_bytes = self.telegram.download_chunk(latest_metadata.message_id).await?;
         debug!("Downloaded {} bytes from Telegram", encrypted_bytes.len());
 
-        // Decrypt
+        // Decrypt; snapshots uploaded before AAD binding are only accepted
+        // until the local store has been migrated
         let encrypted = EncryptedData::from_bytes(&encrypted_bytes)?;
-        let decrypted = decrypt(&self.key, &encrypted, &[])?;
+        let aad = self.snapshot_aad(&latest_metadata.snapshot_id, latest_metadata.version);
+        let unbound_aad: Option<&[u8]> = if self.metadata_store.is_aad_bound() {
+            None
+        } else {
+            Some(&[])
+        };
+        let decrypted = decrypt_bound(&self.key, &encrypted, &aad, unbound_aad)?;
         debug!("Decrypted to {} bytes", decrypted.len());
 
-        // Deserialize
-        let snapshot = MetadataSnapshot::deserialize(&decrypted)?;
+        // Verify the signature and refuse regressions of the epoch chain.
+        // Snapshots from before signing are replaced by the master's next sync.
+        let signed: SignedSnapshot = bincode::deserialize(&decrypted).map_err(|_| {
+            Error::SnapshotVerification(
+                "snapshot is not signed; run `tgcryptfs sync` on the master to publish a signed one"
+                    .to_string(),
+            )
+        })?;
+        let snapshot = signed.verify(&self.trusted_key()?)?;
+        if snapshot.namespace_id != self.namespace_id {
+            return Err(Error::SnapshotVerification(format!(
+                "snapshot belongs to namespace '{}', expected '{}'",
+                snapshot.namespace_id, self.namespace_id
+            )));
+        }
+        self.accept_epoch(&snapshot, signed.hash()).await?;
 
         info!(
             "Downloaded snapshot {} with {} inodes (version {})",
@@ -528,4 +780,123 @@ mod tests {
         assert!(message.contains("production"));
         assert!(message.contains("read-only"));
     }
+
+    fn test_identity() -> MachineIdentity {
+        let config = crate::config::EncryptionConfig {
+            argon2_memory_kib: 1024,
+            argon2_iterations: 1,
+            argon2_parallelism: 1,
+            salt: Vec::new(),
+            aead_suite: Default::default(),
+            keyfile: None,
+            require_bound_chunks: false,
+            require_bound_metadata: true,
+        };
+        MachineIdentity::generate("master".to_string(), &[7u8; 32], &config).unwrap()
+    }
+
+    fn test_snapshot(version: u64) -> MetadataSnapshot {
+        let mut inodes = HashMap::new();
+        inodes.insert(1, Inode::root(1000, 1000, 0o755));
+        MetadataSnapshot::new(Uuid::new_v4(), "test".to_string(), version, inodes, 2)
+    }
+
+    #[test]
+    fn test_signed_snapshot_verification() {
+        let identity = test_identity();
+        let snapshot = test_snapshot(1);
+
+        let signed = SignedSnapshot::sign(&snapshot, &identity).unwrap();
+        let verified = signed.verify(&identity.public_key).unwrap();
+        assert_eq!(verified.id, snapshot.id);
+
+        // Untrusted signer
+        let other = test_identity();
+        assert!(signed.verify(&other.public_key).is_err());
+
+        // Tampered body
+        let mut tampered = signed.clone();
+        let last = tampered.body.len() - 1;
+        tampered.body[last] ^= 0xff;
+        assert!(tampered.verify(&identity.public_key).is_err());
+    }
+
+    #[test]
+    fn test_epoch_state_refuses_rollback() {
+        let state = EpochState {
+            epoch: 5,
+            hash: [1u8; 32],
+        };
+
+        match state.check(&test_snapshot(4), &[2u8; 32]) {
+            Err(Error::SnapshotRollback { epoch, highest }) => {
+                assert_eq!(epoch, 4);
+                assert_eq!(highest, 5);
+            }
+            other => panic!("expected rollback error, got {:?}", other),
+        }
+
+        // Same epoch is only accepted with the same contents
+        assert!(state.check(&test_snapshot(5), &[1u8; 32]).is_ok());
+        assert!(state.check(&test_snapshot(5), &[2u8; 32]).is_err());
+
+        // A direct successor must chain from the current head
+        assert!(state.check(&test_snapshot(6), &[3u8; 32]).is_err());
+        let linked = test_snapshot(6).with_prev_hash([1u8; 32]);
+        assert!(state.check(&linked, &[3u8; 32]).is_ok());
+
+        // Later epochs may skip intermediate snapshots
+        assert!(state.check(&test_snapshot(9), &[4u8; 32]).is_ok());
+    }
+
+    #[test]
+    fn test_epoch_state_persistence() {
+        let store = MetadataStore::in_memory([3u8; KEY_SIZE]).unwrap();
+        assert!(EpochState::load(&store).unwrap().is_none());
+
+        let state = EpochState {
+            epoch: 12,
+            hash: [9u8; 32],
+        };
+        state.save(&store).unwrap();
+        assert_eq!(EpochState::load(&store).unwrap(), Some(state));
+    }
+
+    fn test_manager(store: Arc<MetadataStore>) -> SnapshotManager {
+        let telegram = Arc::new(TelegramBackend::new(Default::default()));
+        SnapshotManager::new([3u8; KEY_SIZE], telegram, store, Uuid::new_v4(), "test".to_string(), 10)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_trusted_key_prefers_configured_master() {
+        let store = Arc::new(MetadataStore::in_memory([3u8; KEY_SIZE]).unwrap());
+        let replica = test_identity();
+        let master = test_identity();
+
+        assert!(test_manager(store.clone()).trusted_key().is_err());
+
+        let manager = test_manager(store.clone()).with_identity(replica.clone());
+        assert_eq!(manager.trusted_key().unwrap(), replica.public_key);
+
+        let manager = test_manager(store)
+            .with_identity(replica)
+            .with_trusted_master(master.public_key);
+        assert_eq!(manager.trusted_key().unwrap(), master.public_key);
+    }
+
+    #[tokio::test]
+    async fn test_allow_rollback_accepts_older_epoch() {
+        let store = Arc::new(MetadataStore::in_memory([3u8; KEY_SIZE]).unwrap());
+        let manager = test_manager(store.clone());
+        manager.accept_epoch(&test_snapshot(5), [1u8; 32]).await.unwrap();
+        assert!(manager.accept_epoch(&test_snapshot(4), [2u8; 32]).await.is_err());
+
+        // The older snapshot is accepted, but the highest epoch is kept
+        let manager = test_manager(store.clone()).with_allow_rollback(true);
+        manager.accept_epoch(&test_snapshot(4), [2u8; 32]).await.unwrap();
+        let state = EpochState::load(&store).unwrap().unwrap();
+        assert_eq!((state.epoch, state.hash), (5, [1u8; 32]));
+        assert!(test_manager(store).accept_epoch(&test_snapshot(4), [2u8; 32]).await.is_err());
+    }
 }
@@ -41,6 +41,9 @@ pub enum Error {
     #[error("Message not found: {0}")]
     MessageNotFound(i32),
 
+    #[error("Backend unreachable: {0}")]
+    Offline(String),
+
     // Chunk errors
     #[error("Chunk not found: {0}")]
     ChunkNotFound(String),
@@ -73,10 +76,31 @@ pub enum Error {
     #[error("Database error: {0}")]
     Database(#[from] sled::Error),
 
+    #[error("Metadata recovery failed: {0}")]
+    Recovery(String),
+
+    #[error("Invalid metadata archive: {0}")]
+    Archive(String),
+
     // Filesystem errors
     #[error("Permission denied")]
     PermissionDenied,
 
+    #[error("Operation not permitted: {0}")]
+    NotPermitted(String),
+
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+
+    #[error("Operation not supported: {0}")]
+    Unsupported(String),
+
+    #[error("No such attribute: {0}")]
+    NoAttribute(String),
+
+    #[error("Read-only while offline: {0}")]
+    ReadOnly(String),
+
     #[error("Invalid file handle: {0}")]
     InvalidFileHandle(u64),
 
@@ -97,6 +121,12 @@ pub enum Error {
     #[error("Snapshot already exists: {0}")]
     SnapshotAlreadyExists(String),
 
+    #[error("Snapshot rollback refused: epoch {epoch} is older than highest seen epoch {highest} (use --allow-rollback to override)")]
+    SnapshotRollback { epoch: u64, highest: u64 },
+
+    #[error("Snapshot verification failed: {0}")]
+    SnapshotVerification(String),
+
     // Version errors
     #[error("Version not found: {0}")]
     VersionNotFound(u64),
@@ -183,12 +213,28 @@ impl Error {
             Error::DirectoryNotEmpty(_) => libc::ENOTEMPTY,
             Error::AlreadyExists(_) => libc::EEXIST,
             Error::PermissionDenied => libc::EACCES,
+            Error::NotPermitted(_) => libc::EPERM,
+            Error::InvalidArgument(_) => libc::EINVAL,
+            Error::Unsupported(_) => libc::EOPNOTSUPP,
+            Error::NoAttribute(_) => libc::ENODATA,
             Error::FileTooLarge { .. } => libc::EFBIG,
+            Error::ReadOnly(_) => libc::EROFS,
             Error::Io(e) => e.raw_os_error().unwrap_or(libc::EIO),
             Error::TelegramRateLimited { .. } => libc::EAGAIN,
             _ => libc::EIO,
         }
     }
+
+    /// Whether the error suggests the backend is unreachable
+    pub fn is_connectivity(&self) -> bool {
+        matches!(
+            self,
+            Error::TelegramClient(_)
+                | Error::TelegramUpload(_)
+                | Error::TelegramDownload(_)
+                | Error::Offline(_)
+        )
+    }
 }
 
 impl From<bincode::Error> for Error {
@@ -0,0 +1,772 @@
+//! Permission checks against the requesting user
+//!
+//! FUSE passes the caller's uid and gid with every request; ownership and
+//! mode checks use them rather than the identity of the mounting process.
+//! FUSE does not forward supplementary groups, so they are read from
+//! `/proc/<pid>/status` of the calling process.
+//!
+//! POSIX ACLs are read from the `system.posix_acl_access` and
+//! `system.posix_acl_default` xattrs in the kernel's binary format, so
+//! `getfacl`/`setfacl` work unchanged when ACL support is enabled.
+
+use crate::error::{Error, Result};
+use crate::metadata::{FileType, Inode, InodeAttributes};
+use fuser::{Request, TimeOrNow};
+use std::time::SystemTime;
+
+/// Read permission (as in access(2))
+pub const MAY_READ: u16 = 4;
+
+/// Write permission
+pub const MAY_WRITE: u16 = 2;
+
+/// Execute/search permission
+pub const MAY_EXEC: u16 = 1;
+
+/// Xattr holding an inode's access ACL
+pub const ACL_ACCESS_XATTR: &str = "system.posix_acl_access";
+
+/// Xattr holding a directory's default ACL
+pub const ACL_DEFAULT_XATTR: &str = "system.posix_acl_default";
+
+/// ACL xattr format version
+const ACL_VERSION: u32 = 2;
+
+// ACL entry tags
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+
+/// Qualifier of entries that have none
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+
+/// Supplementary groups from the `Groups:` line of `/proc/<pid>/status`
+fn parse_status_groups(status: &str) -> Vec<u32> {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Groups:"))
+        .map(|groups| groups.split_whitespace().filter_map(|g| g.parse().ok()).collect())
+        .unwrap_or_default()
+}
+
+/// Permissions needed to open a file with the given `open(2)` flags
+pub fn open_mask(flags: i32) -> u16 {
+    let mask = match flags & libc::O_ACCMODE {
+        libc::O_RDONLY => MAY_READ,
+        libc::O_WRONLY => MAY_WRITE,
+        _ => MAY_READ | MAY_WRITE,
+    };
+    if flags & libc::O_TRUNC != 0 {
+        mask | MAY_WRITE
+    } else {
+        mask
+    }
+}
+
+/// The user making a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Caller {
+    /// User ID
+    pub uid: u32,
+    /// Primary group ID
+    pub gid: u32,
+    /// Supplementary group IDs
+    pub groups: Vec<u32>,
+}
+
+impl Caller {
+    /// Create a caller with no supplementary groups
+    pub fn new(uid: u32, gid: u32) -> Self {
+        Caller {
+            uid,
+            gid,
+            groups: Vec::new(),
+        }
+    }
+
+    /// Set the supplementary groups
+    pub fn with_groups(mut self, groups: Vec<u32>) -> Self {
+        self.groups = groups;
+        self
+    }
+
+    /// The caller of a FUSE request
+    ///
+    /// If the process has already exited (or lives in another PID
+    /// namespace) only its primary group is known.
+    pub fn from_request(req: &Request) -> Self {
+        let groups = std::fs::read_to_string(format!("/proc/{}/status", req.pid()))
+            .map(|status| parse_status_groups(&status))
+            .unwrap_or_default();
+        Caller::new(req.uid(), req.gid()).with_groups(groups)
+    }
+
+    /// Check if the caller is root
+    pub fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+
+    /// Check if the caller owns the inode (root owns everything)
+    pub fn owns(&self, attrs: &InodeAttributes) -> bool {
+        self.is_root() || self.uid == attrs.uid
+    }
+
+    /// Check if the caller is a member of a group
+    pub fn in_group(&self, gid: u32) -> bool {
+        self.gid == gid || self.groups.contains(&gid)
+    }
+
+    /// Check the mode bits for every permission in `mask` (`MAY_*` bits)
+    pub fn may_access(&self, attrs: &InodeAttributes, mask: u16) -> bool {
+        if self.is_root() {
+            // Root may execute only what someone can execute
+            return mask & MAY_EXEC == 0
+                || attrs.kind == FileType::Directory
+                || attrs.perm & 0o111 != 0;
+        }
+        let bits = if self.uid == attrs.uid {
+            attrs.perm >> 6
+        } else if self.in_group(attrs.gid) {
+            attrs.perm >> 3
+        } else {
+            attrs.perm
+        } & 0o7;
+        bits & mask == mask
+    }
+
+    /// Check access to an inode, consulting its access ACL if `acls` is set
+    pub fn check(&self, inode: &Inode, mask: u16, acls: bool) -> Result<()> {
+        let acl = if acls && !self.is_root() {
+            inode
+                .xattrs
+                .get(ACL_ACCESS_XATTR)
+                .and_then(|data| PosixAcl::from_xattr(data).ok())
+        } else {
+            None
+        };
+        let allowed = match acl {
+            Some(acl) => acl.permits(self, &inode.attrs, mask),
+            None => self.may_access(&inode.attrs, mask),
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(Error::PermissionDenied)
+        }
+    }
+
+    /// Check that the caller may remove or rename `victim` out of `dir`
+    ///
+    /// In a sticky directory only the owner of the entry or of the
+    /// directory (or root) may do so.
+    pub fn check_sticky(&self, dir: &InodeAttributes, victim: &InodeAttributes) -> Result<()> {
+        if dir.perm & 0o1000 == 0 || self.owns(dir) || self.owns(victim) {
+            Ok(())
+        } else {
+            Err(Error::NotPermitted("sticky directory".to_string()))
+        }
+    }
+
+    /// Check that the caller may read or write an extended attribute
+    pub fn check_xattr(&self, inode: &Inode, name: &str, write: bool, acls: bool) -> Result<()> {
+        if name == ACL_ACCESS_XATTR || name == ACL_DEFAULT_XATTR {
+            if !acls {
+                return Err(Error::Unsupported(name.to_string()));
+            }
+            if write && !self.owns(&inode.attrs) {
+                return Err(Error::NotPermitted("only the owner can set ACLs".to_string()));
+            }
+            Ok(())
+        } else if name.starts_with("user.") {
+            let mask = if write { MAY_WRITE } else { MAY_READ };
+            self.check(inode, mask, acls)
+        } else if name.starts_with("trusted.") {
+            if self.is_root() {
+                Ok(())
+            } else {
+                Err(Error::NotPermitted("trusted attributes need root".to_string()))
+            }
+        } else if name.starts_with("security.") {
+            if write && !self.owns(&inode.attrs) {
+                return Err(Error::NotPermitted("only the owner can set security attributes".to_string()));
+            }
+            Ok(())
+        } else {
+            Err(Error::Unsupported(name.to_string()))
+        }
+    }
+
+    /// Check whether an extended attribute name is listed for the caller
+    ///
+    /// As in the kernel, `trusted.*` names are only listed for root. Values
+    /// are still subject to `check_xattr`.
+    pub fn lists_xattr(&self, name: &str, acls: bool) -> bool {
+        if name == ACL_ACCESS_XATTR || name == ACL_DEFAULT_XATTR {
+            acls
+        } else if name.starts_with("trusted.") {
+            self.is_root()
+        } else {
+            name.starts_with("user.") || name.starts_with("security.")
+        }
+    }
+
+    /// Give a new inode to the caller
+    ///
+    /// Inside a setgid directory the group is inherited and new directories
+    /// stay setgid. A file the caller creates in a group it is not a member
+    /// of loses its setgid bit.
+    pub fn take_ownership(&self, parent: &InodeAttributes, attrs: &mut InodeAttributes) {
+        attrs.uid = self.uid;
+        attrs.gid = self.gid;
+        if parent.perm & 0o2000 != 0 {
+            attrs.gid = parent.gid;
+            if attrs.kind == FileType::Directory {
+                attrs.perm |= 0o2000;
+            }
+        }
+        if attrs.kind != FileType::Directory && !self.is_root() && !self.in_group(attrs.gid) {
+            attrs.perm &= !0o2000;
+        }
+    }
+}
+
+/// One ACL entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct AclEntry {
+    tag: u16,
+    perm: u16,
+    id: u32,
+}
+
+/// A POSIX access or default ACL
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PosixAcl {
+    entries: Vec<AclEntry>,
+}
+
+impl PosixAcl {
+    /// Decode and validate the xattr representation
+    pub fn from_xattr(data: &[u8]) -> Result<Self> {
+        let invalid = |reason: &str| Error::InvalidArgument(format!("ACL: {}", reason));
+        if data.len() < 4 || !(data.len() - 4).is_multiple_of(8) {
+            return Err(invalid("bad length"));
+        }
+        if u32::from_le_bytes(data[..4].try_into().unwrap()) != ACL_VERSION {
+            return Err(invalid("unsupported version"));
+        }
+
+        let entries: Vec<AclEntry> = data[4..]
+            .chunks_exact(8)
+            .map(|e| AclEntry {
+                tag: u16::from_le_bytes([e[0], e[1]]),
+                perm: u16::from_le_bytes([e[2], e[3]]),
+                id: u32::from_le_bytes([e[4], e[5], e[6], e[7]]),
+            })
+            .collect();
+
+        let count = |tag| entries.iter().filter(|e| e.tag == tag).count();
+        for tag in [ACL_USER_OBJ, ACL_GROUP_OBJ, ACL_OTHER] {
+            if count(tag) != 1 {
+                return Err(invalid("missing or repeated base entry"));
+            }
+        }
+        let named = count(ACL_USER) + count(ACL_GROUP);
+        if count(ACL_MASK) > 1 || (named > 0 && count(ACL_MASK) == 0) {
+            return Err(invalid("named entries need exactly one mask"));
+        }
+        if entries.iter().any(|e| {
+            e.perm & !0o7 != 0
+                || !matches!(e.tag, ACL_USER_OBJ | ACL_USER | ACL_GROUP_OBJ | ACL_GROUP | ACL_MASK | ACL_OTHER)
+        }) {
+            return Err(invalid("bad entry"));
+        }
+        Ok(PosixAcl { entries })
+    }
+
+    /// Encode as the xattr representation
+    pub fn to_xattr(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(4 + 8 * self.entries.len());
+        data.extend_from_slice(&ACL_VERSION.to_le_bytes());
+        for entry in &self.entries {
+            data.extend_from_slice(&entry.tag.to_le_bytes());
+            data.extend_from_slice(&entry.perm.to_le_bytes());
+            data.extend_from_slice(&entry.id.to_le_bytes());
+        }
+        data
+    }
+
+    /// Check whether the ACL is fully described by the mode bits
+    pub fn is_minimal(&self) -> bool {
+        self.entries.len() == 3
+    }
+
+    /// Permission bits (rwxrwxrwx) equivalent to the ACL
+    ///
+    /// The group class shows the mask when there is one.
+    pub fn mode_bits(&self) -> u16 {
+        let group = self.perm(ACL_MASK).unwrap_or_else(|| self.perm(ACL_GROUP_OBJ).unwrap_or(0));
+        self.perm(ACL_USER_OBJ).unwrap_or(0) << 6 | group << 3 | self.perm(ACL_OTHER).unwrap_or(0)
+    }
+
+    /// Follow a chmod: the mode bits replace the owner, group class and other entries
+    pub fn chmod(&mut self, perm: u16) {
+        let group_tag = if self.perm(ACL_MASK).is_some() {
+            ACL_MASK
+        } else {
+            ACL_GROUP_OBJ
+        };
+        for entry in &mut self.entries {
+            match entry.tag {
+                ACL_USER_OBJ => entry.perm = (perm >> 6) & 0o7,
+                ACL_OTHER => entry.perm = perm & 0o7,
+                tag if tag == group_tag => entry.perm = (perm >> 3) & 0o7,
+                _ => {}
+            }
+        }
+    }
+
+    /// Derive a new inode's access ACL and mode from this default ACL
+    ///
+    /// The requested mode is intersected with the ACL, replacing the umask.
+    pub fn inherit(&self, perm: u16) -> (PosixAcl, u16) {
+        let mut acl = self.clone();
+        let group_tag = if acl.perm(ACL_MASK).is_some() {
+            ACL_MASK
+        } else {
+            ACL_GROUP_OBJ
+        };
+        for entry in &mut acl.entries {
+            match entry.tag {
+                ACL_USER_OBJ => entry.perm &= (perm >> 6) & 0o7,
+                ACL_OTHER => entry.perm &= perm & 0o7,
+                tag if tag == group_tag => entry.perm &= (perm >> 3) & 0o7,
+                _ => {}
+            }
+        }
+        let mode = (perm & !0o777) | acl.mode_bits();
+        (acl, mode)
+    }
+
+    /// Evaluate the ACL for a caller (acl(5) access check algorithm)
+    pub fn permits(&self, caller: &Caller, attrs: &InodeAttributes, mask: u16) -> bool {
+        let limit = self.perm(ACL_MASK).unwrap_or(0o7);
+        let grants = |perm: u16| perm & mask == mask;
+
+        if caller.uid == attrs.uid {
+            return grants(self.perm(ACL_USER_OBJ).unwrap_or(0));
+        }
+        if let Some(entry) = self
+            .entries
+            .iter()
+            .find(|e| e.tag == ACL_USER && e.id == caller.uid)
+        {
+            return grants(entry.perm & limit);
+        }
+
+        let mut group_matched = false;
+        for entry in &self.entries {
+            let matches = match entry.tag {
+                ACL_GROUP_OBJ => caller.in_group(attrs.gid),
+                ACL_GROUP => entry.id != ACL_UNDEFINED_ID && caller.in_group(entry.id),
+                _ => false,
+            };
+            if matches {
+                if grants(entry.perm & limit) {
+                    return true;
+                }
+                group_matched = true;
+            }
+        }
+        if group_matched {
+            return false;
+        }
+
+        grants(self.perm(ACL_OTHER).unwrap_or(0))
+    }
+
+    fn perm(&self, tag: u16) -> Option<u16> {
+        self.entries.iter().find(|e| e.tag == tag).map(|e| e.perm)
+    }
+}
+
+/// Attribute changes requested by setattr
+#[derive(Debug, Clone, Default)]
+pub struct AttrChanges {
+    /// New mode (file type bits are ignored)
+    pub mode: Option<u32>,
+    /// New owner
+    pub uid: Option<u32>,
+    /// New group
+    pub gid: Option<u32>,
+    /// New size
+    pub size: Option<u64>,
+    /// New access time (`None` is UTIME_OMIT)
+    pub atime: Option<TimeOrNow>,
+    /// New modification time (`None` is UTIME_OMIT)
+    pub mtime: Option<TimeOrNow>,
+    /// Explicit change time
+    pub ctime: Option<SystemTime>,
+    /// New birth time
+    pub crtime: Option<SystemTime>,
+    /// New file flags
+    pub flags: Option<u32>,
+    /// The change is made through an open file handle (ftruncate)
+    pub via_handle: bool,
+}
+
+impl AttrChanges {
+    /// Check that `caller` may make these changes
+    ///
+    /// Follows chmod(2), chown(2), truncate(2) and utimensat(2): ownership
+    /// changes need root, explicit times need ownership, and setting times
+    /// to now or truncating needs write permission (from the access ACL if
+    /// `acls` is set).
+    pub fn check(&self, caller: &Caller, inode: &Inode, acls: bool) -> Result<()> {
+        let attrs = &inode.attrs;
+        if self.mode.is_some() && !caller.owns(attrs) {
+            return Err(Error::NotPermitted("only the owner can change the mode".to_string()));
+        }
+        if self.uid.is_some_and(|uid| uid != attrs.uid) && !caller.is_root() {
+            return Err(Error::NotPermitted("only root can change the owner".to_string()));
+        }
+        if let Some(gid) = self.gid {
+            let allowed = caller.is_root() || (caller.uid == attrs.uid && caller.in_group(gid));
+            if gid != attrs.gid && !allowed {
+                return Err(Error::NotPermitted("cannot change to that group".to_string()));
+            }
+        }
+        if self.size.is_some() && !self.via_handle {
+            caller.check(inode, MAY_WRITE, acls)?;
+        }
+        for time in [&self.atime, &self.mtime].into_iter().flatten() {
+            match time {
+                TimeOrNow::SpecificTime(_) if !caller.owns(attrs) => {
+                    return Err(Error::NotPermitted("only the owner can set times".to_string()));
+                }
+                TimeOrNow::Now if !caller.owns(attrs) => caller.check(inode, MAY_WRITE, acls)?,
+                _ => {}
+            }
+        }
+        if (self.ctime.is_some() || self.crtime.is_some() || self.flags.is_some())
+            && !caller.owns(attrs)
+        {
+            return Err(Error::NotPermitted("only the owner can change attributes".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Apply every change except the size, which also affects the manifest
+    pub fn apply(&self, caller: &Caller, attrs: &mut InodeAttributes, now: SystemTime) {
+        if let Some(mode) = self.mode {
+            let mut perm = (mode & 0o7777) as u16;
+            // Only members of the file's group may set setgid
+            if !caller.is_root() && !caller.in_group(self.gid.unwrap_or(attrs.gid)) {
+                perm &= !0o2000;
+            }
+            attrs.perm = perm;
+        }
+
+        let uid_changed = self.uid.is_some_and(|uid| uid != attrs.uid);
+        let gid_changed = self.gid.is_some_and(|gid| gid != attrs.gid);
+        if let Some(uid) = self.uid {
+            attrs.uid = uid;
+        }
+        if let Some(gid) = self.gid {
+            attrs.gid = gid;
+        }
+        // An unprivileged ownership change drops setuid/setgid
+        if (uid_changed || gid_changed) && !caller.is_root() && attrs.kind != FileType::Directory {
+            attrs.perm &= !0o6000;
+        }
+
+        let resolve = |time: &TimeOrNow| match time {
+            TimeOrNow::SpecificTime(t) => *t,
+            TimeOrNow::Now => now,
+        };
+        if let Some(atime) = &self.atime {
+            attrs.atime = resolve(atime);
+        }
+        if let Some(mtime) = &self.mtime {
+            attrs.mtime = resolve(mtime);
+        }
+        if let Some(crtime) = self.crtime {
+            attrs.crtime = crtime;
+        }
+        if let Some(flags) = self.flags {
+            attrs.flags = flags;
+        }
+        attrs.ctime = self.ctime.unwrap_or(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn file(uid: u32, gid: u32, perm: u16) -> InodeAttributes {
+        InodeAttributes::new_file(uid, gid, perm)
+    }
+
+    #[test]
+    fn test_may_access_uses_owner_group_other_bits() {
+        let attrs = file(1000, 100, 0o640);
+        assert!(Caller::new(1000, 1).may_access(&attrs, MAY_READ | MAY_WRITE));
+        assert!(Caller::new(2000, 100).may_access(&attrs, MAY_READ));
+        assert!(!Caller::new(2000, 100).may_access(&attrs, MAY_WRITE));
+        assert!(!Caller::new(2000, 200).may_access(&attrs, MAY_READ));
+        assert!(Caller::new(0, 0).may_access(&attrs, MAY_WRITE));
+        assert!(!Caller::new(0, 0).may_access(&attrs, MAY_EXEC));
+    }
+
+    #[test]
+    fn test_supplementary_groups_from_proc_status() {
+        let status = "Name:\tcat\nGid:\t100\t100\t100\t100\nGroups:\t24 300 \nNSpid:\t42\n";
+        let caller = Caller::new(2000, 100).with_groups(parse_status_groups(status));
+        assert_eq!(caller.groups, vec![24, 300]);
+
+        let attrs = file(1000, 300, 0o640);
+        assert!(caller.may_access(&attrs, MAY_READ));
+        assert!(!Caller::new(2000, 100).may_access(&attrs, MAY_READ));
+        assert!(parse_status_groups("Groups:\n").is_empty());
+    }
+
+    #[test]
+    fn test_chmod_and_chown_rules() {
+        let inode = Inode::new_file(2, 1, "f".to_string(), 1000, 100, 0o644);
+        let owner = Caller::new(1000, 100);
+        let other = Caller::new(2000, 100);
+
+        let chmod = AttrChanges {
+            mode: Some(0o100600),
+            ..Default::default()
+        };
+        assert!(chmod.check(&owner, &inode, false).is_ok());
+        assert!(matches!(chmod.check(&other, &inode, false), Err(Error::NotPermitted(_))));
+
+        let chown = AttrChanges {
+            uid: Some(2000),
+            ..Default::default()
+        };
+        assert!(chown.check(&owner, &inode, false).is_err());
+        assert!(chown.check(&Caller::new(0, 0), &inode, false).is_ok());
+
+        let chgrp = AttrChanges {
+            gid: Some(100),
+            ..Default::default()
+        };
+        assert!(chgrp.check(&owner, &inode, false).is_ok());
+        let chgrp_other = AttrChanges {
+            gid: Some(300),
+            ..Default::default()
+        };
+        assert!(chgrp_other.check(&owner, &inode, false).is_err());
+    }
+
+    #[test]
+    fn test_utimens_rules() {
+        let inode = Inode::new_file(2, 1, "f".to_string(), 1000, 100, 0o666);
+        let writer = Caller::new(2000, 200);
+
+        let now = AttrChanges {
+            atime: Some(TimeOrNow::Now),
+            mtime: Some(TimeOrNow::Now),
+            ..Default::default()
+        };
+        assert!(now.check(&writer, &inode, false).is_ok());
+
+        let explicit = AttrChanges {
+            mtime: Some(TimeOrNow::SpecificTime(UNIX_EPOCH)),
+            ..Default::default()
+        };
+        assert!(explicit.check(&writer, &inode, false).is_err());
+
+        let readonly = Inode::new_file(2, 1, "f".to_string(), 1000, 100, 0o644);
+        assert!(matches!(now.check(&writer, &readonly, false), Err(Error::PermissionDenied)));
+    }
+
+    #[test]
+    fn test_apply_sets_times_and_omits() {
+        let mut attrs = file(1000, 100, 0o644);
+        let atime = attrs.atime;
+        let now = UNIX_EPOCH + Duration::new(1_700_000_000, 42);
+        let birth = UNIX_EPOCH - Duration::from_secs(60);
+
+        AttrChanges {
+            mtime: Some(TimeOrNow::Now),
+            crtime: Some(birth),
+            flags: Some(2),
+            ..Default::default()
+        }
+        .apply(&Caller::new(1000, 100), &mut attrs, now);
+
+        assert_eq!(attrs.atime, atime);
+        assert_eq!(attrs.mtime, now);
+        assert_eq!(attrs.ctime, now);
+        assert_eq!(attrs.crtime, birth);
+        assert_eq!(attrs.flags, 2);
+    }
+
+    #[test]
+    fn test_apply_drops_privilege_bits() {
+        let mut attrs = file(1000, 100, 0o644);
+        let owner = Caller::new(1000, 100);
+
+        AttrChanges {
+            mode: Some(0o6755),
+            ..Default::default()
+        }
+        .apply(&Caller::new(1000, 200), &mut attrs, SystemTime::now());
+        assert_eq!(attrs.perm, 0o4755);
+
+        attrs.perm = 0o6755;
+        AttrChanges {
+            gid: Some(100),
+            uid: Some(1000),
+            ..Default::default()
+        }
+        .apply(&owner, &mut attrs, SystemTime::now());
+        assert_eq!(attrs.perm, 0o6755);
+
+        AttrChanges {
+            gid: Some(300),
+            ..Default::default()
+        }
+        .apply(&owner, &mut attrs, SystemTime::now());
+        assert_eq!(attrs.perm, 0o755);
+    }
+
+    fn acl(entries: &[(u16, u16, u32)]) -> Vec<u8> {
+        let mut data = ACL_VERSION.to_le_bytes().to_vec();
+        for (tag, perm, id) in entries {
+            data.extend_from_slice(&tag.to_le_bytes());
+            data.extend_from_slice(&perm.to_le_bytes());
+            data.extend_from_slice(&id.to_le_bytes());
+        }
+        data
+    }
+
+    #[test]
+    fn test_acl_named_entries_limited_by_mask() {
+        let data = acl(&[
+            (ACL_USER_OBJ, 6, ACL_UNDEFINED_ID),
+            (ACL_USER, 6, 2000),
+            (ACL_GROUP_OBJ, 4, ACL_UNDEFINED_ID),
+            (ACL_GROUP, 7, 300),
+            (ACL_MASK, 4, ACL_UNDEFINED_ID),
+            (ACL_OTHER, 0, ACL_UNDEFINED_ID),
+        ]);
+        let parsed = PosixAcl::from_xattr(&data).unwrap();
+        assert_eq!(parsed.to_xattr(), data);
+        assert_eq!(parsed.mode_bits(), 0o640);
+
+        let mut inode = Inode::new_file(2, 1, "f".to_string(), 1000, 100, 0o640);
+        inode.xattrs.insert(ACL_ACCESS_XATTR.to_string(), data);
+
+        let named = Caller::new(2000, 999);
+        assert!(named.check(&inode, MAY_READ, true).is_ok());
+        assert!(named.check(&inode, MAY_WRITE, true).is_err());
+        assert!(named.check(&inode, MAY_READ, false).is_err());
+        assert!(Caller::new(3000, 300).check(&inode, MAY_READ, true).is_ok());
+        assert!(Caller::new(3000, 400).check(&inode, MAY_READ, true).is_err());
+    }
+
+    #[test]
+    fn test_truncate_uses_access_acl() {
+        let mut inode = Inode::new_file(2, 1, "f".to_string(), 1000, 100, 0o644);
+        let writer = acl(&[
+            (ACL_USER_OBJ, 6, ACL_UNDEFINED_ID),
+            (ACL_USER, 6, 2000),
+            (ACL_GROUP_OBJ, 4, ACL_UNDEFINED_ID),
+            (ACL_MASK, 6, ACL_UNDEFINED_ID),
+            (ACL_OTHER, 4, ACL_UNDEFINED_ID),
+        ]);
+        inode.xattrs.insert(ACL_ACCESS_XATTR.to_string(), writer);
+
+        let truncate = AttrChanges {
+            size: Some(0),
+            ..Default::default()
+        };
+        let named = Caller::new(2000, 999);
+        assert!(truncate.check(&named, &inode, true).is_ok());
+        assert!(matches!(truncate.check(&named, &inode, false), Err(Error::PermissionDenied)));
+        assert!(truncate.check(&Caller::new(3000, 300), &inode, true).is_err());
+    }
+
+    #[test]
+    fn test_acl_rejects_malformed() {
+        assert!(PosixAcl::from_xattr(b"\x02\0\0").is_err());
+        let no_mask = acl(&[
+            (ACL_USER_OBJ, 6, ACL_UNDEFINED_ID),
+            (ACL_USER, 6, 2000),
+            (ACL_GROUP_OBJ, 4, ACL_UNDEFINED_ID),
+            (ACL_OTHER, 0, ACL_UNDEFINED_ID),
+        ]);
+        assert!(PosixAcl::from_xattr(&no_mask).is_err());
+        let no_other = acl(&[(ACL_USER_OBJ, 6, ACL_UNDEFINED_ID), (ACL_GROUP_OBJ, 4, ACL_UNDEFINED_ID)]);
+        assert!(PosixAcl::from_xattr(&no_other).is_err());
+    }
+
+    #[test]
+    fn test_default_acl_inherit_and_chmod() {
+        let default = PosixAcl::from_xattr(&acl(&[
+            (ACL_USER_OBJ, 7, ACL_UNDEFINED_ID),
+            (ACL_GROUP, 7, 300),
+            (ACL_GROUP_OBJ, 5, ACL_UNDEFINED_ID),
+            (ACL_MASK, 7, ACL_UNDEFINED_ID),
+            (ACL_OTHER, 5, ACL_UNDEFINED_ID),
+        ]))
+        .unwrap();
+
+        let (mut access, mode) = default.inherit(0o666);
+        assert_eq!(mode, 0o664);
+        assert!(!access.is_minimal());
+
+        access.chmod(0o600);
+        assert_eq!(access.mode_bits(), 0o600);
+    }
+
+    #[test]
+    fn test_sticky_and_ownership() {
+        let dir = InodeAttributes::new_directory(0, 0, 0o1777);
+        let mine = file(1000, 100, 0o644);
+        assert!(Caller::new(1000, 100).check_sticky(&dir, &mine).is_ok());
+        assert!(matches!(
+            Caller::new(2000, 100).check_sticky(&dir, &mine),
+            Err(Error::NotPermitted(_))
+        ));
+        assert!(Caller::new(0, 0).check_sticky(&dir, &mine).is_ok());
+
+        let shared = InodeAttributes::new_directory(0, 300, 0o2775);
+        let mut sub = InodeAttributes::new_directory(0, 0, 0o755);
+        Caller::new(1000, 100).take_ownership(&shared, &mut sub);
+        assert_eq!((sub.uid, sub.gid, sub.perm), (1000, 300, 0o2755));
+
+        let mut setgid_file = file(0, 0, 0o2755);
+        Caller::new(1000, 100).take_ownership(&shared, &mut setgid_file);
+        assert_eq!(setgid_file.perm, 0o755);
+    }
+
+    #[test]
+    fn test_lists_xattr() {
+        let user = Caller::new(1000, 100);
+        let root = Caller::new(0, 0);
+        assert!(user.lists_xattr("user.note", false));
+        assert!(user.lists_xattr("security.selinux", false));
+        assert!(!user.lists_xattr("trusted.overlay.opaque", false));
+        assert!(root.lists_xattr("trusted.overlay.opaque", false));
+        assert!(!user.lists_xattr(ACL_ACCESS_XATTR, false));
+        assert!(user.lists_xattr(ACL_ACCESS_XATTR, true));
+    }
+
+    #[test]
+    fn test_open_mask() {
+        assert_eq!(open_mask(libc::O_RDONLY), MAY_READ);
+        assert_eq!(open_mask(libc::O_WRONLY), MAY_WRITE);
+        assert_eq!(open_mask(libc::O_RDWR), MAY_READ | MAY_WRITE);
+        assert_eq!(open_mask(libc::O_RDONLY | libc::O_TRUNC), MAY_READ | MAY_WRITE);
+    }
+}
@@ -1,27 +1,50 @@
 //! Main FUSE filesystem implementation
 
 use crate::cache::ChunkCache;
-use crate::chunk::{compress_or_original, decompress, ChunkManifest, ChunkRef, Chunker};
-use crate::config::Config;
-use crate::crypto::{decrypt, encrypt, KeyManager};
+use crate::chunk::{compress_or_original, Chunk, ChunkManifest, ChunkRef, Chunker, PENDING_MESSAGE_ID};
+use crate::config::{Config, OfflineWrites};
+use crate::crypto::{chunk_aad, encrypt_segmented, encrypt_with, KeyManager, SegmentedHeader};
 use crate::error::{Error, Result};
+use crate::fs::access::{
+    open_mask, AttrChanges, Caller, PosixAcl, ACL_ACCESS_XATTR, ACL_DEFAULT_XATTR, MAY_EXEC,
+    MAY_READ, MAY_WRITE,
+};
 use crate::fs::handle::HandleManager;
-use crate::metadata::{Inode, MetadataStore};
+use crate::fs::inspect::{self, CACHED_XATTR, EVICT_XATTR};
+use crate::fs::journal::JournalUploader;
+use crate::fs::offline::{Connectivity, UploadQueue};
+use crate::fs::pin::{self, PinKeeper, PIN_XATTR};
+use crate::fs::prefetch::{ChunkFetcher, Prefetcher};
+use crate::fs::stats::{DaemonStats, STATS_FILE};
+use crate::fs::warm::{WarmRequest, Warmer, WARM_XATTR};
+use crate::metadata::{CloudJournal, Inode, MetadataStore, Transaction};
 use crate::telegram::TelegramBackend;
 
 use fuser::{
-    FileType as FuserFileType, Filesystem, ReplyAttr, ReplyCreate, ReplyData,
-    ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyWrite, Request, TimeOrNow,
+    FileType as FuserFileType, Filesystem, KernelConfig, ReplyAttr, ReplyCreate, ReplyData,
+    ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyWrite, ReplyXattr, Request, TimeOrNow,
 };
+use parking_lot::Mutex;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::runtime::Runtime;
-use tracing::{debug, error};
+use tracing::{debug, error, info, warn};
 
 /// TTL for cached attributes
 const TTL: Duration = Duration::from_secs(1);
 
+/// Directory entries fetched per store read in readdir
+const READDIR_PAGE: usize = 256;
+
+/// FUSE_EXPORT_SUPPORT init flag (fuser only exposes it with `abi-7-10`)
+const FUSE_EXPORT_SUPPORT: u32 = 1 << 4;
+
+/// How often runtime statistics are published for `tgcryptfs status`
+const STATS_INTERVAL: Duration = Duration::from_secs(5);
+
 /// Main tgcryptfs filesystem
 pub struct TgCryptFs {
     /// Configuration
@@ -38,12 +61,24 @@ pub struct TgCryptFs {
     chunker: Chunker,
     /// File handle manager
     handles: HandleManager,
+    /// Downloads chunks missing from the cache
+    fetcher: Arc<ChunkFetcher>,
+    /// Background readahead for sequential readers
+    prefetcher: Prefetcher,
+    /// Keeps pinned files cached
+    pins: PinKeeper,
+    /// Cache warmups and the access history
+    warmer: Warmer,
+    /// Backend reachability and the offline upload queue
+    connectivity: Arc<Connectivity>,
     /// Tokio runtime for async operations
     runtime: Runtime,
-    /// UID for this process
-    uid: u32,
-    /// GID for this process
-    gid: u32,
+    /// Uploads the cloud metadata journal (when enabled)
+    journal: Option<JournalUploader>,
+    /// Where runtime statistics are published
+    stats_path: PathBuf,
+    /// When statistics were last published
+    stats_written: Mutex<Option<Instant>>,
 }
 
 impl TgCryptFs {
@@ -58,28 +93,129 @@ impl TgCryptFs {
         let runtime = Runtime::new().map_err(|e| Error::Internal(e.to_string()))?;
 
         let chunker = Chunker::new(&config.chunk);
+        let stats_path = config.data_dir.join(STATS_FILE);
+
+        let keys = Arc::new(keys);
+        let metadata = Arc::new(metadata);
+        let telegram = Arc::new(telegram);
+        let cache = Arc::new(cache);
+        let queue = UploadQueue::open(&config.data_dir, metadata.clone())?;
+        let connectivity = Arc::new(Connectivity::new(true, queue));
+        connectivity.start(
+            &runtime,
+            telegram.clone(),
+            Duration::from_secs(config.offline.probe_interval_secs),
+        );
+        let fetcher = Arc::new(ChunkFetcher::new(
+            keys.clone(),
+            telegram.clone(),
+            cache.clone(),
+            metadata.clone(),
+            connectivity.clone(),
+        )
+        .with_require_bound(config.encryption.require_bound_chunks));
+        let workers = if config.cache.prefetch_enabled {
+            config.cache.prefetch_workers
+        } else {
+            0
+        };
+        let prefetcher = Prefetcher::start(&runtime, fetcher.clone(), workers, config.cache.prefetch_count);
+        let pins = PinKeeper::start(&runtime, metadata.clone(), cache.clone(), fetcher.clone());
+        let warmer = Warmer::start(
+            &runtime,
+            metadata.clone(),
+            cache.clone(),
+            fetcher.clone(),
+            config.cache.prefetch_workers,
+            config.cache.access_history_size,
+        )?;
 
         Ok(TgCryptFs {
             config: Arc::new(config),
-            keys: Arc::new(keys),
-            metadata: Arc::new(metadata),
-            telegram: Arc::new(telegram),
-            cache: Arc::new(cache),
+            keys,
+            metadata,
+            telegram,
+            cache,
             chunker,
             handles: HandleManager::new(),
+            fetcher,
+            prefetcher,
+            pins,
+            warmer,
+            connectivity,
             runtime,
-            uid: unsafe { libc::getuid() },
-            gid: unsafe { libc::getgid() },
+            journal: None,
+            stats_path,
+            stats_written: Mutex::new(None),
         })
     }
 
+    /// Stream metadata changes to the backend through a journal
+    ///
+    /// The store must already be journaling (see `CloudJournal::attach`).
+    pub fn with_journal(mut self, journal: CloudJournal) -> Self {
+        self.journal = Some(JournalUploader::start(
+            &self.runtime,
+            journal,
+            self.metadata.clone(),
+            self.telegram.clone(),
+            self.connectivity.clone(),
+        ));
+        self
+    }
+
+    /// Start without a backend connection
+    ///
+    /// The backend is probed in the background and queued work uploaded
+    /// once it is reachable.
+    pub fn start_offline(self, reason: &Error) -> Self {
+        self.connectivity.set_offline(reason);
+        self
+    }
+
+    /// Let the journal upload task know metadata changed
+    fn journal_changed(&self) {
+        if let Some(journal) = &self.journal {
+            journal.changed();
+        }
+    }
+
+    /// Refuse modifications while offline in read-only mode
+    fn check_writable(&self) -> Result<()> {
+        if self.connectivity.is_online() || self.config.offline.writes == OfflineWrites::Queue {
+            return Ok(());
+        }
+        Err(Error::ReadOnly("backend unreachable".to_string()))
+    }
+
+    /// Publish runtime statistics if due (or unconditionally if forced)
+    fn report_stats(&self, force: bool) {
+        {
+            let mut written = self.stats_written.lock();
+            if !force && written.is_some_and(|t| t.elapsed() < STATS_INTERVAL) {
+                return;
+            }
+            *written = Some(Instant::now());
+        }
+        let stats = DaemonStats::new(self.metadata.cache_stats())
+            .with_pins(self.pins.stats())
+            .with_connectivity(self.connectivity.stats())
+            .with_cache(self.cache.stats(), self.fetcher.stats());
+        if let Err(e) = stats.save(&self.stats_path) {
+            debug!("Failed to write runtime statistics: {}", e);
+        }
+    }
+
     /// Helper to run async code from sync FUSE callbacks
     fn block_on<F: std::future::Future>(&self, f: F) -> F::Output {
         self.runtime.block_on(f)
     }
 
     /// Read file data at a given offset
-    fn read_file_data(&self, inode: &Inode, offset: u64, size: u32) -> Result<Vec<u8>> {
+    ///
+    /// Random reads fetch only the segments they need of uncached chunks;
+    /// sequential reads download and cache whole chunks.
+    fn read_file_data(&self, inode: &Inode, offset: u64, size: u32, random: bool) -> Result<Vec<u8>> {
         let manifest = inode
             .manifest
             .as_ref()
@@ -98,22 +234,17 @@ impl TgCryptFs {
             let chunk_end = current_offset + chunk_ref.original_size;
 
             if chunk_end > offset && current_offset < end {
-                // This chunk overlaps with our range
-                let chunk_data = self.get_chunk_data(chunk_ref)?;
-
-                // Calculate the slice of this chunk we need
-                let slice_start = if offset > current_offset {
-                    (offset - current_offset) as usize
-                } else {
-                    0
-                };
-                let slice_end = if end < chunk_end {
-                    (end - current_offset) as usize
+                // This chunk overlaps with our range; read only the part we need
+                let slice_start = offset.saturating_sub(current_offset);
+                let slice_end = std::cmp::min(end, chunk_end) - current_offset;
+                let len = (slice_end - slice_start) as usize;
+                let chunk_data = if random {
+                    self.block_on(self.fetcher.fetch_random(chunk_ref, slice_start, len))?
                 } else {
-                    chunk_data.len()
+                    self.block_on(self.fetcher.fetch_range(chunk_ref, slice_start, len))?
                 };
 
-                result.extend_from_slice(&chunk_data[slice_start..slice_end]);
+                result.extend_from_slice(&chunk_data);
             }
 
             current_offset = chunk_end;
@@ -122,39 +253,26 @@ impl TgCryptFs {
             }
         }
 
+        // Past the last chunk the file was extended by truncate
+        result.resize((end - offset) as usize, 0);
         Ok(result)
     }
 
-    /// Get chunk data (from cache or Telegram)
-    fn get_chunk_data(&self, chunk_ref: &ChunkRef) -> Result<Vec<u8>> {
-        // Try cache first
-        if let Some(data) = self.cache.get(&chunk_ref.id)? {
-            return Ok(data);
+    /// Queue the chunks after the one holding `last_byte` for prefetch
+    fn read_ahead(&self, inode: &Inode, last_byte: u64) {
+        if let Some(manifest) = &inode.manifest {
+            if let Some((index, _)) = manifest.chunk_at_offset(last_byte) {
+                self.prefetcher.read_ahead(manifest, index);
+            }
         }
-
-        // Download from Telegram
-        let encrypted_bytes = self.block_on(self.telegram.download_chunk(chunk_ref.message_id))?;
-
-        // Decrypt
-        let chunk_key = self.keys.chunk_key(&chunk_ref.id)?;
-        let encrypted = crate::crypto::EncryptedData::from_bytes(&encrypted_bytes)?;
-        let decrypted = decrypt(chunk_key.key(), &encrypted, &[])?;
-
-        // Decompress if needed
-        let data = if chunk_ref.compressed {
-            decompress(&decrypted)?
-        } else {
-            decrypted
-        };
-
-        // Cache for later
-        self.cache.put(&chunk_ref.id, &data)?;
-
-        Ok(data)
     }
 
     /// Write file data (simplified - full implementation would handle partial writes)
+    ///
+    /// While offline (or if an upload fails for lack of a connection) the
+    /// encrypted chunks are queued for upload instead.
     fn write_file_data(&self, ino: u64, data: &[u8]) -> Result<()> {
+        self.check_writable()?;
         let mut inode = self.metadata.get_inode_required(ino)?;
 
         // Create chunks
@@ -166,58 +284,394 @@ impl TgCryptFs {
         manifest.total_size = data.len() as u64;
         manifest.file_hash = file_hash;
 
-        // Upload each chunk
+        // Upload each chunk; references are added when the inode is committed
+        let mut stored: HashMap<String, ChunkRef> = inode
+            .manifest
+            .iter()
+            .flat_map(|old| &old.chunks)
+            .map(|chunk| (chunk.id.clone(), chunk.clone()))
+            .collect();
         for chunk in chunks {
-            // Compress if beneficial
-            let (chunk_data, compressed) =
-                compress_or_original(&chunk.data, self.config.chunk.compression_threshold);
-
-            // Encrypt
-            let chunk_key = self.keys.chunk_key(&chunk.info.id)?;
-            let encrypted = encrypt(chunk_key.key(), &chunk_data, &[])?;
-
-            // Check if chunk already exists (dedup)
-            let message_id = if let Some(msg_id) = self.metadata.get_chunk_ref(&chunk.info.id)? {
-                // Chunk already exists, just add reference
-                self.metadata.save_chunk_ref(&chunk.info.id, msg_id)?;
-                msg_id
-            } else {
-                // Upload new chunk
-                let msg_id = self.block_on(self.telegram.upload_chunk(&chunk.info.id, &encrypted.to_bytes()))?;
-                self.metadata.save_chunk_ref(&chunk.info.id, msg_id)?;
-                msg_id
-            };
-
-            // Add to manifest
-            manifest.chunks.push(ChunkRef {
-                id: chunk.info.id,
-                size: encrypted.size() as u64,
-                message_id,
-                offset: chunk.info.offset,
-                original_size: chunk.data.len() as u64,
-                compressed,
-            });
+            manifest.chunks.push(self.store_chunk(ino, chunk, &mut stored)?);
+        }
 
-            // Cache the uncompressed data
-            self.cache.put(&manifest.chunks.last().unwrap().id, &chunk.data)?;
+        // Reference the new chunks and release the replaced ones together
+        let mut txn = self.metadata.transaction();
+        for chunk in &manifest.chunks {
+            txn.save_chunk_ref(&chunk.id, chunk.message_id);
+        }
+        if let Some(old) = &inode.manifest {
+            for chunk in &old.chunks {
+                txn.decrement_chunk_ref(&chunk.id);
+            }
         }
 
         // Update inode
         inode.manifest = Some(manifest);
         inode.set_size(data.len() as u64);
         inode.bump_version();
+        txn.save_inode(&inode);
+
+        let orphaned = txn.commit()?;
+        self.purge_chunks(orphaned);
+        // The file may be pinned; the new chunks must be kept
+        self.pins.refresh();
+        Ok(())
+    }
+
+    /// Compress, encrypt and upload one chunk of a file
+    ///
+    /// Chunks already stored are reused without sealing them again; `stored`
+    /// holds those stored by the current write or still referenced by the
+    /// file. Its reference is added when the inode is committed.
+    fn store_chunk(&self, ino: u64, chunk: Chunk, stored: &mut HashMap<String, ChunkRef>) -> Result<ChunkRef> {
+        let id = &chunk.info.id;
+        let chunk_ref = match stored.get(id) {
+            Some(existing) => ChunkRef {
+                offset: chunk.info.offset,
+                ..existing.clone()
+            },
+            None => {
+                // Compress if beneficial
+                let (chunk_data, compressed) =
+                    compress_or_original(&chunk.data, self.config.chunk.compression_threshold);
+
+                // Check if chunk already exists (dedup)
+                let queue = self.connectivity.queue();
+                let (message_id, size) = match self.metadata.get_chunk_ref(id)? {
+                    Some(PENDING_MESSAGE_ID) => {
+                        let size = match queue.reference(id, ino)? {
+                            Some(size) => size,
+                            None => {
+                                let encrypted = self.seal_chunk(id, &chunk_data)?;
+                                queue.push(id, ino, &encrypted)?;
+                                encrypted.len() as u64
+                            }
+                        };
+                        (PENDING_MESSAGE_ID, size)
+                    }
+                    // Another file's chunk; its size is not recorded, but it
+                    // was most likely sealed with the current settings
+                    Some(message_id) => (message_id, self.sealed_size(chunk_data.len() as u64)),
+                    None => {
+                        let encrypted = self.seal_chunk(id, &chunk_data)?;
+                        let message_id = self.upload_chunk(id, &encrypted)?;
+                        if message_id == PENDING_MESSAGE_ID {
+                            queue.push(id, ino, &encrypted)?;
+                        }
+                        (message_id, encrypted.len() as u64)
+                    }
+                };
+                ChunkRef {
+                    id: id.clone(),
+                    size,
+                    message_id,
+                    offset: chunk.info.offset,
+                    original_size: chunk.data.len() as u64,
+                    compressed,
+                }
+            }
+        };
+        stored.insert(id.clone(), chunk_ref.clone());
+
+        // Cache the uncompressed data; the chunk is already stored, so a
+        // full cache only costs a later download
+        if let Err(e) = self.cache.put(id, &chunk.data) {
+            warn!("Failed to cache chunk {}: {}", id, e);
+        }
+        Ok(chunk_ref)
+    }
+
+    /// Encrypt a chunk, bound to its identity
+    fn seal_chunk(&self, chunk_id: &str, data: &[u8]) -> Result<Vec<u8>> {
+        let chunk_key = self.keys.chunk_key(chunk_id)?;
+        let aad = chunk_aad(self.metadata.namespace_prefix(), chunk_id);
+        if self.config.chunk.segment_size > 0 {
+            encrypt_segmented(
+                chunk_key.key(),
+                data,
+                &aad,
+                self.keys.aead_suite(),
+                self.config.chunk.segment_size,
+            )
+        } else {
+            Ok(encrypt_with(chunk_key.key(), data, &aad, self.keys.aead_suite())?.to_bytes())
+        }
+    }
+
+    /// Size `len` bytes of chunk data are sealed to with the current settings
+    fn sealed_size(&self, len: u64) -> u64 {
+        let suite = self.keys.aead_suite();
+        match self.config.chunk.segment_size {
+            0 => suite.sealed_size(len),
+            segment_size => SegmentedHeader::new(suite, segment_size).sealed_size(len),
+        }
+    }
+
+    /// Upload a chunk, or return `PENDING_MESSAGE_ID` if it must be queued
+    fn upload_chunk(&self, chunk_id: &str, data: &[u8]) -> Result<i32> {
+        if !self.connectivity.is_online() {
+            return Ok(PENDING_MESSAGE_ID);
+        }
+        match self.block_on(self.telegram.upload_chunk(chunk_id, data)) {
+            Ok(msg_id) => Ok(msg_id),
+            Err(e) if e.is_connectivity() => {
+                self.connectivity.set_offline(&e);
+                self.check_writable()?;
+                Ok(PENDING_MESSAGE_ID)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Delete chunks whose last reference was dropped by a commit
+    ///
+    /// Chunks still queued are discarded; deletions that cannot reach the
+    /// backend are queued.
+    fn purge_chunks(&self, orphaned: Vec<(String, i32)>) {
+        let queue = self.connectivity.queue();
+        for (chunk_id, msg_id) in orphaned {
+            if msg_id == PENDING_MESSAGE_ID || !self.connectivity.is_online() {
+                if let Err(e) = queue.release(&chunk_id, msg_id) {
+                    warn!("Failed to queue deletion of chunk {}: {}", chunk_id, e);
+                }
+            } else if let Err(e) = self.block_on(self.telegram.delete_message(msg_id)) {
+                if e.is_connectivity() {
+                    self.connectivity.set_offline(&e);
+                    let _ = queue.release(&chunk_id, msg_id);
+                }
+            }
+            let _ = self.cache.remove(&chunk_id);
+        }
+    }
+
+    /// Apply setattr changes on behalf of a caller
+    fn set_attributes(&self, caller: Caller, ino: u64, changes: &AttrChanges) -> Result<Inode> {
+        self.check_writable()?;
+        let mut inode = self.metadata.get_inode_required(ino)?;
+        changes.check(&caller, &inode, self.config.mount.posix_acl)?;
+
+        let mut txn = self.metadata.transaction();
+        let mut rechunked = false;
+        if let Some(size) = changes.size {
+            if !inode.is_file() {
+                return Err(Error::NotAFile(inode.name.clone()));
+            }
+            rechunked = self.truncate_file(&mut inode, size, &mut txn)?;
+            inode.set_size(size);
+        }
+        changes.apply(&caller, &mut inode.attrs, SystemTime::now());
+        // The access ACL follows the new mode bits
+        if changes.mode.is_some() {
+            if let Some(mut acl) = inode
+                .xattrs
+                .get(ACL_ACCESS_XATTR)
+                .and_then(|data| PosixAcl::from_xattr(data).ok())
+            {
+                acl.chmod(inode.attrs.perm);
+                inode.xattrs.insert(ACL_ACCESS_XATTR.to_string(), acl.to_xattr());
+            }
+        }
+        txn.save_inode(&inode);
+
+        let orphaned = txn.commit()?;
+        self.purge_chunks(orphaned);
+        if rechunked {
+            self.pins.refresh();
+        }
+        Ok(inode)
+    }
+
+    /// Cut or extend a file's chunks to `size` within a transaction
+    ///
+    /// Chunks past the new end are released and the kept part of the chunk
+    /// holding it is stored as a new chunk. Extending stores nothing: bytes
+    /// past the last chunk read as zeros. Returns whether chunks changed.
+    fn truncate_file(&self, inode: &mut Inode, size: u64, txn: &mut Transaction<'_>) -> Result<bool> {
+        let mut manifest = inode
+            .manifest
+            .take()
+            .unwrap_or_else(|| ChunkManifest::new(inode.version));
+        let truncation = manifest.truncate(size);
+        if truncation.kept_prefix > 0 {
+            let tail = &truncation.removed[0];
+            let data = self.block_on(self.fetcher.fetch_range(tail, 0, truncation.kept_prefix as usize))?;
+            let chunk = self.store_chunk(inode.ino, Chunk::new(data, tail.offset), &mut HashMap::new())?;
+            txn.save_chunk_ref(&chunk.id, chunk.message_id);
+            manifest.chunks.push(chunk);
+        }
+        for chunk in &truncation.removed {
+            txn.decrement_chunk_ref(&chunk.id);
+        }
+
+        let rechunked = !truncation.removed.is_empty();
+        if rechunked {
+            manifest.version = inode.version + 1;
+            inode.bump_version();
+        }
+        inode.manifest = Some(manifest);
+        Ok(rechunked)
+    }
+
+    /// Look up a name in a directory, including "." and ".."
+    ///
+    /// NFS re-export resolves file handles back to their parents by looking
+    /// up ".." directly.
+    fn lookup_entry(&self, caller: &Caller, parent: u64, name: &str) -> Result<Option<Inode>> {
+        let Some(dir) = self.metadata.get_inode(parent)? else {
+            return Ok(None);
+        };
+        self.check_access(caller, &dir, MAY_EXEC)?;
+        match name {
+            "." => Ok(Some(dir)),
+            ".." => self.metadata.get_inode(dir.parent),
+            _ => self.metadata.lookup(parent, name),
+        }
+    }
+
+    /// Set an extended attribute on behalf of a caller
+    ///
+    /// Setting an access ACL also updates the mode bits; an ACL the mode
+    /// bits fully describe is not stored.
+    fn set_xattr(&self, caller: &Caller, ino: u64, name: &str, value: &[u8], flags: i32) -> Result<()> {
+        match name {
+            WARM_XATTR => return self.start_warm(caller, ino, value),
+            EVICT_XATTR => return self.evict_cached(caller, ino),
+            _ => {}
+        }
+        self.check_writable()?;
+        let mut inode = self.metadata.get_inode_required(ino)?;
+        caller.check_xattr(&inode, name, true, self.config.mount.posix_acl)?;
+
+        let exists = inode.xattrs.contains_key(name);
+        if flags & libc::XATTR_CREATE != 0 && exists {
+            return Err(Error::AlreadyExists(name.to_string()));
+        }
+        if flags & libc::XATTR_REPLACE != 0 && !exists {
+            return Err(Error::NoAttribute(name.to_string()));
+        }
+
+        match name {
+            ACL_ACCESS_XATTR => {
+                let acl = PosixAcl::from_xattr(value)?;
+                inode.attrs.perm = (inode.attrs.perm & !0o777) | acl.mode_bits();
+                if acl.is_minimal() {
+                    inode.xattrs.remove(name);
+                } else {
+                    inode.xattrs.insert(name.to_string(), value.to_vec());
+                }
+            }
+            ACL_DEFAULT_XATTR => {
+                if !inode.is_dir() {
+                    return Err(Error::NotADirectory(inode.name.clone()));
+                }
+                PosixAcl::from_xattr(value)?;
+                inode.xattrs.insert(name.to_string(), value.to_vec());
+            }
+            _ => {
+                inode.xattrs.insert(name.to_string(), value.to_vec());
+            }
+        }
+        inode.attrs.ctime = SystemTime::now();
+        self.metadata.save_inode(&inode)?;
+
+        if name == PIN_XATTR {
+            pin::set_pinned(&self.metadata, ino, true)?;
+            self.pins.refresh();
+        }
+        Ok(())
+    }
+
+    /// Start warming the cache from an inode on behalf of a caller
+    ///
+    /// Warming only reads, so it needs read access rather than ownership.
+    fn start_warm(&self, caller: &Caller, ino: u64, value: &[u8]) -> Result<()> {
+        let inode = self.metadata.get_inode_required(ino)?;
+        self.check_access(caller, &inode, MAY_READ)?;
+        let request: WarmRequest = serde_json::from_slice(value)
+            .map_err(|e| Error::InvalidArgument(format!("invalid warm request: {}", e)))?;
+        self.warmer.warm(ino, &request);
+        Ok(())
+    }
+
+    /// Drop the cached chunks of an inode's tree on behalf of a caller
+    ///
+    /// Eviction undoes other users' warming and pinning, so it needs the
+    /// same write access as setting the pin attribute.
+    fn evict_cached(&self, caller: &Caller, ino: u64) -> Result<()> {
+        let inode = self.metadata.get_inode_required(ino)?;
+        caller.check_xattr(&inode, EVICT_XATTR, true, self.config.mount.posix_acl)?;
+        let (chunks, bytes) = inspect::evict(&self.metadata, &self.cache, ino)?;
+        info!("Evicted {} chunks ({} bytes) of inode {} from the cache", chunks, bytes, ino);
+        Ok(())
+    }
+
+    /// Remove an extended attribute on behalf of a caller
+    fn remove_xattr(&self, caller: &Caller, ino: u64, name: &str) -> Result<()> {
+        self.check_writable()?;
+        let mut inode = self.metadata.get_inode_required(ino)?;
+        caller.check_xattr(&inode, name, true, self.config.mount.posix_acl)?;
+        if inode.xattrs.remove(name).is_none() {
+            return Err(Error::NoAttribute(name.to_string()));
+        }
+        inode.attrs.ctime = SystemTime::now();
         self.metadata.save_inode(&inode)?;
 
+        if name == PIN_XATTR {
+            pin::set_pinned(&self.metadata, ino, false)?;
+            self.pins.refresh();
+        }
         Ok(())
     }
 
-    /// Create a new file
-    fn create_file(&self, parent: u64, name: &str, mode: u32) -> Result<Inode> {
-        // Check parent exists and is a directory
+    /// Check a caller's access to an inode
+    fn check_access(&self, caller: &Caller, inode: &Inode, mask: u16) -> Result<()> {
+        caller.check(inode, mask, self.config.mount.posix_acl)
+    }
+
+    /// Set a new inode's mode from the umask or the parent's default ACL
+    ///
+    /// With a default ACL the umask is ignored; the inode gets the ACL
+    /// (and directories inherit it as their own default).
+    fn inherit_permissions(&self, parent: &Inode, inode: &mut Inode, mode: u32, umask: u32) {
+        let requested = (mode & 0o7777) as u16;
+        let default_acl = parent
+            .xattrs
+            .get(ACL_DEFAULT_XATTR)
+            .filter(|_| self.config.mount.posix_acl)
+            .and_then(|data| PosixAcl::from_xattr(data).ok());
+
+        match default_acl {
+            Some(acl) => {
+                let (access, perm) = acl.inherit(requested);
+                inode.attrs.perm = perm;
+                if !access.is_minimal() {
+                    inode.xattrs.insert(ACL_ACCESS_XATTR.to_string(), access.to_xattr());
+                }
+                if inode.is_dir() {
+                    inode.xattrs.insert(ACL_DEFAULT_XATTR.to_string(), acl.to_xattr());
+                }
+            }
+            None => inode.attrs.perm = requested & !(umask as u16),
+        }
+    }
+
+    /// Create a new file owned by the caller
+    fn create_file(
+        &self,
+        caller: &Caller,
+        parent: u64,
+        name: &str,
+        mode: u32,
+        umask: u32,
+    ) -> Result<Inode> {
+        self.check_writable()?;
+        // Check parent exists and is a writable directory
         let mut parent_inode = self.metadata.get_inode_required(parent)?;
         if !parent_inode.is_dir() {
             return Err(Error::NotADirectory(parent_inode.name.clone()));
         }
+        self.check_access(caller, &parent_inode, MAY_WRITE | MAY_EXEC)?;
 
         // Check name doesn't already exist
         if self.metadata.lookup(parent, name)?.is_some() {
@@ -225,99 +679,253 @@ impl TgCryptFs {
         }
 
         // Create new inode
-        let ino = self.metadata.alloc_ino();
-        let inode = Inode::new_file(ino, parent, name.to_string(), self.uid, self.gid, mode as u16);
-
-        // Save inode and update parent
-        self.metadata.save_inode(&inode)?;
-        parent_inode.add_child(ino);
-        self.metadata.save_inode(&parent_inode)?;
+        let ino = self.metadata.alloc_ino()?;
+        let mut inode = Inode::new_file(ino, parent, name.to_string(), caller.uid, caller.gid, 0)
+            .with_generation(self.metadata.generation());
+        self.inherit_permissions(&parent_inode, &mut inode, mode, umask);
+        caller.take_ownership(&parent_inode.attrs, &mut inode.attrs);
+
+        // Save inode and update parent; the store adds the directory entry
+        parent_inode.attrs.touch();
+        let mut txn = self.metadata.transaction();
+        txn.save_inode(&inode).save_inode(&parent_inode);
+        txn.commit()?;
 
         Ok(inode)
     }
 
-    /// Create a new directory
-    fn create_directory(&self, parent: u64, name: &str, mode: u32) -> Result<Inode> {
+    /// Create a new directory owned by the caller
+    fn create_directory(
+        &self,
+        caller: &Caller,
+        parent: u64,
+        name: &str,
+        mode: u32,
+        umask: u32,
+    ) -> Result<Inode> {
+        self.check_writable()?;
         let mut parent_inode = self.metadata.get_inode_required(parent)?;
         if !parent_inode.is_dir() {
             return Err(Error::NotADirectory(parent_inode.name.clone()));
         }
+        self.check_access(caller, &parent_inode, MAY_WRITE | MAY_EXEC)?;
 
         if self.metadata.lookup(parent, name)?.is_some() {
             return Err(Error::AlreadyExists(name.to_string()));
         }
 
-        let ino = self.metadata.alloc_ino();
-        let inode = Inode::new_directory(ino, parent, name.to_string(), self.uid, self.gid, mode as u16);
+        let ino = self.metadata.alloc_ino()?;
+        let mut inode = Inode::new_directory(ino, parent, name.to_string(), caller.uid, caller.gid, 0)
+            .with_generation(self.metadata.generation());
+        self.inherit_permissions(&parent_inode, &mut inode, mode, umask);
+        caller.take_ownership(&parent_inode.attrs, &mut inode.attrs);
 
-        self.metadata.save_inode(&inode)?;
-        parent_inode.add_child(ino);
+        parent_inode.attrs.touch();
         parent_inode.attrs.nlink += 1; // For ..
-        self.metadata.save_inode(&parent_inode)?;
+        let mut txn = self.metadata.transaction();
+        txn.save_inode(&inode).save_inode(&parent_inode);
+        txn.commit()?;
 
         Ok(inode)
     }
 
     /// Remove a file
-    fn remove_file(&self, parent: u64, name: &str) -> Result<()> {
+    fn remove_file(&self, caller: &Caller, parent: u64, name: &str) -> Result<()> {
+        self.check_writable()?;
         let mut parent_inode = self.metadata.get_inode_required(parent)?;
+        self.check_access(caller, &parent_inode, MAY_WRITE | MAY_EXEC)?;
         let inode = self
             .metadata
             .lookup(parent, name)?
             .ok_or_else(|| Error::PathNotFound(name.to_string()))?;
+        caller.check_sticky(&parent_inode.attrs, &inode.attrs)?;
 
         if !inode.is_file() && !inode.is_symlink() {
             return Err(Error::NotAFile(name.to_string()));
         }
 
-        // Decrement chunk references and delete orphaned chunks
+        let mut txn = self.metadata.transaction();
+        Self::stage_remove_file(&mut txn, &mut parent_inode, &inode);
+        txn.save_inode(&parent_inode);
+
+        // Chunks are deleted from Telegram only once the removal is committed
+        let orphaned = txn.commit()?;
+        self.purge_chunks(orphaned);
+        Ok(())
+    }
+
+    /// Stage deleting a file or symlink and dropping its chunk references
+    fn stage_remove_file(txn: &mut Transaction<'_>, parent_inode: &mut Inode, inode: &Inode) {
         if let Some(manifest) = &inode.manifest {
             for chunk in &manifest.chunks {
-                if let Some(msg_id) = self.metadata.decrement_chunk_ref(&chunk.id)? {
-                    // Chunk is orphaned, delete from Telegram
-                    let _ = self.block_on(self.telegram.delete_message(msg_id));
-                    let _ = self.cache.remove(&chunk.id);
-                }
+                txn.decrement_chunk_ref(&chunk.id);
             }
         }
+        txn.delete_inode(inode.ino);
+        parent_inode.attrs.touch();
+    }
 
-        // Delete inode
-        self.metadata.delete_inode(inode.ino)?;
-
-        // Update parent
-        parent_inode.remove_child(inode.ino);
-        self.metadata.save_inode(&parent_inode)?;
-
+    /// Stage deleting an empty directory
+    fn stage_remove_directory(
+        &self,
+        txn: &mut Transaction<'_>,
+        parent_inode: &mut Inode,
+        inode: &Inode,
+    ) -> Result<()> {
+        if self.metadata.has_children(inode.ino)? {
+            return Err(Error::DirectoryNotEmpty(inode.name.clone()));
+        }
+        txn.delete_inode(inode.ino);
+        parent_inode.attrs.touch();
+        parent_inode.attrs.nlink -= 1;
         Ok(())
     }
 
     /// Remove a directory
-    fn remove_directory(&self, parent: u64, name: &str) -> Result<()> {
+    fn remove_directory(&self, caller: &Caller, parent: u64, name: &str) -> Result<()> {
+        self.check_writable()?;
         let mut parent_inode = self.metadata.get_inode_required(parent)?;
+        self.check_access(caller, &parent_inode, MAY_WRITE | MAY_EXEC)?;
         let inode = self
             .metadata
             .lookup(parent, name)?
             .ok_or_else(|| Error::PathNotFound(name.to_string()))?;
+        caller.check_sticky(&parent_inode.attrs, &inode.attrs)?;
 
         if !inode.is_dir() {
             return Err(Error::NotADirectory(name.to_string()));
         }
 
-        if !inode.children.is_empty() {
-            return Err(Error::DirectoryNotEmpty(name.to_string()));
+        let mut txn = self.metadata.transaction();
+        self.stage_remove_directory(&mut txn, &mut parent_inode, &inode)?;
+        txn.save_inode(&parent_inode);
+        txn.commit()?;
+
+        Ok(())
+    }
+
+    /// Check whether `ino` is `dir` or lies below it
+    fn is_within(&self, ino: u64, dir: u64) -> Result<bool> {
+        let mut current = ino;
+        let mut seen = HashSet::new();
+        while seen.insert(current) {
+            if current == dir {
+                return Ok(true);
+            }
+            current = self.metadata.get_inode_required(current)?.parent;
         }
+        Ok(false)
+    }
 
-        self.metadata.delete_inode(inode.ino)?;
-        parent_inode.remove_child(inode.ino);
-        parent_inode.attrs.nlink -= 1;
-        self.metadata.save_inode(&parent_inode)?;
+    /// Move an entry, replacing any existing target, in one transaction
+    fn rename_entry(
+        &self,
+        caller: &Caller,
+        parent: u64,
+        name: &str,
+        newparent: u64,
+        newname: &str,
+    ) -> Result<()> {
+        self.check_writable()?;
+        let mut old_parent = self.metadata.get_inode_required(parent)?;
+        self.check_access(caller, &old_parent, MAY_WRITE | MAY_EXEC)?;
+        let mut inode = self
+            .metadata
+            .lookup(parent, name)?
+            .ok_or_else(|| Error::PathNotFound(name.to_string()))?;
+        caller.check_sticky(&old_parent.attrs, &inode.attrs)?;
+
+        let mut new_parent = if newparent == parent {
+            None
+        } else {
+            let dir = self.metadata.get_inode_required(newparent)?;
+            if !dir.is_dir() {
+                return Err(Error::NotADirectory(dir.name));
+            }
+            self.check_access(caller, &dir, MAY_WRITE | MAY_EXEC)?;
+            // Moving a directory rewrites its ".." entry
+            if inode.is_dir() {
+                self.check_access(caller, &inode, MAY_WRITE)?;
+                if self.is_within(newparent, inode.ino)? {
+                    return Err(Error::InvalidArgument(format!(
+                        "cannot move {} into its own subtree",
+                        name
+                    )));
+                }
+            }
+            Some(dir)
+        };
+
+        let mut txn = self.metadata.transaction();
+
+        // Replace an existing target
+        if let Some(existing) = self.metadata.lookup(newparent, newname)? {
+            if existing.ino == inode.ino {
+                return Ok(());
+            }
+            if existing.is_dir() && !inode.is_dir() {
+                return Err(Error::NotAFile(newname.to_string()));
+            }
+            if !existing.is_dir() && inode.is_dir() {
+                return Err(Error::NotADirectory(newname.to_string()));
+            }
+            let target_attrs = &new_parent.as_ref().unwrap_or(&old_parent).attrs;
+            caller.check_sticky(target_attrs, &existing.attrs)?;
+            let target_dir = new_parent.as_mut().unwrap_or(&mut old_parent);
+            if existing.is_dir() {
+                self.stage_remove_directory(&mut txn, target_dir, &existing)?;
+            } else {
+                Self::stage_remove_file(&mut txn, target_dir, &existing);
+            }
+        }
+
+        // Move between directories; the store moves the directory entry
+        old_parent.attrs.touch();
+        if let Some(new_parent) = new_parent.as_mut() {
+            new_parent.attrs.touch();
+            if inode.is_dir() {
+                old_parent.attrs.nlink -= 1;
+                new_parent.attrs.nlink += 1;
+            }
+            txn.save_inode(new_parent);
+        }
+        txn.save_inode(&old_parent);
+
+        // Update inode
+        inode.parent = newparent;
+        inode.name = newname.to_string();
+        inode.attrs.ctime = SystemTime::now();
+        txn.save_inode(&inode);
 
+        let orphaned = txn.commit()?;
+        self.purge_chunks(orphaned);
         Ok(())
     }
 }
 
 impl Filesystem for TgCryptFs {
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+    fn init(&mut self, _req: &Request, config: &mut KernelConfig) -> std::result::Result<(), libc::c_int> {
+        // Lookups of "." and ".." let the mount be re-exported over NFS
+        if let Err(unsupported) = config.add_capabilities(FUSE_EXPORT_SUPPORT) {
+            warn!("Kernel does not support FUSE export (capabilities {:#x})", unsupported);
+        }
+        Ok(())
+    }
+
+    fn destroy(&mut self) {
+        // Push everything still pending before the mount goes away
+        self.warmer.flush_history();
+        if let Some(journal) = &self.journal {
+            self.block_on(journal.flush());
+        }
+        if let Err(e) = self.cache.save_index() {
+            warn!("Failed to save cache index: {}", e);
+        }
+        // Statistics are only meaningful while mounted
+        let _ = std::fs::remove_file(&self.stats_path);
+    }
+
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
         let name = match name.to_str() {
             Some(n) => n,
             None => {
@@ -327,10 +935,11 @@ impl Filesystem for TgCryptFs {
         };
 
         debug!("lookup: parent={}, name={}", parent, name);
+        self.report_stats(false);
 
-        match self.metadata.lookup(parent, name) {
+        match self.lookup_entry(&Caller::from_request(req), parent, name) {
             Ok(Some(inode)) => {
-                reply.entry(&TTL, &inode.attrs.to_fuser(inode.ino), 0);
+                reply.entry(&TTL, &inode.attrs.to_fuser(inode.ino), inode.generation);
             }
             Ok(None) => {
                 reply.error(libc::ENOENT);
@@ -344,6 +953,7 @@ impl Filesystem for TgCryptFs {
 
     fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
         debug!("getattr: ino={}", ino);
+        self.report_stats(false);
 
         match self.metadata.get_inode(ino) {
             Ok(Some(inode)) => {
@@ -361,7 +971,7 @@ impl Filesystem for TgCryptFs {
 
     fn setattr(
         &mut self,
-        _req: &Request,
+        req: &Request,
         ino: u64,
         mode: Option<u32>,
         uid: Option<u32>,
@@ -369,62 +979,39 @@ impl Filesystem for TgCryptFs {
         size: Option<u64>,
         atime: Option<TimeOrNow>,
         mtime: Option<TimeOrNow>,
-        _ctime: Option<SystemTime>,
-        _fh: Option<u64>,
-        _crtime: Option<SystemTime>,
+        ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        crtime: Option<SystemTime>,
         _chgtime: Option<SystemTime>,
         _bkuptime: Option<SystemTime>,
-        _flags: Option<u32>,
+        flags: Option<u32>,
         reply: ReplyAttr,
     ) {
         debug!("setattr: ino={}", ino);
 
-        match self.metadata.get_inode(ino) {
-            Ok(Some(mut inode)) => {
-                if let Some(m) = mode {
-                    inode.attrs.perm = m as u16;
-                }
-                if let Some(u) = uid {
-                    inode.attrs.uid = u;
-                }
-                if let Some(g) = gid {
-                    inode.attrs.gid = g;
-                }
-                if let Some(s) = size {
-                    // Truncate
-                    if s == 0 && inode.is_file() {
-                        inode.manifest = Some(ChunkManifest::new(inode.version + 1));
-                    }
-                    inode.set_size(s);
-                }
-                if let Some(a) = atime {
-                    inode.attrs.atime = match a {
-                        TimeOrNow::SpecificTime(t) => t,
-                        TimeOrNow::Now => SystemTime::now(),
-                    };
-                }
-                if let Some(m) = mtime {
-                    inode.attrs.mtime = match m {
-                        TimeOrNow::SpecificTime(t) => t,
-                        TimeOrNow::Now => SystemTime::now(),
-                    };
-                }
-
-                inode.attrs.ctime = SystemTime::now();
-
-                match self.metadata.save_inode(&inode) {
-                    Ok(_) => reply.attr(&TTL, &inode.attrs.to_fuser(ino)),
-                    Err(e) => reply.error(e.to_errno()),
-                }
-            }
-            Ok(None) => reply.error(libc::ENOENT),
+        let changes = AttrChanges {
+            mode,
+            uid,
+            gid,
+            size,
+            atime,
+            mtime,
+            ctime,
+            crtime,
+            flags,
+            via_handle: fh.is_some(),
+        };
+        match self.set_attributes(Caller::from_request(req), ino, &changes) {
+            Ok(inode) => reply.attr(&TTL, &inode.attrs.to_fuser(ino)),
             Err(e) => reply.error(e.to_errno()),
         }
+
+        self.journal_changed();
     }
 
     fn readdir(
         &mut self,
-        _req: &Request,
+        req: &Request,
         ino: u64,
         _fh: u64,
         offset: i64,
@@ -448,27 +1035,40 @@ impl Filesystem for TgCryptFs {
             reply.error(libc::ENOTDIR);
             return;
         }
+        if let Err(e) = self.check_access(&Caller::from_request(req), &inode, MAY_READ) {
+            reply.error(e.to_errno());
+            return;
+        }
+
+        // "." and ".." take cookies 1 and 2; entries resume after the last cookie
+        if offset < 1 && reply.add(ino, 1, FuserFileType::Directory, ".") {
+            reply.ok();
+            return;
+        }
+        if offset < 2 && reply.add(inode.parent, 2, FuserFileType::Directory, "..") {
+            reply.ok();
+            return;
+        }
 
-        let mut entries = vec![
-            (ino, FuserFileType::Directory, ".".to_string()),
-            (inode.parent, FuserFileType::Directory, "..".to_string()),
-        ];
+        let mut after = offset.max(0) as u64;
+        loop {
+            let page = match self.metadata.read_dir(ino, after, READDIR_PAGE) {
+                Ok(page) => page,
+                Err(e) => {
+                    reply.error(e.to_errno());
+                    return;
+                }
+            };
+            let last_page = page.len() < READDIR_PAGE;
 
-        // Get children
-        match self.metadata.get_children(ino) {
-            Ok(children) => {
-                for child in children {
-                    entries.push((child.ino, child.attrs.kind.to_fuser(), child.name.clone()));
+            for (cookie, entry) in page {
+                if reply.add(entry.ino, cookie as i64, entry.kind.to_fuser(), &entry.name) {
+                    reply.ok();
+                    return;
                 }
+                after = cookie;
             }
-            Err(e) => {
-                reply.error(e.to_errno());
-                return;
-            }
-        }
-
-        for (i, (ino, kind, name)) in entries.iter().enumerate().skip(offset as usize) {
-            if reply.add(*ino, (i + 1) as i64, *kind, name) {
+            if last_page {
                 break;
             }
         }
@@ -476,7 +1076,7 @@ impl Filesystem for TgCryptFs {
         reply.ok();
     }
 
-    fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+    fn open(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
         debug!("open: ino={}, flags={}", ino, flags);
 
         match self.metadata.get_inode(ino) {
@@ -485,7 +1085,18 @@ impl Filesystem for TgCryptFs {
                     reply.error(libc::EISDIR);
                     return;
                 }
+                if let Err(e) = self.check_access(&Caller::from_request(req), &inode, open_mask(flags)) {
+                    reply.error(e.to_errno());
+                    return;
+                }
+                if flags & libc::O_ACCMODE != libc::O_RDONLY || flags & libc::O_TRUNC != 0 {
+                    if let Err(e) = self.check_writable() {
+                        reply.error(e.to_errno());
+                        return;
+                    }
+                }
                 let fh = self.handles.open(ino, flags);
+                self.warmer.record_access(ino);
                 reply.opened(fh, 0);
             }
             Ok(None) => reply.error(libc::ENOENT),
@@ -497,7 +1108,7 @@ impl Filesystem for TgCryptFs {
         &mut self,
         _req: &Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         size: u32,
         _flags: i32,
@@ -518,8 +1129,25 @@ impl Filesystem for TgCryptFs {
             }
         };
 
-        match self.read_file_data(&inode, offset as u64, size) {
-            Ok(data) => reply.data(&data),
+        let random = !self
+            .handles
+            .with_handle(fh, |h| h.continues_read(offset as u64))
+            .unwrap_or(true);
+        match self.read_file_data(&inode, offset as u64, size, random) {
+            Ok(data) => {
+                reply.data(&data);
+                let sequential = self
+                    .handles
+                    .with_handle(fh, |h| h.record_read(offset as u64, data.len() as u64))
+                    .unwrap_or(false);
+                if sequential && !data.is_empty() {
+                    self.read_ahead(&inode, offset as u64 + data.len() as u64 - 1);
+                }
+            }
+            Err(Error::Offline(reason)) => {
+                debug!("read unavailable offline: {}", reason);
+                reply.error(self.config.offline.uncached_read_error.errno());
+            }
             Err(e) => {
                 error!("read error: {}", e);
                 reply.error(e.to_errno());
@@ -574,15 +1202,16 @@ impl Filesystem for TgCryptFs {
         }
 
         reply.ok();
+        self.journal_changed();
     }
 
     fn create(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
         mode: u32,
-        _umask: u32,
+        umask: u32,
         flags: i32,
         reply: ReplyCreate,
     ) {
@@ -596,25 +1225,27 @@ impl Filesystem for TgCryptFs {
 
         debug!("create: parent={}, name={}, mode={:o}", parent, name, mode);
 
-        match self.create_file(parent, name, mode) {
+        match self.create_file(&Caller::from_request(req), parent, name, mode, umask) {
             Ok(inode) => {
                 let fh = self.handles.open(inode.ino, flags);
-                reply.created(&TTL, &inode.attrs.to_fuser(inode.ino), 0, fh, 0);
+                reply.created(&TTL, &inode.attrs.to_fuser(inode.ino), inode.generation, fh, 0);
             }
             Err(e) => {
                 error!("create error: {}", e);
                 reply.error(e.to_errno());
             }
         }
+
+        self.journal_changed();
     }
 
     fn mkdir(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
         mode: u32,
-        _umask: u32,
+        umask: u32,
         reply: ReplyEntry,
     ) {
         let name = match name.to_str() {
@@ -627,18 +1258,20 @@ impl Filesystem for TgCryptFs {
 
         debug!("mkdir: parent={}, name={}, mode={:o}", parent, name, mode);
 
-        match self.create_directory(parent, name, mode) {
+        match self.create_directory(&Caller::from_request(req), parent, name, mode, umask) {
             Ok(inode) => {
-                reply.entry(&TTL, &inode.attrs.to_fuser(inode.ino), 0);
+                reply.entry(&TTL, &inode.attrs.to_fuser(inode.ino), inode.generation);
             }
             Err(e) => {
                 error!("mkdir error: {}", e);
                 reply.error(e.to_errno());
             }
         }
+
+        self.journal_changed();
     }
 
-    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         let name = match name.to_str() {
             Some(n) => n,
             None => {
@@ -649,16 +1282,18 @@ impl Filesystem for TgCryptFs {
 
         debug!("unlink: parent={}, name={}", parent, name);
 
-        match self.remove_file(parent, name) {
+        match self.remove_file(&Caller::from_request(req), parent, name) {
             Ok(_) => reply.ok(),
             Err(e) => {
                 error!("unlink error: {}", e);
                 reply.error(e.to_errno());
             }
         }
+
+        self.journal_changed();
     }
 
-    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+    fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         let name = match name.to_str() {
             Some(n) => n,
             None => {
@@ -669,18 +1304,20 @@ impl Filesystem for TgCryptFs {
 
         debug!("rmdir: parent={}, name={}", parent, name);
 
-        match self.remove_directory(parent, name) {
+        match self.remove_directory(&Caller::from_request(req), parent, name) {
             Ok(_) => reply.ok(),
             Err(e) => {
                 error!("rmdir error: {}", e);
                 reply.error(e.to_errno());
             }
         }
+
+        self.journal_changed();
     }
 
     fn rename(
         &mut self,
-        _req: &Request,
+        req: &Request,
         parent: u64,
         name: &OsStr,
         newparent: u64,
@@ -708,8 +1345,21 @@ impl Filesystem for TgCryptFs {
             parent, name, newparent, newname
         );
 
-        // Get source inode
-        let mut inode = match self.metadata.lookup(parent, name) {
+        match self.rename_entry(&Caller::from_request(req), parent, name, newparent, newname) {
+            Ok(_) => reply.ok(),
+            Err(e) => {
+                error!("rename error: {}", e);
+                reply.error(e.to_errno());
+            }
+        }
+
+        self.journal_changed();
+    }
+
+    fn access(&mut self, req: &Request, ino: u64, mask: i32, reply: ReplyEmpty) {
+        debug!("access: ino={}, mask={}", ino, mask);
+
+        let inode = match self.metadata.get_inode(ino) {
             Ok(Some(i)) => i,
             Ok(None) => {
                 reply.error(libc::ENOENT);
@@ -721,47 +1371,129 @@ impl Filesystem for TgCryptFs {
             }
         };
 
-        // Check if target exists
-        if let Ok(Some(existing)) = self.metadata.lookup(newparent, newname) {
-            // Remove existing
-            if existing.is_dir() {
-                if let Err(e) = self.remove_directory(newparent, newname) {
-                    reply.error(e.to_errno());
-                    return;
-                }
-            } else if let Err(e) = self.remove_file(newparent, newname) {
-                reply.error(e.to_errno());
+        // F_OK only asks whether the inode exists; R_OK/W_OK/X_OK match MAY_*
+        let mask = (mask & 0o7) as u16;
+        match self.check_access(&Caller::from_request(req), &inode, mask) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.to_errno()),
+        }
+    }
+
+    fn setxattr(
+        &mut self,
+        req: &Request,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        let name = match name.to_str() {
+            Some(n) => n,
+            None => {
+                reply.error(libc::EINVAL);
                 return;
             }
+        };
+
+        debug!("setxattr: ino={}, name={}, size={}", ino, name, value.len());
+
+        match self.set_xattr(&Caller::from_request(req), ino, name, value, flags) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.to_errno()),
         }
 
-        // Update old parent
-        if let Ok(mut old_parent) = self.metadata.get_inode_required(parent) {
-            old_parent.remove_child(inode.ino);
-            if inode.is_dir() {
-                old_parent.attrs.nlink -= 1;
+        self.journal_changed();
+    }
+
+    fn getxattr(&mut self, req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let name = match name.to_str() {
+            Some(n) => n,
+            None => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+
+        debug!("getxattr: ino={}, name={}, size={}", ino, name, size);
+
+        let inode = match self.metadata.get_inode_required(ino) {
+            Ok(i) => i,
+            Err(e) => {
+                reply.error(e.to_errno());
+                return;
             }
-            let _ = self.metadata.save_inode(&old_parent);
+        };
+        let caller = Caller::from_request(req);
+        if let Err(e) = caller.check_xattr(&inode, name, false, self.config.mount.posix_acl) {
+            reply.error(e.to_errno());
+            return;
         }
 
-        // Update new parent
-        if let Ok(mut new_parent) = self.metadata.get_inode_required(newparent) {
-            new_parent.add_child(inode.ino);
-            if inode.is_dir() {
-                new_parent.attrs.nlink += 1;
+        // Warmup progress and cache residency are reported by the mount, not stored
+        let value = match name {
+            WARM_XATTR => self.warmer.progress(ino).and_then(|p| serde_json::to_vec(&p).ok()),
+            CACHED_XATTR => inspect::cached_file(&self.cache, &inode).and_then(|c| serde_json::to_vec(&c).ok()),
+            _ => inode.xattrs.get(name).cloned(),
+        };
+        match value {
+            Some(value) if size == 0 => reply.size(value.len() as u32),
+            Some(value) if (size as usize) < value.len() => reply.error(libc::ERANGE),
+            Some(value) => reply.data(&value),
+            None => reply.error(libc::ENODATA),
+        }
+    }
+
+    fn listxattr(&mut self, req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        debug!("listxattr: ino={}, size={}", ino, size);
+
+        let inode = match self.metadata.get_inode_required(ino) {
+            Ok(i) => i,
+            Err(e) => {
+                reply.error(e.to_errno());
+                return;
             }
-            let _ = self.metadata.save_inode(&new_parent);
+        };
+
+        // Names are NUL-terminated; privileged namespaces are hidden
+        let caller = Caller::from_request(req);
+        let mut names = Vec::new();
+        let visible = inode
+            .xattrs
+            .keys()
+            .filter(|name| caller.lists_xattr(name, self.config.mount.posix_acl));
+        for name in visible {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
         }
 
-        // Update inode
-        inode.parent = newparent;
-        inode.name = newname.to_string();
-        inode.attrs.ctime = SystemTime::now();
+        if size == 0 {
+            reply.size(names.len() as u32);
+        } else if (size as usize) < names.len() {
+            reply.error(libc::ERANGE);
+        } else {
+            reply.data(&names);
+        }
+    }
 
-        match self.metadata.save_inode(&inode) {
-            Ok(_) => reply.ok(),
+    fn removexattr(&mut self, req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        let name = match name.to_str() {
+            Some(n) => n,
+            None => {
+                reply.error(libc::EINVAL);
+                return;
+            }
+        };
+
+        debug!("removexattr: ino={}, name={}", ino, name);
+
+        match self.remove_xattr(&Caller::from_request(req), ino, name) {
+            Ok(()) => reply.ok(),
             Err(e) => reply.error(e.to_errno()),
         }
+
+        self.journal_changed();
     }
 
     fn statfs(&mut self, _req: &Request, _ino: u64, reply: fuser::ReplyStatfs) {
@@ -779,3 +1511,87 @@ impl Filesystem for TgCryptFs {
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::testing::{project_tree, test_fs};
+
+    #[test]
+    fn test_rename_into_own_subtree_is_rejected() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let fs = test_fs(temp.path(), project_tree());
+        let root = Caller::new(0, 0);
+
+        let err = fs.rename_entry(&root, 1, "project", 3, "moved").unwrap_err();
+        assert_eq!(err.to_errno(), libc::EINVAL);
+        let err = fs.rename_entry(&root, 1, "project", 2, "moved").unwrap_err();
+        assert_eq!(err.to_errno(), libc::EINVAL);
+
+        // Nothing moved, and a sibling subtree is still a valid target
+        assert_eq!(fs.metadata.get_inode(2).unwrap().unwrap().parent, 1);
+        fs.metadata
+            .save_inode(&Inode::new_directory(6, 1, "docs".to_string(), 0, 0, 0o755))
+            .unwrap();
+        fs.rename_entry(&root, 1, "project", 6, "project").unwrap();
+        assert_eq!(fs.metadata.get_inode(2).unwrap().unwrap().parent, 6);
+    }
+
+    #[test]
+    fn test_rename_across_file_and_directory_is_rejected() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let fs = test_fs(temp.path(), project_tree());
+        let root = Caller::new(0, 0);
+
+        // File onto a directory
+        let err = fs.rename_entry(&root, 2, "f5", 2, "src").unwrap_err();
+        assert_eq!(err.to_errno(), libc::EISDIR);
+        // Directory onto a file
+        let err = fs.rename_entry(&root, 2, "src", 2, "f5").unwrap_err();
+        assert_eq!(err.to_errno(), libc::ENOTDIR);
+
+        // Both entries survive
+        assert_eq!(fs.metadata.lookup(2, "f5").unwrap().unwrap().ino, 5);
+        assert_eq!(fs.metadata.lookup(2, "src").unwrap().unwrap().ino, 3);
+    }
+
+    #[test]
+    fn test_dedup_keeps_stored_chunk_size() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let mut fs = test_fs(temp.path(), project_tree());
+        Arc::get_mut(&mut fs.config).unwrap().chunk.segment_size = 0;
+        fs.connectivity.set_offline(&Error::Offline("test".to_string()));
+        let data = vec![3u8; 1000];
+
+        fs.write_file_data(5, &data).unwrap();
+        let first = fs.metadata.get_inode(5).unwrap().unwrap().manifest.unwrap();
+        let queued = fs.connectivity.queue().pending();
+
+        // Sealing in segments now would change the size, but the queued
+        // chunk is reused as it was stored
+        Arc::get_mut(&mut fs.config).unwrap().chunk.segment_size = 100;
+        fs.write_file_data(4, &data).unwrap();
+        let second = fs.metadata.get_inode(4).unwrap().unwrap().manifest.unwrap();
+        assert_eq!(second.chunks, first.chunks);
+        assert_eq!(fs.connectivity.queue().pending(), queued);
+    }
+
+    #[test]
+    fn test_evict_needs_write_access() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let store = project_tree();
+        let mut file = store.get_inode(5).unwrap().unwrap();
+        file.attrs.uid = 1000;
+        file.attrs.gid = 100;
+        store.save_inode(&file).unwrap();
+        let fs = test_fs(temp.path(), store);
+
+        // Readable is not enough
+        let reader = Caller::new(2000, 100);
+        let err = fs.set_xattr(&reader, 5, EVICT_XATTR, b"", 0).unwrap_err();
+        assert_eq!(err.to_errno(), libc::EACCES);
+
+        let owner = Caller::new(1000, 100);
+        fs.set_xattr(&owner, 5, EVICT_XATTR, b"", 0).unwrap();
+    }
+}
@@ -12,7 +12,7 @@ pub struct FileHandle {
     pub flags: i32,
     /// Write buffer (for buffered writes)
     pub write_buffer: RwLock<Vec<u8>>,
-    /// Read position
+    /// End of the last read (where a sequential reader continues)
     pub read_pos: AtomicU64,
     /// Dirty flag (has uncommitted writes)
     pub dirty: std::sync::atomic::AtomicBool,
@@ -47,6 +47,18 @@ impl FileHandle {
         (self.flags & libc::O_APPEND) != 0
     }
 
+    /// Whether a read at `offset` would continue the previous one
+    pub fn continues_read(&self, offset: u64) -> bool {
+        self.read_pos.load(Ordering::SeqCst) == offset
+    }
+
+    /// Record a read and report whether it continued the previous one
+    ///
+    /// A first read from the start of the file counts as sequential.
+    pub fn record_read(&self, offset: u64, size: u64) -> bool {
+        self.read_pos.swap(offset + size, Ordering::SeqCst) == offset
+    }
+
     /// Mark as dirty
     pub fn mark_dirty(&self) {
         self.dirty.store(true, Ordering::SeqCst);
@@ -117,7 +129,6 @@ impl HandleManager {
     }
 
     /// Get handle reference for operations
-    #[allow(dead_code)]
     pub fn with_handle<F, R>(&self, fh: u64, f: F) -> Option<R>
     where
         F: FnOnce(&FileHandle) -> R,
@@ -192,6 +203,15 @@ mod tests {
         assert!(manager.is_valid(fh2));
     }
 
+    #[test]
+    fn test_sequential_read_detection() {
+        let handle = FileHandle::new(1, libc::O_RDONLY);
+        assert!(handle.record_read(0, 4096));
+        assert!(handle.record_read(4096, 4096));
+        assert!(!handle.record_read(65536, 4096));
+        assert!(handle.record_read(69632, 4096));
+    }
+
     #[test]
     fn test_write_buffer() {
         let handle = FileHandle::new(1, libc::O_WRONLY);
@@ -0,0 +1,119 @@
+//! Cache inspection through the mount
+//!
+//! `tgcryptfs cache ls` reads the `user.tgcryptfs.cached` xattr of each
+//! file to report how much of it is cached, and `tgcryptfs cache evict`
+//! sets `user.tgcryptfs.evict` on a file or directory to drop its chunks.
+//! Both are answered by the running mount and never stored.
+
+use crate::cache::ChunkCache;
+use crate::error::Result;
+use crate::fs::warm::{self, WarmRequest};
+use crate::metadata::{Inode, MetadataStore};
+use serde::{Deserialize, Serialize};
+
+/// Xattr reporting how much of a file is cached (get)
+pub const CACHED_XATTR: &str = "user.tgcryptfs.cached";
+
+/// Xattr dropping a file's or directory's chunks from the cache (set)
+pub const EVICT_XATTR: &str = "user.tgcryptfs.evict";
+
+/// Cache residency of a file, read from the cached xattr as JSON
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachedFile {
+    /// File size
+    pub size: u64,
+    /// Chunks of the file
+    pub chunks: usize,
+    /// Chunks in the cache
+    pub cached_chunks: usize,
+    /// Bytes of the file in the cache
+    pub cached_bytes: u64,
+    /// Cached chunks exempt from eviction
+    pub pinned_chunks: usize,
+}
+
+/// How much of a regular file is cached
+pub fn cached_file(cache: &ChunkCache, inode: &Inode) -> Option<CachedFile> {
+    if !inode.is_file() {
+        return None;
+    }
+    let mut cached = CachedFile {
+        size: inode.attrs.size,
+        ..Default::default()
+    };
+    for chunk in inode.manifest.iter().flat_map(|m| &m.chunks) {
+        cached.chunks += 1;
+        if cache.contains(&chunk.id) {
+            cached.cached_chunks += 1;
+            cached.cached_bytes += chunk.original_size;
+            if cache.is_pinned(&chunk.id) {
+                cached.pinned_chunks += 1;
+            }
+        }
+    }
+    Some(cached)
+}
+
+/// Drop the cached chunks of the tree at `ino`
+///
+/// Pinned chunks are kept. Returns the chunks and bytes dropped.
+pub fn evict(store: &MetadataStore, cache: &ChunkCache, ino: u64) -> Result<(usize, u64)> {
+    let request = WarmRequest {
+        recursive: true,
+        ..Default::default()
+    };
+    let mut evicted = (0, 0);
+    for chunk in warm::select(store, &[ino], &request)?.chunks {
+        if cache.is_pinned(&chunk.id) || !cache.contains(&chunk.id) {
+            continue;
+        }
+        cache.remove(&chunk.id)?;
+        evicted.0 += 1;
+        evicted.1 += chunk.original_size;
+    }
+    Ok(evicted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CacheConfig;
+    use crate::fs::testing::file_with_chunks;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_cached_and_evict() {
+        let temp = TempDir::new().unwrap();
+        let config = CacheConfig {
+            max_size: 10000,
+            cache_dir: temp.path().to_path_buf(),
+            ..crate::config::Config::default().cache
+        };
+        let cache = ChunkCache::new(&config).unwrap();
+        let store = MetadataStore::in_memory([1u8; 32]).unwrap();
+
+        let mut inode = file_with_chunks(2, 1, &["a", "b", "c"]);
+        inode.attrs.size = 30;
+        store.save_inode(&inode).unwrap();
+
+        cache.put("a", &[0u8; 10]).unwrap();
+        cache.put("b", &[0u8; 10]).unwrap();
+        cache.set_pinned(["b".to_string()].into_iter().collect());
+        let cached = cached_file(&cache, &inode).unwrap();
+        assert_eq!(
+            cached,
+            CachedFile {
+                size: 30,
+                chunks: 3,
+                cached_chunks: 2,
+                cached_bytes: 20,
+                pinned_chunks: 1,
+            }
+        );
+
+        // The pinned chunk stays
+        assert_eq!(evict(&store, &cache, 2).unwrap(), (1, 10));
+        assert_eq!(cached_file(&cache, &inode).unwrap().cached_bytes, 10);
+        assert!(cached_file(&cache, &Inode::new_directory(3, 1, "d".to_string(), 0, 0, 0o755)).is_none());
+    }
+}
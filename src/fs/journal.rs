@@ -0,0 +1,90 @@
+//! Background upload of the cloud metadata journal
+//!
+//! Uploading a batch, or a compacted checkpoint of the whole store, can take
+//! seconds (rate limits included), so it never runs on a FUSE request thread.
+//! Mutating operations only wake the upload task; it also wakes on its own
+//! every flush interval. Unmounting forces a final, blocking upload.
+
+use crate::fs::offline::Connectivity;
+use crate::metadata::{CloudJournal, MetadataStore};
+use crate::telegram::TelegramBackend;
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+use tokio::sync::Notify;
+use tracing::warn;
+
+/// Background job uploading the metadata journal
+pub struct JournalUploader {
+    /// Shared with the upload task
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    journal: CloudJournal,
+    store: Arc<MetadataStore>,
+    telegram: Arc<TelegramBackend>,
+    connectivity: Arc<Connectivity>,
+    /// Wakes the upload task
+    notify: Notify,
+    /// Keeps the task and a forced flush from uploading the same batch
+    uploading: tokio::sync::Mutex<()>,
+}
+
+impl JournalUploader {
+    /// Spawn the upload task on the runtime
+    pub fn start(
+        runtime: &Runtime,
+        journal: CloudJournal,
+        store: Arc<MetadataStore>,
+        telegram: Arc<TelegramBackend>,
+        connectivity: Arc<Connectivity>,
+    ) -> Self {
+        let inner = Arc::new(Inner {
+            journal,
+            store,
+            telegram,
+            connectivity,
+            notify: Notify::new(),
+            uploading: tokio::sync::Mutex::new(()),
+        });
+        runtime.spawn(upload_journal(inner.clone()));
+        JournalUploader { inner }
+    }
+
+    /// Metadata changed; upload soon if a batch is due
+    pub fn changed(&self) {
+        self.inner.notify.notify_one();
+    }
+
+    /// Upload everything pending now
+    ///
+    /// While offline operations stay in the local journal.
+    pub async fn flush(&self) {
+        self.inner.sync(true).await;
+    }
+}
+
+impl Inner {
+    /// Upload pending operations if due (or unconditionally if forced)
+    async fn sync(&self, force: bool) {
+        let _uploading = self.uploading.lock().await;
+        if !self.connectivity.is_online() || (!force && !self.journal.is_due(&self.store)) {
+            return;
+        }
+        if let Err(e) = self.journal.sync(&self.store, &self.telegram).await {
+            if e.is_connectivity() {
+                self.connectivity.set_offline(&e);
+            }
+            warn!("Failed to upload metadata journal: {}", e);
+        }
+    }
+}
+
+/// Upload whenever notified or the flush interval passes
+async fn upload_journal(inner: Arc<Inner>) {
+    let interval = inner.journal.flush_interval();
+    loop {
+        let _ = tokio::time::timeout(interval, inner.notify.notified()).await;
+        inner.sync(false).await;
+    }
+}
@@ -3,10 +3,22 @@
 //! Implements the FUSE filesystem interface, translating
 //! filesystem operations to our encrypted cloud backend.
 
+pub mod access;
 mod filesystem;
 mod handle;
+pub mod inspect;
+mod journal;
+pub mod offline;
 pub mod overlay;
+pub mod pin;
+mod prefetch;
+mod stats;
+#[cfg(test)]
+pub(crate) mod testing;
+pub mod warm;
 
 pub use filesystem::TgCryptFs;
 pub use handle::FileHandle;
 pub use overlay::{OverlayConfig, OverlayFs};
+pub use prefetch::DownloadStats;
+pub use stats::{DaemonStats, STATS_FILE};
@@ -0,0 +1,416 @@
+//! Offline operation
+//!
+//! When the backend cannot be reached the filesystem keeps serving from the
+//! local metadata and cache. Reads of chunks that are not cached fail at
+//! once instead of retrying, and writes are either refused or have their
+//! encrypted chunks spooled to a local upload queue, referenced in the
+//! manifests by `PENDING_MESSAGE_ID`. Metadata changes wait in the local
+//! journal as usual. A background task re-probes the connection and, once
+//! it is back, uploads the queue and points the manifests at the new
+//! messages.
+
+use crate::chunk::PENDING_MESSAGE_ID;
+use crate::error::{Error, Result};
+use crate::metadata::MetadataStore;
+use crate::telegram::TelegramBackend;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::runtime::Runtime;
+use tokio::sync::Notify;
+use tracing::{debug, info, warn};
+
+/// Directory (under the data directory) holding spooled chunks
+const QUEUE_DIR: &str = "upload_queue";
+
+/// Local metadata key holding the queue index
+const QUEUE_KEY: &str = "local:upload_queue";
+
+/// How long a connection probe may take
+const PROBE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Check that the backend is reachable and the session authorized
+pub async fn probe(telegram: &TelegramBackend) -> Result<()> {
+    let check = async {
+        if !telegram.is_connected() {
+            telegram.connect().await?;
+        }
+        if telegram.is_authorized().await? {
+            Ok(())
+        } else {
+            Err(Error::TelegramAuthRequired)
+        }
+    };
+    let result = match tokio::time::timeout(PROBE_TIMEOUT, check).await {
+        Ok(result) => result,
+        Err(_) => Err(Error::Offline("connection attempt timed out".to_string())),
+    };
+    if result.is_err() {
+        // The next probe starts from a fresh connection
+        telegram.disconnect().await;
+    }
+    result
+}
+
+/// Chunk waiting to be uploaded
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PendingChunk {
+    /// Size of the encrypted chunk
+    size: u64,
+    /// Inodes whose manifests were written with the chunk
+    inodes: BTreeSet<u64>,
+}
+
+/// Persistent state of the upload queue
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct QueueIndex {
+    /// Chunks awaiting upload
+    chunks: BTreeMap<String, PendingChunk>,
+    /// Messages whose chunks were released while offline
+    deletes: BTreeSet<i32>,
+}
+
+/// Chunks written while offline
+///
+/// Encrypted chunks are spooled to files; the index lives in the local
+/// (never journaled) metadata so it survives restarts.
+pub struct UploadQueue {
+    dir: PathBuf,
+    store: Arc<MetadataStore>,
+    index: Mutex<QueueIndex>,
+}
+
+impl UploadQueue {
+    /// Open the queue in a data directory
+    pub fn open(data_dir: &Path, store: Arc<MetadataStore>) -> Result<Self> {
+        let dir = data_dir.join(QUEUE_DIR);
+        std::fs::create_dir_all(&dir)?;
+        let index = match store.get_metadata(QUEUE_KEY)? {
+            Some(data) => bincode::deserialize(&data)?,
+            None => QueueIndex::default(),
+        };
+        Ok(UploadQueue {
+            dir,
+            store,
+            index: Mutex::new(index),
+        })
+    }
+
+    /// Persist the index
+    fn save(&self, index: &QueueIndex) -> Result<()> {
+        self.store.save_metadata(QUEUE_KEY, &bincode::serialize(index)?)
+    }
+
+    /// Spool file of a chunk
+    fn chunk_path(&self, chunk_id: &str) -> PathBuf {
+        self.dir.join(chunk_id)
+    }
+
+    /// Queue an encrypted chunk written to an inode's manifest
+    pub fn push(&self, chunk_id: &str, ino: u64, encrypted: &[u8]) -> Result<()> {
+        let path = self.chunk_path(chunk_id);
+        if !path.exists() {
+            let tmp = path.with_extension("tmp");
+            std::fs::write(&tmp, encrypted)?;
+            std::fs::rename(&tmp, &path)?;
+        }
+
+        let mut index = self.index.lock();
+        let pending = index.chunks.entry(chunk_id.to_string()).or_default();
+        pending.size = encrypted.len() as u64;
+        pending.inodes.insert(ino);
+        self.save(&index)
+    }
+
+    /// Add an inode to the references of a queued chunk
+    ///
+    /// Returns the chunk's encrypted size, or None if it is not queued.
+    pub fn reference(&self, chunk_id: &str, ino: u64) -> Result<Option<u64>> {
+        let mut index = self.index.lock();
+        let Some(pending) = index.chunks.get_mut(chunk_id) else {
+            return Ok(None);
+        };
+        pending.inodes.insert(ino);
+        let size = pending.size;
+        self.save(&index)?;
+        Ok(Some(size))
+    }
+
+    /// Read a spooled chunk, if it is still queued
+    pub fn read(&self, chunk_id: &str) -> Result<Option<Vec<u8>>> {
+        if !self.index.lock().chunks.contains_key(chunk_id) {
+            return Ok(None);
+        }
+        match std::fs::read(self.chunk_path(chunk_id)) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// A chunk's last reference was dropped while it was queued or offline
+    ///
+    /// Queued chunks are simply discarded; uploaded ones are deleted from
+    /// the backend on the next resync.
+    pub fn release(&self, chunk_id: &str, message_id: i32) -> Result<()> {
+        let mut index = self.index.lock();
+        if index.chunks.remove(chunk_id).is_some() {
+            let _ = std::fs::remove_file(self.chunk_path(chunk_id));
+        } else if message_id != PENDING_MESSAGE_ID {
+            index.deletes.insert(message_id);
+        } else {
+            return Ok(());
+        }
+        self.save(&index)
+    }
+
+    /// Whether anything waits for the backend
+    pub fn has_work(&self) -> bool {
+        let index = self.index.lock();
+        !index.chunks.is_empty() || !index.deletes.is_empty()
+    }
+
+    /// Queued chunks and their total size
+    pub fn pending(&self) -> (usize, u64) {
+        let index = self.index.lock();
+        (index.chunks.len(), index.chunks.values().map(|c| c.size).sum())
+    }
+
+    /// Messages waiting to be deleted
+    fn deletes(&self) -> Vec<i32> {
+        self.index.lock().deletes.iter().copied().collect()
+    }
+
+    /// A queued message deletion was carried out
+    fn deleted(&self, message_id: i32) -> Result<()> {
+        let mut index = self.index.lock();
+        index.deletes.remove(&message_id);
+        self.save(&index)
+    }
+
+    /// Queued chunk IDs
+    fn chunk_ids(&self) -> Vec<String> {
+        self.index.lock().chunks.keys().cloned().collect()
+    }
+
+    /// Record a chunk's upload, rewriting the references to it
+    ///
+    /// Returns false if the chunk was released in the meantime, in which
+    /// case the uploaded message is redundant.
+    fn uploaded(&self, chunk_id: &str, message_id: i32) -> Result<bool> {
+        let mut index = self.index.lock();
+        let Some(pending) = index.chunks.remove(chunk_id) else {
+            return Ok(false);
+        };
+
+        // Adding and dropping a reference names the message without changing
+        // the count; an unreferenced chunk comes back as orphaned
+        let mut txn = self.store.transaction();
+        txn.save_chunk_ref(chunk_id, message_id).decrement_chunk_ref(chunk_id);
+        for ino in &pending.inodes {
+            txn.resolve_pending_chunk(*ino, chunk_id, message_id);
+        }
+        let referenced = txn.commit()?.is_empty();
+
+        self.save(&index)?;
+        let _ = std::fs::remove_file(self.chunk_path(chunk_id));
+        Ok(referenced)
+    }
+}
+
+/// Connectivity summary published for `tgcryptfs status`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectivityStats {
+    /// Whether the backend is reachable
+    pub online: bool,
+    /// When the state last changed (seconds since the Unix epoch)
+    pub since: u64,
+    /// Why the backend was last considered unreachable
+    pub last_error: Option<String>,
+    /// Chunks waiting to be uploaded
+    pub queued_chunks: usize,
+    /// Size of the waiting chunks
+    pub queued_bytes: u64,
+}
+
+impl Default for ConnectivityStats {
+    fn default() -> Self {
+        ConnectivityStats {
+            online: true,
+            since: 0,
+            last_error: None,
+            queued_chunks: 0,
+            queued_bytes: 0,
+        }
+    }
+}
+
+/// Whether the backend is reachable, and what waits for it
+pub struct Connectivity {
+    online: AtomicBool,
+    /// When the state last changed and the last error
+    changed: Mutex<(SystemTime, Option<String>)>,
+    /// Wakes the probe task
+    notify: Notify,
+    /// Chunks written while offline
+    queue: UploadQueue,
+}
+
+impl Connectivity {
+    /// Create the connectivity state
+    pub fn new(online: bool, queue: UploadQueue) -> Self {
+        Connectivity {
+            online: AtomicBool::new(online),
+            changed: Mutex::new((SystemTime::now(), None)),
+            notify: Notify::new(),
+            queue,
+        }
+    }
+
+    /// Whether the backend is believed reachable
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::SeqCst)
+    }
+
+    /// The offline upload queue
+    pub fn queue(&self) -> &UploadQueue {
+        &self.queue
+    }
+
+    /// Mark the backend unreachable and probe it soon
+    pub fn set_offline(&self, reason: &Error) {
+        if self.online.swap(false, Ordering::SeqCst) {
+            warn!("Backend unreachable, continuing offline: {}", reason);
+            *self.changed.lock() = (SystemTime::now(), Some(reason.to_string()));
+        }
+        self.notify.notify_one();
+    }
+
+    /// Mark the backend reachable again
+    fn set_online(&self) {
+        if !self.online.swap(true, Ordering::SeqCst) {
+            info!("Backend reachable again");
+            self.changed.lock().0 = SystemTime::now();
+        }
+    }
+
+    /// Current state
+    pub fn stats(&self) -> ConnectivityStats {
+        let (since, last_error) = self.changed.lock().clone();
+        let (queued_chunks, queued_bytes) = self.queue.pending();
+        ConnectivityStats {
+            online: self.is_online(),
+            since: since.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            last_error,
+            queued_chunks,
+            queued_bytes,
+        }
+    }
+
+    /// Spawn the task that re-probes the backend and drains the queue
+    ///
+    /// The task runs until the runtime shuts down.
+    pub fn start(self: &Arc<Self>, runtime: &Runtime, telegram: Arc<TelegramBackend>, interval: Duration) {
+        runtime.spawn(reconnect(self.clone(), telegram, interval));
+    }
+}
+
+/// Probe while offline and upload queued work once online
+async fn reconnect(connectivity: Arc<Connectivity>, telegram: Arc<TelegramBackend>, interval: Duration) {
+    loop {
+        if !connectivity.is_online() {
+            match probe(&telegram).await {
+                Ok(()) => connectivity.set_online(),
+                Err(e) => debug!("Backend still unreachable: {}", e),
+            }
+        }
+        if connectivity.is_online() && connectivity.queue.has_work() {
+            if let Err(e) = resync(&connectivity.queue, &telegram).await {
+                if e.is_connectivity() {
+                    connectivity.set_offline(&e);
+                } else {
+                    warn!("Failed to upload offline changes: {}", e);
+                }
+            }
+        }
+        let _ = tokio::time::timeout(interval, connectivity.notify.notified()).await;
+    }
+}
+
+/// Upload queued chunks and carry out queued deletions
+async fn resync(queue: &UploadQueue, telegram: &TelegramBackend) -> Result<()> {
+    let ids = queue.chunk_ids();
+    if !ids.is_empty() {
+        info!("Uploading {} chunks written offline", ids.len());
+    }
+    for chunk_id in ids {
+        let Some(data) = queue.read(&chunk_id)? else {
+            continue;
+        };
+        let message_id = telegram.upload_chunk(&chunk_id, &data).await?;
+        if !queue.uploaded(&chunk_id, message_id)? {
+            telegram.delete_message(message_id).await?;
+        }
+    }
+
+    for message_id in queue.deletes() {
+        telegram.delete_message(message_id).await?;
+        queue.deleted(message_id)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::testing::file_with_chunk_messages;
+    use crate::metadata::Inode;
+    use tempfile::TempDir;
+
+    fn pending_file(ino: u64, chunk_id: &str) -> Inode {
+        file_with_chunk_messages(ino, 1, &[(chunk_id, PENDING_MESSAGE_ID)])
+    }
+
+    #[test]
+    fn test_queue_upload() {
+        let temp = TempDir::new().unwrap();
+        let store = Arc::new(MetadataStore::in_memory([2u8; 32]).unwrap());
+        store.save_inode(&pending_file(2, "a")).unwrap();
+        store.save_chunk_ref("a", PENDING_MESSAGE_ID).unwrap();
+
+        let queue = UploadQueue::open(temp.path(), store.clone()).unwrap();
+        queue.push("a", 2, b"sealed").unwrap();
+        assert_eq!(queue.pending(), (1, 6));
+
+        // The index survives a restart
+        let queue = UploadQueue::open(temp.path(), store.clone()).unwrap();
+        assert_eq!(queue.read("a").unwrap().unwrap(), b"sealed");
+
+        assert!(queue.uploaded("a", 7).unwrap());
+        assert!(!queue.has_work());
+        assert!(queue.read("a").unwrap().is_none());
+        assert_eq!(store.get_chunk_ref("a").unwrap(), Some(7));
+        let inode = store.get_inode(2).unwrap().unwrap();
+        assert_eq!(inode.manifest.unwrap().chunks[0].message_id, 7);
+    }
+
+    #[test]
+    fn test_queue_release() {
+        let temp = TempDir::new().unwrap();
+        let store = Arc::new(MetadataStore::in_memory([2u8; 32]).unwrap());
+        let queue = UploadQueue::open(temp.path(), store).unwrap();
+
+        queue.push("a", 2, b"sealed").unwrap();
+        queue.release("a", PENDING_MESSAGE_ID).unwrap();
+        queue.release("b", 9).unwrap();
+        assert_eq!(queue.pending(), (0, 0));
+        assert_eq!(queue.deletes(), [9]);
+
+        // Chunks released mid-upload make the upload redundant
+        assert!(!queue.uploaded("a", 10).unwrap());
+    }
+}
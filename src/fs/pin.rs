@@ -0,0 +1,223 @@
+//! Pinned ("keep offline") files
+//!
+//! Setting the `user.tgcryptfs.pinned` xattr on a file or directory (which
+//! `tgcryptfs pin` does through the mount) adds it to a set of pinned
+//! inodes. Keeping files offline is a choice made per machine, so the set
+//! is kept under a `local:` key and never journaled, exported or
+//! replicated. A background job resolves the set to chunks, downloads any
+//! that are missing and exempts them from cache eviction, so pinned trees
+//! stay readable without a connection. It runs again after pins change,
+//! after local writes and periodically to pick up remote changes.
+
+use crate::cache::ChunkCache;
+use crate::chunk::ChunkRef;
+use crate::error::Result;
+use crate::fs::prefetch::ChunkFetcher;
+use crate::metadata::MetadataStore;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tokio::sync::Notify;
+use tracing::{debug, warn};
+
+/// Xattr marking an inode as pinned
+pub const PIN_XATTR: &str = "user.tgcryptfs.pinned";
+
+/// Local metadata key holding the pinned inode numbers
+const PINS_KEY: &str = "local:pinned_inodes";
+
+/// Serializes updates of the pin set (from FUSE calls and the refresh job)
+static PINS_LOCK: Mutex<()> = Mutex::new(());
+
+/// How often pins are refreshed without a trigger
+const REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Pinned inode numbers
+pub fn load_pins(store: &MetadataStore) -> Result<BTreeSet<u64>> {
+    match store.get_metadata(PINS_KEY)? {
+        Some(data) => Ok(bincode::deserialize(&data)?),
+        None => Ok(BTreeSet::new()),
+    }
+}
+
+/// Pin or unpin an inode
+pub fn set_pinned(store: &MetadataStore, ino: u64, pinned: bool) -> Result<()> {
+    let _lock = PINS_LOCK.lock();
+    let mut pins = load_pins(store)?;
+    let changed = if pinned { pins.insert(ino) } else { pins.remove(&ino) };
+    if changed {
+        store.save_metadata(PINS_KEY, &bincode::serialize(&pins)?)?;
+    }
+    Ok(())
+}
+
+/// Pinned data summary
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PinStats {
+    /// Pinned files and directories
+    pub roots: usize,
+    /// Files covered by pins
+    pub files: usize,
+    /// Size of the pinned files
+    pub bytes: u64,
+    /// Pinned bytes already in the cache
+    pub cached_bytes: u64,
+}
+
+/// Chunks covered by the pins
+pub struct PinnedChunks {
+    /// Distinct chunks
+    pub chunks: Vec<ChunkRef>,
+    /// Summary (without `cached_bytes`)
+    pub stats: PinStats,
+    /// Pins whose inode no longer exists
+    pub stale: Vec<u64>,
+}
+
+/// Walk the pinned trees and collect their chunks
+pub fn resolve_pins(store: &MetadataStore) -> Result<PinnedChunks> {
+    let pins: Vec<u64> = load_pins(store)?.into_iter().collect();
+    let mut stats = PinStats {
+        roots: pins.len(),
+        ..Default::default()
+    };
+    let mut chunks: HashMap<String, ChunkRef> = HashMap::new();
+
+    let stale = store.walk_trees(&pins, |inode, _| {
+        if let Some(manifest) = inode.manifest {
+            stats.files += 1;
+            stats.bytes += manifest.total_size;
+            for chunk in manifest.chunks {
+                chunks.entry(chunk.id.clone()).or_insert(chunk);
+            }
+        }
+        true
+    })?;
+
+    Ok(PinnedChunks {
+        chunks: chunks.into_values().collect(),
+        stats,
+        stale,
+    })
+}
+
+/// Background job keeping pinned chunks cached
+pub struct PinKeeper {
+    /// Requests a refresh
+    notify: Arc<Notify>,
+    /// Result of the last refresh
+    stats: Arc<Mutex<PinStats>>,
+}
+
+impl PinKeeper {
+    /// Spawn the refresh task on the runtime
+    pub fn start(
+        runtime: &Runtime,
+        store: Arc<MetadataStore>,
+        cache: Arc<ChunkCache>,
+        fetcher: Arc<ChunkFetcher>,
+    ) -> Self {
+        let notify = Arc::new(Notify::new());
+        let stats = Arc::new(Mutex::new(PinStats::default()));
+        runtime.spawn(keep_pins(store, cache, fetcher, notify.clone(), stats.clone()));
+        PinKeeper { notify, stats }
+    }
+
+    /// Refresh soon (pins or pinned files changed)
+    pub fn refresh(&self) {
+        self.notify.notify_one();
+    }
+
+    /// Summary from the last refresh
+    pub fn stats(&self) -> PinStats {
+        self.stats.lock().clone()
+    }
+}
+
+/// Refresh pins now, then whenever notified or the interval passes
+async fn keep_pins(
+    store: Arc<MetadataStore>,
+    cache: Arc<ChunkCache>,
+    fetcher: Arc<ChunkFetcher>,
+    notify: Arc<Notify>,
+    stats: Arc<Mutex<PinStats>>,
+) {
+    loop {
+        match refresh_pins(&store, &cache, &fetcher).await {
+            Ok(current) => *stats.lock() = current,
+            Err(e) => warn!("Failed to refresh pinned files: {}", e),
+        }
+        let _ = tokio::time::timeout(REFRESH_INTERVAL, notify.notified()).await;
+    }
+}
+
+/// Pin the current chunks in the cache and download missing ones
+async fn refresh_pins(store: &MetadataStore, cache: &ChunkCache, fetcher: &ChunkFetcher) -> Result<PinStats> {
+    let pinned = resolve_pins(store)?;
+    for ino in &pinned.stale {
+        set_pinned(store, *ino, false)?;
+    }
+    cache.set_pinned(pinned.chunks.iter().map(|c| c.id.clone()).collect());
+
+    let mut stats = pinned.stats;
+    stats.roots -= pinned.stale.len();
+    for chunk in &pinned.chunks {
+        if !cache.contains(&chunk.id) {
+            if let Err(e) = fetcher.fetch(chunk).await {
+                debug!("Failed to fetch pinned chunk {}: {}", chunk.id, e);
+                continue;
+            }
+        }
+        stats.cached_bytes += chunk.original_size;
+    }
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::testing::{file_with_chunks, project_tree};
+
+    #[test]
+    fn test_resolve_pinned_tree() {
+        let store = project_tree();
+        store.save_inode(&file_with_chunks(6, 1, &["d"])).unwrap();
+
+        set_pinned(&store, 2, true).unwrap();
+        set_pinned(&store, 4, true).unwrap();
+        set_pinned(&store, 99, true).unwrap();
+
+        let pinned = resolve_pins(&store).unwrap();
+        let mut ids: Vec<_> = pinned.chunks.iter().map(|c| c.id.as_str()).collect();
+        ids.sort();
+        assert_eq!(ids, ["a", "b", "c"]);
+        assert_eq!(pinned.stats.files, 2);
+        assert_eq!(pinned.stats.bytes, 40);
+        assert_eq!(pinned.stale, [99]);
+
+        set_pinned(&store, 2, false).unwrap();
+        assert_eq!(load_pins(&store).unwrap().into_iter().collect::<Vec<_>>(), [4, 99]);
+    }
+
+    #[test]
+    fn test_concurrent_pins_kept() {
+        let store = Arc::new(MetadataStore::in_memory([1u8; 32]).unwrap());
+        let threads: Vec<_> = (0..8u64)
+            .map(|t| {
+                let store = store.clone();
+                std::thread::spawn(move || {
+                    for i in 0..25 {
+                        set_pinned(&store, t * 100 + i, true).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(load_pins(&store).unwrap().len(), 200);
+    }
+}
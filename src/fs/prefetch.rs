@@ -0,0 +1,375 @@
+//! Chunk fetching and background prefetch
+//!
+//! Sequential reads queue the next chunks of a file's manifest on the
+//! cache's prefetch queue. A fixed pool of tasks on the filesystem's
+//! runtime drains the queue, downloading, verifying and caching each chunk
+//! so that playback does not stall at every chunk boundary. Random reads
+//! of uncached chunks download only the segments they need instead.
+
+use crate::cache::ChunkCache;
+use crate::chunk::{decompress, ChunkManifest, ChunkRef, PENDING_MESSAGE_ID};
+use crate::crypto::{
+    chunk_aad, decrypt_bound, decrypt_segmented, decrypt_segments, EncryptedData, KeyManager,
+    may_be_segmented, SegmentedHeader, KEY_SIZE, MAX_SEGMENTED_HEADER_SIZE,
+};
+use crate::error::{Error, Result};
+use crate::fs::offline::Connectivity;
+use crate::metadata::MetadataStore;
+use crate::telegram::TelegramBackend;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+use tokio::sync::Notify;
+use tracing::{debug, warn};
+
+/// Backend download counters
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DownloadStats {
+    /// Chunks downloaded whole
+    pub chunks: u64,
+    /// Random reads served by downloading part of a chunk
+    pub ranges: u64,
+    /// Bytes received from the backend
+    pub bytes: u64,
+}
+
+/// Downloads, decrypts and caches chunks
+///
+/// Concurrent fetches of the same chunk are coalesced: a read that needs a
+/// chunk the prefetcher is already downloading waits for it instead of
+/// downloading it again. While offline, chunks that are neither cached nor
+/// queued for upload fail immediately with `Error::Offline`.
+pub struct ChunkFetcher {
+    keys: Arc<KeyManager>,
+    telegram: Arc<TelegramBackend>,
+    cache: Arc<ChunkCache>,
+    /// Resolves chunks uploaded after their manifest was written
+    store: Arc<MetadataStore>,
+    /// Backend reachability and the offline upload queue
+    connectivity: Arc<Connectivity>,
+    /// Per-chunk locks of fetches in progress
+    inflight: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+    /// Chunks downloaded whole
+    downloads: AtomicU64,
+    /// Ranged downloads
+    range_downloads: AtomicU64,
+    /// Bytes received from the backend
+    downloaded_bytes: AtomicU64,
+    /// Reject chunks sealed without AAD binding
+    require_bound: bool,
+}
+
+impl ChunkFetcher {
+    /// Create a fetcher
+    pub fn new(
+        keys: Arc<KeyManager>,
+        telegram: Arc<TelegramBackend>,
+        cache: Arc<ChunkCache>,
+        store: Arc<MetadataStore>,
+        connectivity: Arc<Connectivity>,
+    ) -> Self {
+        ChunkFetcher {
+            keys,
+            telegram,
+            cache,
+            store,
+            connectivity,
+            inflight: Mutex::new(HashMap::new()),
+            downloads: AtomicU64::new(0),
+            range_downloads: AtomicU64::new(0),
+            downloaded_bytes: AtomicU64::new(0),
+            require_bound: false,
+        }
+    }
+
+    /// Reject chunks sealed without AAD binding and legacy headerless chunks
+    pub fn with_require_bound(mut self, require_bound: bool) -> Self {
+        self.require_bound = require_bound;
+        self
+    }
+
+    /// Download counters since the mount started
+    pub fn stats(&self) -> DownloadStats {
+        DownloadStats {
+            chunks: self.downloads.load(Ordering::Relaxed),
+            ranges: self.range_downloads.load(Ordering::Relaxed),
+            bytes: self.downloaded_bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Get a chunk's data from the cache, downloading it on a miss
+    pub async fn fetch(&self, chunk_ref: &ChunkRef) -> Result<Vec<u8>> {
+        self.fetch_with(chunk_ref, || self.cache.get(&chunk_ref.id), self.download(chunk_ref))
+            .await
+    }
+
+    /// Get part of a chunk's data, downloading the chunk on a miss
+    ///
+    /// A cached chunk is read page by page rather than loaded whole.
+    pub async fn fetch_range(&self, chunk_ref: &ChunkRef, offset: u64, len: usize) -> Result<Vec<u8>> {
+        self.fetch_with(
+            chunk_ref,
+            || self.cache.read(&chunk_ref.id, offset, len),
+            async { Ok(slice(self.download(chunk_ref).await?, offset, len)) },
+        )
+        .await
+    }
+
+    /// Get part of a chunk's data for a random read
+    ///
+    /// On a miss only the segments covering the range are downloaded, and
+    /// the chunk is not cached. Chunks that are compressed or sealed whole
+    /// are downloaded and cached as by [`fetch_range`](Self::fetch_range).
+    pub async fn fetch_random(&self, chunk_ref: &ChunkRef, offset: u64, len: usize) -> Result<Vec<u8>> {
+        self.fetch_with(
+            chunk_ref,
+            || self.cache.read(&chunk_ref.id, offset, len),
+            self.download_range(chunk_ref, offset, len),
+        )
+        .await
+    }
+
+    /// Serve a chunk from the cache, or run `download` on a miss
+    async fn fetch_with(
+        &self,
+        chunk_ref: &ChunkRef,
+        cached: impl FnOnce() -> Result<Option<Vec<u8>>>,
+        download: impl Future<Output = Result<Vec<u8>>>,
+    ) -> Result<Vec<u8>> {
+        let lock = self
+            .inflight
+            .lock()
+            .entry(chunk_ref.id.clone())
+            .or_default()
+            .clone();
+
+        let result = {
+            let _guard = lock.lock().await;
+            match cached() {
+                Ok(Some(data)) => Ok(data),
+                Ok(None) => download.await,
+                Err(e) => Err(e),
+            }
+        };
+
+        // The map and this function hold the only references once nobody waits
+        let mut inflight = self.inflight.lock();
+        if Arc::strong_count(&lock) == 2 {
+            inflight.remove(&chunk_ref.id);
+        }
+        result
+    }
+
+    /// Download (or read from the upload queue), decrypt, verify and cache a chunk
+    async fn download(&self, chunk_ref: &ChunkRef) -> Result<Vec<u8>> {
+        let encrypted_bytes = match self.connectivity.queue().read(&chunk_ref.id)? {
+            Some(spooled) => spooled,
+            None => {
+                if !self.connectivity.is_online() {
+                    return Err(Error::Offline(format!("chunk {} is not cached", chunk_ref.id)));
+                }
+                let message_id = self.message_id(chunk_ref)?;
+                self.downloads.fetch_add(1, Ordering::Relaxed);
+                self.remote(self.telegram.download_chunk(message_id)).await?
+            }
+        };
+
+        let decrypted = self.decrypt(chunk_ref, &encrypted_bytes)?;
+        let data = if chunk_ref.compressed {
+            decompress(&decrypted)?
+        } else {
+            decrypted
+        };
+
+        // Chunk IDs are the BLAKE3 hash of the plaintext
+        let hash = blake3::hash(&data).to_hex().to_string();
+        if hash != chunk_ref.id {
+            return Err(Error::ChunkVerificationFailed {
+                expected: chunk_ref.id.clone(),
+                got: hash,
+            });
+        }
+
+        // The chunk is already in hand, so failing to cache it must not fail the read
+        if let Err(e) = self.cache.put(&chunk_ref.id, &data) {
+            warn!("Failed to cache chunk {}: {}", chunk_ref.id, e);
+        }
+        Ok(data)
+    }
+
+    /// Download and decrypt only the segments of a chunk covering a range
+    ///
+    /// The segments are authenticated by the chunk key and AAD, both bound
+    /// to the chunk ID, in place of the whole-chunk hash check. Chunks whose
+    /// size shows they were sealed whole are downloaded without probing.
+    async fn download_range(&self, chunk_ref: &ChunkRef, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let ranged = !chunk_ref.compressed
+            && may_be_segmented(chunk_ref.size, chunk_ref.original_size)
+            && self.connectivity.is_online();
+        let message_id = match self.message_id(chunk_ref) {
+            Ok(message_id) if ranged => message_id,
+            _ => return Ok(slice(self.download(chunk_ref).await?, offset, len)),
+        };
+
+        let head = self
+            .remote(self.telegram.download_chunk_range(message_id, 0, MAX_SEGMENTED_HEADER_SIZE as u64))
+            .await?;
+        let header = match SegmentedHeader::parse(&head) {
+            Some(header) if header.sealed_size(chunk_ref.original_size) == chunk_ref.size => header,
+            _ => return Ok(slice(self.download(chunk_ref).await?, offset, len)),
+        };
+        let Some(range) = header.segment_range(chunk_ref.original_size, offset, len as u64) else {
+            return Ok(Vec::new());
+        };
+
+        self.range_downloads.fetch_add(1, Ordering::Relaxed);
+        let sealed = self
+            .remote(self.telegram.download_chunk_range(message_id, range.start, range.end - range.start))
+            .await?;
+        let chunk_key = self.keys.chunk_key(&chunk_ref.id)?;
+        let aad = chunk_aad(self.store.namespace_prefix(), &chunk_ref.id);
+        let plaintext = decrypt_segments(
+            chunk_key.key(),
+            &header,
+            &aad,
+            range.first,
+            &sealed,
+            chunk_ref.original_size,
+        )?;
+        debug!(
+            "Read {} bytes of chunk {} from {} downloaded",
+            len,
+            chunk_ref.id,
+            sealed.len()
+        );
+        Ok(slice(plaintext, range.skip as u64, len))
+    }
+
+    /// Decrypt a downloaded chunk, sealed whole or in segments
+    fn decrypt(&self, chunk_ref: &ChunkRef, encrypted_bytes: &[u8]) -> Result<Vec<u8>> {
+        let chunk_key = self.keys.chunk_key(&chunk_ref.id)?;
+        let aad = chunk_aad(self.store.namespace_prefix(), &chunk_ref.id);
+        let segmented = SegmentedHeader::parse(encrypted_bytes)
+            .map(|_| decrypt_segmented(chunk_key.key(), encrypted_bytes, &aad));
+        match segmented {
+            Some(Ok(data)) => Ok(data),
+            // A blob sealed whole can start like a segmented header by chance
+            Some(Err(e)) => self.decrypt_whole(chunk_key.key(), encrypted_bytes, &aad).map_err(|_| e),
+            None => self.decrypt_whole(chunk_key.key(), encrypted_bytes, &aad),
+        }
+    }
+
+    /// Decrypt a chunk sealed as a single envelope
+    fn decrypt_whole(&self, key: &[u8; KEY_SIZE], encrypted_bytes: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let encrypted = EncryptedData::from_bytes(encrypted_bytes)?;
+        // Chunks uploaded before AAD binding were sealed with empty AAD; they
+        // stay readable because each chunk key is already derived from its ID,
+        // unless the volume requires binding
+        let unbound_aad: Option<&[u8]> = if self.require_bound { None } else { Some(&[]) };
+        decrypt_bound(key, &encrypted, aad, unbound_aad)
+    }
+
+    /// Run a backend request, going offline if the backend is unreachable
+    async fn remote(&self, request: impl Future<Output = Result<Vec<u8>>>) -> Result<Vec<u8>> {
+        request
+            .await
+            .inspect(|data| {
+                self.downloaded_bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+            })
+            .inspect_err(|e| {
+                if e.is_connectivity() {
+                    self.connectivity.set_offline(e);
+                }
+            })
+    }
+
+    /// Message holding a chunk
+    ///
+    /// Manifests written offline name the chunk's message only once the
+    /// upload queue has been drained; the reference table knows it sooner.
+    fn message_id(&self, chunk_ref: &ChunkRef) -> Result<i32> {
+        if !chunk_ref.is_pending() {
+            return Ok(chunk_ref.message_id);
+        }
+        self.store
+            .get_chunk_ref(&chunk_ref.id)?
+            .filter(|&message_id| message_id != PENDING_MESSAGE_ID)
+            .ok_or_else(|| Error::ChunkNotFound(chunk_ref.id.clone()))
+    }
+}
+
+/// `len` bytes of `data` from `offset`, or fewer at its end
+fn slice(mut data: Vec<u8>, offset: u64, len: usize) -> Vec<u8> {
+    let end = data.len().min(offset.saturating_add(len as u64) as usize);
+    data.truncate(end);
+    data.split_off((offset as usize).min(end))
+}
+
+/// Pool of background prefetch workers
+pub struct Prefetcher {
+    cache: Arc<ChunkCache>,
+    /// Wakes idle workers when chunks are queued
+    notify: Arc<Notify>,
+    /// Chunks to read ahead of a sequential reader
+    readahead: usize,
+}
+
+impl Prefetcher {
+    /// Spawn `workers` tasks on the runtime
+    ///
+    /// The tasks run until the runtime shuts down.
+    pub fn start(runtime: &Runtime, fetcher: Arc<ChunkFetcher>, workers: usize, readahead: usize) -> Self {
+        let notify = Arc::new(Notify::new());
+        for _ in 0..workers {
+            runtime.spawn(worker(fetcher.clone(), notify.clone()));
+        }
+        Prefetcher {
+            cache: fetcher.cache.clone(),
+            notify,
+            readahead: if workers == 0 { 0 } else { readahead },
+        }
+    }
+
+    /// Queue the chunks following chunk `index` of a manifest
+    pub fn read_ahead(&self, manifest: &ChunkManifest, index: usize) {
+        let next: Vec<ChunkRef> = manifest
+            .chunks
+            .iter()
+            .skip(index + 1)
+            .take(self.readahead)
+            .filter(|chunk| !self.cache.contains(&chunk.id))
+            .cloned()
+            .collect();
+        if next.is_empty() {
+            return;
+        }
+
+        let queued = self.cache.queue_prefetch(next);
+        for _ in 0..queued {
+            self.notify.notify_one();
+        }
+    }
+}
+
+/// Drain the prefetch queue, then sleep until more chunks are queued
+async fn worker(fetcher: Arc<ChunkFetcher>, notify: Arc<Notify>) {
+    loop {
+        while let Some(chunk_ref) = fetcher.cache.next_prefetch() {
+            if fetcher.cache.contains(&chunk_ref.id) {
+                continue;
+            }
+            match fetcher.fetch(&chunk_ref).await {
+                Ok(_) => {
+                    fetcher.cache.mark_prefetched(&chunk_ref.id);
+                    debug!("Prefetched chunk {}", chunk_ref.id);
+                }
+                Err(e) => debug!("Prefetch of chunk {} failed: {}", chunk_ref.id, e),
+            }
+        }
+        notify.notified().await;
+    }
+}
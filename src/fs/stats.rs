@@ -0,0 +1,181 @@
+//! Runtime statistics published by a mounted filesystem
+//!
+//! The daemon periodically writes a small JSON file to the data directory
+//! so `tgcryptfs status` and `tgcryptfs cache` can report live counters
+//! without attaching to the (locked) metadata database.
+
+use crate::cache::CacheStats;
+use crate::error::Result;
+use crate::fs::offline::ConnectivityStats;
+use crate::fs::pin::PinStats;
+use crate::fs::prefetch::DownloadStats;
+use crate::metadata::InodeCacheStats;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// File name of the statistics file in the data directory
+pub const STATS_FILE: &str = "daemon_stats.json";
+
+/// Snapshot of a running daemon's counters
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DaemonStats {
+    /// Process ID of the daemon
+    pub pid: u32,
+    /// Start time of the daemon process, so a reused pid is not mistaken
+    /// for it (None where the platform does not report it)
+    pub started: Option<u64>,
+    /// When the snapshot was taken (seconds since the Unix epoch)
+    pub updated: u64,
+    /// Metadata inode cache
+    pub inode_cache: InodeCacheStats,
+    /// Pinned files
+    #[serde(default)]
+    pub pins: PinStats,
+    /// Backend reachability and queued offline writes
+    #[serde(default)]
+    pub connectivity: ConnectivityStats,
+    /// Chunk cache
+    #[serde(default)]
+    pub cache: Option<CacheStats>,
+    /// Backend downloads
+    #[serde(default)]
+    pub downloads: DownloadStats,
+}
+
+impl DaemonStats {
+    /// Snapshot the current process's counters
+    pub fn new(inode_cache: InodeCacheStats) -> Self {
+        DaemonStats {
+            pid: std::process::id(),
+            started: process_start(std::process::id()),
+            updated: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            inode_cache,
+            pins: PinStats::default(),
+            connectivity: ConnectivityStats::default(),
+            cache: None,
+            downloads: DownloadStats::default(),
+        }
+    }
+
+    /// Include pinned file statistics
+    pub fn with_pins(mut self, pins: PinStats) -> Self {
+        self.pins = pins;
+        self
+    }
+
+    /// Include the connection state
+    pub fn with_connectivity(mut self, connectivity: ConnectivityStats) -> Self {
+        self.connectivity = connectivity;
+        self
+    }
+
+    /// Include chunk cache and download statistics
+    pub fn with_cache(mut self, cache: CacheStats, downloads: DownloadStats) -> Self {
+        self.cache = Some(cache);
+        self.downloads = downloads;
+        self
+    }
+
+    /// Seconds since the snapshot was taken
+    pub fn age_secs(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs().saturating_sub(self.updated))
+            .unwrap_or(0)
+    }
+
+    /// Whether the daemon that wrote the snapshot is still running
+    ///
+    /// A daemon that exited (or crashed) leaves its last snapshot behind,
+    /// and its pid may since have been given to another process.
+    pub fn is_running(&self) -> bool {
+        if let Some(started) = self.started {
+            return process_start(self.pid) == Some(started);
+        }
+        let Ok(pid) = i32::try_from(self.pid) else {
+            return false;
+        };
+        pid > 0
+            && (unsafe { libc::kill(pid, 0) } == 0
+                || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM))
+    }
+
+    /// Write atomically (temp file + rename)
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Load the last snapshot, if a daemon has written one
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        match std::fs::read(path) {
+            Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Start time of a process in clock ticks since boot
+///
+/// Read from `/proc/<pid>/stat`, so only available on Linux.
+fn process_start(pid: u32) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command name may contain spaces; fields resume after its ')'
+    let fields = &stat[stat.rfind(')')? + 1..];
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_stats_roundtrip() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join(STATS_FILE);
+        assert!(DaemonStats::load(&path).unwrap().is_none());
+
+        let stats = DaemonStats::new(InodeCacheStats {
+            hits: 3,
+            misses: 1,
+            ..Default::default()
+        })
+        .with_pins(PinStats {
+            roots: 1,
+            bytes: 10,
+            ..Default::default()
+        });
+        stats.save(&path).unwrap();
+
+        let loaded = DaemonStats::load(&path).unwrap().unwrap();
+        assert_eq!(loaded.pid, std::process::id());
+        assert_eq!(loaded.inode_cache, stats.inode_cache);
+        assert_eq!(loaded.pins, stats.pins);
+        assert!(loaded.is_running());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_exited_daemon() {
+        let mut stats = DaemonStats::new(InodeCacheStats::default());
+        assert!(stats.started.is_some());
+
+        // A live process with another start time reused the daemon's pid
+        stats.started = stats.started.map(|started| started + 1);
+        assert!(!stats.is_running());
+
+        let mut child = std::process::Command::new("true").spawn().unwrap();
+        stats.pid = child.id();
+        stats.started = process_start(child.id());
+        child.wait().unwrap();
+        assert!(!stats.is_running());
+    }
+}
@@ -0,0 +1,76 @@
+//! Fixtures shared by the filesystem tests
+
+use std::path::Path;
+
+use crate::cache::ChunkCache;
+use crate::chunk::{ChunkManifest, ChunkRef};
+use crate::config::Config;
+use crate::crypto::{KeyManager, MasterKey};
+use crate::fs::TgCryptFs;
+use crate::metadata::{Inode, MetadataStore};
+use crate::telegram::TelegramBackend;
+
+/// A 10-byte chunk stored in the given message
+pub fn chunk_ref(id: &str, message_id: i32) -> ChunkRef {
+    ChunkRef {
+        id: id.to_string(),
+        size: 10,
+        message_id,
+        offset: 0,
+        original_size: 10,
+        compressed: false,
+    }
+}
+
+/// A file made of 10-byte chunks with the given IDs
+pub fn file_with_chunks(ino: u64, parent: u64, ids: &[&str]) -> Inode {
+    let chunks: Vec<_> = ids.iter().map(|id| (*id, 1)).collect();
+    file_with_chunk_messages(ino, parent, &chunks)
+}
+
+/// A file made of 10-byte chunks with the given IDs and message IDs
+pub fn file_with_chunk_messages(ino: u64, parent: u64, chunks: &[(&str, i32)]) -> Inode {
+    let mut inode = Inode::new_file(ino, parent, format!("f{}", ino), 0, 0, 0o644);
+    let mut manifest = ChunkManifest::new(1);
+    for (id, message_id) in chunks {
+        manifest.chunks.push(chunk_ref(id, *message_id));
+        manifest.total_size += 10;
+    }
+    inode.manifest = Some(manifest);
+    inode
+}
+
+/// A store holding `project/` (inode 2) with file 5 (chunks b, c) and
+/// `project/src/` (inode 3) with file 4 (chunks a, b)
+pub fn project_tree() -> MetadataStore {
+    let store = MetadataStore::in_memory([1u8; 32]).unwrap();
+    store
+        .save_inode(&Inode::new_directory(2, 1, "project".to_string(), 0, 0, 0o755))
+        .unwrap();
+    store
+        .save_inode(&Inode::new_directory(3, 2, "src".to_string(), 0, 0, 0o755))
+        .unwrap();
+    store.save_inode(&file_with_chunks(4, 3, &["a", "b"])).unwrap();
+    store.save_inode(&file_with_chunks(5, 2, &["b", "c"])).unwrap();
+    store
+}
+
+/// A filesystem over `store` keeping its local state under `dir`
+///
+/// The backend is never connected; only metadata operations work.
+pub fn test_fs(dir: &Path, store: MetadataStore) -> TgCryptFs {
+    let mut config = Config {
+        data_dir: dir.to_path_buf(),
+        ..Config::default()
+    };
+    config.cache.cache_dir = dir.join("cache");
+    config.encryption.argon2_memory_kib = 1024;
+    config.encryption.argon2_iterations = 1;
+    config.encryption.argon2_parallelism = 1;
+
+    let master = MasterKey::from_password(b"password", &config.encryption).unwrap();
+    let keys = KeyManager::new(master).unwrap();
+    let cache = ChunkCache::new(&config.cache).unwrap();
+    let telegram = TelegramBackend::new(config.telegram.clone());
+    TgCryptFs::new(config, keys, store, telegram, cache).unwrap()
+}
@@ -0,0 +1,415 @@
+//! Cache warmup and access history
+//!
+//! Setting the `user.tgcryptfs.warm` xattr on a file or directory (which
+//! `tgcryptfs cache warm` does through the mount) starts a job that
+//! downloads its chunks into the cache through the mount's chunk fetcher;
+//! reading the xattr back reports the job's progress. Files are selected by
+//! the job itself, so setting the xattr returns at once even for a large
+//! tree.
+//!
+//! With access history enabled, the mount also remembers which files were
+//! opened most recently. The list is kept encrypted in the metadata store,
+//! which journals it to the cloud and includes it in metadata exports, so a
+//! warmup of the recent files can restore a useful cache after it was
+//! cleared or on a machine recovered from the journal.
+
+use crate::cache::ChunkCache;
+use crate::chunk::ChunkRef;
+use crate::error::Result;
+use crate::fs::prefetch::ChunkFetcher;
+use crate::metadata::MetadataStore;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::{Handle, Runtime};
+use tokio::task::JoinSet;
+use tracing::{debug, info, warn};
+
+/// Xattr that starts a warmup (set) or reports its progress (get)
+pub const WARM_XATTR: &str = "user.tgcryptfs.warm";
+
+/// Metadata key holding the access history
+const HISTORY_KEY: &str = "access_history";
+
+/// How often a changed access history is saved
+const HISTORY_FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What to warm, written to the warm xattr as JSON
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WarmRequest {
+    /// Include subdirectories (otherwise only a directory's own files)
+    #[serde(default)]
+    pub recursive: bool,
+    /// Stop adding files once this many bytes are selected
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+    /// Warm the most recently opened files instead of the target
+    #[serde(default)]
+    pub recent: bool,
+}
+
+/// Progress of a warmup, read from the warm xattr as JSON
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WarmProgress {
+    /// Files selected
+    pub files: usize,
+    /// Distinct chunks of the selected files
+    pub chunks: usize,
+    /// Size of those chunks
+    pub bytes: u64,
+    /// Chunks now cached
+    pub cached_chunks: usize,
+    /// Bytes now cached
+    pub cached_bytes: u64,
+    /// Chunks that could not be downloaded
+    pub failed_chunks: usize,
+    /// Every chunk has been cached or has failed
+    pub done: bool,
+    /// Why the files could not be selected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Files and chunks selected for a warmup
+pub(super) struct Selection {
+    pub(super) files: usize,
+    pub(super) chunks: Vec<ChunkRef>,
+    pub(super) bytes: u64,
+}
+
+/// Most recently opened files, most recent first
+pub struct AccessHistory {
+    /// Entries kept
+    capacity: usize,
+    /// Inode numbers
+    entries: Mutex<VecDeque<u64>>,
+    /// Changed since last saved
+    dirty: AtomicBool,
+}
+
+impl AccessHistory {
+    /// Load the history saved in the store
+    pub fn load(store: &MetadataStore, capacity: usize) -> Result<Self> {
+        let mut entries: VecDeque<u64> = match store.get_metadata(HISTORY_KEY)? {
+            Some(data) => bincode::deserialize::<Vec<u64>>(&data)?.into(),
+            None => VecDeque::new(),
+        };
+        entries.truncate(capacity);
+        Ok(AccessHistory {
+            capacity,
+            entries: Mutex::new(entries),
+            dirty: AtomicBool::new(false),
+        })
+    }
+
+    /// Record that a file was opened
+    pub fn record(&self, ino: u64) {
+        let mut entries = self.entries.lock();
+        if entries.front() == Some(&ino) {
+            return;
+        }
+        entries.retain(|&e| e != ino);
+        entries.push_front(ino);
+        entries.truncate(self.capacity);
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Recorded files, most recent first
+    pub fn recent(&self) -> Vec<u64> {
+        self.entries.lock().iter().copied().collect()
+    }
+
+    /// Save the history if it changed
+    pub fn flush(&self, store: &MetadataStore) -> Result<()> {
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let entries = self.recent();
+        store
+            .save_metadata(HISTORY_KEY, &bincode::serialize(&entries)?)
+            .inspect_err(|_| self.dirty.store(true, Ordering::Relaxed))
+    }
+}
+
+/// Runs warmup jobs and keeps the access history
+pub struct Warmer {
+    store: Arc<MetadataStore>,
+    cache: Arc<ChunkCache>,
+    fetcher: Arc<ChunkFetcher>,
+    /// Runtime the jobs run on
+    runtime: Handle,
+    /// Concurrent downloads per job
+    workers: usize,
+    /// Recently opened files (when enabled)
+    history: Option<Arc<AccessHistory>>,
+    /// Progress of the latest job per target inode
+    jobs: Mutex<HashMap<u64, Arc<Mutex<WarmProgress>>>>,
+}
+
+impl Warmer {
+    /// Create the warmer, keeping `history_size` recently opened files
+    ///
+    /// The history is saved periodically by a task on the runtime.
+    pub fn start(
+        runtime: &Runtime,
+        store: Arc<MetadataStore>,
+        cache: Arc<ChunkCache>,
+        fetcher: Arc<ChunkFetcher>,
+        workers: usize,
+        history_size: usize,
+    ) -> Result<Self> {
+        let history = if history_size > 0 {
+            let history = Arc::new(AccessHistory::load(&store, history_size)?);
+            runtime.spawn(save_history(store.clone(), history.clone()));
+            Some(history)
+        } else {
+            None
+        };
+        Ok(Warmer {
+            store,
+            cache,
+            fetcher,
+            runtime: runtime.handle().clone(),
+            workers: workers.max(1),
+            history,
+            jobs: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Start warming the tree at `ino`
+    ///
+    /// Returns once the job is started; files are selected by the job, and
+    /// its progress reports no files until then. A job already running for
+    /// the same inode keeps running; its progress is replaced by the new
+    /// job's.
+    pub fn warm(&self, ino: u64, request: &WarmRequest) {
+        let roots = if request.recent {
+            self.history
+                .as_ref()
+                .map(|h| h.recent())
+                .unwrap_or_default()
+        } else {
+            vec![ino]
+        };
+        let progress = Arc::new(Mutex::new(WarmProgress::default()));
+        self.jobs.lock().insert(ino, progress.clone());
+
+        let store = self.store.clone();
+        let cache = self.cache.clone();
+        let fetcher = self.fetcher.clone();
+        let workers = self.workers;
+        let request = request.clone();
+        self.runtime.spawn(async move {
+            let selected =
+                tokio::task::spawn_blocking(move || select(&store, &roots, &request)).await;
+            let selection = match selected {
+                Ok(Ok(selection)) => selection,
+                Ok(Err(e)) => return fail_job(&progress, ino, e.to_string()),
+                Err(e) => return fail_job(&progress, ino, e.to_string()),
+            };
+            info!(
+                "Warming {} files ({} chunks, {} bytes) from inode {}",
+                selection.files,
+                selection.chunks.len(),
+                selection.bytes,
+                ino
+            );
+            {
+                let mut progress = progress.lock();
+                progress.files = selection.files;
+                progress.chunks = selection.chunks.len();
+                progress.bytes = selection.bytes;
+            }
+            run_job(cache, fetcher, selection.chunks, workers, progress).await;
+        });
+    }
+
+    /// Progress of the latest job started on `ino`
+    pub fn progress(&self, ino: u64) -> Option<WarmProgress> {
+        self.jobs.lock().get(&ino).map(|p| p.lock().clone())
+    }
+
+    /// Record that a file was opened
+    pub fn record_access(&self, ino: u64) {
+        if let Some(history) = &self.history {
+            history.record(ino);
+        }
+    }
+
+    /// Save the access history now
+    pub fn flush_history(&self) {
+        if let Some(history) = &self.history {
+            if let Err(e) = history.flush(&self.store) {
+                warn!("Failed to save access history: {}", e);
+            }
+        }
+    }
+}
+
+/// Collect the chunks of the files under `roots`, in order, within the byte budget
+///
+/// Files that do not fit in the remaining budget are skipped.
+pub(super) fn select(
+    store: &MetadataStore,
+    roots: &[u64],
+    request: &WarmRequest,
+) -> Result<Selection> {
+    let budget = request.max_bytes.unwrap_or(u64::MAX);
+    let mut selection = Selection {
+        files: 0,
+        chunks: Vec::new(),
+        bytes: 0,
+    };
+    let mut seen_chunks = HashSet::new();
+
+    // Breadth first, so a budget is spent on the shallowest files
+    store.walk_trees(roots, |inode, root| {
+        if inode.is_dir() {
+            return root || request.recursive;
+        }
+        let Some(manifest) = inode.manifest else {
+            return false;
+        };
+        let new: Vec<ChunkRef> = manifest
+            .chunks
+            .into_iter()
+            .filter(|c| !seen_chunks.contains(&c.id))
+            .collect();
+        let size: u64 = new.iter().map(|c| c.original_size).sum();
+        if selection.bytes + size > budget {
+            return false;
+        }
+        selection.files += 1;
+        selection.bytes += size;
+        for chunk in new {
+            if seen_chunks.insert(chunk.id.clone()) {
+                selection.chunks.push(chunk);
+            }
+        }
+        false
+    })?;
+    Ok(selection)
+}
+
+/// End a job whose files could not be selected
+fn fail_job(progress: &Mutex<WarmProgress>, ino: u64, error: String) {
+    warn!(
+        "Failed to select files to warm from inode {}: {}",
+        ino, error
+    );
+    let mut progress = progress.lock();
+    progress.error = Some(error);
+    progress.done = true;
+}
+
+/// Download the chunks not cached yet, `workers` at a time
+async fn run_job(
+    cache: Arc<ChunkCache>,
+    fetcher: Arc<ChunkFetcher>,
+    chunks: Vec<ChunkRef>,
+    workers: usize,
+    progress: Arc<Mutex<WarmProgress>>,
+) {
+    let mut pending = chunks.into_iter();
+    let mut running = JoinSet::new();
+    loop {
+        while running.len() < workers {
+            let Some(chunk) = pending.next() else { break };
+            if cache.contains(&chunk.id) {
+                let mut progress = progress.lock();
+                progress.cached_chunks += 1;
+                progress.cached_bytes += chunk.original_size;
+                continue;
+            }
+            let fetcher = fetcher.clone();
+            running.spawn(async move {
+                let result = fetcher.fetch(&chunk).await;
+                (chunk, result)
+            });
+        }
+
+        let Some(joined) = running.join_next().await else {
+            break;
+        };
+        let mut progress = progress.lock();
+        match joined {
+            Ok((chunk, Ok(_))) => {
+                progress.cached_chunks += 1;
+                progress.cached_bytes += chunk.original_size;
+            }
+            Ok((chunk, Err(e))) => {
+                debug!("Failed to warm chunk {}: {}", chunk.id, e);
+                progress.failed_chunks += 1;
+            }
+            Err(e) => {
+                warn!("Warmup task failed: {}", e);
+                progress.failed_chunks += 1;
+            }
+        }
+    }
+    progress.lock().done = true;
+}
+
+/// Save the access history whenever it changed
+async fn save_history(store: Arc<MetadataStore>, history: Arc<AccessHistory>) {
+    loop {
+        tokio::time::sleep(HISTORY_FLUSH_INTERVAL).await;
+        if let Err(e) = history.flush(&store) {
+            warn!("Failed to save access history: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::testing::project_tree;
+
+    fn ids(selection: &Selection) -> Vec<&str> {
+        let mut ids: Vec<_> = selection.chunks.iter().map(|c| c.id.as_str()).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn test_select_tree() {
+        let store = project_tree();
+
+        let shallow = select(&store, &[2], &WarmRequest::default()).unwrap();
+        assert_eq!((shallow.files, ids(&shallow)), (1, vec!["b", "c"]));
+
+        let request = WarmRequest {
+            recursive: true,
+            ..Default::default()
+        };
+        let deep = select(&store, &[2], &request).unwrap();
+        assert_eq!((deep.files, deep.bytes), (2, 30));
+        assert_eq!(ids(&deep), ["a", "b", "c"]);
+
+        // The shallower file is selected first and the next one does not fit
+        let request = WarmRequest {
+            recursive: true,
+            max_bytes: Some(25),
+            ..Default::default()
+        };
+        let budgeted = select(&store, &[2], &request).unwrap();
+        assert_eq!((budgeted.files, ids(&budgeted)), (1, vec!["b", "c"]));
+    }
+
+    #[test]
+    fn test_access_history() {
+        let store = MetadataStore::in_memory([1u8; 32]).unwrap();
+        let history = AccessHistory::load(&store, 3).unwrap();
+        for ino in [5, 6, 7, 5, 8] {
+            history.record(ino);
+        }
+        assert_eq!(history.recent(), [8, 5, 7]);
+
+        history.flush(&store).unwrap();
+        let reloaded = AccessHistory::load(&store, 2).unwrap();
+        assert_eq!(reloaded.recent(), [8, 5]);
+    }
+}
@@ -7,15 +7,21 @@
 //!   tgcryptfs status               - Show filesystem status
 //!   tgcryptfs snapshot <name>      - Create a snapshot
 
-use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use clap::{Args, Parser, Subcommand};
+use std::path::{Path, PathBuf};
 use tgcryptfs::{
-    cache::ChunkCache,
-    config::Config,
-    crypto::{KeyManager, MasterKey},
-    fs::{overlay::{OverlayConfig, OverlayFs}, TgCryptFs},
-    metadata::MetadataStore,
-    telegram::TelegramBackend,
+    cache::{CacheStats, ChunkCache, SharedDir},
+    config::{Config, EncryptionConfig},
+    crypto::{KeyManager, Keyfile, MasterKey, KEY_SIZE},
+    fs::{
+        offline, overlay::{OverlayConfig, OverlayFs}, inspect::{CachedFile, CACHED_XATTR, EVICT_XATTR}, pin::PIN_XATTR, warm::{WarmProgress, WarmRequest, WARM_XATTR}, DaemonStats, TgCryptFs,
+        STATS_FILE,
+    },
+    metadata::{
+        fetch_volume_descriptor, fsck, recover, CloudJournal, FsckProblem, HardLinkStore,
+        MetadataArchive, MetadataStore, VolumeDescriptor, ARCHIVE_VERSION,
+    },
+    telegram::{EncryptedSession, TelegramBackend},
     Error, Result,
 };
 use tracing::{error, info, warn, Level};
@@ -39,6 +45,14 @@ struct Cli {
     command: Commands,
 }
 
+/// Keyfile option of the commands that derive the master key
+#[derive(Args)]
+struct KeyfileArg {
+    /// Keyfile combined with the password (also looked up in $CREDENTIALS_DIRECTORY)
+    #[arg(long)]
+    keyfile: Option<PathBuf>,
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Initialize a new tgcryptfs
@@ -54,6 +68,10 @@ enum Commands {
         /// Phone number for authentication
         #[arg(long)]
         phone: Option<String>,
+
+        /// Generate a keyfile at this path and require it alongside the password
+        #[arg(long)]
+        generate_keyfile: Option<PathBuf>,
     },
 
     /// Authenticate with the cloud backend
@@ -69,6 +87,13 @@ enum Commands {
         /// 2FA password (if required)
         #[arg(long)]
         password: Option<String>,
+
+        /// Read encryption password from file
+        #[arg(long)]
+        password_file: Option<PathBuf>,
+
+        #[command(flatten)]
+        keyfile: KeyfileArg,
     },
 
     /// Mount the filesystem
@@ -84,10 +109,21 @@ enum Commands {
         #[arg(long)]
         allow_other: bool,
 
+        /// Have the kernel check permission bits (faster, but ignores ACLs)
+        #[arg(long)]
+        default_permissions: bool,
+
+        /// Honour POSIX ACLs (getfacl/setfacl)
+        #[arg(long)]
+        acl: bool,
+
         /// Read encryption password from file
         #[arg(long)]
         password_file: Option<PathBuf>,
 
+        #[command(flatten)]
+        keyfile: KeyfileArg,
+
         /// Enable overlay mode (lower layer read-only, writes go to upper layer)
         #[arg(long)]
         overlay: bool,
@@ -95,6 +131,10 @@ enum Commands {
         /// Lower layer path for overlay mode (defaults to home directory)
         #[arg(long)]
         lower_path: Option<PathBuf>,
+
+        /// Start offline without contacting the backend
+        #[arg(long)]
+        offline: bool,
     },
 
     /// Unmount the filesystem
@@ -103,8 +143,27 @@ enum Commands {
         mount_point: PathBuf,
     },
 
+    /// Keep a file or directory on the mount available offline
+    Pin {
+        /// Path inside the mounted filesystem
+        path: PathBuf,
+    },
+
+    /// Stop keeping a file or directory available offline
+    Unpin {
+        /// Path inside the mounted filesystem
+        path: PathBuf,
+    },
+
     /// Show filesystem status
-    Status,
+    Status {
+        /// Read encryption password from file (needed to check the connection)
+        #[arg(long)]
+        password_file: Option<PathBuf>,
+
+        #[command(flatten)]
+        keyfile: KeyfileArg,
+    },
 
     /// Create a snapshot
     Snapshot {
@@ -130,6 +189,9 @@ enum Commands {
         /// Clear the cache
         #[arg(long)]
         clear: bool,
+
+        #[command(subcommand)]
+        action: Option<CacheCommands>,
     },
 
     /// Sync local state with Telegram
@@ -137,6 +199,17 @@ enum Commands {
         /// Force full sync
         #[arg(long)]
         full: bool,
+
+        /// Accept a snapshot older than the highest epoch already seen
+        #[arg(long)]
+        allow_rollback: bool,
+
+        /// Read encryption password from file
+        #[arg(long)]
+        password_file: Option<PathBuf>,
+
+        #[command(flatten)]
+        keyfile: KeyfileArg,
     },
 
     /// Machine management
@@ -161,6 +234,9 @@ enum Commands {
         #[arg(long)]
         password_file: Option<PathBuf>,
 
+        #[command(flatten)]
+        keyfile: KeyfileArg,
+
         /// Perform a dry run (don't actually modify data)
         #[arg(long)]
         dry_run: bool,
@@ -170,11 +246,143 @@ enum Commands {
         force: bool,
     },
 
+    /// Rebind existing metadata ciphertexts to their object identity
+    MigrateAad {
+        /// Read encryption password from file
+        #[arg(long)]
+        password_file: Option<PathBuf>,
+
+        #[command(flatten)]
+        keyfile: KeyfileArg,
+
+        /// Perform a dry run (don't actually modify data)
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Rebuild the metadata database from the cloud journal
+    Recover {
+        /// Read encryption password from file
+        #[arg(long)]
+        password_file: Option<PathBuf>,
+
+        #[command(flatten)]
+        keyfile: KeyfileArg,
+
+        /// Replace an existing metadata database
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// Check the metadata database for inconsistencies (filesystem must be unmounted)
+    Fsck {
+        /// Read encryption password from file
+        #[arg(long)]
+        password_file: Option<PathBuf>,
+
+        #[command(flatten)]
+        keyfile: KeyfileArg,
+
+        /// Repair the problems that were found
+        #[arg(long)]
+        repair: bool,
+
+        /// Also check chunk messages in the cloud backend
+        #[arg(long)]
+        online: bool,
+
+        /// Delete unreferenced chunk uploads from the backend (requires --online --repair)
+        ///
+        /// The first run only lists them; a later run deletes those that
+        /// were listed and are still unreferenced. Refused when the account
+        /// is shared by namespaces or a cluster.
+        #[arg(long)]
+        delete_orphans: bool,
+    },
+
+    /// Export or import the metadata database (filesystem must be unmounted)
+    #[command(subcommand)]
+    Metadata(MetadataCommands),
+
     /// Time Machine backup management
     #[command(subcommand)]
     Timemachine(TimemachineCommands),
 }
 
+#[derive(Subcommand)]
+enum CacheCommands {
+    /// Download a mounted file or directory into the cache
+    Warm {
+        /// Path on a mounted tgcryptfs
+        path: PathBuf,
+
+        /// Include subdirectories
+        #[arg(short, long)]
+        recursive: bool,
+
+        /// Stop selecting files once this many bytes are selected
+        #[arg(long)]
+        max_bytes: Option<u64>,
+
+        /// Warm the most recently opened files of the mount at PATH instead
+        #[arg(long)]
+        recent: bool,
+    },
+
+    /// Drop a mounted file's or directory's chunks from the cache
+    Evict {
+        /// Path on a mounted tgcryptfs
+        path: PathBuf,
+    },
+
+    /// List cached files under a mounted path, most cached first
+    Ls {
+        /// Path on a mounted tgcryptfs
+        #[arg(default_value = ".")]
+        path: PathBuf,
+
+        /// Show at most this many files
+        #[arg(short = 'n', long)]
+        limit: Option<usize>,
+    },
+}
+
+#[derive(Subcommand)]
+enum MetadataCommands {
+    /// Write an encrypted, portable archive of all metadata
+    ///
+    /// Snapshots are not exported: their contents live in the cloud and the
+    /// archive only carries their index entries.
+    Export {
+        /// Archive file to write
+        output: PathBuf,
+
+        /// Read encryption password from file
+        #[arg(long)]
+        password_file: Option<PathBuf>,
+
+        #[command(flatten)]
+        keyfile: KeyfileArg,
+    },
+
+    /// Restore the metadata database from an archive
+    Import {
+        /// Archive file to read
+        input: PathBuf,
+
+        /// Read encryption password from file
+        #[arg(long)]
+        password_file: Option<PathBuf>,
+
+        #[command(flatten)]
+        keyfile: KeyfileArg,
+
+        /// Replace an existing metadata database
+        #[arg(long)]
+        force: bool,
+    },
+}
+
 #[derive(Subcommand)]
 enum MachineCommands {
     /// Initialize machine identity
@@ -241,7 +449,14 @@ enum ClusterCommands {
 #[derive(Subcommand)]
 enum RaidCommands {
     /// Show RAID array status
-    Status,
+    Status {
+        /// Read encryption password from file
+        #[arg(long)]
+        password_file: Option<PathBuf>,
+
+        #[command(flatten)]
+        keyfile: KeyfileArg,
+    },
 
     /// Rebuild data for a failed account
     Rebuild {
@@ -353,22 +568,52 @@ fn run_command(command: Commands, config_path: &PathBuf) -> Result<()> {
             api_id,
             api_hash,
             phone,
-        } => cmd_init(config_path, api_id, api_hash, phone),
+            generate_keyfile,
+        } => cmd_init(config_path, api_id, api_hash, phone, generate_keyfile),
 
-        Commands::Auth { phone, code, password } => cmd_auth(config_path, &phone, code, password),
+        Commands::Auth {
+            phone,
+            code,
+            password,
+            password_file,
+            keyfile: KeyfileArg { keyfile },
+        } => cmd_auth(config_path, &phone, code, password, password_file, keyfile),
 
         Commands::Mount {
             mount_point,
             foreground,
             allow_other,
+            default_permissions,
+            acl,
             password_file,
+            keyfile: KeyfileArg { keyfile },
             overlay,
             lower_path,
-        } => cmd_mount(config_path, &mount_point, foreground, allow_other, password_file, overlay, lower_path),
+            offline,
+        } => cmd_mount(
+            config_path,
+            &mount_point,
+            foreground,
+            allow_other,
+            default_permissions,
+            acl,
+            password_file,
+            keyfile,
+            overlay,
+            lower_path,
+            offline,
+        ),
 
         Commands::Unmount { mount_point } => cmd_unmount(&mount_point),
 
-        Commands::Status => cmd_status(config_path),
+        Commands::Pin { path } => cmd_pin(&path, true),
+
+        Commands::Unpin { path } => cmd_pin(&path, false),
+
+        Commands::Status {
+            password_file,
+            keyfile: KeyfileArg { keyfile },
+        } => cmd_status(config_path, password_file, keyfile),
 
         Commands::Snapshot { name, description } => cmd_snapshot(config_path, &name, description),
 
@@ -376,9 +621,31 @@ fn run_command(command: Commands, config_path: &PathBuf) -> Result<()> {
 
         Commands::Restore { snapshot } => cmd_restore(config_path, &snapshot),
 
-        Commands::Cache { clear } => cmd_cache(config_path, clear),
-
-        Commands::Sync { full } => cmd_sync(config_path, full),
+        Commands::Cache { clear, action } => match action {
+            Some(CacheCommands::Warm {
+                path,
+                recursive,
+                max_bytes,
+                recent,
+            }) => cmd_cache_warm(
+                &path,
+                &WarmRequest {
+                    recursive,
+                    max_bytes,
+                    recent,
+                },
+            ),
+            Some(CacheCommands::Evict { path }) => cmd_cache_evict(&path),
+            Some(CacheCommands::Ls { path, limit }) => cmd_cache_ls(&path, limit),
+            None => cmd_cache(config_path, clear),
+        },
+
+        Commands::Sync {
+            full,
+            allow_rollback,
+            password_file,
+            keyfile: KeyfileArg { keyfile },
+        } => cmd_sync(config_path, full, allow_rollback, password_file, keyfile),
 
         Commands::Machine(machine_cmd) => run_machine_command(machine_cmd, config_path),
 
@@ -390,9 +657,32 @@ fn run_command(command: Commands, config_path: &PathBuf) -> Result<()> {
 
         Commands::Migrate {
             password_file,
+            keyfile: KeyfileArg { keyfile },
+            dry_run,
+            force,
+        } => cmd_migrate(config_path, password_file, keyfile, dry_run, force),
+
+        Commands::MigrateAad {
+            password_file,
+            keyfile: KeyfileArg { keyfile },
             dry_run,
+        } => cmd_migrate_aad(config_path, password_file, keyfile, dry_run),
+
+        Commands::Recover {
+            password_file,
+            keyfile: KeyfileArg { keyfile },
             force,
-        } => cmd_migrate(config_path, password_file, dry_run, force),
+        } => cmd_recover(config_path, password_file, keyfile, force),
+
+        Commands::Fsck {
+            password_file,
+            keyfile: KeyfileArg { keyfile },
+            repair,
+            online,
+            delete_orphans,
+        } => cmd_fsck(config_path, password_file, keyfile, repair, online, delete_orphans),
+
+        Commands::Metadata(metadata_cmd) => run_metadata_command(metadata_cmd, config_path),
 
         Commands::Timemachine(tm_cmd) => run_timemachine_command(tm_cmd, config_path),
     }
@@ -428,7 +718,10 @@ fn run_cluster_command(command: ClusterCommands, config_path: &PathBuf) -> Resul
 
 fn run_raid_command(command: RaidCommands, config_path: &PathBuf) -> Result<()> {
     match command {
-        RaidCommands::Status => cmd_raid_status(config_path),
+        RaidCommands::Status {
+            password_file,
+            keyfile: KeyfileArg { keyfile },
+        } => cmd_raid_status(config_path, password_file, keyfile),
         RaidCommands::Rebuild { account_id } => cmd_raid_rebuild(config_path, account_id),
         RaidCommands::Scrub { repair } => cmd_raid_scrub(config_path, repair),
         RaidCommands::AddAccount {
@@ -448,6 +741,7 @@ fn cmd_init(
     api_id: i32,
     api_hash: String,
     phone: Option<String>,
+    generate_keyfile: Option<PathBuf>,
 ) -> Result<()> {
     info!("Initializing tgcryptfs...");
 
@@ -471,6 +765,13 @@ fn cmd_init(
 
     config.telegram.phone = phone;
 
+    // Generate a keyfile to be required alongside the password
+    if let Some(path) = generate_keyfile {
+        Keyfile::generate().write(&path)?;
+        info!("Keyfile written to {:?} - keep a backup, the volume cannot be unlocked without it", path);
+        config.encryption.keyfile = Some(path);
+    }
+
     // Ensure config directory exists
     if let Some(parent) = config_path.parent() {
         std::fs::create_dir_all(parent)?;
@@ -492,15 +793,80 @@ fn cmd_init(
     Ok(())
 }
 
-fn cmd_auth(config_path: &PathBuf, phone: &str, code_opt: Option<String>, password_opt: Option<String>) -> Result<()> {
-    let config = Config::load(config_path)?;
+/// Read the encryption password from a file or prompt for it
+fn read_password(password_file: Option<PathBuf>) -> Result<String> {
+    if let Some(path) = password_file {
+        Ok(std::fs::read_to_string(&path)
+            .map_err(|e| Error::Internal(format!("Failed to read password file: {}", e)))?
+            .trim()
+            .to_string())
+    } else {
+        rpassword::prompt_password("Enter encryption password: ")
+            .map_err(|e| Error::Internal(e.to_string()))
+    }
+}
+
+/// Derive the master key from a password and, if configured, a keyfile
+///
+/// An explicit `keyfile` overrides `encryption.keyfile` from the config.
+fn master_key_from_password(
+    encryption: &EncryptionConfig,
+    password: &str,
+    keyfile: Option<PathBuf>,
+) -> Result<MasterKey> {
+    let master_key = MasterKey::from_password(password.as_bytes(), encryption)?;
+    match keyfile.or_else(|| encryption.keyfile.clone()) {
+        Some(path) => master_key.with_keyfile(&Keyfile::load(&path)?),
+        None => Ok(master_key),
+    }
+}
+
+/// Read the password and derive the master key
+fn derive_master_key(
+    config: &Config,
+    password_file: Option<PathBuf>,
+    keyfile: Option<PathBuf>,
+) -> Result<MasterKey> {
+    let password = read_password(password_file)?;
+    master_key_from_password(&config.encryption, &password, keyfile)
+}
+
+/// Derive the Telegram session key from the encryption password
+///
+/// Saves the salt to the config when it is generated here.
+fn derive_session_key(
+    config: &mut Config,
+    config_path: &PathBuf,
+    password_file: Option<PathBuf>,
+    keyfile: Option<PathBuf>,
+) -> Result<[u8; KEY_SIZE]> {
+    let master_key = derive_master_key(config, password_file, keyfile)?;
+    if config.encryption.salt.is_empty() {
+        config.encryption.salt = master_key.salt().to_vec();
+        config.save(config_path)?;
+    }
+    master_key.session_key()
+}
+
+fn cmd_auth(
+    config_path: &PathBuf,
+    phone: &str,
+    code_opt: Option<String>,
+    password_opt: Option<String>,
+    password_file: Option<PathBuf>,
+    keyfile: Option<PathBuf>,
+) -> Result<()> {
+    let mut config = Config::load(config_path)?;
+
+    // The session is stored encrypted under a key derived from the volume password
+    let session_key = derive_session_key(&mut config, config_path, password_file, keyfile)?;
 
     info!("Authenticating with cloud backend...");
 
     let runtime = tokio::runtime::Runtime::new().map_err(|e| Error::Internal(e.to_string()))?;
 
     runtime.block_on(async {
-        let backend = TelegramBackend::new(config.telegram.clone());
+        let backend = TelegramBackend::new(config.telegram.clone()).with_session_key(session_key);
         backend.connect().await?;
 
         if backend.is_authorized().await? {
@@ -548,18 +914,25 @@ fn cmd_auth(config_path: &PathBuf, phone: &str, code_opt: Option<String>, passwo
     })
 }
 
+#[allow(clippy::too_many_arguments)]
 fn cmd_mount(
     config_path: &PathBuf,
     mount_point: &PathBuf,
     foreground: bool,
     allow_other: bool,
+    default_permissions: bool,
+    acl: bool,
     password_file: Option<PathBuf>,
+    keyfile: Option<PathBuf>,
     overlay: bool,
     lower_path: Option<PathBuf>,
+    force_offline: bool,
 ) -> Result<()> {
     let mut config = Config::load(config_path)?;
     config.mount.mount_point = mount_point.clone();
     config.mount.allow_other = allow_other;
+    config.mount.default_permissions |= default_permissions;
+    config.mount.posix_acl |= acl;
 
     // Build mount options
     let mut options = vec![
@@ -570,6 +943,12 @@ fn cmd_mount(
     if allow_other {
         options.push(fuser::MountOption::AllowOther);
     }
+    if config.mount.default_permissions {
+        if config.mount.posix_acl {
+            warn!("default_permissions makes the kernel ignore ACLs for access checks");
+        }
+        options.push(fuser::MountOption::DefaultPermissions);
+    }
 
     // Ensure mount point exists
     std::fs::create_dir_all(mount_point)?;
@@ -607,20 +986,9 @@ fn cmd_mount(
         // Standard mode: Telegram-backed filesystem
         info!("Starting tgcryptfs...");
 
-        // Get password for key derivation
-        let password = if let Some(path) = password_file {
-            std::fs::read_to_string(&path)
-                .map_err(|e| Error::Internal(format!("Failed to read password file: {}", e)))?
-                .trim()
-                .to_string()
-        } else {
-            rpassword::prompt_password("Enter encryption password: ")
-                .map_err(|e| Error::Internal(e.to_string()))?
-        };
-
-        // Derive master key
-        let master_key = MasterKey::from_password(password.as_bytes(), &config.encryption)?;
-        let key_manager = KeyManager::new(master_key)?;
+        // Derive master key from the password (and keyfile, if any)
+        let master_key = derive_master_key(&config, password_file, keyfile)?;
+        let key_manager = KeyManager::new(master_key)?.with_envelope(&config.encryption);
 
         // Update config with salt if new
         if config.encryption.salt.is_empty() {
@@ -630,26 +998,58 @@ fn cmd_mount(
 
         // Create metadata store
         let metadata_path = config.data_dir.join("metadata.db");
-        let metadata = MetadataStore::open(&metadata_path, *key_manager.metadata_key())?;
+        let metadata = MetadataStore::open(&metadata_path, *key_manager.metadata_key(), &config.encryption)?
+            .with_cache_limits(config.cache.inode_cache_size, config.cache.negative_cache_entries);
 
         // Create Telegram backend
-        let telegram = TelegramBackend::new(config.telegram.clone());
+        let telegram = TelegramBackend::new(config.telegram.clone())
+            .with_session_key(key_manager.session_key()?);
 
-        // Connect to Telegram
+        // Connect to Telegram, falling back to offline operation
         let runtime = tokio::runtime::Runtime::new().map_err(|e| Error::Internal(e.to_string()))?;
-        runtime.block_on(async {
-            telegram.connect().await?;
-            if !telegram.is_authorized().await? {
-                return Err(Error::TelegramAuthRequired);
+        let unreachable = if force_offline {
+            Some(Error::Offline("mounted with --offline".to_string()))
+        } else {
+            match runtime.block_on(offline::probe(&telegram)) {
+                Ok(()) => None,
+                Err(Error::TelegramAuthRequired) => return Err(Error::TelegramAuthRequired),
+                Err(e) if config.offline.enabled => {
+                    warn!("Cannot reach the backend ({}); mounting offline", e);
+                    Some(e)
+                }
+                Err(e) => return Err(e),
             }
-            Ok::<_, Error>(())
-        })?;
+        };
 
         // Create cache
-        let cache = ChunkCache::new(&config.cache)?;
+        let cache = if config.cache.shared {
+            ChunkCache::shared(&config.cache, &key_manager.cache_domain()?)?
+        } else {
+            ChunkCache::new(&config.cache)?
+        };
+
+        // Stream metadata changes to the cloud so the volume can be recovered
+        let journal = if config.journal.enabled {
+            let journal = CloudJournal::new(
+                config.journal.clone(),
+                *key_manager.metadata_key(),
+                VolumeDescriptor::from_config(&config.encryption),
+            );
+            journal.attach(&metadata)?;
+            Some(journal)
+        } else {
+            warn!("Metadata journal disabled; metadata.db is the only copy of the filesystem tree");
+            None
+        };
 
         // Create filesystem
-        let fs = TgCryptFs::new(config.clone(), key_manager, metadata, telegram, cache)?;
+        let mut fs = TgCryptFs::new(config.clone(), key_manager, metadata, telegram, cache)?;
+        if let Some(journal) = journal {
+            fs = fs.with_journal(journal);
+        }
+        if let Some(reason) = unreachable {
+            fs = fs.start_offline(&reason);
+        }
 
         info!("Mounting at {:?}", mount_point);
 
@@ -690,8 +1090,44 @@ fn cmd_unmount(mount_point: &PathBuf) -> Result<()> {
     }
 }
 
-fn cmd_status(config_path: &PathBuf) -> Result<()> {
-    let config = Config::load(config_path)?;
+fn cmd_pin(path: &Path, pinned: bool) -> Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+
+    // The running daemon tracks pins through an xattr on the mounted path
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| Error::InvalidArgument(format!("{:?}", path)))?;
+    let c_name = std::ffi::CString::new(PIN_XATTR).expect("xattr name has no NUL");
+    let ret = unsafe {
+        if pinned {
+            libc::setxattr(c_path.as_ptr(), c_name.as_ptr(), b"1".as_ptr().cast(), 1, 0)
+        } else {
+            libc::removexattr(c_path.as_ptr(), c_name.as_ptr())
+        }
+    };
+    if ret != 0 {
+        let err = std::io::Error::last_os_error();
+        if !pinned && err.raw_os_error() == Some(libc::ENODATA) {
+            println!("{:?} is not pinned", path);
+            return Ok(());
+        }
+        return Err(Error::Internal(format!(
+            "cannot {} {:?} (is it on a mounted tgcryptfs?): {}",
+            if pinned { "pin" } else { "unpin" },
+            path,
+            err
+        )));
+    }
+
+    if pinned {
+        println!("Pinned {:?}; its chunks are downloaded in the background", path);
+    } else {
+        println!("Unpinned {:?}", path);
+    }
+    Ok(())
+}
+
+fn cmd_status(config_path: &PathBuf, password_file: Option<PathBuf>, keyfile: Option<PathBuf>) -> Result<()> {
+    let mut config = Config::load(config_path)?;
 
     println!("tgcryptfs Status");
     println!("=================");
@@ -705,10 +1141,82 @@ fn cmd_status(config_path: &PathBuf) -> Result<()> {
     println!("Deduplication: {}", if config.chunk.dedup_enabled { "enabled" } else { "disabled" });
     println!("Versioning: {}", if config.versioning.enabled { "enabled" } else { "disabled" });
 
+    match DaemonStats::load(&config.data_dir.join(STATS_FILE)) {
+        Ok(Some(stats)) if !stats.is_running() => {
+            println!("Inode cache: not mounted (pid {} has exited)", stats.pid)
+        }
+        Ok(Some(stats)) => {
+            let cache = &stats.inode_cache;
+            println!();
+            println!("Inode cache (pid {}, updated {}s ago):", stats.pid, stats.age_secs());
+            println!(
+                "  Entries: {} ({:.1} of {:.1} MB)",
+                cache.entries,
+                cache.bytes as f64 / 1024.0 / 1024.0,
+                cache.capacity as f64 / 1024.0 / 1024.0
+            );
+            println!(
+                "  Hits: {}, misses: {} ({:.1}% hit ratio), evictions: {}",
+                cache.hits,
+                cache.misses,
+                cache.hit_ratio() * 100.0,
+                cache.evictions
+            );
+            println!(
+                "  Negative lookups: {} cached, {} hits",
+                cache.negative_entries, cache.negative_hits
+            );
+            let pins = &stats.pins;
+            println!(
+                "Pinned: {} paths, {} files, {:.1} of {:.1} MB cached",
+                pins.roots,
+                pins.files,
+                pins.cached_bytes as f64 / 1024.0 / 1024.0,
+                pins.bytes as f64 / 1024.0 / 1024.0
+            );
+            let conn = &stats.connectivity;
+            if conn.online {
+                println!("Connectivity: online");
+            } else {
+                println!(
+                    "Connectivity: OFFLINE for {}s ({})",
+                    stats.updated.saturating_sub(conn.since),
+                    conn.last_error.as_deref().unwrap_or("unknown reason")
+                );
+            }
+            if conn.queued_chunks > 0 {
+                println!(
+                    "Upload queue: {} chunks, {:.1} MB waiting for the backend",
+                    conn.queued_chunks,
+                    conn.queued_bytes as f64 / 1024.0 / 1024.0
+                );
+            }
+            println!();
+        }
+        Ok(None) => println!("Inode cache: not mounted"),
+        Err(e) => println!("Inode cache: unreadable statistics ({})", e),
+    }
+
+    let session_file = &config.telegram.session_file;
+    if EncryptedSession::is_plaintext(session_file)
+        || EncryptedSession::is_plaintext(session_file.with_extension("session"))
+    {
+        println!("Session storage: PLAINTEXT (encrypted on next auth or mount)");
+    } else {
+        println!("Session storage: encrypted");
+    }
+
+    // Checking the connection requires decrypting the session
+    let Some(password_file) = password_file else {
+        println!("Cloud backend: not checked (pass --password-file to connect)");
+        return Ok(());
+    };
+    let session_key = derive_session_key(&mut config, config_path, Some(password_file), keyfile)?;
+
     // Check cloud backend connection
     let runtime = tokio::runtime::Runtime::new().map_err(|e| Error::Internal(e.to_string()))?;
     runtime.block_on(async {
-        let backend = TelegramBackend::new(config.telegram.clone());
+        let backend = TelegramBackend::new(config.telegram.clone()).with_session_key(session_key);
         match backend.connect().await {
             Ok(_) => {
                 if backend.is_authorized().await.unwrap_or(false) {
@@ -755,7 +1263,40 @@ fn cmd_restore(_config_path: &PathBuf, snapshot: &str) -> Result<()> {
 fn cmd_cache(config_path: &PathBuf, clear: bool) -> Result<()> {
     let config = Config::load(config_path)?;
 
-    if clear {
+    // A running mount publishes the counters of the cache it is using,
+    // and owns its entry list
+    let daemon = DaemonStats::load(&config.data_dir.join(STATS_FILE))?;
+    if let Some(daemon) = daemon.filter(DaemonStats::is_running) {
+        if clear {
+            return Err(Error::InvalidConfig(format!(
+                "cache is in use by the mount with pid {}; unmount before clearing it \
+                 (or drop files with `tgcryptfs cache evict`)",
+                daemon.pid
+            )));
+        }
+        if let Some(stats) = &daemon.cache {
+            print_live_cache_stats(&daemon, stats);
+            return Ok(());
+        }
+    }
+
+    if config.cache.shared {
+        // Entries are split by key domain; report and clear them as a whole
+        let shared = SharedDir::open(&config.cache.cache_dir)?;
+        if clear {
+            info!("Clearing shared cache...");
+            shared.clear()?;
+            info!("Cache cleared");
+        } else {
+            println!("Shared Cache Statistics");
+            println!("=======================");
+            println!("Size: {} / {} MB",
+                shared.usage()? / 1024 / 1024,
+                config.cache.max_size / 1024 / 1024
+            );
+            println!("Key domains: {}", shared.domains()?);
+        }
+    } else if clear {
         info!("Clearing cache...");
         let cache = ChunkCache::new(&config.cache)?;
         cache.clear()?;
@@ -772,57 +1313,329 @@ fn cmd_cache(config_path: &PathBuf, clear: bool) -> Result<()> {
             stats.utilization()
         );
         println!("Chunks cached: {}", stats.chunk_count);
+        println!("Eviction policy: {:?}", stats.policy);
         println!("Prefetch queue: {}", stats.prefetch_queue_len);
     }
 
     Ok(())
 }
 
-fn cmd_sync(_config_path: &PathBuf, full: bool) -> Result<()> {
-    info!("Syncing with cloud backend...");
+/// Set an xattr on a path (the mounted daemon handles tgcryptfs requests)
+fn set_xattr(path: &Path, name: &str, value: &[u8]) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
 
-    if full {
-        info!("Performing full sync...");
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+    let c_name = std::ffi::CString::new(name).expect("xattr name has no NUL");
+    let ret = unsafe {
+        libc::setxattr(c_path.as_ptr(), c_name.as_ptr(), value.as_ptr().cast(), value.len(), 0)
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
     }
-
-    println!("Sync not yet fully implemented");
     Ok(())
 }
 
-fn cmd_machine_init(config_path: &PathBuf, name: Option<String>) -> Result<()> {
-    use tgcryptfs::config::ConfigV2;
-    use uuid::Uuid;
-
-    info!("Initializing machine identity...");
+/// Read a small xattr of a path
+fn get_xattr(path: &Path, name: &str) -> std::io::Result<Vec<u8>> {
+    use std::os::unix::ffi::OsStrExt;
 
-    // Load or create config
-    let mut config = if config_path.exists() {
-        ConfigV2::load(config_path)?
-    } else {
-        ConfigV2::from_env()?
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::InvalidInput))?;
+    let c_name = std::ffi::CString::new(name).expect("xattr name has no NUL");
+    let mut buf = vec![0u8; 4096];
+    let len = unsafe {
+        libc::getxattr(c_path.as_ptr(), c_name.as_ptr(), buf.as_mut_ptr().cast(), buf.len())
     };
-
-    // Set machine name if provided
-    if let Some(name) = name {
-        config.machine.name = name;
+    if len < 0 {
+        return Err(std::io::Error::last_os_error());
     }
+    buf.truncate(len as usize);
+    Ok(buf)
+}
 
-    // Generate machine ID if not already set
-    if config.machine.id == "auto" || config.machine.id.is_empty() {
-        config.machine.id = Uuid::new_v4().to_string();
-    }
+fn print_live_cache_stats(daemon: &DaemonStats, stats: &CacheStats) {
+    let mb = |bytes: u64| bytes as f64 / 1024.0 / 1024.0;
 
-    // Ensure parent directory exists
-    if let Some(parent) = config_path.parent() {
-        std::fs::create_dir_all(parent)?;
+    println!("Cache Statistics (pid {}, updated {}s ago)", daemon.pid, daemon.age_secs());
+    println!("================");
+    println!(
+        "Size: {:.1} / {:.1} MB ({:.1}%)",
+        mb(stats.current_size + stats.quarantine_size),
+        mb(stats.max_size),
+        stats.utilization()
+    );
+    if let Some(shared) = stats.shared_size {
+        println!("Shared cache: {:.1} MB across all mounts", mb(shared));
     }
+    println!("Chunks cached: {} ({:.1} MB pinned)", stats.chunk_count, mb(stats.pinned_size));
+    println!("Eviction policy: {:?}", stats.policy);
+    println!(
+        "Hits: {}, misses: {} ({:.1}% hit ratio), evictions: {}, quarantined: {} ({:.1} MB kept)",
+        stats.hits,
+        stats.misses,
+        stats.hit_ratio() * 100.0,
+        stats.evictions,
+        stats.quarantined,
+        mb(stats.quarantine_size)
+    );
+    println!("Memory pages: {:.1} MB, {} page hits", mb(stats.memory_size), stats.page_hits);
+    let downloads = &daemon.downloads;
+    println!(
+        "Served from cache: {:.1} MB; downloaded: {:.1} MB ({} chunks, {} ranged reads)",
+        mb(stats.bytes_served),
+        mb(downloads.bytes),
+        downloads.chunks,
+        downloads.ranges
+    );
+    println!(
+        "Prefetch: {} chunks, {} used ({:.1}%), {} evicted unread, {} queued",
+        stats.prefetched,
+        stats.prefetch_used,
+        stats.prefetch_effectiveness() * 100.0,
+        stats.prefetch_wasted,
+        stats.prefetch_queue_len
+    );
+}
 
-    config.save(config_path)?;
+fn cmd_cache_warm(path: &Path, request: &WarmRequest) -> Result<()> {
+    // The running daemon runs the warmup and reports progress through an xattr
+    set_xattr(path, WARM_XATTR, &serde_json::to_vec(request)?).map_err(|e| {
+        Error::Internal(format!("cannot warm {:?} (is it on a mounted tgcryptfs?): {}", path, e))
+    })?;
 
-    println!("Machine initialized:");
-    println!("  ID: {}", config.machine.id);
-    println!("  Name: {}", config.machine.name);
-    println!("  Config: {:?}", config_path);
+    loop {
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        let value = get_xattr(path, WARM_XATTR).map_err(|e| {
+            Error::Internal(format!("cannot read warmup progress of {:?}: {}", path, e))
+        })?;
+        let progress: WarmProgress = serde_json::from_slice(&value)?;
+        print!(
+            "\r{} files: {}/{} chunks, {}/{} MB cached",
+            progress.files,
+            progress.cached_chunks,
+            progress.chunks,
+            progress.cached_bytes / 1024 / 1024,
+            progress.bytes / 1024 / 1024
+        );
+        let _ = std::io::Write::flush(&mut std::io::stdout());
+        if progress.done {
+            println!();
+            if let Some(error) = progress.error {
+                return Err(Error::Internal(format!("cannot warm {:?}: {}", path, error)));
+            }
+            if progress.failed_chunks > 0 {
+                println!("{} chunks could not be downloaded", progress.failed_chunks);
+            }
+            return Ok(());
+        }
+    }
+}
+
+fn cmd_cache_evict(path: &Path) -> Result<()> {
+    set_xattr(path, EVICT_XATTR, b"1").map_err(|e| {
+        Error::Internal(format!("cannot evict {:?} (is it on a mounted tgcryptfs?): {}", path, e))
+    })?;
+    println!("Evicted {:?} from the cache (pinned chunks are kept)", path);
+    Ok(())
+}
+
+fn cmd_cache_ls(path: &Path, limit: Option<usize>) -> Result<()> {
+    // Walk the mounted tree, asking the daemon how much of each file is cached
+    let mut files: Vec<(PathBuf, CachedFile)> = Vec::new();
+    let mut skipped: Vec<(PathBuf, std::io::Error)> = Vec::new();
+    let mut stack = vec![path.to_path_buf()];
+    while let Some(current) = stack.pop() {
+        let metadata = match std::fs::symlink_metadata(&current) {
+            Ok(metadata) => metadata,
+            Err(e) if current == path => return Err(e.into()),
+            Err(e) => {
+                skipped.push((current, e));
+                continue;
+            }
+        };
+        if metadata.is_dir() {
+            let entries = match std::fs::read_dir(&current) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    skipped.push((current, e));
+                    continue;
+                }
+            };
+            for entry in entries {
+                match entry {
+                    Ok(entry) => stack.push(entry.path()),
+                    Err(e) => skipped.push((current.clone(), e)),
+                }
+            }
+        } else if metadata.is_file() {
+            let value = match get_xattr(&current, CACHED_XATTR) {
+                Ok(value) => value,
+                // Only a tgcryptfs mount answers the xattr at all
+                Err(e) if matches!(e.raw_os_error(), Some(libc::ENODATA | libc::ENOTSUP)) => {
+                    return Err(Error::Internal(format!(
+                        "cannot inspect {:?} (is it on a mounted tgcryptfs?): {}",
+                        current, e
+                    )));
+                }
+                Err(e) => {
+                    skipped.push((current, e));
+                    continue;
+                }
+            };
+            let cached: CachedFile = serde_json::from_slice(&value)?;
+            if cached.cached_bytes > 0 {
+                files.push((current, cached));
+            }
+        }
+    }
+
+    files.sort_by(|a, b| b.1.cached_bytes.cmp(&a.1.cached_bytes).then_with(|| a.0.cmp(&b.0)));
+    let total: u64 = files.iter().map(|(_, c)| c.cached_bytes).sum();
+    let count = files.len();
+    for (file, cached) in files.into_iter().take(limit.unwrap_or(usize::MAX)) {
+        println!(
+            "{:>10.1} MB {:>5.1}%{} {}",
+            cached.cached_bytes as f64 / 1024.0 / 1024.0,
+            if cached.size == 0 { 100.0 } else { cached.cached_bytes as f64 * 100.0 / cached.size as f64 },
+            if cached.pinned_chunks > 0 { " pinned" } else { "       " },
+            file.display()
+        );
+    }
+    println!("{} cached files, {:.1} MB", count, total as f64 / 1024.0 / 1024.0);
+    if !skipped.is_empty() {
+        println!("{} entries could not be read and were skipped:", skipped.len());
+        for (entry, e) in &skipped {
+            println!("  {}: {}", entry.display(), e);
+        }
+    }
+    Ok(())
+}
+
+fn cmd_sync(
+    config_path: &PathBuf,
+    full: bool,
+    allow_rollback: bool,
+    password_file: Option<PathBuf>,
+    keyfile: Option<PathBuf>,
+) -> Result<()> {
+    use std::sync::Arc;
+    use tgcryptfs::config::{ConfigV2, DistributionMode, ReplicaRole};
+    use tgcryptfs::distributed::{
+        identity::IdentityStore, SnapshotManager, SyncConfig, SyncDaemon,
+    };
+
+    info!("Syncing with cloud backend...");
+
+    if full {
+        info!("Performing full sync...");
+    }
+
+    let config = ConfigV2::load(config_path)?;
+    let (DistributionMode::MasterReplica, Some(mr_config), Some(cluster_id)) = (
+        config.distribution.mode,
+        &config.distribution.master_replica,
+        &config.distribution.cluster_id,
+    ) else {
+        println!("Sync is only implemented for master-replica clusters");
+        return Ok(());
+    };
+
+    let master_public_key = mr_config.master_public_key()?;
+    if mr_config.role == ReplicaRole::Replica && master_public_key.is_none() {
+        return Err(Error::InvalidConfig(
+            "master_public_key is required on replicas".to_string(),
+        ));
+    }
+    if allow_rollback {
+        warn!("Rollback protection disabled: older snapshots will be accepted");
+    }
+
+    let password = read_password(password_file)?;
+    let master_key = master_key_from_password(&config.encryption, &password, keyfile)?;
+
+    // Snapshots are signed with this machine's persistent identity
+    std::fs::create_dir_all(&config.data_dir)?;
+    let identity_db = sled::open(config.data_dir.join("identity.db"))
+        .map_err(|e| Error::Internal(format!("Failed to open identity store: {}", e)))?;
+    let identity = IdentityStore::new(identity_db)
+        .map_err(|e| Error::Internal(format!("Failed to open identity store: {}", e)))?
+        .get_or_create(config.machine.name.clone(), master_key.key(), &config.encryption)
+        .map_err(|e| Error::Internal(format!("Failed to load machine identity: {}", e)))?;
+
+    let key_manager = KeyManager::new(master_key)?.with_envelope(&config.encryption);
+    let metadata_path = config.data_dir.join("metadata.db");
+    let metadata = MetadataStore::open(&metadata_path, *key_manager.metadata_key(), &config.encryption)?;
+    let telegram = TelegramBackend::new(config.telegram.clone())
+        .with_session_key(key_manager.session_key()?);
+
+    let sync_config = match mr_config.role {
+        ReplicaRole::Master => {
+            println!("Snapshot signing key: {}", hex::encode(identity.public_key));
+            SyncConfig::master(mr_config.sync_interval_secs, mr_config.snapshot_retention)
+        }
+        ReplicaRole::Replica => SyncConfig::replica(mr_config.sync_interval_secs),
+    };
+
+    let mut manager = SnapshotManager::new(
+        *key_manager.metadata_key(),
+        Arc::new(telegram),
+        Arc::new(metadata),
+        identity.machine_id,
+        cluster_id.clone(),
+        mr_config.snapshot_retention,
+    )?
+    .with_identity(identity)
+    .with_allow_rollback(allow_rollback);
+    if let Some(public_key) = master_public_key {
+        manager = manager.with_trusted_master(public_key);
+    }
+
+    let runtime = tokio::runtime::Runtime::new().map_err(|e| Error::Internal(e.to_string()))?;
+    runtime.block_on(async {
+        SyncDaemon::new(Arc::new(manager), sync_config)
+            .sync_now()
+            .await
+    })?;
+
+    println!("Sync complete");
+    Ok(())
+}
+
+fn cmd_machine_init(config_path: &PathBuf, name: Option<String>) -> Result<()> {
+    use tgcryptfs::config::ConfigV2;
+    use uuid::Uuid;
+
+    info!("Initializing machine identity...");
+
+    // Load or create config
+    let mut config = if config_path.exists() {
+        ConfigV2::load(config_path)?
+    } else {
+        ConfigV2::from_env()?
+    };
+
+    // Set machine name if provided
+    if let Some(name) = name {
+        config.machine.name = name;
+    }
+
+    // Generate machine ID if not already set
+    if config.machine.id == "auto" || config.machine.id.is_empty() {
+        config.machine.id = Uuid::new_v4().to_string();
+    }
+
+    // Ensure parent directory exists
+    if let Some(parent) = config_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    config.save(config_path)?;
+
+    println!("Machine initialized:");
+    println!("  ID: {}", config.machine.id);
+    println!("  Name: {}", config.machine.name);
+    println!("  Config: {:?}", config_path);
 
     Ok(())
 }
@@ -998,11 +1811,15 @@ fn cmd_cluster_join(config_path: &PathBuf, cluster_id: String, role: String) ->
                 },
                 sync_interval_secs: 60,
                 snapshot_retention: 10,
+                master_public_key: None,
             });
 
             println!("Joined cluster '{}' as {}", cluster_id, role);
             if role == "replica" {
-                println!("NOTE: You must set the master_id in the config file");
+                println!(
+                    "NOTE: You must set the master_id and master_public_key in the config file \
+                     (the master prints its signing key on `tgcryptfs sync`)"
+                );
             }
         }
         "node" => {
@@ -1052,6 +1869,10 @@ fn cmd_cluster_status(config_path: &PathBuf) -> Result<()> {
         println!("  Master ID: {}", mr_config.master_id);
         println!("  Sync Interval: {}s", mr_config.sync_interval_secs);
         println!("  Snapshot Retention: {}", mr_config.snapshot_retention);
+        println!(
+            "  Master Public Key: {}",
+            mr_config.master_public_key.as_deref().unwrap_or("not set")
+        );
     }
 
     if let Some(dist_config) = &config.distribution.distributed {
@@ -1074,6 +1895,7 @@ fn cmd_cluster_status(config_path: &PathBuf) -> Result<()> {
 fn cmd_migrate(
     config_path: &PathBuf,
     password_file: Option<PathBuf>,
+    keyfile: Option<PathBuf>,
     dry_run: bool,
     force: bool,
 ) -> Result<()> {
@@ -1086,19 +1908,8 @@ fn cmd_migrate(
         info!("DRY RUN MODE - no changes will be made");
     }
 
-    // Get password
-    let password = if let Some(path) = password_file {
-        std::fs::read_to_string(&path)
-            .map_err(|e| Error::Internal(format!("Failed to read password file: {}", e)))?
-            .trim()
-            .to_string()
-    } else {
-        rpassword::prompt_password("Enter encryption password: ")
-            .map_err(|e| Error::Internal(e.to_string()))?
-    };
-
     // Derive master key
-    let master_key = MasterKey::from_password(password.as_bytes(), &config.encryption)?;
+    let master_key = derive_master_key(&config, password_file, keyfile)?;
 
     // Get salt from config
     if config.encryption.salt.is_empty() {
@@ -1191,14 +2002,66 @@ fn cmd_migrate(
     Ok(())
 }
 
-fn cmd_raid_status(config_path: &PathBuf) -> Result<()> {
+fn cmd_migrate_aad(
+    config_path: &PathBuf,
+    password_file: Option<PathBuf>,
+    keyfile: Option<PathBuf>,
+    dry_run: bool,
+) -> Result<()> {
+    let mut config = Config::load(config_path)?;
+
+    let metadata_path = config.data_dir.join("metadata.db");
+    if !metadata_path.exists() {
+        return Err(Error::Internal("Metadata database not found - nothing to migrate".to_string()));
+    }
+
+    let master_key = derive_master_key(&config, password_file, keyfile)?;
+    let key_manager = KeyManager::new(master_key)?.with_envelope(&config.encryption);
+    let metadata = MetadataStore::open(&metadata_path, *key_manager.metadata_key(), &config.encryption)?;
+
+    if metadata.is_aad_bound() {
+        println!("All metadata is already bound to its identity. No migration needed.");
+        return Ok(());
+    }
+
+    if dry_run {
+        let stats = metadata.get_stats()?;
+        println!("Dry run complete. Would rebind:");
+        println!("  - {} inodes in {:?}", stats.inode_count, metadata_path);
+        println!("\nRun without --dry-run to perform the actual migration.");
+        return Ok(());
+    }
+
+    println!("Binding metadata to object identity...");
+    let stats = metadata.bind_aad()?;
+
+    println!("\nMigration complete!");
+    println!("  Entries bound: {}", stats.entries_migrated);
+    println!("  Entries failed: {}", stats.entries_failed);
+
+    if stats.entries_failed > 0 {
+        warn!("Some entries failed to migrate; unbound entries are still accepted. Check logs for details.");
+    } else {
+        config.encryption.require_bound_metadata = true;
+        config.save(config_path)?;
+        println!("Unbound metadata entries are now rejected (encryption.require_bound_metadata).");
+    }
+
+    Ok(())
+}
+
+fn cmd_raid_status(
+    config_path: &PathBuf,
+    password_file: Option<PathBuf>,
+    keyfile: Option<PathBuf>,
+) -> Result<()> {
     use tgcryptfs::config::ConfigV2;
     use tgcryptfs::raid::{AccountPool, ArrayStatus};
 
-    let config = ConfigV2::load(config_path)?;
+    let mut config = ConfigV2::load(config_path)?;
 
     // Check if erasure coding is configured
-    let pool_config = config.pool.ok_or_else(|| {
+    let pool_config = config.pool.clone().ok_or_else(|| {
         Error::InvalidConfig("No pool configuration found. Run 'tgcryptfs raid add-account' first.".to_string())
     })?;
 
@@ -1230,10 +2093,19 @@ fn cmd_raid_status(config_path: &PathBuf) -> Result<()> {
     }
     println!();
 
+    // Account sessions are encrypted under the volume's session key
+    let password = read_password(password_file)?;
+    let master_key = master_key_from_password(&config.encryption, &password, keyfile)?;
+    if config.encryption.salt.is_empty() {
+        config.encryption.salt = master_key.salt().to_vec();
+        config.save(config_path)?;
+    }
+    let session_key = master_key.session_key()?;
+
     // Try to connect and get live status
     let runtime = tokio::runtime::Runtime::new().map_err(|e| Error::Internal(e.to_string()))?;
     runtime.block_on(async {
-        match AccountPool::new(pool_config) {
+        match AccountPool::with_session_key(pool_config, session_key) {
             Ok(pool) => {
                 if let Err(e) = pool.connect_all().await {
                     warn!("Could not connect to all accounts: {}", e);
@@ -1459,6 +2331,513 @@ fn expand_tilde(path: &PathBuf) -> PathBuf {
     path.clone()
 }
 
+fn cmd_recover(
+    config_path: &PathBuf,
+    password_file: Option<PathBuf>,
+    keyfile: Option<PathBuf>,
+    force: bool,
+) -> Result<()> {
+    let mut config = Config::load(config_path)?;
+
+    let metadata_path = config.data_dir.join("metadata.db");
+    if metadata_path.exists() && !force {
+        return Err(Error::InvalidConfig(format!(
+            "{:?} already exists - use --force to replace it",
+            metadata_path
+        )));
+    }
+    if config.encryption.salt.is_empty() {
+        return Err(Error::InvalidConfig(
+            "No Telegram session - run 'tgcryptfs auth' first".to_string(),
+        ));
+    }
+
+    let password = read_password(password_file)?;
+
+    // Until the volume's own salt is known, the session is still encrypted
+    // under the salt generated on this machine
+    let local_session_key = master_key_from_password(&config.encryption, &password, None)?.session_key()?;
+    let backend = TelegramBackend::new(config.telegram.clone()).with_session_key(local_session_key);
+    let session_path = backend.session_path();
+
+    let recovery_path = config.data_dir.join("metadata.db.recover");
+    if recovery_path.exists() {
+        std::fs::remove_dir_all(&recovery_path)?;
+    }
+
+    let runtime = tokio::runtime::Runtime::new().map_err(|e| Error::Internal(e.to_string()))?;
+    let result = runtime.block_on(async {
+        backend.connect().await?;
+        if !backend.is_authorized().await? {
+            return Err(Error::TelegramAuthRequired);
+        }
+
+        info!("Fetching volume descriptor...");
+        let volume = fetch_volume_descriptor(&backend).await?;
+        volume.apply_to(&mut config.encryption)?;
+        // The rebuilt store holds only bound entries
+        config.encryption.require_bound_metadata = true;
+        config.encryption.keyfile = if volume.keyfile_required {
+            Some(keyfile.ok_or_else(|| {
+                Error::InvalidConfig("This volume requires a keyfile (--keyfile)".to_string())
+            })?)
+        } else {
+            None
+        };
+
+        let master_key = master_key_from_password(&config.encryption, &password, None)?;
+        let key_manager = KeyManager::new(master_key)?.with_envelope(&config.encryption);
+
+        info!("Replaying metadata journal...");
+        let metadata = MetadataStore::open(&recovery_path, *key_manager.metadata_key(), &config.encryption)?;
+        let stats = recover(&metadata, &backend, key_manager.metadata_key()).await?;
+
+        backend.disconnect().await;
+        Ok::<_, Error>((stats, key_manager.session_key()?))
+    });
+
+    // The client must be gone before its session file is rewritten
+    drop(backend);
+    drop(runtime);
+
+    let (stats, session_key) = match result {
+        Ok(result) => result,
+        Err(e) => {
+            let _ = std::fs::remove_dir_all(&recovery_path);
+            return Err(e);
+        }
+    };
+
+    if metadata_path.exists() {
+        std::fs::remove_dir_all(&metadata_path)?;
+    }
+    std::fs::rename(&recovery_path, &metadata_path)?;
+
+    EncryptedSession::open(&session_path, local_session_key)?.rekey(session_key)?;
+    config.save(config_path)?;
+
+    println!("Recovery complete:");
+    match stats.checkpoint_seq {
+        Some(seq) => println!("  Checkpoint: seq {}", seq),
+        None => println!("  Checkpoint: none"),
+    }
+    println!("  Batches replayed: {}", stats.batches_applied);
+    println!("  Operations applied: {}", stats.ops_applied);
+    println!("  Last sequence: {}", stats.last_seq);
+    if stats.gaps > 0 {
+        println!("  WARNING: {} gap(s) in the journal; some recent changes may be missing", stats.gaps);
+    }
+    println!("\nMetadata restored to {:?}", metadata_path);
+
+    Ok(())
+}
+
+/// Uploads fsck listed as orphaned, awaiting deletion by a later run
+const ORPHAN_LIST_FILE: &str = "fsck_orphans.json";
+
+/// Why the backend account may hold chunks of other volumes, if it may
+fn shared_account_reason(config_path: &PathBuf, metadata: &MetadataStore) -> Option<&'static str> {
+    use tgcryptfs::config::{ConfigV2, DistributionMode};
+
+    if metadata.is_namespaced() {
+        return Some("the metadata store is namespaced");
+    }
+    let config = ConfigV2::load(config_path).ok()?;
+    if !config.namespaces.is_empty() {
+        Some("namespaces are configured")
+    } else if config.distribution.mode != DistributionMode::Standalone {
+        Some("the account is shared by a cluster")
+    } else {
+        None
+    }
+}
+
+/// Delete orphaned uploads that a previous run listed
+///
+/// Uploads not listed before are only listed, so every deletion follows
+/// a run that showed it.
+fn delete_orphaned_uploads(
+    runtime: &tokio::runtime::Runtime,
+    backend: &TelegramBackend,
+    data_dir: &Path,
+    report: &fsck::FsckReport,
+) -> Result<()> {
+    let list_path = data_dir.join(ORPHAN_LIST_FILE);
+    let listed: Vec<(i32, String)> = match std::fs::read(&list_path) {
+        Ok(bytes) => serde_json::from_slice(&bytes)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+
+    let (confirmed, unconfirmed): (Vec<_>, Vec<_>) = report
+        .problems
+        .iter()
+        .filter_map(|problem| match problem {
+            FsckProblem::OrphanedUpload { message_id, name } => Some((*message_id, name.clone())),
+            _ => None,
+        })
+        .partition(|orphan| listed.contains(orphan));
+
+    let mut deleted = 0;
+    for (message_id, _) in &confirmed {
+        match runtime.block_on(backend.delete_message(*message_id)) {
+            Ok(()) => deleted += 1,
+            Err(e) => warn!("Failed to delete message {}: {}", message_id, e),
+        }
+    }
+    if !confirmed.is_empty() {
+        println!("Deleted {} orphaned upload(s)", deleted);
+    }
+
+    if unconfirmed.is_empty() {
+        let _ = std::fs::remove_file(&list_path);
+    } else {
+        println!("\nOrphaned uploads listed for deletion:");
+        for (message_id, name) in &unconfirmed {
+            println!("  message {} ({})", message_id, name);
+        }
+        std::fs::write(&list_path, serde_json::to_vec(&unconfirmed)?)?;
+        println!(
+            "Run again with --delete-orphans to delete these {} upload(s).",
+            unconfirmed.len()
+        );
+    }
+    Ok(())
+}
+
+fn cmd_fsck(
+    config_path: &PathBuf,
+    password_file: Option<PathBuf>,
+    keyfile: Option<PathBuf>,
+    repair: bool,
+    online: bool,
+    delete_orphans: bool,
+) -> Result<()> {
+    let config = Config::load(config_path)?;
+
+    let metadata_path = config.data_dir.join("metadata.db");
+    if !metadata_path.exists() {
+        return Err(Error::Internal("Metadata database not found - nothing to check".to_string()));
+    }
+    if delete_orphans && !(online && repair) {
+        return Err(Error::InvalidConfig(
+            "--delete-orphans requires --online and --repair".to_string(),
+        ));
+    }
+
+    let master_key = derive_master_key(&config, password_file, keyfile)?;
+    let key_manager = KeyManager::new(master_key)?.with_envelope(&config.encryption);
+    let metadata = MetadataStore::open(&metadata_path, *key_manager.metadata_key(), &config.encryption)?;
+    if delete_orphans {
+        if let Some(reason) = shared_account_reason(config_path, &metadata) {
+            return Err(Error::InvalidConfig(format!(
+                "--delete-orphans refused: {}; chunk uploads cannot be attributed to this volume",
+                reason
+            )));
+        }
+    }
+
+    let runtime = tokio::runtime::Runtime::new().map_err(|e| Error::Internal(e.to_string()))?;
+    let backend = if online {
+        let backend = TelegramBackend::new(config.telegram.clone())
+            .with_session_key(key_manager.session_key()?);
+        runtime.block_on(async {
+            backend.connect().await?;
+            if !backend.is_authorized().await? {
+                return Err(Error::TelegramAuthRequired);
+            }
+            Ok::<_, Error>(())
+        })?;
+        Some(backend)
+    } else {
+        None
+    };
+    let remote = match &backend {
+        Some(backend) => {
+            info!("Listing chunk messages...");
+            Some(runtime.block_on(backend.list_chunks())?)
+        }
+        None => None,
+    };
+
+    let report = fsck::check(&metadata, remote.as_deref())?;
+    println!(
+        "Checked {} inodes and {} chunk references",
+        report.inodes_checked, report.chunks_checked
+    );
+    if report.queued_uploads > 0 {
+        println!(
+            "{} chunk(s) written offline are still queued for upload",
+            report.queued_uploads
+        );
+    }
+    for problem in &report.problems {
+        println!("  {}", problem);
+    }
+    if report.is_clean() {
+        println!("No problems found.");
+        return Ok(());
+    }
+    println!(
+        "{} problem(s), {} repairable",
+        report.problems.len(),
+        report.repairable()
+    );
+
+    if !repair {
+        println!("\nRun with --repair to fix them.");
+        return Ok(());
+    }
+
+    let stats = fsck::repair(&metadata, &report)?;
+    println!("\nRepaired {} problem(s)", stats.repaired);
+    if stats.moved_to_lost_found > 0 {
+        println!(
+            "Moved {} orphaned inode(s) to /{}",
+            stats.moved_to_lost_found,
+            fsck::LOST_AND_FOUND
+        );
+    }
+
+    if delete_orphans {
+        if let Some(backend) = &backend {
+            delete_orphaned_uploads(&runtime, backend, &config.data_dir, &report)?;
+        }
+    }
+    if let Some(backend) = &backend {
+        runtime.block_on(backend.disconnect());
+    }
+
+    // Moving orphans changes link counts, so check once more
+    let remaining = fsck::check(&metadata, None)?;
+    if remaining.is_clean() {
+        println!("Metadata is now consistent.");
+    } else {
+        println!("{} problem(s) remain:", remaining.problems.len());
+        for problem in &remaining.problems {
+            println!("  {}", problem);
+        }
+    }
+
+    Ok(())
+}
+
+fn run_metadata_command(command: MetadataCommands, config_path: &PathBuf) -> Result<()> {
+    match command {
+        MetadataCommands::Export {
+            output,
+            password_file,
+            keyfile: KeyfileArg { keyfile },
+        } => cmd_metadata_export(config_path, &output, password_file, keyfile),
+        MetadataCommands::Import {
+            input,
+            password_file,
+            keyfile: KeyfileArg { keyfile },
+            force,
+        } => cmd_metadata_import(config_path, &input, password_file, keyfile, force),
+    }
+}
+
+fn cmd_metadata_export(
+    config_path: &PathBuf,
+    output: &PathBuf,
+    password_file: Option<PathBuf>,
+    keyfile: Option<PathBuf>,
+) -> Result<()> {
+    let config = Config::load(config_path)?;
+
+    let metadata_path = config.data_dir.join("metadata.db");
+    if !metadata_path.exists() {
+        return Err(Error::Internal("Metadata database not found - nothing to export".to_string()));
+    }
+
+    let master_key = derive_master_key(&config, password_file, keyfile)?;
+    let key_manager = KeyManager::new(master_key)?.with_envelope(&config.encryption);
+    let metadata = MetadataStore::open(&metadata_path, *key_manager.metadata_key(), &config.encryption)?;
+
+    let hardlink_path = config.data_dir.join("overlay_hardlinks.db");
+    let hardlinks = if hardlink_path.exists() {
+        Some(HardLinkStore::open(&hardlink_path)?)
+    } else {
+        None
+    };
+
+    let archive = MetadataArchive::export(
+        &metadata,
+        hardlinks.as_ref(),
+        VolumeDescriptor::from_config(&config.encryption),
+    )?;
+    let data = archive.seal(key_manager.metadata_key())?;
+
+    // Write next to the target so a partial archive never replaces a good one
+    let tmp = output.with_extension("tmp");
+    std::fs::write(&tmp, &data)?;
+    std::fs::rename(&tmp, output)?;
+
+    println!("Exported metadata to {:?}:", output);
+    println!("  Inodes: {}", archive.contents.inodes.len());
+    println!("  Chunk references: {}", archive.contents.chunk_refs.len());
+    println!("  Metadata entries: {}", archive.contents.metadata.len());
+    println!("  Hard link records: {}", archive.contents.hardlinks.len());
+    println!("  Size: {} bytes (format version {})", data.len(), ARCHIVE_VERSION);
+    Ok(())
+}
+
+/// Append a suffix to a path's file name
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// Move a staged database over a live one, keeping the live one as a backup
+///
+/// Records the replacement, with its backup, for `restore_replaced`.
+fn swap_in(staging: &Path, live: &Path, replaced: &mut Vec<(PathBuf, Option<PathBuf>)>) -> Result<()> {
+    let backup = if live.exists() {
+        let backup = with_suffix(live, ".bak");
+        std::fs::rename(live, &backup)?;
+        Some(backup)
+    } else {
+        None
+    };
+    replaced.push((live.to_path_buf(), backup));
+    std::fs::rename(staging, live)?;
+    Ok(())
+}
+
+/// Put back the databases `swap_in` replaced
+fn restore_replaced(replaced: &[(PathBuf, Option<PathBuf>)]) {
+    for (live, backup) in replaced.iter().rev() {
+        let _ = std::fs::remove_dir_all(live);
+        if let Some(backup) = backup {
+            if let Err(e) = std::fs::rename(backup, live) {
+                error!("Failed to restore {:?} from {:?}: {}", live, backup, e);
+            }
+        }
+    }
+}
+
+fn cmd_metadata_import(
+    config_path: &PathBuf,
+    input: &PathBuf,
+    password_file: Option<PathBuf>,
+    keyfile: Option<PathBuf>,
+    force: bool,
+) -> Result<()> {
+    let mut config = Config::load(config_path)?;
+
+    let metadata_path = config.data_dir.join("metadata.db");
+    if metadata_path.exists() && !force {
+        return Err(Error::InvalidConfig(format!(
+            "{:?} already exists - use --force to replace it",
+            metadata_path
+        )));
+    }
+
+    let data = std::fs::read(input)?;
+    let header = MetadataArchive::read_header(&data)?;
+    let password = read_password(password_file)?;
+
+    // Adopting the archive's salt changes the key the session is stored under
+    let local_session_key = if config.encryption.salt.is_empty() {
+        None
+    } else {
+        Some(master_key_from_password(&config.encryption, &password, None)?.session_key()?)
+    };
+
+    header.volume.apply_to(&mut config.encryption)?;
+    // The imported store holds only bound entries
+    config.encryption.require_bound_metadata = true;
+    config.encryption.keyfile = if header.volume.keyfile_required {
+        Some(keyfile.ok_or_else(|| {
+            Error::InvalidConfig("This volume requires a keyfile (--keyfile)".to_string())
+        })?)
+    } else {
+        None
+    };
+    let master_key = master_key_from_password(&config.encryption, &password, None)?;
+    let key_manager = KeyManager::new(master_key)?.with_envelope(&config.encryption);
+    let archive = MetadataArchive::open(&data, key_manager.metadata_key())?;
+
+    // Stage everything next to the live databases. The hard link database
+    // is always replaced: records left from the old metadata would describe
+    // the wrong inodes.
+    let hardlink_path = config.data_dir.join("overlay_hardlinks.db");
+    let staged = [
+        (with_suffix(&metadata_path, ".import"), metadata_path.clone()),
+        (with_suffix(&hardlink_path, ".import"), hardlink_path),
+    ];
+    for (staging, live) in &staged {
+        let backup = with_suffix(live, ".bak");
+        if backup.exists() {
+            return Err(Error::InvalidConfig(format!(
+                "{:?} is left from an interrupted import - restore or remove it first",
+                backup
+            )));
+        }
+        if staging.exists() {
+            std::fs::remove_dir_all(staging)?;
+        }
+    }
+    let result = (|| {
+        let metadata = MetadataStore::open(&staged[0].0, *key_manager.metadata_key(), &config.encryption)?;
+        let hardlinks = HardLinkStore::open(&staged[1].0)?;
+        archive.import(&metadata, Some(&hardlinks))
+    })();
+    let stats = match result {
+        Ok(stats) => stats,
+        Err(e) => {
+            for (staging, _) in &staged {
+                let _ = std::fs::remove_dir_all(staging);
+            }
+            return Err(e);
+        }
+    };
+
+    // Switch over, keeping what is replaced until every step succeeded
+    let original_config = std::fs::read(config_path)?;
+    let mut replaced = Vec::new();
+    let result = (|| {
+        config.save(config_path)?;
+        for (staging, live) in &staged {
+            swap_in(staging, live, &mut replaced)?;
+        }
+        if let Some(local_session_key) = local_session_key {
+            let session_path = TelegramBackend::new(config.telegram.clone()).session_path();
+            if session_path.exists() {
+                EncryptedSession::open(&session_path, local_session_key)?
+                    .rekey(key_manager.session_key()?)?;
+            }
+        }
+        Ok::<_, Error>(())
+    })();
+    if let Err(e) = result {
+        error!("Import failed, restoring the previous metadata: {}", e);
+        restore_replaced(&replaced);
+        for (staging, _) in &staged {
+            let _ = std::fs::remove_dir_all(staging);
+        }
+        if let Err(e) = tgcryptfs::config::write_atomic(config_path, &original_config) {
+            error!("Failed to restore {:?}: {}", config_path, e);
+        }
+        return Err(e);
+    }
+    for (_, backup) in replaced {
+        if let Some(backup) = backup {
+            std::fs::remove_dir_all(&backup)?;
+        }
+    }
+
+    println!("Import complete:");
+    println!("  Inodes: {}", stats.inodes);
+    println!("  Chunk references: {}", stats.chunk_refs);
+    println!("  Metadata entries: {}", stats.metadata);
+    println!("  Hard link records: {}", stats.hardlinks);
+    println!("\nMetadata restored to {:?}", metadata_path);
+    Ok(())
+}
+
 fn run_timemachine_command(command: TimemachineCommands, config_path: &PathBuf) -> Result<()> {
     match command {
         TimemachineCommands::Init { size, name } => cmd_timemachine_init(config_path, &size, &name),
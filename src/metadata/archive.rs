@@ -0,0 +1,300 @@
+//! Portable metadata archives
+//!
+//! `tgcryptfs metadata export` writes every inode (with its manifest and
+//! xattrs), chunk reference count, general metadata entry and hard link
+//! record to a single file that does not depend on sled's on-disk format.
+//! Snapshots are not included: their contents are stored in the cloud, and
+//! only their index entries travel with the general metadata entries.
+//!
+//! Layout: `magic (8) | format version (1) | header len (4, BE) | header |
+//! payload`. The header is plaintext JSON holding the volume descriptor so
+//! the key can be re-derived on a fresh machine; the payload is the
+//! bincode-encoded contents, encrypted under the metadata key with the
+//! header bound as AAD.
+
+use crate::crypto::{decrypt_bound, encrypt_with, object_aad, EncryptedData, ObjectKind, KEY_SIZE};
+use crate::error::{Error, Result};
+use crate::metadata::{
+    decode_inodes, HardLinkStore, Inode, InodeLayout, JournalOp, MetadataStore, VolumeDescriptor,
+};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+/// Magic bytes at the start of every archive
+pub const ARCHIVE_MAGIC: &[u8; 8] = b"TGCFSMDA";
+
+/// Current archive format version
+///
+/// Version 1 archives hold inodes in the layout from before generations.
+pub const ARCHIVE_VERSION: u8 = 2;
+
+/// Plaintext archive header
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchiveHeader {
+    /// When the archive was written (seconds since the Unix epoch)
+    pub created: u64,
+    /// How to derive the key that opens the payload
+    pub volume: VolumeDescriptor,
+}
+
+/// Everything a metadata store holds, in a format-independent form
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ArchiveContents {
+    /// Inodes, including manifests and xattrs
+    pub inodes: Vec<Inode>,
+    /// Chunk references as (chunk ID, message ID, reference count)
+    pub chunk_refs: Vec<(String, i32, u32)>,
+    /// General metadata entries (local-only entries are excluded)
+    pub metadata: Vec<(String, Vec<u8>)>,
+    /// Hard link records as (inode, paths)
+    pub hardlinks: Vec<(u64, Vec<PathBuf>)>,
+}
+
+/// Counts of restored records
+#[derive(Debug, Default)]
+pub struct ImportStats {
+    /// Inodes restored
+    pub inodes: usize,
+    /// Chunk references restored
+    pub chunk_refs: usize,
+    /// Metadata entries restored
+    pub metadata: usize,
+    /// Inodes with hard link records restored
+    pub hardlinks: usize,
+}
+
+/// A decrypted metadata archive
+#[derive(Debug)]
+pub struct MetadataArchive {
+    /// Plaintext header
+    pub header: ArchiveHeader,
+    /// Archived records
+    pub contents: ArchiveContents,
+}
+
+impl MetadataArchive {
+    /// Capture the contents of a store (and optionally its hard link records)
+    pub fn export(
+        store: &MetadataStore,
+        hardlinks: Option<&HardLinkStore>,
+        volume: VolumeDescriptor,
+    ) -> Result<Self> {
+        let mut contents = ArchiveContents::default();
+        for op in store.journal_snapshot()? {
+            match op {
+                JournalOp::PutInode(inode) => contents.inodes.push(*inode),
+                JournalOp::PutChunkRef {
+                    chunk_id,
+                    message_id,
+                    ref_count,
+                } => contents.chunk_refs.push((chunk_id, message_id, ref_count)),
+                JournalOp::PutMetadata { key, value } => contents.metadata.push((key, value)),
+                JournalOp::DeleteInode(_) | JournalOp::DeleteChunkRef(_) => {}
+            }
+        }
+        if let Some(hardlinks) = hardlinks {
+            contents.hardlinks = hardlinks.all_links()?;
+        }
+
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Ok(MetadataArchive {
+            header: ArchiveHeader { created, volume },
+            contents,
+        })
+    }
+
+    /// Restore the archive into an empty store
+    pub fn import(&self, store: &MetadataStore, hardlinks: Option<&HardLinkStore>) -> Result<ImportStats> {
+        if store.get_stats()?.inode_count > 1 {
+            return Err(Error::AlreadyExists(
+                "metadata store is not empty; import needs a fresh store".to_string(),
+            ));
+        }
+
+        let mut stats = ImportStats::default();
+        for inode in &self.contents.inodes {
+            store.apply_journal_op(&JournalOp::PutInode(Box::new(inode.clone())))?;
+            stats.inodes += 1;
+        }
+        for (chunk_id, message_id, ref_count) in &self.contents.chunk_refs {
+            store.apply_journal_op(&JournalOp::PutChunkRef {
+                chunk_id: chunk_id.clone(),
+                message_id: *message_id,
+                ref_count: *ref_count,
+            })?;
+            stats.chunk_refs += 1;
+        }
+        for (key, value) in &self.contents.metadata {
+            store.apply_journal_op(&JournalOp::PutMetadata {
+                key: key.clone(),
+                value: value.clone(),
+            })?;
+            stats.metadata += 1;
+        }
+        if let Some(hardlinks) = hardlinks {
+            for (ino, paths) in &self.contents.hardlinks {
+                for path in paths {
+                    hardlinks.create_link(*ino, path)?;
+                }
+                stats.hardlinks += 1;
+            }
+            hardlinks.flush()?;
+        }
+
+        store.clear_cache();
+        store.flush()?;
+        info!(
+            "Imported {} inodes, {} chunk references",
+            stats.inodes, stats.chunk_refs
+        );
+        Ok(stats)
+    }
+
+    /// Encrypt and encode the archive
+    pub fn seal(&self, key: &[u8; KEY_SIZE]) -> Result<Vec<u8>> {
+        let header = serde_json::to_vec(&self.header)?;
+        let payload = encrypt_with(
+            key,
+            &bincode::serialize(&self.contents)?,
+            &Self::aad(&header, ARCHIVE_VERSION),
+            self.header.volume.aead_suite,
+        )?;
+
+        let mut data = Vec::with_capacity(13 + header.len() + payload.size());
+        data.extend_from_slice(ARCHIVE_MAGIC);
+        data.push(ARCHIVE_VERSION);
+        data.extend_from_slice(&(header.len() as u32).to_be_bytes());
+        data.extend_from_slice(&header);
+        data.extend_from_slice(&payload.to_bytes());
+        Ok(data)
+    }
+
+    /// Read the plaintext header without decrypting
+    pub fn read_header(data: &[u8]) -> Result<ArchiveHeader> {
+        let (_, header, _) = Self::split(data)?;
+        Ok(serde_json::from_slice(header)?)
+    }
+
+    /// Decode and decrypt an archive
+    pub fn open(data: &[u8], key: &[u8; KEY_SIZE]) -> Result<Self> {
+        let (version, header_bytes, payload) = Self::split(data)?;
+        let header = serde_json::from_slice(header_bytes)?;
+        let encrypted = EncryptedData::from_bytes(payload)?;
+        let contents = decrypt_bound(key, &encrypted, &Self::aad(header_bytes, version), None)
+            .map_err(|_| Error::Archive("cannot decrypt (wrong password or keyfile?)".to_string()))?;
+        let layout = if version == 1 {
+            InodeLayout::Legacy
+        } else {
+            InodeLayout::Current
+        };
+        Ok(MetadataArchive {
+            header,
+            contents: decode_inodes(&contents, layout)?,
+        })
+    }
+
+    /// Split an archive into its format version, header and payload
+    fn split(data: &[u8]) -> Result<(u8, &[u8], &[u8])> {
+        if data.len() < 13 || &data[..8] != ARCHIVE_MAGIC {
+            return Err(Error::Archive("not a tgcryptfs metadata archive".to_string()));
+        }
+        let version = data[8];
+        if version == 0 || version > ARCHIVE_VERSION {
+            return Err(Error::Archive(format!("unsupported format version {}", version)));
+        }
+        let len = u32::from_be_bytes(data[9..13].try_into().unwrap()) as usize;
+        if data.len() < 13 + len {
+            return Err(Error::Archive("truncated header".to_string()));
+        }
+        Ok((version, &data[13..13 + len], &data[13 + len..]))
+    }
+
+    /// AAD binding the payload to the header and format version
+    fn aad(header: &[u8], version: u8) -> Vec<u8> {
+        object_aad(ObjectKind::Archive, None, header, version as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::EncryptionConfig;
+    use tempfile::TempDir;
+
+    fn volume() -> VolumeDescriptor {
+        VolumeDescriptor::from_config(&EncryptionConfig::default())
+    }
+
+    #[test]
+    fn test_export_import_roundtrip() {
+        let key = [5u8; KEY_SIZE];
+        let source = MetadataStore::in_memory(key).unwrap();
+        let dir = Inode::new_directory(2, 1, "docs".to_string(), 0, 0, 0o755);
+        let mut file = Inode::new_file(3, 2, "a.txt".to_string(), 0, 0, 0o644);
+        file.xattrs.insert("user.tag".to_string(), b"blue".to_vec());
+        source.save_inode(&dir).unwrap();
+        source.save_inode(&file).unwrap();
+        source.save_chunk_ref("c1", 10).unwrap();
+        source.save_chunk_ref("c1", 10).unwrap();
+        source.save_metadata("setting", b"value").unwrap();
+
+        let temp = TempDir::new().unwrap();
+        let links = HardLinkStore::open(temp.path().join("links")).unwrap();
+        links.create_link(3, std::path::Path::new("/docs/a.txt")).unwrap();
+
+        let sealed = MetadataArchive::export(&source, Some(&links), volume())
+            .unwrap()
+            .seal(&key)
+            .unwrap();
+        assert_eq!(MetadataArchive::read_header(&sealed).unwrap().volume, volume());
+
+        let target = MetadataStore::in_memory(key).unwrap();
+        let target_links = HardLinkStore::open(temp.path().join("links2")).unwrap();
+        let stats = MetadataArchive::open(&sealed, &key)
+            .unwrap()
+            .import(&target, Some(&target_links))
+            .unwrap();
+        assert_eq!(stats.inodes, 3);
+        assert_eq!(stats.hardlinks, 1);
+
+        let restored = target.lookup(2, "a.txt").unwrap().unwrap();
+        assert_eq!(restored.xattrs.get("user.tag").unwrap(), b"blue");
+        assert!(target.chunk_refs().unwrap().contains(&("c1".to_string(), 10, 2)));
+        assert_eq!(target.get_metadata("setting").unwrap().unwrap(), b"value");
+        assert_eq!(target_links.get_link_count(3), 1);
+        assert_eq!(target.alloc_ino().unwrap(), 4);
+    }
+
+    #[test]
+    fn test_open_rejects_wrong_key_and_tampered_header() {
+        let key = [5u8; KEY_SIZE];
+        let store = MetadataStore::in_memory(key).unwrap();
+        let mut sealed = MetadataArchive::export(&store, None, volume())
+            .unwrap()
+            .seal(&key)
+            .unwrap();
+
+        assert!(MetadataArchive::open(&sealed, &[6u8; KEY_SIZE]).is_err());
+
+        // Flip a byte inside the JSON header
+        sealed[20] ^= 0x01;
+        assert!(MetadataArchive::open(&sealed, &key).is_err());
+        assert!(MetadataArchive::open(b"not an archive", &key).is_err());
+    }
+
+    #[test]
+    fn test_import_requires_empty_store() {
+        let key = [5u8; KEY_SIZE];
+        let store = MetadataStore::in_memory(key).unwrap();
+        store
+            .save_inode(&Inode::new_file(2, 1, "a".to_string(), 0, 0, 0o644))
+            .unwrap();
+        let archive = MetadataArchive::export(&store, None, volume()).unwrap();
+        assert!(archive.import(&store, None).is_err());
+    }
+}
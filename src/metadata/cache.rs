@@ -0,0 +1,263 @@
+//! Bounded in-memory inode cache
+//!
+//! Inodes are evicted least-recently-used once their approximate memory
+//! footprint exceeds the configured byte capacity. Failed lookups are
+//! remembered separately (keyed by the hashed parent-index key) so that
+//! repeated misses skip the database.
+
+use crate::cache::LruCache;
+use crate::metadata::Inode;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// Inode cache counters and occupancy
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InodeCacheStats {
+    /// Cached inodes
+    pub entries: usize,
+    /// Approximate memory held by cached inodes
+    pub bytes: u64,
+    /// Byte capacity
+    pub capacity: u64,
+    /// Inode reads served from memory
+    pub hits: u64,
+    /// Inode reads that went to the database
+    pub misses: u64,
+    /// Inodes dropped to stay under capacity
+    pub evictions: u64,
+    /// Cached failed lookups
+    pub negative_entries: usize,
+    /// Lookups answered by the negative cache
+    pub negative_hits: u64,
+}
+
+impl InodeCacheStats {
+    /// Fraction of inode reads served from memory
+    pub fn hit_ratio(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+/// Size-bounded LRU cache of inodes with a negative lookup cache
+pub struct InodeCache {
+    /// Cached inodes and their accounted size
+    entries: HashMap<u64, (Inode, usize)>,
+    /// Recency order of cached inodes
+    lru: LruCache<u64>,
+    /// Sum of accounted sizes
+    bytes: usize,
+    /// Byte capacity
+    capacity: usize,
+    /// Parent-index keys known to be absent
+    negative: HashSet<Vec<u8>>,
+    /// Recency order of negative entries
+    negative_lru: LruCache<Vec<u8>>,
+    /// Maximum negative entries
+    negative_capacity: usize,
+    /// Counters
+    stats: InodeCacheStats,
+}
+
+impl InodeCache {
+    /// Create a cache holding at most `capacity` bytes of inodes
+    pub fn new(capacity: u64, negative_capacity: usize) -> Self {
+        InodeCache {
+            entries: HashMap::new(),
+            lru: LruCache::new(),
+            bytes: 0,
+            capacity: capacity as usize,
+            negative: HashSet::new(),
+            negative_lru: LruCache::new(),
+            negative_capacity,
+            stats: InodeCacheStats::default(),
+        }
+    }
+
+    /// Change the limits, evicting as needed
+    pub fn set_limits(&mut self, capacity: u64, negative_capacity: usize) {
+        self.capacity = capacity as usize;
+        self.negative_capacity = negative_capacity;
+        self.evict();
+        self.evict_negative();
+    }
+
+    /// Get a cached inode, counting the hit or miss
+    pub fn get(&mut self, ino: u64) -> Option<Inode> {
+        match self.entries.get(&ino) {
+            Some((inode, _)) => {
+                let inode = inode.clone();
+                self.stats.hits += 1;
+                self.lru.touch(&ino);
+                self.lru.compact_stale();
+                Some(inode)
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Cache an inode, evicting older ones to stay under capacity
+    pub fn insert(&mut self, inode: Inode) {
+        self.remove(inode.ino);
+        let size = inode.memory_size();
+        if size > self.capacity {
+            return;
+        }
+        self.bytes += size;
+        self.lru.insert(inode.ino);
+        self.entries.insert(inode.ino, (inode, size));
+        self.evict();
+    }
+
+    /// Drop an inode
+    pub fn remove(&mut self, ino: u64) {
+        if let Some((_, size)) = self.entries.remove(&ino) {
+            self.bytes -= size;
+            self.lru.remove(&ino);
+            self.lru.compact_stale();
+        }
+    }
+
+    /// Check whether a lookup is known to miss, counting the hit
+    pub fn is_negative(&mut self, key: &[u8]) -> bool {
+        if self.negative.contains(key) {
+            self.stats.negative_hits += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Remember that a lookup missed
+    pub fn insert_negative(&mut self, key: Vec<u8>) {
+        if self.negative_capacity == 0 || !self.negative.insert(key.clone()) {
+            return;
+        }
+        self.negative_lru.insert(key);
+        self.evict_negative();
+    }
+
+    /// Forget a failed lookup once the entry exists
+    pub fn remove_negative(&mut self, key: &[u8]) {
+        if self.negative.remove(key) {
+            self.negative_lru.remove(&key.to_vec());
+            self.negative_lru.compact_stale();
+        }
+    }
+
+    /// Drop everything (counters are kept)
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.lru.clear();
+        self.bytes = 0;
+        self.negative.clear();
+        self.negative_lru.clear();
+    }
+
+    /// Current counters and occupancy
+    pub fn stats(&self) -> InodeCacheStats {
+        InodeCacheStats {
+            entries: self.entries.len(),
+            bytes: self.bytes as u64,
+            capacity: self.capacity as u64,
+            negative_entries: self.negative.len(),
+            ..self.stats.clone()
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.bytes > self.capacity {
+            let Some(ino) = self.lru.pop_oldest() else {
+                break;
+            };
+            if let Some((_, size)) = self.entries.remove(&ino) {
+                self.bytes -= size;
+                self.stats.evictions += 1;
+            }
+        }
+    }
+
+    fn evict_negative(&mut self) {
+        while self.negative.len() > self.negative_capacity {
+            let Some(key) = self.negative_lru.pop_oldest() else {
+                break;
+            };
+            self.negative.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(ino: u64) -> Inode {
+        Inode::new_file(ino, 1, format!("file{}", ino), 1000, 1000, 0o644)
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used_by_size() {
+        let size = file(2).memory_size() as u64;
+        let mut cache = InodeCache::new(size * 3, 0);
+
+        cache.insert(file(2));
+        cache.insert(file(3));
+        cache.insert(file(4));
+        assert!(cache.get(2).is_some());
+
+        // 3 is now the oldest
+        cache.insert(file(5));
+        assert!(cache.get(3).is_none());
+        assert!(cache.get(2).is_some());
+
+        let stats = cache.stats();
+        assert_eq!(stats.entries, 3);
+        assert!(stats.bytes <= stats.capacity);
+        assert_eq!(stats.evictions, 1);
+        assert_eq!(stats.hits, 2);
+        assert_eq!(stats.misses, 1);
+    }
+
+    #[test]
+    fn test_oversized_inode_not_cached() {
+        let mut cache = InodeCache::new(16, 0);
+        cache.insert(file(2));
+        assert!(cache.get(2).is_none());
+        assert_eq!(cache.stats().bytes, 0);
+    }
+
+    #[test]
+    fn test_negative_entries_bounded() {
+        let mut cache = InodeCache::new(0, 2);
+        cache.insert_negative(b"a".to_vec());
+        cache.insert_negative(b"b".to_vec());
+        cache.insert_negative(b"c".to_vec());
+
+        assert!(!cache.is_negative(b"a"));
+        assert!(cache.is_negative(b"c"));
+        cache.remove_negative(b"c");
+        assert!(!cache.is_negative(b"c"));
+
+        let stats = cache.stats();
+        assert_eq!(stats.negative_entries, 1);
+        assert_eq!(stats.negative_hits, 1);
+    }
+
+    #[test]
+    fn test_negative_order_compacted_after_creates() {
+        let mut cache = InodeCache::new(0, 100);
+        for i in 0..10_000u32 {
+            let key = i.to_le_bytes().to_vec();
+            cache.insert_negative(key.clone());
+            cache.remove_negative(&key);
+        }
+        assert!(cache.negative_lru.order_len() <= 1024);
+    }
+}
@@ -0,0 +1,751 @@
+//! Offline consistency checker for the metadata store
+//!
+//! Cross-checks the inode tree (parent pointers, the parent index, directory
+//! entries and link counts), chunk reference counts against file manifests,
+//! and optionally chunk message IDs against the backend listing. Parent
+//! pointers are treated as authoritative: index and directory entries are
+//! repaired to match them, and inodes whose parent chain does not reach the
+//! root are moved to `/lost+found`.
+
+use crate::chunk::PENDING_MESSAGE_ID;
+use crate::error::Result;
+use crate::metadata::{Inode, MetadataStore};
+use crate::telegram::{TelegramMessage, CHUNK_FILE_PREFIX};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use tracing::{info, warn};
+
+/// Root inode number
+const ROOT_INO: u64 = 1;
+
+/// Directory that receives orphaned inodes
+pub const LOST_AND_FOUND: &str = "lost+found";
+
+/// A single inconsistency found by fsck
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsckProblem {
+    /// Inode blob could not be decrypted or decoded
+    UnreadableInode { ino: u64, error: String },
+    /// Index entry points to an inode that does not exist
+    DanglingIndexEntry { key: Vec<u8>, parent: u64, ino: u64 },
+    /// Index entry no longer matches the inode's parent and name
+    StaleIndexEntry { key: Vec<u8>, parent: u64, ino: u64 },
+    /// Inode cannot be found through the index
+    MissingIndexEntry { ino: u64 },
+    /// Directory entry for an inode that does not exist or lives elsewhere
+    DanglingDirEntry { parent: u64, cookie: u64 },
+    /// Inode has no directory entry in its parent
+    MissingDirEntry { parent: u64, child: u64 },
+    /// Inode's parent chain does not reach the root
+    Orphan { ino: u64 },
+    /// Link count differs from the tree structure
+    WrongLinkCount { ino: u64, expected: u32, actual: u32 },
+    /// Stored reference count differs from manifest usage
+    RefCountMismatch { chunk_id: String, expected: u32, actual: u32 },
+    /// Chunk reference not used by any manifest
+    UnreferencedChunk { chunk_id: String, message_id: i32 },
+    /// Manifest uses a chunk with no reference entry
+    MissingChunkRef { chunk_id: String, message_id: i32, uses: u32 },
+    /// Manifest and chunk reference disagree on the message ID
+    MessageIdMismatch { ino: u64, chunk_id: String, manifest: i32, stored: i32 },
+    /// Referenced chunk message is not present in the backend
+    MissingRemoteChunk { chunk_id: String, message_id: i32 },
+    /// Chunk message in the backend that nothing references
+    ///
+    /// Only messages named like this volume's chunk uploads are reported.
+    /// Chunk names carry no volume identity, so another volume or namespace
+    /// on the same account can own them.
+    OrphanedUpload { message_id: i32, name: String },
+}
+
+impl FsckProblem {
+    /// Check whether `repair` can fix this problem in the store
+    pub fn is_repairable(&self) -> bool {
+        !matches!(
+            self,
+            FsckProblem::UnreadableInode { .. }
+                | FsckProblem::MessageIdMismatch { .. }
+                | FsckProblem::MissingRemoteChunk { .. }
+                | FsckProblem::OrphanedUpload { .. }
+        )
+    }
+}
+
+impl fmt::Display for FsckProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FsckProblem::UnreadableInode { ino, error } => {
+                write!(f, "inode {} is unreadable: {}", ino, error)
+            }
+            FsckProblem::DanglingIndexEntry { parent, ino, .. } => {
+                write!(f, "index entry in {} points to missing inode {}", parent, ino)
+            }
+            FsckProblem::StaleIndexEntry { parent, ino, .. } => {
+                write!(f, "stale index entry in {} for inode {}", parent, ino)
+            }
+            FsckProblem::MissingIndexEntry { ino } => {
+                write!(f, "inode {} has no index entry", ino)
+            }
+            FsckProblem::DanglingDirEntry { parent, cookie } => {
+                write!(f, "directory {} has stale entry {}", parent, cookie)
+            }
+            FsckProblem::MissingDirEntry { parent, child } => {
+                write!(f, "inode {} is missing from directory {}", child, parent)
+            }
+            FsckProblem::Orphan { ino } => write!(f, "inode {} is unreachable from root", ino),
+            FsckProblem::WrongLinkCount {
+                ino,
+                expected,
+                actual,
+            } => write!(f, "inode {} has nlink {}, expected {}", ino, actual, expected),
+            FsckProblem::RefCountMismatch {
+                chunk_id,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "chunk {} has ref count {}, expected {}",
+                chunk_id, actual, expected
+            ),
+            FsckProblem::UnreferencedChunk {
+                chunk_id,
+                message_id,
+            } => write!(
+                f,
+                "chunk {} (message {}) is not used by any file",
+                chunk_id, message_id
+            ),
+            FsckProblem::MissingChunkRef {
+                chunk_id,
+                message_id,
+                ..
+            } => write!(
+                f,
+                "chunk {} (message {}) has no reference entry",
+                chunk_id, message_id
+            ),
+            FsckProblem::MessageIdMismatch {
+                ino,
+                chunk_id,
+                manifest,
+                stored,
+            } => write!(
+                f,
+                "inode {} uses chunk {} as message {}, reference says {}",
+                ino, chunk_id, manifest, stored
+            ),
+            FsckProblem::MissingRemoteChunk {
+                chunk_id,
+                message_id,
+            } => write!(
+                f,
+                "chunk {} (message {}) is missing from the backend",
+                chunk_id, message_id
+            ),
+            FsckProblem::OrphanedUpload { message_id, name } => {
+                write!(f, "backend message {} ({}) is not referenced", message_id, name)
+            }
+        }
+    }
+}
+
+/// Result of a consistency check
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    /// Problems found
+    pub problems: Vec<FsckProblem>,
+    /// Inodes examined
+    pub inodes_checked: usize,
+    /// Chunk references examined
+    pub chunks_checked: usize,
+    /// Chunks still waiting in the offline upload queue
+    pub queued_uploads: usize,
+}
+
+impl FsckReport {
+    /// Check whether the store is consistent
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// Number of problems `repair` can fix
+    pub fn repairable(&self) -> usize {
+        self.problems.iter().filter(|p| p.is_repairable()).count()
+    }
+}
+
+/// Check the store, and the backend listing when one is given
+pub fn check(store: &MetadataStore, remote: Option<&[TelegramMessage]>) -> Result<FsckReport> {
+    let mut report = FsckReport::default();
+
+    // Load every inode
+    let mut inodes: BTreeMap<u64, Inode> = BTreeMap::new();
+    for ino in store.inode_numbers()? {
+        match store.get_inode(ino) {
+            Ok(Some(inode)) => {
+                inodes.insert(ino, inode);
+            }
+            Ok(None) => {}
+            Err(e) => report.problems.push(FsckProblem::UnreadableInode {
+                ino,
+                error: e.to_string(),
+            }),
+        }
+    }
+    report.inodes_checked = inodes.len();
+
+    check_entries(store, &inodes, &mut report)?;
+    check_tree(&inodes, &mut report);
+    check_chunks(store, &inodes, remote, &mut report)?;
+
+    info!(
+        "fsck: {} inodes, {} chunk refs, {} problems",
+        report.inodes_checked,
+        report.chunks_checked,
+        report.problems.len()
+    );
+    Ok(report)
+}
+
+/// Cross-check the parent index and directory entries against the inodes
+fn check_entries(
+    store: &MetadataStore,
+    inodes: &BTreeMap<u64, Inode>,
+    report: &mut FsckReport,
+) -> Result<()> {
+    for (key, parent, ino) in store.index_entries()? {
+        match inodes.get(&ino) {
+            None => report
+                .problems
+                .push(FsckProblem::DanglingIndexEntry { key, parent, ino }),
+            Some(inode) if !store.is_index_key_for(&key, inode) => report
+                .problems
+                .push(FsckProblem::StaleIndexEntry { key, parent, ino }),
+            Some(_) => {}
+        }
+    }
+
+    for (parent, cookie, entry) in store.dir_entries()? {
+        let inode = entry.as_ref().and_then(|e| inodes.get(&e.ino));
+        let valid = match (inode, &entry) {
+            (Some(inode), Some(entry)) => {
+                inode.parent == parent
+                    && inode.name == entry.name
+                    && store.entry_cookie(inode)? == Some(cookie)
+            }
+            _ => false,
+        };
+        if !valid {
+            report
+                .problems
+                .push(FsckProblem::DanglingDirEntry { parent, cookie });
+        }
+    }
+
+    for inode in inodes.values().filter(|i| i.ino != i.parent) {
+        match store.entry_cookie(inode)? {
+            None => report
+                .problems
+                .push(FsckProblem::MissingIndexEntry { ino: inode.ino }),
+            Some(cookie) if !store.has_dir_entry(inode.parent, cookie)? => {
+                report.problems.push(FsckProblem::MissingDirEntry {
+                    parent: inode.parent,
+                    child: inode.ino,
+                })
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(())
+}
+
+/// Check reachability and link counts
+fn check_tree(inodes: &BTreeMap<u64, Inode>, report: &mut FsckReport) {
+    let orphans = find_orphans(inodes);
+    for inode in inodes.values() {
+        if inode.ino == ROOT_INO {
+            continue;
+        }
+        if orphans.contains(&inode.ino) {
+            report.problems.push(FsckProblem::Orphan { ino: inode.ino });
+        }
+    }
+
+    let mut subdirs: HashMap<u64, u32> = HashMap::new();
+    for inode in inodes.values() {
+        if inode.is_dir() && inode.ino != ROOT_INO && !orphans.contains(&inode.ino) {
+            *subdirs.entry(inode.parent).or_default() += 1;
+        }
+    }
+    for inode in inodes.values() {
+        if orphans.contains(&inode.ino) {
+            continue;
+        }
+        let expected = if inode.is_dir() {
+            2 + subdirs.get(&inode.ino).copied().unwrap_or(0)
+        } else {
+            1
+        };
+        if inode.attrs.nlink != expected {
+            report.problems.push(FsckProblem::WrongLinkCount {
+                ino: inode.ino,
+                expected,
+                actual: inode.attrs.nlink,
+            });
+        }
+    }
+}
+
+/// Find inodes whose parent chain does not reach the root
+fn find_orphans(inodes: &BTreeMap<u64, Inode>) -> HashSet<u64> {
+    let mut reachable: HashSet<u64> = HashSet::new();
+    let mut orphans = HashSet::new();
+    if inodes.contains_key(&ROOT_INO) {
+        reachable.insert(ROOT_INO);
+    }
+
+    for &start in inodes.keys() {
+        let mut path = Vec::new();
+        let mut seen = HashSet::new();
+        let mut current = start;
+        let ok = loop {
+            if reachable.contains(&current) {
+                break true;
+            }
+            if orphans.contains(&current) || !seen.insert(current) {
+                break false;
+            }
+            match inodes.get(&current) {
+                Some(inode) if current == start || inode.is_dir() => {
+                    path.push(current);
+                    current = inode.parent;
+                }
+                _ => break false,
+            }
+        };
+        if ok {
+            reachable.extend(path);
+        } else {
+            orphans.extend(path);
+        }
+    }
+    orphans
+}
+
+/// Cross-check chunk references against manifests and the backend
+fn check_chunks(
+    store: &MetadataStore,
+    inodes: &BTreeMap<u64, Inode>,
+    remote: Option<&[TelegramMessage]>,
+    report: &mut FsckReport,
+) -> Result<()> {
+    let refs: HashMap<String, (i32, u32)> = store
+        .chunk_refs()?
+        .into_iter()
+        .map(|(id, message_id, count)| (id, (message_id, count)))
+        .collect();
+    report.chunks_checked = refs.len();
+
+    // Count how often each chunk is used across manifests
+    let mut uses: BTreeMap<&str, (i32, u32)> = BTreeMap::new();
+    for inode in inodes.values() {
+        let Some(manifest) = &inode.manifest else {
+            continue;
+        };
+        for chunk in &manifest.chunks {
+            uses.entry(&chunk.id).or_insert((chunk.message_id, 0)).1 += 1;
+            if let Some(&(stored, _)) = refs.get(&chunk.id) {
+                if stored != chunk.message_id {
+                    report.problems.push(FsckProblem::MessageIdMismatch {
+                        ino: inode.ino,
+                        chunk_id: chunk.id.clone(),
+                        manifest: chunk.message_id,
+                        stored,
+                    });
+                }
+            }
+        }
+    }
+
+    for (&chunk_id, &(message_id, count)) in &uses {
+        match refs.get(chunk_id) {
+            None => report.problems.push(FsckProblem::MissingChunkRef {
+                chunk_id: chunk_id.to_string(),
+                message_id,
+                uses: count,
+            }),
+            Some(&(_, stored)) if stored != count => {
+                report.problems.push(FsckProblem::RefCountMismatch {
+                    chunk_id: chunk_id.to_string(),
+                    expected: count,
+                    actual: stored,
+                })
+            }
+            Some(_) => {}
+        }
+    }
+
+    let mut ref_ids: Vec<_> = refs.iter().collect();
+    ref_ids.sort();
+    for (chunk_id, &(message_id, _)) in &ref_ids {
+        if !uses.contains_key(chunk_id.as_str()) {
+            report.problems.push(FsckProblem::UnreferencedChunk {
+                chunk_id: chunk_id.to_string(),
+                message_id,
+            });
+        }
+    }
+
+    if let Some(remote) = remote {
+        let present: HashSet<i32> = remote.iter().map(|m| m.id).collect();
+        let referenced: HashSet<i32> = refs
+            .values()
+            .map(|&(id, _)| id)
+            .chain(uses.values().map(|&(id, _)| id))
+            .collect();
+
+        for (chunk_id, &(message_id, _)) in &ref_ids {
+            // Written offline; the chunk is in the upload queue, not the backend
+            if message_id == PENDING_MESSAGE_ID {
+                report.queued_uploads += 1;
+                continue;
+            }
+            if !present.contains(&message_id) {
+                report.problems.push(FsckProblem::MissingRemoteChunk {
+                    chunk_id: chunk_id.to_string(),
+                    message_id,
+                });
+            }
+        }
+        for message in remote {
+            let name = message.filename.as_deref().unwrap_or_default();
+            if is_chunk_upload_name(name) && !referenced.contains(&message.id) {
+                report.problems.push(FsckProblem::OrphanedUpload {
+                    message_id: message.id,
+                    name: name.to_string(),
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Check whether a message name is a whole-chunk upload of a volume
+///
+/// Chunk IDs are BLAKE3 content hashes; snapshots and RAID blocks share the
+/// prefix but not the ID format.
+pub fn is_chunk_upload_name(name: &str) -> bool {
+    name.strip_prefix(CHUNK_FILE_PREFIX).is_some_and(|id| {
+        id.len() == 64 && id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    })
+}
+
+/// Statistics from a repair run
+#[derive(Debug, Default)]
+pub struct RepairStats {
+    /// Problems fixed
+    pub repaired: usize,
+    /// Problems left for manual attention
+    pub skipped: usize,
+    /// Inodes moved to lost+found
+    pub moved_to_lost_found: usize,
+}
+
+/// Fix every repairable problem in a report
+///
+/// Run `check` again afterwards; moving orphans can change link counts.
+pub fn repair(store: &MetadataStore, report: &FsckReport) -> Result<RepairStats> {
+    let mut stats = RepairStats::default();
+    let mut orphans = Vec::new();
+
+    for problem in &report.problems {
+        match problem {
+            FsckProblem::DanglingIndexEntry { key, .. } | FsckProblem::StaleIndexEntry { key, .. } => {
+                store.remove_index_entry(key)?;
+            }
+            FsckProblem::MissingIndexEntry { ino: child } | FsckProblem::MissingDirEntry { child, .. } => {
+                // Saving rewrites the missing index and directory entries
+                let inode = store.get_inode_required(*child)?;
+                store.save_inode(&inode)?;
+            }
+            FsckProblem::DanglingDirEntry { parent, cookie } => {
+                store.remove_dir_entry(*parent, *cookie)?;
+            }
+            FsckProblem::Orphan { ino } => orphans.push(*ino),
+            FsckProblem::WrongLinkCount { ino, expected, .. } => {
+                let mut inode = store.get_inode_required(*ino)?;
+                inode.attrs.nlink = *expected;
+                store.save_inode(&inode)?;
+            }
+            FsckProblem::RefCountMismatch {
+                chunk_id, expected, ..
+            } => {
+                if let Some(message_id) = store.get_chunk_ref(chunk_id)? {
+                    store.set_chunk_ref(chunk_id, message_id, *expected)?;
+                }
+            }
+            FsckProblem::UnreferencedChunk { chunk_id, .. } => {
+                store.remove_chunk_ref(chunk_id)?;
+            }
+            FsckProblem::MissingChunkRef {
+                chunk_id,
+                message_id,
+                uses,
+            } => {
+                store.set_chunk_ref(chunk_id, *message_id, *uses)?;
+            }
+            _ => {
+                stats.skipped += 1;
+                continue;
+            }
+        }
+        stats.repaired += 1;
+    }
+
+    if !orphans.is_empty() {
+        stats.moved_to_lost_found = move_to_lost_found(store, &orphans)?;
+    }
+
+    store.flush()?;
+    Ok(stats)
+}
+
+/// Reattach orphaned inodes under /lost+found
+///
+/// Only the top of each orphaned subtree is moved; its descendants follow.
+fn move_to_lost_found(store: &MetadataStore, orphans: &[u64]) -> Result<usize> {
+    let lost_found = match store.lookup(ROOT_INO, LOST_AND_FOUND)? {
+        Some(dir) if dir.is_dir() => dir,
+        _ => {
+            let mut root = store.get_inode_required(ROOT_INO)?;
+            let dir = Inode::new_directory(
+                store.alloc_ino()?,
+                ROOT_INO,
+                LOST_AND_FOUND.to_string(),
+                root.attrs.uid,
+                root.attrs.gid,
+                0o700,
+            )
+            .with_generation(store.generation());
+            root.attrs.nlink += 1;
+            let mut txn = store.transaction();
+            txn.save_inode(&dir).save_inode(&root);
+            txn.commit()?;
+            dir
+        }
+    };
+
+    let orphan_set: HashSet<u64> = orphans.iter().copied().collect();
+    let mut lost_found = lost_found;
+    let mut moved = 0;
+
+    for &ino in orphans {
+        let mut inode = store.get_inode_required(ino)?;
+        // Descendants of another orphan move along with it
+        if orphan_set.contains(&inode.parent) && !in_cycle(store, ino, &orphan_set)? {
+            if let Ok(parent) = store.get_inode_required(inode.parent) {
+                if parent.is_dir() {
+                    continue;
+                }
+            }
+        }
+
+        inode.parent = lost_found.ino;
+        inode.name = format!("#{}", ino);
+        lost_found.attrs.touch();
+        if inode.is_dir() {
+            lost_found.attrs.nlink += 1;
+        }
+        let mut txn = store.transaction();
+        txn.save_inode(&inode).save_inode(&lost_found);
+        txn.commit()?;
+        warn!("Moved orphaned inode {} to /{}", ino, LOST_AND_FOUND);
+        moved += 1;
+    }
+
+    Ok(moved)
+}
+
+/// Check whether an orphan's parent chain loops back to itself
+fn in_cycle(store: &MetadataStore, ino: u64, orphans: &HashSet<u64>) -> Result<bool> {
+    let mut seen = HashSet::new();
+    let mut current = store.get_inode_required(ino)?.parent;
+    while orphans.contains(&current) && seen.insert(current) {
+        if current == ino {
+            return Ok(true);
+        }
+        current = store.get_inode_required(current)?.parent;
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::KEY_SIZE;
+    use crate::fs::testing::file_with_chunk_messages;
+
+    fn store() -> MetadataStore {
+        MetadataStore::in_memory([6u8; KEY_SIZE]).unwrap()
+    }
+
+    fn add_file(store: &MetadataStore, parent: u64, name: &str, chunks: &[(&str, i32)]) -> Inode {
+        let mut file = file_with_chunk_messages(store.alloc_ino().unwrap(), parent, chunks);
+        file.name = name.to_string();
+        store.save_inode(&file).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_clean_store() {
+        let store = store();
+        add_file(&store, ROOT_INO, "a", &[("c1", 10)]);
+        store.save_chunk_ref("c1", 10).unwrap();
+
+        let report = check(&store, None).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+    }
+
+    #[test]
+    fn test_detects_and_repairs_tree_problems() {
+        let store = store();
+        let file = add_file(&store, ROOT_INO, "a", &[]);
+
+        // Inode pointing at a parent that no longer exists
+        let lost = Inode::new_file(store.alloc_ino().unwrap(), 999, "lost".to_string(), 0, 0, 0o644);
+        store.save_inode(&lost).unwrap();
+
+        // A real file loses its directory entry
+        let cookie = store.entry_cookie(&file).unwrap().unwrap();
+        store.remove_dir_entry(ROOT_INO, cookie).unwrap();
+
+        // A deleted inode leaves its directory entry behind
+        let ghost = add_file(&store, ROOT_INO, "ghost", &[]);
+        let ghost_cookie = store.entry_cookie(&ghost).unwrap().unwrap();
+        let (ghost_key, _, _) = store
+            .index_entries()
+            .unwrap()
+            .into_iter()
+            .find(|(_, _, ino)| *ino == ghost.ino)
+            .unwrap();
+        store.remove_index_entry(&ghost_key).unwrap();
+        store.delete_inode(ghost.ino).unwrap();
+
+        let report = check(&store, None).unwrap();
+        assert!(report.problems.contains(&FsckProblem::Orphan { ino: lost.ino }));
+        assert!(report.problems.contains(&FsckProblem::DanglingDirEntry {
+            parent: ROOT_INO,
+            cookie: ghost_cookie
+        }));
+        assert!(report.problems.contains(&FsckProblem::MissingDirEntry {
+            parent: ROOT_INO,
+            child: file.ino
+        }));
+
+        let stats = repair(&store, &report).unwrap();
+        assert_eq!(stats.moved_to_lost_found, 1);
+
+        let report = check(&store, None).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        let lost_found = store.lookup(ROOT_INO, LOST_AND_FOUND).unwrap().unwrap();
+        let moved = store.lookup(lost_found.ino, &format!("#{}", lost.ino)).unwrap();
+        assert!(moved.is_some());
+        let names: Vec<String> = store
+            .read_dir(ROOT_INO, 0, 10)
+            .unwrap()
+            .into_iter()
+            .map(|(_, entry)| entry.name)
+            .collect();
+        assert_eq!(names, vec!["a".to_string(), LOST_AND_FOUND.to_string()]);
+    }
+
+    #[test]
+    fn test_rename_leaves_no_stale_index_entry() {
+        let store = store();
+        let mut file = add_file(&store, ROOT_INO, "old", &[]);
+        file.name = "new".to_string();
+        store.save_inode(&file).unwrap();
+
+        assert!(store.lookup(ROOT_INO, "old").unwrap().is_none());
+        assert!(check(&store, None).unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_chunk_refcounts() {
+        let store = store();
+        add_file(&store, ROOT_INO, "a", &[("c1", 10), ("c1", 10)]);
+        add_file(&store, ROOT_INO, "b", &[("c2", 11)]);
+        store.save_chunk_ref("c1", 10).unwrap();
+        store.save_chunk_ref("stale", 12).unwrap();
+        add_file(&store, ROOT_INO, "offline", &[("queued", PENDING_MESSAGE_ID)]);
+        store.save_chunk_ref("queued", PENDING_MESSAGE_ID).unwrap();
+
+        let remote = vec![
+            TelegramMessage {
+                id: 10,
+                filename: Some(format!("{}c1", CHUNK_FILE_PREFIX)),
+                size: 1,
+                date: 0,
+            },
+            TelegramMessage {
+                id: 99,
+                filename: Some(format!("{}{}", CHUNK_FILE_PREFIX, "ab".repeat(32))),
+                size: 1,
+                date: 0,
+            },
+            // Snapshots and RAID blocks share the prefix but are not chunks
+            TelegramMessage {
+                id: 100,
+                filename: Some(format!("{}tgfs_snapshot_ns_1", CHUNK_FILE_PREFIX)),
+                size: 1,
+                date: 0,
+            },
+            TelegramMessage {
+                id: 101,
+                filename: Some(format!("{}{}_3", CHUNK_FILE_PREFIX, "ab".repeat(32))),
+                size: 1,
+                date: 0,
+            },
+        ];
+
+        let report = check(&store, Some(&remote)).unwrap();
+        assert!(report.problems.contains(&FsckProblem::RefCountMismatch {
+            chunk_id: "c1".to_string(),
+            expected: 2,
+            actual: 1
+        }));
+        assert!(report.problems.contains(&FsckProblem::MissingChunkRef {
+            chunk_id: "c2".to_string(),
+            message_id: 11,
+            uses: 1
+        }));
+        assert!(report.problems.contains(&FsckProblem::UnreferencedChunk {
+            chunk_id: "stale".to_string(),
+            message_id: 12
+        }));
+        assert!(report.problems.contains(&FsckProblem::MissingRemoteChunk {
+            chunk_id: "stale".to_string(),
+            message_id: 12
+        }));
+        let orphans: Vec<_> = report
+            .problems
+            .iter()
+            .filter_map(|p| match p {
+                FsckProblem::OrphanedUpload { message_id, .. } => Some(*message_id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(orphans, vec![99]);
+        assert!(!report.problems.iter().any(|p| matches!(
+            p,
+            FsckProblem::MissingRemoteChunk { chunk_id, .. } if chunk_id == "queued"
+        )));
+        assert_eq!(report.queued_uploads, 1);
+
+        repair(&store, &report).unwrap();
+        let report = check(&store, None).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+        assert!(store.get_chunk_ref("stale").unwrap().is_none());
+    }
+}
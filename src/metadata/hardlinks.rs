@@ -223,6 +223,26 @@ impl HardLinkStore {
         self.link_counts.len()
     }
 
+    /// Get every tracked inode with its paths
+    ///
+    /// # Returns
+    /// `(inode, paths)` pairs in inode order
+    ///
+    /// # Errors
+    /// Returns an error if the database operation fails
+    pub fn all_links(&self) -> Result<Vec<(u64, Vec<PathBuf>)>> {
+        let mut links = Vec::new();
+        for item in self.inode_paths.iter() {
+            let (key, bytes) = item?;
+            if key.len() < 8 {
+                continue;
+            }
+            let inode = u64::from_be_bytes(key[..8].try_into().unwrap());
+            links.push((inode, bincode::deserialize(&bytes)?));
+        }
+        Ok(links)
+    }
+
     /// Remove all tracking data for an inode
     ///
     /// Useful for cleanup operations when an inode is deleted.
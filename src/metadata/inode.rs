@@ -3,8 +3,11 @@
 //! Each file and directory is represented by an inode with
 //! associated attributes and chunk references.
 
-use crate::chunk::ChunkManifest;
-use serde::{Deserialize, Serialize};
+use crate::chunk::{ChunkManifest, ChunkRef};
+use crate::error::Result;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::cell::Cell;
 use std::time::SystemTime;
 
 /// File type enumeration
@@ -29,6 +32,55 @@ impl FileType {
     }
 }
 
+/// Nanosecond timestamps that may predate the Unix epoch
+///
+/// Serde's own `SystemTime` encoding rejects times before 1970, which `tar`
+/// and `touch -d` can set. Seconds are stored signed; for times after the
+/// epoch the encoding is identical to serde's, so existing inodes decode.
+mod timestamp {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    #[derive(Serialize, Deserialize)]
+    #[serde(rename = "SystemTime")]
+    struct Timestamp {
+        secs_since_epoch: i64,
+        nanos_since_epoch: u32,
+    }
+
+    pub fn serialize<S: Serializer>(time: &SystemTime, serializer: S) -> Result<S::Ok, S::Error> {
+        let timestamp = match time.duration_since(UNIX_EPOCH) {
+            Ok(d) => Timestamp {
+                secs_since_epoch: d.as_secs() as i64,
+                nanos_since_epoch: d.subsec_nanos(),
+            },
+            Err(e) => {
+                // Round down so the nanoseconds stay non-negative
+                let before = e.duration();
+                let (secs, nanos) = match before.subsec_nanos() {
+                    0 => (-(before.as_secs() as i64), 0),
+                    n => (-(before.as_secs() as i64) - 1, 1_000_000_000 - n),
+                };
+                Timestamp {
+                    secs_since_epoch: secs,
+                    nanos_since_epoch: nanos,
+                }
+            }
+        };
+        timestamp.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SystemTime, D::Error> {
+        let t = Timestamp::deserialize(deserializer)?;
+        let nanos = Duration::from_nanos(t.nanos_since_epoch as u64);
+        Ok(if t.secs_since_epoch >= 0 {
+            UNIX_EPOCH + Duration::from_secs(t.secs_since_epoch as u64) + nanos
+        } else {
+            UNIX_EPOCH - Duration::from_secs(t.secs_since_epoch.unsigned_abs()) + nanos
+        })
+    }
+}
+
 /// Inode attributes (POSIX-like)
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InodeAttributes {
@@ -37,12 +89,16 @@ pub struct InodeAttributes {
     /// Number of blocks
     pub blocks: u64,
     /// Access time
+    #[serde(with = "timestamp")]
     pub atime: SystemTime,
     /// Modification time
+    #[serde(with = "timestamp")]
     pub mtime: SystemTime,
     /// Change time
+    #[serde(with = "timestamp")]
     pub ctime: SystemTime,
-    /// Creation time
+    /// Creation (birth) time
+    #[serde(with = "timestamp")]
     pub crtime: SystemTime,
     /// File type
     pub kind: FileType,
@@ -155,8 +211,34 @@ impl InodeAttributes {
     }
 }
 
+/// A directory entry, stored apart from the directory inode
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DirEntry {
+    /// Inode number of the entry
+    pub ino: u64,
+    /// Entry name
+    pub name: String,
+    /// Entry type
+    pub kind: FileType,
+}
+
+impl DirEntry {
+    /// Build the entry under which an inode appears in its parent
+    pub fn for_inode(inode: &Inode) -> Self {
+        DirEntry {
+            ino: inode.ino,
+            name: inode.name.clone(),
+            kind: inode.attrs.kind,
+        }
+    }
+}
+
 /// Inode representing a file or directory
+///
+/// Decode stored inodes with [`decode_inodes`], which also reads inodes
+/// written before they had a generation.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(remote = "Self")]
 pub struct Inode {
     /// Inode number
     pub ino: u64,
@@ -170,12 +252,98 @@ pub struct Inode {
     pub manifest: Option<ChunkManifest>,
     /// Symlink target (for symlinks)
     pub symlink_target: Option<String>,
-    /// Child inode numbers (for directories)
-    pub children: Vec<u64>,
+    /// Child list from before directory entries were stored separately
+    ///
+    /// Kept so older inodes still decode; emptied by the store's migration
+    /// and never written again. Use `MetadataStore::read_dir` instead.
+    pub legacy_children: Vec<u64>,
     /// Current version number
     pub version: u64,
     /// Extended attributes
     pub xattrs: std::collections::HashMap<String, Vec<u8>>,
+    /// Generation distinguishing reuses of the same inode number
+    ///
+    /// Zero for inodes written before generations existed.
+    pub generation: u64,
+}
+
+/// Inode layout before generations were added
+#[derive(Deserialize)]
+#[cfg_attr(test, derive(Serialize))]
+struct LegacyInode {
+    ino: u64,
+    parent: u64,
+    name: String,
+    attrs: InodeAttributes,
+    manifest: Option<ChunkManifest>,
+    symlink_target: Option<String>,
+    legacy_children: Vec<u64>,
+    version: u64,
+    xattrs: std::collections::HashMap<String, Vec<u8>>,
+}
+
+impl From<LegacyInode> for Inode {
+    fn from(legacy: LegacyInode) -> Self {
+        Inode {
+            ino: legacy.ino,
+            parent: legacy.parent,
+            name: legacy.name,
+            attrs: legacy.attrs,
+            manifest: legacy.manifest,
+            symlink_target: legacy.symlink_target,
+            legacy_children: legacy.legacy_children,
+            version: legacy.version,
+            xattrs: legacy.xattrs,
+            generation: 0,
+        }
+    }
+}
+
+/// Serialized layout of inodes
+///
+/// Bincode data does not describe itself, and a value holding several inodes
+/// can decode in the wrong layout without an error, so the layout is never
+/// guessed: every container records it in a format version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InodeLayout {
+    /// Before inodes had a generation
+    Legacy,
+    /// Current layout
+    Current,
+}
+
+thread_local! {
+    /// Set while [`decode_inodes`] decodes the legacy layout
+    static LEGACY_LAYOUT: Cell<bool> = const { Cell::new(false) };
+}
+
+impl Serialize for Inode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        Inode::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Inode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        if LEGACY_LAYOUT.with(Cell::get) {
+            LegacyInode::deserialize(deserializer).map(Inode::from)
+        } else {
+            Inode::deserialize(deserializer)
+        }
+    }
+}
+
+/// Decode bincode data holding inodes (alone or inside another value)
+///
+/// Inodes in the legacy layout read with a generation of zero.
+pub fn decode_inodes<T: DeserializeOwned>(data: &[u8], layout: InodeLayout) -> Result<T> {
+    if layout == InodeLayout::Current {
+        return Ok(bincode::deserialize(data)?);
+    }
+    LEGACY_LAYOUT.with(|legacy| legacy.set(true));
+    let decoded = bincode::deserialize(data);
+    LEGACY_LAYOUT.with(|legacy| legacy.set(false));
+    Ok(decoded?)
 }
 
 impl Inode {
@@ -188,9 +356,10 @@ impl Inode {
             attrs: InodeAttributes::new_directory(uid, gid, perm),
             manifest: None,
             symlink_target: None,
-            children: Vec::new(),
+            legacy_children: Vec::new(),
             version: 0,
             xattrs: std::collections::HashMap::new(),
+            generation: 0,
         }
     }
 
@@ -203,9 +372,10 @@ impl Inode {
             attrs: InodeAttributes::new_file(uid, gid, perm),
             manifest: Some(ChunkManifest::new(0)),
             symlink_target: None,
-            children: Vec::new(),
+            legacy_children: Vec::new(),
             version: 0,
             xattrs: std::collections::HashMap::new(),
+            generation: 0,
         }
     }
 
@@ -225,9 +395,10 @@ impl Inode {
             attrs: InodeAttributes::new_directory(uid, gid, perm),
             manifest: None,
             symlink_target: None,
-            children: Vec::new(),
+            legacy_children: Vec::new(),
             version: 0,
             xattrs: std::collections::HashMap::new(),
+            generation: 0,
         }
     }
 
@@ -248,12 +419,19 @@ impl Inode {
             attrs: InodeAttributes::new_symlink(uid, gid, target_len),
             manifest: None,
             symlink_target: Some(target),
-            children: Vec::new(),
+            legacy_children: Vec::new(),
             version: 0,
             xattrs: std::collections::HashMap::new(),
+            generation: 0,
         }
     }
 
+    /// Set the generation number
+    pub fn with_generation(mut self, generation: u64) -> Self {
+        self.generation = generation;
+        self
+    }
+
     /// Check if this is a directory
     pub fn is_dir(&self) -> bool {
         self.attrs.kind == FileType::Directory
@@ -269,20 +447,6 @@ impl Inode {
         self.attrs.kind == FileType::Symlink
     }
 
-    /// Add a child to a directory
-    pub fn add_child(&mut self, child_ino: u64) {
-        if !self.children.contains(&child_ino) {
-            self.children.push(child_ino);
-            self.attrs.touch();
-        }
-    }
-
-    /// Remove a child from a directory
-    pub fn remove_child(&mut self, child_ino: u64) {
-        self.children.retain(|&c| c != child_ino);
-        self.attrs.touch();
-    }
-
     /// Update file size
     pub fn set_size(&mut self, size: u64) {
         self.attrs.size = size;
@@ -294,18 +458,77 @@ impl Inode {
     pub fn bump_version(&mut self) {
         self.version += 1;
     }
+
+    /// Approximate heap and inline memory held by this inode
+    pub fn memory_size(&self) -> usize {
+        let manifest = self.manifest.as_ref().map_or(0, |m| {
+            m.file_hash.capacity()
+                + m.chunks
+                    .iter()
+                    .map(|c| std::mem::size_of::<ChunkRef>() + c.id.capacity())
+                    .sum::<usize>()
+        });
+        let xattrs: usize = self
+            .xattrs
+            .iter()
+            .map(|(k, v)| k.capacity() + v.capacity() + 2 * std::mem::size_of::<usize>())
+            .sum();
+
+        std::mem::size_of::<Inode>()
+            + self.name.capacity()
+            + self.symlink_target.as_ref().map_or(0, String::capacity)
+            + self.legacy_children.capacity() * std::mem::size_of::<u64>()
+            + manifest
+            + xattrs
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn legacy(inode: &Inode) -> LegacyInode {
+        LegacyInode {
+            ino: inode.ino,
+            parent: inode.parent,
+            name: inode.name.clone(),
+            attrs: inode.attrs.clone(),
+            manifest: inode.manifest.clone(),
+            symlink_target: inode.symlink_target.clone(),
+            legacy_children: inode.legacy_children.clone(),
+            version: inode.version,
+            xattrs: inode.xattrs.clone(),
+        }
+    }
+
+    #[test]
+    fn test_decode_legacy_layout() {
+        let mut file = Inode::new_file(2, 1, "a.txt".to_string(), 1000, 1000, 0o644);
+        file.xattrs.insert("user.tag".to_string(), b"x".to_vec());
+        let dir = Inode::new_directory(3, 1, "docs".to_string(), 1000, 1000, 0o755);
+
+        let single: Inode = decode_inodes(&bincode::serialize(&legacy(&file)).unwrap(), InodeLayout::Legacy).unwrap();
+        assert_eq!((single.ino, single.name.as_str(), single.generation), (2, "a.txt", 0));
+        assert_eq!(single.xattrs, file.xattrs);
+
+        // Inodes nested in a larger value, as in archives and journal batches
+        let nested = bincode::serialize(&(vec![legacy(&file), legacy(&dir)], 7u64)).unwrap();
+        let (inodes, tail): (Vec<Inode>, u64) = decode_inodes(&nested, InodeLayout::Legacy).unwrap();
+        assert_eq!(inodes.iter().map(|i| i.ino).collect::<Vec<_>>(), [2, 3]);
+        assert_eq!(tail, 7);
+
+        let current = bincode::serialize(&file.with_generation(5)).unwrap();
+        let current: Inode = decode_inodes(&current, InodeLayout::Current).unwrap();
+        assert_eq!(current.generation, 5);
+    }
 
     #[test]
     fn test_root_inode() {
         let root = Inode::root(1000, 1000, 0o755);
         assert_eq!(root.ino, 1);
         assert!(root.is_dir());
-        assert!(root.children.is_empty());
+        assert!(root.legacy_children.is_empty());
     }
 
     #[test]
@@ -317,17 +540,57 @@ mod tests {
     }
 
     #[test]
-    fn test_directory_children() {
-        let mut dir = Inode::new_directory(2, 1, "subdir".to_string(), 1000, 1000, 0o755);
-        assert!(dir.children.is_empty());
+    fn test_memory_size_grows_with_manifest() {
+        let mut file = Inode::new_file(2, 1, "test.txt".to_string(), 1000, 1000, 0o644);
+        let empty = file.memory_size();
+        assert!(empty >= std::mem::size_of::<Inode>());
+
+        let manifest = file.manifest.as_mut().unwrap();
+        for i in 0..100 {
+            manifest.chunks.push(ChunkRef {
+                id: format!("{:064x}", i),
+                size: 0,
+                message_id: i,
+                offset: 0,
+                original_size: 0,
+                compressed: false,
+            });
+        }
+        assert!(file.memory_size() >= empty + 100 * (std::mem::size_of::<ChunkRef>() + 64));
+    }
+
+    #[test]
+    fn test_timestamps_keep_nanoseconds_and_pre_epoch() {
+        let mut attrs = InodeAttributes::new_file(0, 0, 0o644);
+        attrs.mtime = UNIX_EPOCH + Duration::new(1_700_000_000, 123_456_789);
+        attrs.atime = UNIX_EPOCH - Duration::new(86_400, 250_000_000);
+        attrs.crtime = UNIX_EPOCH - Duration::from_secs(5);
 
-        dir.add_child(3);
-        dir.add_child(4);
-        assert_eq!(dir.children.len(), 2);
+        let decoded: InodeAttributes =
+            bincode::deserialize(&bincode::serialize(&attrs).unwrap()).unwrap();
+        assert_eq!(decoded.mtime, attrs.mtime);
+        assert_eq!(decoded.atime, attrs.atime);
+        assert_eq!(decoded.crtime, attrs.crtime);
 
-        dir.remove_child(3);
-        assert_eq!(dir.children.len(), 1);
-        assert!(dir.children.contains(&4));
+        // Post-epoch times keep serde's own SystemTime encoding
+        assert_eq!(
+            bincode::serialize(&attrs.mtime).unwrap(),
+            bincode::serialize(&{
+                #[derive(Serialize)]
+                struct Wrap(#[serde(with = "timestamp")] SystemTime);
+                Wrap(attrs.mtime)
+            })
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_dir_entry_for_inode() {
+        let dir = Inode::new_directory(2, 1, "subdir".to_string(), 1000, 1000, 0o755);
+        let entry = DirEntry::for_inode(&dir);
+        assert_eq!(entry.ino, 2);
+        assert_eq!(entry.name, "subdir");
+        assert_eq!(entry.kind, FileType::Directory);
     }
 
     #[test]
@@ -0,0 +1,608 @@
+//! Cloud-resident metadata journal
+//!
+//! Mutations recorded by `MetadataStore` are uploaded to the backend as
+//! encrypted batches, with a compacted checkpoint every few batches. Together
+//! with the plaintext volume descriptor (salt and KDF parameters) they let
+//! `tgcryptfs recover` rebuild `metadata.db` from the password and the cloud
+//! account alone.
+
+use crate::config::{EncryptionConfig, JournalConfig};
+use crate::crypto::{
+    decrypt_bound, encrypt_with, object_aad, AeadSuite, EncryptedData, ObjectKind, KEY_SIZE,
+};
+use crate::error::{Error, Result};
+use crate::metadata::{decode_inodes, Inode, InodeLayout, MetadataStore};
+use crate::telegram::{TelegramBackend, TelegramMessage, METADATA_FILE_PREFIX};
+use parking_lot::Mutex;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+use zeroize::Zeroizing;
+
+/// Current journal format version
+///
+/// Version 1 objects hold inodes in the layout from before generations.
+pub const JOURNAL_VERSION: u8 = 2;
+
+/// Local metadata key holding the upload state
+pub const JOURNAL_STATE_KEY: &str = "local:journal_state";
+
+/// Name of the volume descriptor object
+const VOLUME_NAME: &str = "volume";
+
+/// Name prefix of journal batch objects
+const BATCH_PREFIX: &str = "journal_";
+
+/// Name prefix of checkpoint objects
+const CHECKPOINT_PREFIX: &str = "checkpoint_";
+
+/// A single metadata mutation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum JournalOp {
+    /// Inode created or updated
+    PutInode(Box<Inode>),
+    /// Inode deleted
+    DeleteInode(u64),
+    /// Chunk reference created or its count changed
+    PutChunkRef {
+        chunk_id: String,
+        message_id: i32,
+        ref_count: u32,
+    },
+    /// Chunk reference dropped
+    DeleteChunkRef(String),
+    /// General metadata entry written
+    PutMetadata { key: String, value: Vec<u8> },
+}
+
+/// Plaintext description of how to derive the volume key
+///
+/// Uploaded once so a fresh machine can re-derive the key from the password.
+/// None of it is secret; a tampered descriptor only makes decryption fail.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VolumeDescriptor {
+    /// Format version
+    pub version: u8,
+    /// Hex-encoded Argon2id salt
+    pub salt: String,
+    /// Argon2 memory cost in KiB
+    pub argon2_memory_kib: u32,
+    /// Argon2 time cost
+    pub argon2_iterations: u32,
+    /// Argon2 parallelism
+    pub argon2_parallelism: u32,
+    /// AEAD suite for new ciphertexts
+    pub aead_suite: AeadSuite,
+    /// Whether a keyfile is combined with the password
+    pub keyfile_required: bool,
+}
+
+impl VolumeDescriptor {
+    /// Describe the volume from its encryption config
+    pub fn from_config(config: &EncryptionConfig) -> Self {
+        VolumeDescriptor {
+            version: JOURNAL_VERSION,
+            salt: hex::encode(&config.salt),
+            argon2_memory_kib: config.argon2_memory_kib,
+            argon2_iterations: config.argon2_iterations,
+            argon2_parallelism: config.argon2_parallelism,
+            aead_suite: config.aead_suite,
+            keyfile_required: config.keyfile.is_some(),
+        }
+    }
+
+    /// Copy the key derivation parameters into an encryption config
+    pub fn apply_to(&self, config: &mut EncryptionConfig) -> Result<()> {
+        config.salt = hex::decode(&self.salt)
+            .map_err(|e| Error::Recovery(format!("Invalid salt in volume descriptor: {}", e)))?;
+        config.argon2_memory_kib = self.argon2_memory_kib;
+        config.argon2_iterations = self.argon2_iterations;
+        config.argon2_parallelism = self.argon2_parallelism;
+        config.aead_suite = self.aead_suite;
+        Ok(())
+    }
+}
+
+/// Uploaded batch of journal operations
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JournalBatch {
+    /// Format version
+    pub version: u8,
+    /// Operations with their sequence numbers
+    pub ops: Vec<(u64, JournalOp)>,
+}
+
+/// Compacted state of the whole store
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Format version
+    pub version: u8,
+    /// Highest sequence number reflected in the checkpoint
+    pub seq: u64,
+    /// Operations recreating the store
+    pub ops: Vec<JournalOp>,
+}
+
+/// Upload progress, kept in the local (never journaled) metadata
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct JournalState {
+    /// Highest uploaded sequence number
+    pub last_seq: u64,
+    /// Message holding the volume descriptor
+    pub volume_message: Option<i32>,
+    /// Message holding the latest checkpoint
+    pub checkpoint_message: Option<i32>,
+    /// Sequence number of the latest checkpoint
+    pub checkpoint_seq: u64,
+    /// Batches uploaded since the latest checkpoint
+    pub batch_messages: Vec<i32>,
+}
+
+impl JournalState {
+    /// Load the state from the store
+    pub fn load(store: &MetadataStore) -> Result<Self> {
+        match store.get_metadata(JOURNAL_STATE_KEY)? {
+            Some(data) => Ok(bincode::deserialize(&data)?),
+            None => Ok(JournalState::default()),
+        }
+    }
+
+    /// Save the state to the store
+    pub fn save(&self, store: &MetadataStore) -> Result<()> {
+        store.save_metadata(JOURNAL_STATE_KEY, &bincode::serialize(self)?)
+    }
+}
+
+/// Result of rebuilding a store from the cloud journal
+#[derive(Debug, Default)]
+pub struct RecoveryStats {
+    /// Sequence number of the checkpoint used, if any
+    pub checkpoint_seq: Option<u64>,
+    /// Batches replayed after the checkpoint
+    pub batches_applied: usize,
+    /// Operations applied in total
+    pub ops_applied: u64,
+    /// Highest sequence number applied
+    pub last_seq: u64,
+    /// Missing sequence ranges between batches
+    pub gaps: usize,
+}
+
+/// Uploads the metadata journal to the backend
+pub struct CloudJournal {
+    /// Batching and checkpoint settings
+    config: JournalConfig,
+    /// Metadata encryption key
+    key: Zeroizing<[u8; KEY_SIZE]>,
+    /// Descriptor uploaded alongside the journal
+    volume: VolumeDescriptor,
+    /// Time of the last successful sync
+    last_sync: Mutex<Instant>,
+}
+
+impl CloudJournal {
+    /// Create a journal uploader
+    pub fn new(config: JournalConfig, key: [u8; KEY_SIZE], volume: VolumeDescriptor) -> Self {
+        CloudJournal {
+            config,
+            key: Zeroizing::new(key),
+            volume,
+            last_sync: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Start journaling mutations, continuing after the last upload
+    pub fn attach(&self, store: &MetadataStore) -> Result<()> {
+        let state = JournalState::load(store)?;
+        store.enable_journal(state.last_seq)
+    }
+
+    /// Longest time pending operations wait for an upload
+    pub fn flush_interval(&self) -> Duration {
+        Duration::from_secs(self.config.flush_interval_secs)
+    }
+
+    /// Check whether pending operations should be uploaded now
+    pub fn is_due(&self, store: &MetadataStore) -> bool {
+        let pending = store.journal_len();
+        if pending == 0 {
+            return false;
+        }
+        pending >= self.config.batch_size || self.last_sync.lock().elapsed() >= self.flush_interval()
+    }
+
+    /// Upload pending operations, checkpointing when due
+    ///
+    /// Returns the number of operations uploaded.
+    pub async fn sync(&self, store: &MetadataStore, backend: &TelegramBackend) -> Result<usize> {
+        let mut state = JournalState::load(store)?;
+
+        if state.volume_message.is_none() {
+            let descriptor = serde_json::to_vec(&self.volume)?;
+            state.volume_message = Some(backend.upload_metadata(VOLUME_NAME, &descriptor).await?);
+            state.save(store)?;
+            info!("Uploaded volume descriptor");
+        }
+
+        let uploaded = self.upload_pending(store, backend, &mut state).await?;
+
+        // A volume journaled for the first time needs a base checkpoint
+        if state.checkpoint_message.is_none()
+            || state.batch_messages.len() >= self.config.checkpoint_interval as usize
+        {
+            self.upload_checkpoint(store, backend, &mut state).await?;
+        }
+
+        *self.last_sync.lock() = Instant::now();
+        Ok(uploaded)
+    }
+
+    /// Upload a compacted checkpoint and drop the batches it supersedes
+    pub async fn checkpoint(&self, store: &MetadataStore, backend: &TelegramBackend) -> Result<()> {
+        let mut state = JournalState::load(store)?;
+        self.upload_pending(store, backend, &mut state).await?;
+        self.upload_checkpoint(store, backend, &mut state).await
+    }
+
+    /// Upload every pending operation in batches
+    async fn upload_pending(
+        &self,
+        store: &MetadataStore,
+        backend: &TelegramBackend,
+        state: &mut JournalState,
+    ) -> Result<usize> {
+        let mut uploaded = 0;
+        loop {
+            let ops = store.pending_journal(self.config.batch_size.max(1))?;
+            let (Some(&(first, _)), Some(&(last, _))) = (ops.first(), ops.last()) else {
+                break;
+            };
+
+            let count = ops.len();
+            let name = batch_name(first, last);
+            let batch = JournalBatch {
+                version: JOURNAL_VERSION,
+                ops,
+            };
+            let data = self.seal(&name, &bincode::serialize(&batch)?)?;
+            let message_id = backend.upload_metadata(&name, &data).await?;
+
+            store.ack_journal(last)?;
+            state.last_seq = last;
+            state.batch_messages.push(message_id);
+            state.save(store)?;
+
+            debug!("Uploaded journal batch {} ({} ops)", name, count);
+            uploaded += count;
+        }
+        Ok(uploaded)
+    }
+
+    /// Upload a checkpoint of the whole store
+    async fn upload_checkpoint(
+        &self,
+        store: &MetadataStore,
+        backend: &TelegramBackend,
+        state: &mut JournalState,
+    ) -> Result<()> {
+        let checkpoint = Checkpoint {
+            version: JOURNAL_VERSION,
+            seq: state.last_seq,
+            ops: store.journal_snapshot()?,
+        };
+        let name = checkpoint_name(checkpoint.seq);
+        let data = self.seal(&name, &bincode::serialize(&checkpoint)?)?;
+        let message_id = backend.upload_metadata(&name, &data).await?;
+
+        // Superseded objects are only an optimization to remove
+        let superseded: Vec<i32> = state
+            .batch_messages
+            .drain(..)
+            .chain(state.checkpoint_message.replace(message_id))
+            .collect();
+        state.checkpoint_seq = checkpoint.seq;
+        state.save(store)?;
+
+        for old in superseded {
+            if let Err(e) = backend.delete_message(old).await {
+                warn!("Failed to delete superseded journal message {}: {}", old, e);
+            }
+        }
+
+        info!(
+            "Uploaded metadata checkpoint at seq {} ({} entries)",
+            checkpoint.seq,
+            checkpoint.ops.len()
+        );
+        Ok(())
+    }
+
+    /// Encrypt an object, bound to its name
+    fn seal(&self, name: &str, data: &[u8]) -> Result<Vec<u8>> {
+        let aad = object_aad(ObjectKind::Journal, None, name.as_bytes(), 0);
+        let encrypted = encrypt_with(&self.key, data, &aad, self.volume.aead_suite)?;
+        Ok(encrypted.to_bytes())
+    }
+}
+
+/// Decrypt an uploaded journal object
+fn open(key: &[u8; KEY_SIZE], name: &str, data: &[u8]) -> Result<Vec<u8>> {
+    let aad = object_aad(ObjectKind::Journal, None, name.as_bytes(), 0);
+    let encrypted = EncryptedData::from_bytes(data)?;
+    decrypt_bound(key, &encrypted, &aad, None)
+}
+
+/// Decode a batch or checkpoint in the inode layout of its format version
+///
+/// Both start with their version, a single byte in bincode.
+fn decode_object<T: DeserializeOwned>(data: &[u8]) -> Result<T> {
+    let layout = match data.first() {
+        Some(1) => InodeLayout::Legacy,
+        Some(&JOURNAL_VERSION) => InodeLayout::Current,
+        Some(version) => {
+            return Err(Error::Recovery(format!(
+                "Unsupported journal format version {}",
+                version
+            )))
+        }
+        None => return Err(Error::Recovery("Empty journal object".to_string())),
+    };
+    decode_inodes(data, layout)
+}
+
+/// Object name of a batch
+fn batch_name(first: u64, last: u64) -> String {
+    format!("{}{:016x}_{:016x}", BATCH_PREFIX, first, last)
+}
+
+/// Parse the sequence range from a batch name
+fn parse_batch_name(name: &str) -> Option<(u64, u64)> {
+    let (first, last) = name.strip_prefix(BATCH_PREFIX)?.split_once('_')?;
+    Some((
+        u64::from_str_radix(first, 16).ok()?,
+        u64::from_str_radix(last, 16).ok()?,
+    ))
+}
+
+/// Object name of a checkpoint
+fn checkpoint_name(seq: u64) -> String {
+    format!("{}{:016x}", CHECKPOINT_PREFIX, seq)
+}
+
+/// Parse the sequence number from a checkpoint name
+fn parse_checkpoint_name(name: &str) -> Option<u64> {
+    u64::from_str_radix(name.strip_prefix(CHECKPOINT_PREFIX)?, 16).ok()
+}
+
+/// Object name of a listed metadata message
+fn object_name(message: &TelegramMessage) -> Option<&str> {
+    message.filename.as_deref()?.strip_prefix(METADATA_FILE_PREFIX)
+}
+
+/// Download the most recent volume descriptor
+pub async fn fetch_volume_descriptor(backend: &TelegramBackend) -> Result<VolumeDescriptor> {
+    let messages = backend.list_metadata().await?;
+    let message = messages
+        .iter()
+        .filter(|m| object_name(m) == Some(VOLUME_NAME))
+        .max_by_key(|m| m.id)
+        .ok_or_else(|| Error::Recovery("No volume descriptor found in the cloud".to_string()))?;
+
+    let data = backend.download_metadata(message.id).await?;
+    Ok(serde_json::from_slice(&data)?)
+}
+
+/// Rebuild a store from the checkpoint and batches in the cloud
+///
+/// The store should be freshly created and must not be journaling.
+pub async fn recover(
+    store: &MetadataStore,
+    backend: &TelegramBackend,
+    key: &[u8; KEY_SIZE],
+) -> Result<RecoveryStats> {
+    let messages = backend.list_metadata().await?;
+
+    let mut checkpoint_message = None;
+    let mut batch_messages = Vec::new();
+    let mut volume_message = None;
+    for message in &messages {
+        let Some(name) = object_name(message) else {
+            continue;
+        };
+        if name == VOLUME_NAME {
+            volume_message = volume_message.max(Some(message.id));
+        } else if let Some(seq) = parse_checkpoint_name(name) {
+            if checkpoint_message.as_ref().is_none_or(|(best, _, _)| seq > *best) {
+                checkpoint_message = Some((seq, message.id, name.to_string()));
+            }
+        } else if let Some((first, last)) = parse_batch_name(name) {
+            batch_messages.push((first, last, message.id, name.to_string()));
+        }
+    }
+
+    let checkpoint = match &checkpoint_message {
+        Some((_, id, name)) => {
+            let data = open(key, name, &backend.download_metadata(*id).await?)?;
+            Some(decode_object::<Checkpoint>(&data)?)
+        }
+        None => None,
+    };
+
+    let base = checkpoint.as_ref().map_or(0, |c| c.seq);
+    batch_messages.retain(|(_, last, _, _)| *last > base);
+    batch_messages.sort_by_key(|(first, _, _, _)| *first);
+
+    let mut batches = Vec::with_capacity(batch_messages.len());
+    for (_, _, id, name) in &batch_messages {
+        let data = open(key, name, &backend.download_metadata(*id).await?)?;
+        batches.push(decode_object::<JournalBatch>(&data)?);
+    }
+
+    if checkpoint.is_none() && batches.is_empty() {
+        return Err(Error::Recovery("No metadata journal found in the cloud".to_string()));
+    }
+
+    let stats = replay(store, checkpoint, batches)?;
+
+    // Keep journaling from where the cloud copy ends
+    JournalState {
+        last_seq: stats.last_seq,
+        volume_message,
+        checkpoint_message: checkpoint_message.map(|(_, id, _)| id),
+        checkpoint_seq: stats.checkpoint_seq.unwrap_or(0),
+        batch_messages: batch_messages.iter().map(|(_, _, id, _)| *id).collect(),
+    }
+    .save(store)?;
+    store.flush()?;
+
+    Ok(stats)
+}
+
+/// Apply a checkpoint and the batches that follow it to a store
+pub fn replay(
+    store: &MetadataStore,
+    checkpoint: Option<Checkpoint>,
+    batches: Vec<JournalBatch>,
+) -> Result<RecoveryStats> {
+    let mut stats = RecoveryStats::default();
+
+    if let Some(checkpoint) = checkpoint {
+        for op in &checkpoint.ops {
+            store.apply_journal_op(op)?;
+            stats.ops_applied += 1;
+        }
+        stats.checkpoint_seq = Some(checkpoint.seq);
+        stats.last_seq = checkpoint.seq;
+    }
+
+    for batch in batches {
+        for (seq, op) in &batch.ops {
+            if *seq <= stats.last_seq {
+                continue;
+            }
+            if *seq != stats.last_seq + 1 {
+                warn!(
+                    "Journal gap: operations {}..{} are missing",
+                    stats.last_seq + 1,
+                    seq
+                );
+                stats.gaps += 1;
+            }
+            store.apply_journal_op(op)?;
+            stats.ops_applied += 1;
+            stats.last_seq = *seq;
+        }
+        stats.batches_applied += 1;
+    }
+
+    store.clear_cache();
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_object_names_roundtrip() {
+        assert_eq!(parse_batch_name(&batch_name(1, 0x2a)), Some((1, 0x2a)));
+        assert_eq!(parse_checkpoint_name(&checkpoint_name(77)), Some(77));
+        assert_eq!(parse_batch_name(&checkpoint_name(77)), None);
+        assert_eq!(parse_checkpoint_name(VOLUME_NAME), None);
+    }
+
+    #[test]
+    fn test_sealed_object_bound_to_name() {
+        let key = [4u8; KEY_SIZE];
+        let journal = CloudJournal::new(
+            JournalConfig::default(),
+            key,
+            VolumeDescriptor::from_config(&EncryptionConfig::default()),
+        );
+
+        let sealed = journal.seal("journal_a", b"ops").unwrap();
+        assert_eq!(open(&key, "journal_a", &sealed).unwrap(), b"ops");
+        assert!(open(&key, "journal_b", &sealed).is_err());
+    }
+
+    #[test]
+    fn test_version_one_objects_use_legacy_layout() {
+        let file = Inode::new_file(2, 1, "a".to_string(), 0, 0, 0o644).with_generation(7);
+        let batch = JournalBatch {
+            version: 1,
+            ops: vec![(1, JournalOp::PutInode(Box::new(file.clone()))), (2, JournalOp::DeleteInode(9))],
+        };
+        // Drop the generation, the last field of the first inode
+        let mut data = bincode::serialize(&batch).unwrap();
+        let end = 1 + 8 + 8 + 4 + bincode::serialized_size(&file).unwrap() as usize;
+        data.drain(end - 8..end);
+
+        let decoded: JournalBatch = decode_object(&data).unwrap();
+        match &decoded.ops[..] {
+            [(1, JournalOp::PutInode(inode)), (2, JournalOp::DeleteInode(9))] => {
+                assert_eq!((inode.name.as_str(), inode.generation), ("a", 0));
+            }
+            ops => panic!("misdecoded batch: {:?}", ops),
+        }
+
+        data[0] = JOURNAL_VERSION + 1;
+        assert!(decode_object::<JournalBatch>(&data).is_err());
+    }
+
+    #[test]
+    fn test_store_journal_pending_and_ack() {
+        let store = MetadataStore::in_memory([1u8; KEY_SIZE]).unwrap();
+        store.enable_journal(0).unwrap();
+
+        let mut root = store.get_inode(1).unwrap().unwrap();
+        root.name = "renamed".to_string();
+        store.save_inode(&root).unwrap();
+        store.save_chunk_ref("c1", 10).unwrap();
+        store.save_metadata(JOURNAL_STATE_KEY, b"local only").unwrap();
+
+        let pending = store.pending_journal(10).unwrap();
+        assert_eq!(pending.iter().map(|(seq, _)| *seq).collect::<Vec<_>>(), vec![1, 2]);
+
+        store.ack_journal(1).unwrap();
+        assert_eq!(store.journal_len(), 1);
+
+        // Numbering continues after the last uploaded sequence
+        let reopened = MetadataStore::in_memory([1u8; KEY_SIZE]).unwrap();
+        reopened.enable_journal(41).unwrap();
+        reopened.save_chunk_ref("c2", 11).unwrap();
+        assert_eq!(reopened.pending_journal(10).unwrap()[0].0, 42);
+    }
+
+    #[test]
+    fn test_replay_rebuilds_store() {
+        let source = MetadataStore::in_memory([2u8; KEY_SIZE]).unwrap();
+        let mut file = Inode::new_file(2, 1, "a.txt".to_string(), 0, 0, 0o644);
+        source.save_inode(&file).unwrap();
+        source.save_chunk_ref("c1", 10).unwrap();
+        let checkpoint = Checkpoint {
+            version: JOURNAL_VERSION,
+            seq: 0,
+            ops: source.journal_snapshot().unwrap(),
+        };
+
+        // Changes after the checkpoint arrive as a batch
+        source.enable_journal(0).unwrap();
+        file.name = "b.txt".to_string();
+        source.save_inode(&file).unwrap();
+        source.decrement_chunk_ref("c1").unwrap();
+        let batch = JournalBatch {
+            version: JOURNAL_VERSION,
+            ops: source.pending_journal(10).unwrap(),
+        };
+
+        let target = MetadataStore::in_memory([2u8; KEY_SIZE]).unwrap();
+        let stats = replay(&target, Some(checkpoint), vec![batch]).unwrap();
+
+        assert_eq!(stats.last_seq, 2);
+        assert_eq!(stats.gaps, 0);
+        assert_eq!(target.get_inode(2).unwrap().unwrap().name, "b.txt");
+        assert!(target.get_chunk_ref("c1").unwrap().is_none());
+        assert_eq!(target.alloc_ino().unwrap(), 3);
+    }
+}
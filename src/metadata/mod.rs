@@ -3,14 +3,25 @@
 //! Stores encrypted filesystem metadata in SQLite.
 //! All metadata is encrypted before storage using the metadata key.
 
+mod archive;
+mod cache;
+pub mod fsck;
 mod hardlinks;
 mod inode;
+mod journal;
 mod store;
 mod version;
 mod xattr;
 
+pub use archive::{ArchiveContents, ArchiveHeader, ImportStats, MetadataArchive, ARCHIVE_VERSION};
+pub use cache::{InodeCache, InodeCacheStats};
+pub use fsck::{FsckProblem, FsckReport, RepairStats};
 pub use hardlinks::HardLinkStore;
-pub use inode::{FileType, Inode, InodeAttributes};
-pub use store::MetadataStore;
+pub use inode::{decode_inodes, DirEntry, FileType, Inode, InodeAttributes, InodeLayout};
+pub use journal::{
+    fetch_volume_descriptor, recover, replay, Checkpoint, CloudJournal, JournalBatch, JournalOp,
+    JournalState, RecoveryStats, VolumeDescriptor, JOURNAL_STATE_KEY,
+};
+pub use store::{MetadataStore, Transaction};
 pub use version::{FileVersion, VersionManager};
 pub use xattr::XattrStore;
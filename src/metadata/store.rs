@@ -2,16 +2,37 @@
 //!
 //! All metadata is encrypted before storage. The database contains
 //! encrypted blobs that can only be read with the correct key.
-
-use crate::crypto::{decrypt, encrypt, EncryptedData, KEY_SIZE};
+//!
+//! Every blob is bound (via AAD) to the namespace and key it is stored
+//! under, so one inode or entry cannot be swapped for another.
+//!
+//! Filenames never appear in plaintext: parent-index keys hold a keyed
+//! BLAKE3 hash of the name under a subkey of the metadata key.
+//!
+//! When journaling is enabled, every mutation is also appended to a local
+//! write-ahead journal that `CloudJournal` uploads to the backend.
+
+use crate::chunk::PENDING_MESSAGE_ID;
+use crate::config::{EncryptionConfig, DEFAULT_INODE_CACHE_SIZE, DEFAULT_NEGATIVE_CACHE_ENTRIES};
+use crate::crypto::{
+    decrypt_bound, encrypt_with, object_aad, AeadSuite, EncryptedData, ObjectKind, KEY_SIZE,
+};
 use crate::error::{Error, Result};
-use crate::metadata::Inode;
-use parking_lot::RwLock;
+use crate::metadata::cache::{InodeCache, InodeCacheStats};
+use crate::metadata::journal::JournalOp;
+use crate::metadata::{decode_inodes, DirEntry, Inode, InodeLayout};
+use crate::migration::MigrationStats;
+use parking_lot::Mutex;
+use serde::de::DeserializeOwned;
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+    Transactional, TransactionalTree,
+};
 use sled::{Db, Tree};
-use std::collections::HashMap;
+use std::collections::{HashSet, VecDeque};
 use std::path::Path;
-use std::sync::atomic::{AtomicU64, Ordering};
-use tracing::{debug, info};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use tracing::{debug, info, warn};
 
 /// Key prefixes for different data types
 #[allow(dead_code)]
@@ -23,32 +44,165 @@ const CHUNK_PREFIX: &[u8] = b"chk:";
 #[allow(dead_code)]
 const META_PREFIX: &[u8] = b"meta:";
 
+/// Metadata key marking that the parent index uses hashed filenames
+const INDEX_HASHED_KEY: &str = "index_hashed";
+
+/// Metadata key marking that directory entries are stored separately
+const DIRENTS_KEY: &str = "dirents";
+
+/// Local metadata key holding the generation of the current open
+const GENERATION_KEY: &str = "local:inode_generation";
+
+/// Local metadata key holding the end of the reserved inode-number range
+const INO_RESERVED_KEY: &str = "local:ino_reserved";
+
+/// Inode numbers reserved (and persisted) at a time
+const INO_RESERVATION_BLOCK: u64 = 1024;
+
+/// Object version sealed into the AAD of inodes and journal operations
+///
+/// Entries sealed with object version 0 (or unbound) hold inodes in the
+/// layout from before generations.
+const INODE_LAYOUT_VERSION: u64 = 1;
+
+/// Directory entries read per page while walking trees
+const WALK_PAGE: usize = 256;
+
+/// First directory entry cookie; 1 and 2 are "." and ".."
+pub const FIRST_DIRENT_COOKIE: u64 = 3;
+
+/// BLAKE3 context for the parent-index subkey
+const INDEX_KEY_CONTEXT: &str = "tgcryptfs-parent-index-v1";
+
+/// Prefix of metadata keys that stay local and are never journaled
+pub const LOCAL_METADATA_PREFIX: &str = "local:";
+
 /// Encrypted metadata store using sled
 pub struct MetadataStore {
     /// Sled database
     db: Db,
     /// Inodes tree
     inodes: Tree,
-    /// Parent-name index tree
+    /// Parent-name index tree: (parent, name hash) -> (ino, cookie)
     parent_index: Tree,
+    /// Directory entries: (parent, cookie) -> encrypted `DirEntry`
+    dirents: Tree,
     /// Chunk references tree
     chunks: Tree,
     /// General metadata tree
     metadata: Tree,
     /// Encryption key for metadata
     key: [u8; KEY_SIZE],
+    /// AEAD suite for newly written entries
+    aead_suite: AeadSuite,
+    /// Key for hashing filenames in the parent index
+    index_key: [u8; KEY_SIZE],
     /// Next available inode number
     next_ino: AtomicU64,
-    /// In-memory inode cache
-    cache: RwLock<HashMap<u64, Inode>>,
+    /// End (exclusive) of the inode numbers reserved on disk
+    ino_reserved: Mutex<u64>,
+    /// Generation stamped on inodes created by this open
+    generation: AtomicU64,
+    /// Bounded in-memory inode and negative lookup cache
+    cache: Mutex<InodeCache>,
     /// Optional namespace prefix for storage keys
     namespace_prefix: Option<String>,
+    /// Reject ciphertexts not bound to their identity
+    aad_bound: AtomicBool,
+    /// Pending journal operations, keyed by sequence number
+    journal: Tree,
+    /// Record mutations in the journal
+    journal_enabled: AtomicBool,
+    /// Next journal sequence number
+    journal_seq: AtomicU64,
+    /// Serializes commits so journal sequence numbers stay contiguous
+    commit_lock: Mutex<()>,
+}
+
+/// A single staged change in a transaction
+#[derive(Debug, Clone)]
+enum TxnOp {
+    PutInode(Box<Inode>),
+    DeleteInode(u64),
+    AddChunkRef { chunk_id: String, message_id: i32 },
+    DecrementChunkRef(String),
+    ResolvePendingChunk { ino: u64, chunk_id: String, message_id: i32 },
+}
+
+/// Metadata changes committed atomically
+///
+/// Inodes, their index entries, chunk reference counts and the matching
+/// journal entries are written in one sled transaction: after a crash
+/// either every staged change is visible or none is.
+#[must_use = "a transaction does nothing until committed"]
+pub struct Transaction<'a> {
+    store: &'a MetadataStore,
+    ops: Vec<TxnOp>,
+}
+
+impl Transaction<'_> {
+    /// Stage saving an inode
+    pub fn save_inode(&mut self, inode: &Inode) -> &mut Self {
+        self.ops.push(TxnOp::PutInode(Box::new(inode.clone())));
+        self
+    }
+
+    /// Stage deleting an inode and its index entry
+    pub fn delete_inode(&mut self, ino: u64) -> &mut Self {
+        self.ops.push(TxnOp::DeleteInode(ino));
+        self
+    }
+
+    /// Stage adding a reference to a chunk
+    pub fn save_chunk_ref(&mut self, chunk_id: &str, message_id: i32) -> &mut Self {
+        self.ops.push(TxnOp::AddChunkRef {
+            chunk_id: chunk_id.to_string(),
+            message_id,
+        });
+        self
+    }
+
+    /// Stage dropping a reference to a chunk
+    pub fn decrement_chunk_ref(&mut self, chunk_id: &str) -> &mut Self {
+        self.ops.push(TxnOp::DecrementChunkRef(chunk_id.to_string()));
+        self
+    }
+
+    /// Stage pointing an inode's queued references to a chunk at its message
+    ///
+    /// The inode is read inside the transaction and left alone unless it
+    /// still references the chunk as queued, so a concurrent change to it
+    /// is never overwritten.
+    pub fn resolve_pending_chunk(&mut self, ino: u64, chunk_id: &str, message_id: i32) -> &mut Self {
+        self.ops.push(TxnOp::ResolvePendingChunk {
+            ino,
+            chunk_id: chunk_id.to_string(),
+            message_id,
+        });
+        self
+    }
+
+    /// Check whether nothing has been staged
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Apply every staged change atomically
+    ///
+    /// Returns the (chunk ID, message ID) of every chunk whose last reference
+    /// was dropped; these can be deleted from Telegram and the cache.
+    pub fn commit(self) -> Result<Vec<(String, i32)>> {
+        self.store.apply_ops(&self.ops, true)
+    }
 }
 
 impl MetadataStore {
     /// Open or create a metadata store
-    pub fn open<P: AsRef<Path>>(path: P, key: [u8; KEY_SIZE]) -> Result<Self> {
-        Self::open_with_namespace(path, key, None)
+    ///
+    /// The AEAD suite and binding policy come from `config` and apply to
+    /// every read and write, including any migration run while opening.
+    pub fn open<P: AsRef<Path>>(path: P, key: [u8; KEY_SIZE], config: &EncryptionConfig) -> Result<Self> {
+        Self::open_with_namespace(path, key, None, config)
     }
 
     /// Open or create a metadata store with a namespace prefix
@@ -56,29 +210,43 @@ impl MetadataStore {
         path: P,
         key: [u8; KEY_SIZE],
         namespace_prefix: Option<String>,
+        config: &EncryptionConfig,
     ) -> Result<Self> {
-        let db = sled::open(path.as_ref())?;
+        Self::from_db(sled::open(path.as_ref())?, key, namespace_prefix, config)
+    }
 
+    /// Create an in-memory store (for testing)
+    pub fn in_memory(key: [u8; KEY_SIZE]) -> Result<Self> {
+        Self::in_memory_with_namespace(key, None)
+    }
+
+    /// Create an in-memory store with namespace prefix (for testing)
+    pub fn in_memory_with_namespace(
+        key: [u8; KEY_SIZE],
+        namespace_prefix: Option<String>,
+    ) -> Result<Self> {
+        let db = sled::Config::new().temporary(true).open()?;
+        Self::from_db(db, key, namespace_prefix, &EncryptionConfig::default())
+    }
+
+    /// Open the store's trees in `db`, creating the root or migrating as needed
+    fn from_db(
+        db: Db,
+        key: [u8; KEY_SIZE],
+        namespace_prefix: Option<String>,
+        config: &EncryptionConfig,
+    ) -> Result<Self> {
         // Use namespace-prefixed tree names if namespace is provided
-        let (inodes_name, parent_name, chunks_name, metadata_name) = match &namespace_prefix {
-            Some(prefix) => (
-                format!("{}:inodes", prefix),
-                format!("{}:parent_index", prefix),
-                format!("{}:chunks", prefix),
-                format!("{}:metadata", prefix),
-            ),
-            None => (
-                "inodes".to_string(),
-                "parent_index".to_string(),
-                "chunks".to_string(),
-                "metadata".to_string(),
-            ),
+        let tree = |name: &str| match &namespace_prefix {
+            Some(prefix) => db.open_tree(format!("{}:{}", prefix, name)),
+            None => db.open_tree(name),
         };
-
-        let inodes = db.open_tree(&inodes_name)?;
-        let parent_index = db.open_tree(&parent_name)?;
-        let chunks = db.open_tree(&chunks_name)?;
-        let metadata = db.open_tree(&metadata_name)?;
+        let inodes = tree("inodes")?;
+        let parent_index = tree("parent_index")?;
+        let dirents = tree("dirents")?;
+        let chunks = tree("chunks")?;
+        let metadata = tree("metadata")?;
+        let journal = tree("journal")?;
 
         // Get max inode number
         let max_ino = inodes
@@ -95,22 +263,49 @@ impl MetadataStore {
             .max()
             .unwrap_or(0);
 
+        // A fresh store has no unbound entries
+        let aad_bound = max_ino == 0 || config.require_bound_metadata;
+        if !aad_bound {
+            warn!("Metadata entries not bound to their identity are accepted; run `tgcryptfs migrate-aad`");
+        }
+
         let store = MetadataStore {
             db,
             inodes,
             parent_index,
+            dirents,
             chunks,
             metadata,
             key,
+            index_key: blake3::derive_key(INDEX_KEY_CONTEXT, &key),
             next_ino: AtomicU64::new(max_ino + 1),
-            cache: RwLock::new(HashMap::new()),
+            ino_reserved: Mutex::new(0),
+            generation: AtomicU64::new(0),
+            cache: Mutex::new(InodeCache::new(
+                DEFAULT_INODE_CACHE_SIZE,
+                DEFAULT_NEGATIVE_CACHE_ENTRIES,
+            )),
             namespace_prefix,
+            aead_suite: config.aead_suite,
+            aad_bound: AtomicBool::new(aad_bound),
+            journal,
+            journal_enabled: AtomicBool::new(false),
+            journal_seq: AtomicU64::new(1),
+            commit_lock: Mutex::new(()),
         };
 
         // Initialize root if needed
         if max_ino == 0 {
             store.init_root()?;
+            store.put_metadata(INDEX_HASHED_KEY, &[1])?;
+            store.put_metadata(DIRENTS_KEY, &[1])?;
+        } else if store.get_metadata(INDEX_HASHED_KEY)?.is_none()
+            || store.get_metadata(DIRENTS_KEY)?.is_none()
+        {
+            store.rebuild_index()?;
         }
+        store.load_ino_reservation()?;
+        store.advance_generation()?;
 
         info!(
             "Metadata store opened, max inode: {}, namespace: {:?}",
@@ -119,55 +314,6 @@ impl MetadataStore {
         Ok(store)
     }
 
-    /// Create an in-memory store (for testing)
-    pub fn in_memory(key: [u8; KEY_SIZE]) -> Result<Self> {
-        Self::in_memory_with_namespace(key, None)
-    }
-
-    /// Create an in-memory store with namespace prefix (for testing)
-    pub fn in_memory_with_namespace(
-        key: [u8; KEY_SIZE],
-        namespace_prefix: Option<String>,
-    ) -> Result<Self> {
-        let db = sled::Config::new().temporary(true).open()?;
-
-        // Use namespace-prefixed tree names if namespace is provided
-        let (inodes_name, parent_name, chunks_name, metadata_name) = match &namespace_prefix {
-            Some(prefix) => (
-                format!("{}:inodes", prefix),
-                format!("{}:parent_index", prefix),
-                format!("{}:chunks", prefix),
-                format!("{}:metadata", prefix),
-            ),
-            None => (
-                "inodes".to_string(),
-                "parent_index".to_string(),
-                "chunks".to_string(),
-                "metadata".to_string(),
-            ),
-        };
-
-        let inodes = db.open_tree(&inodes_name)?;
-        let parent_index = db.open_tree(&parent_name)?;
-        let chunks = db.open_tree(&chunks_name)?;
-        let metadata = db.open_tree(&metadata_name)?;
-
-        let store = MetadataStore {
-            db,
-            inodes,
-            parent_index,
-            chunks,
-            metadata,
-            key,
-            next_ino: AtomicU64::new(1),
-            cache: RwLock::new(HashMap::new()),
-            namespace_prefix,
-        };
-
-        store.init_root()?;
-        Ok(store)
-    }
-
     /// Initialize the root inode
     fn init_root(&self) -> Result<()> {
         let uid = unsafe { libc::getuid() };
@@ -180,8 +326,48 @@ impl MetadataStore {
     }
 
     /// Allocate a new inode number
-    pub fn alloc_ino(&self) -> u64 {
-        self.next_ino.fetch_add(1, Ordering::SeqCst)
+    ///
+    /// Numbers are reserved on disk in blocks before they are handed out,
+    /// so a number is never reused after a crash, even if the inode that
+    /// held it was deleted.
+    pub fn alloc_ino(&self) -> Result<u64> {
+        let ino = self.next_ino.fetch_add(1, Ordering::SeqCst);
+        let mut reserved = self.ino_reserved.lock();
+        if ino >= *reserved {
+            let end = ino + INO_RESERVATION_BLOCK;
+            self.put_metadata(INO_RESERVED_KEY, &end.to_be_bytes())?;
+            self.db.flush()?;
+            *reserved = end;
+        }
+        Ok(ino)
+    }
+
+    /// Generation to stamp on inodes created now
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Skip past inode numbers reserved by an earlier open
+    fn load_ino_reservation(&self) -> Result<()> {
+        if let Some(value) = self.get_metadata(INO_RESERVED_KEY)? {
+            if value.len() == 8 {
+                let end = u64::from_be_bytes(value[..8].try_into().unwrap());
+                self.next_ino.fetch_max(end, Ordering::SeqCst);
+            }
+        }
+        Ok(())
+    }
+
+    /// Start a new generation for this open
+    fn advance_generation(&self) -> Result<()> {
+        let previous = match self.get_metadata(GENERATION_KEY)? {
+            Some(value) if value.len() == 8 => u64::from_be_bytes(value[..8].try_into().unwrap()),
+            _ => 0,
+        };
+        let generation = previous + 1;
+        self.put_metadata(GENERATION_KEY, &generation.to_be_bytes())?;
+        self.generation.store(generation, Ordering::SeqCst);
+        Ok(())
     }
 
     /// Create inode key from ino
@@ -190,59 +376,402 @@ impl MetadataStore {
     }
 
     /// Create parent-name index key
-    fn parent_name_key(parent: u64, name: &str) -> Vec<u8> {
-        let mut key = Vec::with_capacity(8 + name.len());
+    ///
+    /// The parent stays in the clear so children can be scanned by prefix;
+    /// the name is replaced by its keyed hash.
+    fn parent_name_key(&self, parent: u64, name: &str) -> Vec<u8> {
+        let mut hasher = blake3::Hasher::new_keyed(&self.index_key);
+        hasher.update(&parent.to_be_bytes());
+        hasher.update(name.as_bytes());
+
+        let mut key = Vec::with_capacity(8 + blake3::OUT_LEN);
         key.extend_from_slice(&parent.to_be_bytes());
-        key.extend_from_slice(name.as_bytes());
+        key.extend_from_slice(hasher.finalize().as_bytes());
         key
     }
 
+    /// AAD binding an inode blob to its number, namespace and layout
+    fn inode_aad(&self, ino: u64, version: u64) -> Vec<u8> {
+        object_aad(ObjectKind::Inode, self.namespace_prefix(), &ino.to_be_bytes(), version)
+    }
+
+    /// AAD binding a metadata blob to its key and namespace
+    fn metadata_aad(&self, key: &[u8]) -> Vec<u8> {
+        object_aad(ObjectKind::Metadata, self.namespace_prefix(), key, 0)
+    }
+
+    /// AAD accepted for entries written before binding, if still allowed
+    fn unbound_aad(&self) -> Option<&'static [u8]> {
+        if self.aad_bound.load(Ordering::SeqCst) {
+            None
+        } else {
+            Some(&[])
+        }
+    }
+
+    /// Seal a value with the configured suite
+    fn seal(&self, data: &[u8], aad: &[u8]) -> Result<EncryptedData> {
+        encrypt_with(&self.key, data, aad, self.aead_suite)
+    }
+
+    /// Open an inode-bearing entry sealed under `aad(version)`
+    ///
+    /// The object version the entry authenticates under tells the layout.
+    fn open_inodes<T: DeserializeOwned>(
+        &self,
+        data: &[u8],
+        aad: impl Fn(u64) -> Vec<u8>,
+        unbound_aad: Option<&[u8]>,
+    ) -> Result<T> {
+        let encrypted = EncryptedData::from_bytes(data)?;
+        if let Ok(decrypted) = decrypt_bound(&self.key, &encrypted, &aad(INODE_LAYOUT_VERSION), None) {
+            return decode_inodes(&decrypted, InodeLayout::Current);
+        }
+        let decrypted = decrypt_bound(&self.key, &encrypted, &aad(0), unbound_aad)?;
+        decode_inodes(&decrypted, InodeLayout::Legacy)
+    }
+
     /// Encrypt an inode for storage
     fn encrypt_inode(&self, inode: &Inode) -> Result<Vec<u8>> {
         let data = bincode::serialize(inode)?;
-        let encrypted = encrypt(&self.key, &data, &[])?;
+        let encrypted = self.seal(&data, &self.inode_aad(inode.ino, INODE_LAYOUT_VERSION))?;
         Ok(encrypted.to_bytes())
     }
 
     /// Decrypt an inode from storage
-    fn decrypt_inode(&self, data: &[u8]) -> Result<Inode> {
-        let encrypted = EncryptedData::from_bytes(data)?;
-        let decrypted = decrypt(&self.key, &encrypted, &[])?;
-        let inode: Inode = bincode::deserialize(&decrypted)?;
-        Ok(inode)
+    fn decrypt_inode(&self, ino: u64, data: &[u8]) -> Result<Inode> {
+        self.open_inodes(data, |version| self.inode_aad(ino, version), self.unbound_aad())
     }
 
     /// Save an inode to the database
     pub fn save_inode(&self, inode: &Inode) -> Result<()> {
-        let encrypted = self.encrypt_inode(inode)?;
+        self.apply_ops(&[TxnOp::PutInode(Box::new(inode.clone()))], true)?;
+        Ok(())
+    }
+
+    /// Write an inode and its index entry without journaling
+    fn put_inode(&self, inode: &Inode) -> Result<()> {
+        self.apply_ops(&[TxnOp::PutInode(Box::new(inode.clone()))], false)?;
+        Ok(())
+    }
+
+    /// Start a transaction spanning several inodes and chunk references
+    pub fn transaction(&self) -> Transaction<'_> {
+        Transaction {
+            store: self,
+            ops: Vec::new(),
+        }
+    }
+
+    /// Apply operations in a single sled transaction
+    ///
+    /// Returns the chunks whose last reference was dropped.
+    fn apply_ops(&self, ops: &[TxnOp], journal: bool) -> Result<Vec<(String, i32)>> {
+        let _guard = self.commit_lock.lock();
+        let journal = journal && self.is_journal_enabled();
+        let first_seq = self.journal_seq.load(Ordering::SeqCst);
+
+        // Cookies are allocated up front: generate_id must not run inside a transaction
+        let mut cookies = Vec::new();
+        for op in ops {
+            if let TxnOp::PutInode(_) | TxnOp::ResolvePendingChunk { .. } = op {
+                cookies.push(self.db.generate_id()? + FIRST_DIRENT_COOKIE);
+            }
+        }
+
+        let trees = (&self.inodes, &self.parent_index, &self.dirents, &self.chunks, &self.journal);
+        let result = trees.transaction(
+            |(inodes, index, dirents, chunks, journal_tree)| {
+                let mut orphaned = Vec::new();
+                let mut seq = first_seq;
+                let mut cookies = cookies.iter();
+                for op in ops {
+                    let record = match op {
+                        TxnOp::PutInode(inode) => {
+                            let cookie = *cookies.next().expect("cookie per inode");
+                            self.txn_put_inode(inodes, index, dirents, inode, cookie)?;
+                            Some(JournalOp::PutInode(inode.clone()))
+                        }
+                        TxnOp::DeleteInode(ino) => {
+                            self.txn_delete_inode(inodes, index, dirents, *ino)?;
+                            Some(JournalOp::DeleteInode(*ino))
+                        }
+                        TxnOp::AddChunkRef {
+                            chunk_id,
+                            message_id,
+                        } => {
+                            let (stored, ref_count) = match chunks.get(chunk_id.as_bytes())? {
+                                Some(data) if data.len() >= 8 => (
+                                    i32::from_be_bytes(data[..4].try_into().unwrap()),
+                                    u32::from_be_bytes(data[4..8].try_into().unwrap()) + 1,
+                                ),
+                                _ => (PENDING_MESSAGE_ID, 1),
+                            };
+                            // A chunk queued offline may have been uploaded meanwhile
+                            let message_id = if *message_id == PENDING_MESSAGE_ID {
+                                stored
+                            } else {
+                                *message_id
+                            };
+                            chunks.insert(
+                                chunk_id.as_bytes(),
+                                Self::chunk_ref_value(message_id, ref_count),
+                            )?;
+                            Some(JournalOp::PutChunkRef {
+                                chunk_id: chunk_id.clone(),
+                                message_id,
+                                ref_count,
+                            })
+                        }
+                        TxnOp::DecrementChunkRef(chunk_id) => match chunks.get(chunk_id.as_bytes())? {
+                            Some(data) if data.len() >= 8 => {
+                                let message_id = i32::from_be_bytes(data[..4].try_into().unwrap());
+                                let ref_count = u32::from_be_bytes(data[4..8].try_into().unwrap());
+                                if ref_count <= 1 {
+                                    chunks.remove(chunk_id.as_bytes())?;
+                                    orphaned.push((chunk_id.clone(), message_id));
+                                    Some(JournalOp::DeleteChunkRef(chunk_id.clone()))
+                                } else {
+                                    chunks.insert(
+                                        chunk_id.as_bytes(),
+                                        Self::chunk_ref_value(message_id, ref_count - 1),
+                                    )?;
+                                    Some(JournalOp::PutChunkRef {
+                                        chunk_id: chunk_id.clone(),
+                                        message_id,
+                                        ref_count: ref_count - 1,
+                                    })
+                                }
+                            }
+                            _ => None,
+                        },
+                        TxnOp::ResolvePendingChunk {
+                            ino,
+                            chunk_id,
+                            message_id,
+                        } => {
+                            let cookie = *cookies.next().expect("cookie per inode");
+                            self.txn_resolve_pending(inodes, index, dirents, *ino, chunk_id, *message_id, cookie)?
+                                .map(|inode| JournalOp::PutInode(Box::new(inode)))
+                        }
+                    };
+
+                    if let (true, Some(record)) = (journal, record) {
+                        let entry = self.seal_journal_op(seq, &record).map_err(ConflictableTransactionError::Abort)?;
+                        journal_tree.insert(&seq.to_be_bytes()[..], entry)?;
+                        seq += 1;
+                    }
+                }
+                Ok((orphaned, seq))
+            },
+        );
+
+        let (orphaned, next_seq) = result.map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => Error::from(e),
+        })?;
+        self.journal_seq.store(next_seq, Ordering::SeqCst);
+
+        // Only touch the cache once the transaction is durable
+        let mut cache = self.cache.lock();
+        for op in ops {
+            match op {
+                TxnOp::PutInode(inode) => {
+                    debug!("Saved inode {} ({})", inode.ino, inode.name);
+                    if inode.ino != inode.parent {
+                        cache.remove_negative(&self.parent_name_key(inode.parent, &inode.name));
+                    }
+                    cache.insert((**inode).clone());
+                }
+                TxnOp::DeleteInode(ino) => {
+                    debug!("Deleted inode {}", ino);
+                    cache.remove(*ino);
+                }
+                TxnOp::ResolvePendingChunk { ino, .. } => cache.remove(*ino),
+                _ => {}
+            }
+        }
+        Ok(orphaned)
+    }
+
+    /// Write an inode, its index entry and directory entry inside a transaction
+    fn txn_put_inode(
+        &self,
+        inodes: &TransactionalTree,
+        index: &TransactionalTree,
+        dirents: &TransactionalTree,
+        inode: &Inode,
+        new_cookie: u64,
+    ) -> ConflictableTransactionResult<(), Error> {
         let key = Self::inode_key(inode.ino);
+        let encrypted = self
+            .encrypt_inode(inode)
+            .map_err(ConflictableTransactionError::Abort)?;
+        let previous = inodes
+            .get(key)?
+            .and_then(|data| self.decrypt_inode(inode.ino, &data).ok());
+        inodes.insert(&key[..], encrypted)?;
+
+        // The root is its own parent and has no entry
+        if inode.ino == inode.parent {
+            return Ok(());
+        }
 
-        // Save inode data
-        self.inodes.insert(key, encrypted)?;
+        // Drop the entry left under the old name by a rename
+        if let Some(previous) = previous {
+            if previous.parent != inode.parent || previous.name != inode.name {
+                self.txn_unlink_entry(index, dirents, &previous)?;
+            }
+        }
+
+        let index_key = self.parent_name_key(inode.parent, &inode.name);
+        let cookie = match Self::parse_index_value(index.get(&index_key)?.as_deref()) {
+            Some((ino, Some(cookie))) if ino == inode.ino => cookie,
+            _ => {
+                index.insert(index_key, &Self::index_value(inode.ino, new_cookie)[..])?;
+                new_cookie
+            }
+        };
 
-        // Update parent-name index
-        let parent_key = Self::parent_name_key(inode.parent, &inode.name);
-        self.parent_index.insert(parent_key, &key[..])?;
+        let dirent_key = Self::dirent_key(inode.parent, cookie);
+        if dirents.get(dirent_key)?.is_none() {
+            let entry = self
+                .encrypt_dirent(&dirent_key, &DirEntry::for_inode(inode))
+                .map_err(ConflictableTransactionError::Abort)?;
+            dirents.insert(&dirent_key[..], entry)?;
+        }
+        Ok(())
+    }
+
+    /// Point an inode's queued references to a chunk at its message inside a transaction
+    ///
+    /// Returns the updated inode, or None if it no longer references the
+    /// chunk as queued.
+    #[allow(clippy::too_many_arguments)]
+    fn txn_resolve_pending(
+        &self,
+        inodes: &TransactionalTree,
+        index: &TransactionalTree,
+        dirents: &TransactionalTree,
+        ino: u64,
+        chunk_id: &str,
+        message_id: i32,
+        new_cookie: u64,
+    ) -> ConflictableTransactionResult<Option<Inode>, Error> {
+        let Some(data) = inodes.get(Self::inode_key(ino))? else {
+            return Ok(None);
+        };
+        let mut inode = self
+            .decrypt_inode(ino, &data)
+            .map_err(ConflictableTransactionError::Abort)?;
+        let mut resolved = false;
+        if let Some(manifest) = inode.manifest.as_mut() {
+            for chunk in manifest.chunks.iter_mut() {
+                if chunk.id == chunk_id && chunk.is_pending() {
+                    chunk.message_id = message_id;
+                    resolved = true;
+                }
+            }
+        }
+        if !resolved {
+            return Ok(None);
+        }
+        self.txn_put_inode(inodes, index, dirents, &inode, new_cookie)?;
+        Ok(Some(inode))
+    }
 
-        // Update cache
-        self.cache.write().insert(inode.ino, inode.clone());
+    /// Remove an inode, its index entry and directory entry inside a transaction
+    fn txn_delete_inode(
+        &self,
+        inodes: &TransactionalTree,
+        index: &TransactionalTree,
+        dirents: &TransactionalTree,
+        ino: u64,
+    ) -> ConflictableTransactionResult<(), Error> {
+        let key = Self::inode_key(ino);
+        if let Some(data) = inodes.get(key)? {
+            let inode = self
+                .decrypt_inode(ino, &data)
+                .map_err(ConflictableTransactionError::Abort)?;
+            if inode.ino != inode.parent {
+                self.txn_unlink_entry(index, dirents, &inode)?;
+            }
+        }
+        inodes.remove(&key[..])?;
+        Ok(())
+    }
 
-        debug!("Saved inode {} ({})", inode.ino, inode.name);
+    /// Remove the index and directory entries an inode is listed under
+    fn txn_unlink_entry(
+        &self,
+        index: &TransactionalTree,
+        dirents: &TransactionalTree,
+        inode: &Inode,
+    ) -> ConflictableTransactionResult<(), Error> {
+        let index_key = self.parent_name_key(inode.parent, &inode.name);
+        if let Some((ino, cookie)) = Self::parse_index_value(index.get(&index_key)?.as_deref()) {
+            if ino == inode.ino {
+                index.remove(index_key)?;
+                if let Some(cookie) = cookie {
+                    dirents.remove(&Self::dirent_key(inode.parent, cookie)[..])?;
+                }
+            }
+        }
         Ok(())
     }
 
+    /// Encode a parent-index value
+    fn index_value(ino: u64, cookie: u64) -> [u8; 16] {
+        let mut value = [0u8; 16];
+        value[..8].copy_from_slice(&ino.to_be_bytes());
+        value[8..].copy_from_slice(&cookie.to_be_bytes());
+        value
+    }
+
+    /// Decode a parent-index value into (ino, cookie)
+    ///
+    /// Entries written before directory entries existed have no cookie.
+    fn parse_index_value(value: Option<&[u8]>) -> Option<(u64, Option<u64>)> {
+        let value = value?;
+        let ino = u64::from_be_bytes(value.get(..8)?.try_into().unwrap());
+        let cookie = value
+            .get(8..16)
+            .map(|bytes| u64::from_be_bytes(bytes.try_into().unwrap()));
+        Some((ino, cookie))
+    }
+
+    /// Create directory entry key from parent and cookie
+    fn dirent_key(parent: u64, cookie: u64) -> [u8; 16] {
+        Self::index_value(parent, cookie)
+    }
+
+    /// Encrypt a directory entry bound to its key
+    fn encrypt_dirent(&self, key: &[u8], entry: &DirEntry) -> Result<Vec<u8>> {
+        let data = bincode::serialize(entry)?;
+        let aad = object_aad(ObjectKind::DirEntry, self.namespace_prefix(), key, 0);
+        Ok(self.seal(&data, &aad)?.to_bytes())
+    }
+
+    /// Decrypt a directory entry
+    fn decrypt_dirent(&self, key: &[u8], data: &[u8]) -> Result<DirEntry> {
+        let encrypted = EncryptedData::from_bytes(data)?;
+        let aad = object_aad(ObjectKind::DirEntry, self.namespace_prefix(), key, 0);
+        let decrypted = decrypt_bound(&self.key, &encrypted, &aad, None)?;
+        Ok(bincode::deserialize(&decrypted)?)
+    }
+
     /// Get an inode by number
     pub fn get_inode(&self, ino: u64) -> Result<Option<Inode>> {
         // Check cache first
-        if let Some(inode) = self.cache.read().get(&ino) {
-            return Ok(Some(inode.clone()));
+        if let Some(inode) = self.cache.lock().get(ino) {
+            return Ok(Some(inode));
         }
 
         let key = Self::inode_key(ino);
         match self.inodes.get(key)? {
             Some(data) => {
-                let inode = self.decrypt_inode(&data)?;
-                self.cache.write().insert(ino, inode.clone());
+                let inode = self.decrypt_inode(ino, &data)?;
+                self.cache.lock().insert(inode.clone());
                 Ok(Some(inode))
             }
             None => Ok(None),
@@ -257,77 +786,145 @@ impl MetadataStore {
 
     /// Lookup a child by name in a directory
     pub fn lookup(&self, parent: u64, name: &str) -> Result<Option<Inode>> {
-        let parent_key = Self::parent_name_key(parent, name);
-
-        match self.parent_index.get(parent_key)? {
-            Some(ino_bytes) => {
-                if ino_bytes.len() >= 8 {
-                    let ino = u64::from_be_bytes(ino_bytes[..8].try_into().unwrap());
-                    self.get_inode(ino)
-                } else {
-                    Ok(None)
+        let parent_key = self.parent_name_key(parent, name);
+
+        // Hold the cache lock across the read so a concurrent commit cannot
+        // create the entry between the miss and recording it
+        let ino = {
+            let mut cache = self.cache.lock();
+            if cache.is_negative(&parent_key) {
+                return Ok(None);
+            }
+            match self.parent_index.get(&parent_key)? {
+                Some(ino_bytes) if ino_bytes.len() >= 8 => {
+                    u64::from_be_bytes(ino_bytes[..8].try_into().unwrap())
+                }
+                Some(_) => return Ok(None),
+                None => {
+                    cache.insert_negative(parent_key);
+                    return Ok(None);
                 }
             }
-            None => Ok(None),
-        }
+        };
+        self.get_inode(ino)
     }
 
     /// Delete an inode
     pub fn delete_inode(&self, ino: u64) -> Result<()> {
-        // Get the inode first to remove from parent index
-        if let Some(inode) = self.get_inode(ino)? {
-            let parent_key = Self::parent_name_key(inode.parent, &inode.name);
-            self.parent_index.remove(parent_key)?;
-        }
+        self.apply_ops(&[TxnOp::DeleteInode(ino)], true)?;
+        Ok(())
+    }
 
-        let key = Self::inode_key(ino);
-        self.inodes.remove(key)?;
-        self.cache.write().remove(&ino);
-        debug!("Deleted inode {}", ino);
+    /// Remove an inode and its index entry without journaling
+    fn remove_inode(&self, ino: u64) -> Result<()> {
+        self.apply_ops(&[TxnOp::DeleteInode(ino)], false)?;
         Ok(())
     }
 
+    /// Read directory entries with a cookie greater than `after`
+    ///
+    /// Entries come back in cookie order, so a reader can resume from the
+    /// last cookie it saw.
+    pub fn read_dir(&self, parent: u64, after: u64, limit: usize) -> Result<Vec<(u64, DirEntry)>> {
+        let start = Self::dirent_key(parent, after.saturating_add(1).max(FIRST_DIRENT_COOKIE));
+        let end = Self::dirent_key(parent, u64::MAX);
+
+        let mut entries = Vec::new();
+        for item in self.dirents.range(start..=end).take(limit) {
+            let (key, value) = item?;
+            let cookie = u64::from_be_bytes(key[8..16].try_into().unwrap());
+            entries.push((cookie, self.decrypt_dirent(&key, &value)?));
+        }
+        Ok(entries)
+    }
+
+    /// Check whether a directory has any entries
+    pub fn has_children(&self, parent: u64) -> Result<bool> {
+        Ok(self.dirents.scan_prefix(parent.to_be_bytes()).next().transpose()?.is_some())
+    }
+
     /// Get all children of a directory
     pub fn get_children(&self, parent: u64) -> Result<Vec<Inode>> {
-        let prefix = parent.to_be_bytes();
         let mut children = Vec::new();
-
-        for result in self.parent_index.scan_prefix(&prefix) {
-            let (_, ino_bytes) = result?;
-            if ino_bytes.len() >= 8 {
-                let ino = u64::from_be_bytes(ino_bytes[..8].try_into().unwrap());
-                if let Some(inode) = self.get_inode(ino)? {
-                    // Make sure it's actually a child (not just prefix match)
-                    // Also exclude the parent itself (root inode is its own parent)
-                    if inode.parent == parent && inode.ino != parent {
-                        children.push(inode);
-                    }
+        for item in self.dirents.scan_prefix(parent.to_be_bytes()) {
+            let (key, value) = item?;
+            let entry = self.decrypt_dirent(&key, &value)?;
+            if let Some(inode) = self.get_inode(entry.ino)? {
+                if inode.parent == parent {
+                    children.push(inode);
                 }
             }
         }
-
         Ok(children)
     }
 
-    /// Save a chunk reference
-    pub fn save_chunk_ref(&self, chunk_id: &str, message_id: i32) -> Result<()> {
-        let key = chunk_id.as_bytes();
+    /// Visit every inode under `roots`, breadth first and each only once
+    ///
+    /// `visit` gets each inode and whether it is one of the roots; a
+    /// directory's entries are visited only if it returns true for it.
+    /// Directories are read a page at a time. Returns the roots that no
+    /// longer exist.
+    pub fn walk_trees(
+        &self,
+        roots: &[u64],
+        mut visit: impl FnMut(Inode, bool) -> bool,
+    ) -> Result<Vec<u64>> {
+        let mut missing = Vec::new();
+        let mut seen = HashSet::new();
+        let mut queue: VecDeque<(u64, bool)> = roots.iter().map(|&ino| (ino, true)).collect();
+        while let Some((ino, root)) = queue.pop_front() {
+            if !seen.insert(ino) {
+                continue;
+            }
+            let Some(inode) = self.get_inode(ino)? else {
+                if root {
+                    missing.push(ino);
+                }
+                continue;
+            };
 
-        // Get existing ref count or start at 0
-        let ref_count = match self.chunks.get(key)? {
-            Some(data) if data.len() >= 8 => {
-                let count = u32::from_be_bytes(data[4..8].try_into().unwrap());
-                count + 1
+            let is_dir = inode.is_dir();
+            if !visit(inode, root) || !is_dir {
+                continue;
+            }
+            let mut after = 0;
+            loop {
+                let page = self.read_dir(ino, after, WALK_PAGE)?;
+                let last_page = page.len() < WALK_PAGE;
+                for (cookie, entry) in page {
+                    queue.push_back((entry.ino, false));
+                    after = cookie;
+                }
+                if last_page {
+                    break;
+                }
             }
-            _ => 1,
+        }
+        Ok(missing)
+    }
+
+    /// Save a chunk reference
+    pub fn save_chunk_ref(&self, chunk_id: &str, message_id: i32) -> Result<()> {
+        let op = TxnOp::AddChunkRef {
+            chunk_id: chunk_id.to_string(),
+            message_id,
         };
+        self.apply_ops(&[op], true)?;
+        Ok(())
+    }
 
-        // Store: message_id (4 bytes) + ref_count (4 bytes)
+    /// Encode a chunk reference: message_id (4 bytes) + ref_count (4 bytes)
+    fn chunk_ref_value(message_id: i32, ref_count: u32) -> Vec<u8> {
         let mut value = Vec::with_capacity(8);
         value.extend_from_slice(&message_id.to_be_bytes());
         value.extend_from_slice(&ref_count.to_be_bytes());
+        value
+    }
 
-        self.chunks.insert(key, value)?;
+    /// Write a chunk reference without journaling
+    fn put_chunk_ref(&self, chunk_id: &str, message_id: i32, ref_count: u32) -> Result<()> {
+        self.chunks
+            .insert(chunk_id.as_bytes(), Self::chunk_ref_value(message_id, ref_count))?;
         Ok(())
     }
 
@@ -345,34 +942,149 @@ impl MetadataStore {
     }
 
     /// Decrement chunk reference count
+    ///
+    /// Returns the message ID once the last reference is gone, so the chunk
+    /// can be deleted from Telegram.
     pub fn decrement_chunk_ref(&self, chunk_id: &str) -> Result<Option<i32>> {
-        let key = chunk_id.as_bytes();
+        let orphaned = self.apply_ops(&[TxnOp::DecrementChunkRef(chunk_id.to_string())], true)?;
+        Ok(orphaned.first().map(|(_, message_id)| *message_id))
+    }
 
-        match self.chunks.get(key)? {
-            Some(data) if data.len() >= 8 => {
-                let msg_id = i32::from_be_bytes(data[..4].try_into().unwrap());
-                let ref_count = u32::from_be_bytes(data[4..8].try_into().unwrap());
+    /// List every chunk reference as (chunk ID, message ID, ref count)
+    pub fn chunk_refs(&self) -> Result<Vec<(String, i32, u32)>> {
+        let mut refs = Vec::new();
+        for item in self.chunks.iter() {
+            let (key, value) = item?;
+            if value.len() < 8 {
+                continue;
+            }
+            refs.push((
+                String::from_utf8_lossy(&key).into_owned(),
+                i32::from_be_bytes(value[..4].try_into().unwrap()),
+                u32::from_be_bytes(value[4..8].try_into().unwrap()),
+            ));
+        }
+        Ok(refs)
+    }
 
-                if ref_count <= 1 {
-                    // Delete the reference
-                    self.chunks.remove(key)?;
-                    Ok(Some(msg_id)) // Return message_id to delete from Telegram
-                } else {
-                    // Decrement count
-                    let mut value = Vec::with_capacity(8);
-                    value.extend_from_slice(&msg_id.to_be_bytes());
-                    value.extend_from_slice(&(ref_count - 1).to_be_bytes());
-                    self.chunks.insert(key, value)?;
-                    Ok(None)
-                }
+    /// Overwrite a chunk reference count
+    pub fn set_chunk_ref(&self, chunk_id: &str, message_id: i32, ref_count: u32) -> Result<()> {
+        self.put_chunk_ref(chunk_id, message_id, ref_count)?;
+        self.record(JournalOp::PutChunkRef {
+            chunk_id: chunk_id.to_string(),
+            message_id,
+            ref_count,
+        })
+    }
+
+    /// Remove a chunk reference regardless of its count
+    pub fn remove_chunk_ref(&self, chunk_id: &str) -> Result<()> {
+        self.chunks.remove(chunk_id.as_bytes())?;
+        self.record(JournalOp::DeleteChunkRef(chunk_id.to_string()))
+    }
+
+    /// List every stored inode number
+    pub fn inode_numbers(&self) -> Result<Vec<u64>> {
+        let mut inos = Vec::new();
+        for key in self.inodes.iter().keys() {
+            let key = key?;
+            if key.len() >= 8 {
+                inos.push(u64::from_be_bytes(key[..8].try_into().unwrap()));
             }
-            _ => Ok(None),
         }
+        Ok(inos)
+    }
+
+    /// List every parent-index entry as (raw key, parent, inode number)
+    pub fn index_entries(&self) -> Result<Vec<(Vec<u8>, u64, u64)>> {
+        let mut entries = Vec::new();
+        for item in self.parent_index.iter() {
+            let (key, value) = item?;
+            let parent = match key.get(..8) {
+                Some(bytes) => u64::from_be_bytes(bytes.try_into().unwrap()),
+                None => 0,
+            };
+            let ino = match value.get(..8) {
+                Some(bytes) => u64::from_be_bytes(bytes.try_into().unwrap()),
+                None => 0,
+            };
+            entries.push((key.to_vec(), parent, ino));
+        }
+        Ok(entries)
+    }
+
+    /// Check whether a raw index key is the one an inode should be found under
+    pub fn is_index_key_for(&self, key: &[u8], inode: &Inode) -> bool {
+        self.parent_name_key(inode.parent, &inode.name) == key
+    }
+
+    /// Check whether an inode can be found through the parent index
+    pub fn has_index_entry(&self, inode: &Inode) -> Result<bool> {
+        Ok(self.entry_cookie(inode)?.is_some())
+    }
+
+    /// Get the cookie of the directory entry an inode is indexed under
+    ///
+    /// Returns `Some(0)` for a legacy index entry without a cookie.
+    pub fn entry_cookie(&self, inode: &Inode) -> Result<Option<u64>> {
+        let key = self.parent_name_key(inode.parent, &inode.name);
+        Ok(match Self::parse_index_value(self.parent_index.get(key)?.as_deref()) {
+            Some((ino, cookie)) if ino == inode.ino => Some(cookie.unwrap_or(0)),
+            _ => None,
+        })
+    }
+
+    /// Remove a parent-index entry by its raw key
+    pub fn remove_index_entry(&self, key: &[u8]) -> Result<()> {
+        self.parent_index.remove(key)?;
+        Ok(())
+    }
+
+    /// List every directory entry as (parent, cookie, entry)
+    ///
+    /// The entry is `None` when it cannot be decrypted.
+    pub fn dir_entries(&self) -> Result<Vec<(u64, u64, Option<DirEntry>)>> {
+        let mut entries = Vec::new();
+        for item in self.dirents.iter() {
+            let (key, value) = item?;
+            if key.len() < 16 {
+                continue;
+            }
+            let parent = u64::from_be_bytes(key[..8].try_into().unwrap());
+            let cookie = u64::from_be_bytes(key[8..16].try_into().unwrap());
+            entries.push((parent, cookie, self.decrypt_dirent(&key, &value).ok()));
+        }
+        Ok(entries)
+    }
+
+    /// Check whether a directory entry exists under a parent and cookie
+    pub fn has_dir_entry(&self, parent: u64, cookie: u64) -> Result<bool> {
+        Ok(self.dirents.contains_key(Self::dirent_key(parent, cookie))?)
+    }
+
+    /// Remove a directory entry by parent and cookie
+    pub fn remove_dir_entry(&self, parent: u64, cookie: u64) -> Result<()> {
+        self.dirents.remove(Self::dirent_key(parent, cookie))?;
+        Ok(())
     }
 
     /// Save general metadata
+    ///
+    /// Keys starting with `local:` are never journaled.
     pub fn save_metadata(&self, key: &str, value: &[u8]) -> Result<()> {
-        let encrypted = encrypt(&self.key, value, &[])?;
+        self.put_metadata(key, value)?;
+        if key.starts_with(LOCAL_METADATA_PREFIX) {
+            return Ok(());
+        }
+        self.record(JournalOp::PutMetadata {
+            key: key.to_string(),
+            value: value.to_vec(),
+        })
+    }
+
+    /// Write a metadata entry without journaling
+    fn put_metadata(&self, key: &str, value: &[u8]) -> Result<()> {
+        let encrypted = self.seal(value, &self.metadata_aad(key.as_bytes()))?;
         self.metadata.insert(key.as_bytes(), encrypted.to_bytes())?;
         Ok(())
     }
@@ -382,13 +1094,286 @@ impl MetadataStore {
         match self.metadata.get(key.as_bytes())? {
             Some(data) => {
                 let encrypted = EncryptedData::from_bytes(&data)?;
-                let decrypted = decrypt(&self.key, &encrypted, &[])?;
+                let aad = self.metadata_aad(key.as_bytes());
+                let decrypted = decrypt_bound(&self.key, &encrypted, &aad, self.unbound_aad())?;
                 Ok(Some(decrypted))
             }
             None => Ok(None),
         }
     }
 
+    /// Start recording mutations in the journal
+    ///
+    /// `last_seq` is the highest sequence number already uploaded, so
+    /// numbering continues across restarts once the journal is drained.
+    pub fn enable_journal(&self, last_seq: u64) -> Result<()> {
+        let pending_max = match self.journal.last()? {
+            Some((key, _)) if key.len() >= 8 => u64::from_be_bytes(key[..8].try_into().unwrap()),
+            _ => 0,
+        };
+        self.journal_seq
+            .store(last_seq.max(pending_max) + 1, Ordering::SeqCst);
+        self.journal_enabled.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Check whether mutations are being journaled
+    pub fn is_journal_enabled(&self) -> bool {
+        self.journal_enabled.load(Ordering::SeqCst)
+    }
+
+    /// AAD binding a journal entry to its sequence number, namespace and layout
+    fn journal_aad(&self, seq: u64, version: u64) -> Vec<u8> {
+        object_aad(ObjectKind::Journal, self.namespace_prefix(), &seq.to_be_bytes(), version)
+    }
+
+    /// Append an operation to the journal if journaling is enabled
+    fn record(&self, op: JournalOp) -> Result<()> {
+        if !self.is_journal_enabled() {
+            return Ok(());
+        }
+
+        let _guard = self.commit_lock.lock();
+        let seq = self.journal_seq.fetch_add(1, Ordering::SeqCst);
+        self.journal.insert(seq.to_be_bytes(), self.seal_journal_op(seq, &op)?)?;
+        Ok(())
+    }
+
+    /// Encrypt a journal operation bound to its sequence number
+    fn seal_journal_op(&self, seq: u64, op: &JournalOp) -> Result<Vec<u8>> {
+        let data = bincode::serialize(op)?;
+        Ok(self.seal(&data, &self.journal_aad(seq, INODE_LAYOUT_VERSION))?.to_bytes())
+    }
+
+    /// Number of journal operations not yet uploaded
+    pub fn journal_len(&self) -> usize {
+        self.journal.len()
+    }
+
+    /// Get up to `limit` pending journal operations in sequence order
+    pub fn pending_journal(&self, limit: usize) -> Result<Vec<(u64, JournalOp)>> {
+        let mut ops = Vec::new();
+        for item in self.journal.iter().take(limit) {
+            let (key, value) = item?;
+            if key.len() < 8 {
+                continue;
+            }
+            let seq = u64::from_be_bytes(key[..8].try_into().unwrap());
+            ops.push((seq, self.open_inodes(&value, |version| self.journal_aad(seq, version), None)?));
+        }
+        Ok(ops)
+    }
+
+    /// Drop journal operations up to and including `seq` once uploaded
+    pub fn ack_journal(&self, seq: u64) -> Result<()> {
+        for item in self.journal.range(..=seq.to_be_bytes()) {
+            let (key, _) = item?;
+            self.journal.remove(key)?;
+        }
+        Ok(())
+    }
+
+    /// Describe the whole store as journal operations (for checkpoints)
+    pub fn journal_snapshot(&self) -> Result<Vec<JournalOp>> {
+        let mut ops = Vec::new();
+
+        for item in self.inodes.iter() {
+            let (key, value) = item?;
+            if key.len() < 8 {
+                continue;
+            }
+            let ino = u64::from_be_bytes(key[..8].try_into().unwrap());
+            ops.push(JournalOp::PutInode(Box::new(self.decrypt_inode(ino, &value)?)));
+        }
+
+        for item in self.chunks.iter() {
+            let (key, value) = item?;
+            if value.len() < 8 {
+                continue;
+            }
+            ops.push(JournalOp::PutChunkRef {
+                chunk_id: String::from_utf8_lossy(&key).into_owned(),
+                message_id: i32::from_be_bytes(value[..4].try_into().unwrap()),
+                ref_count: u32::from_be_bytes(value[4..8].try_into().unwrap()),
+            });
+        }
+
+        for item in self.metadata.iter() {
+            let (key, _) = item?;
+            let key = String::from_utf8_lossy(&key).into_owned();
+            if key.starts_with(LOCAL_METADATA_PREFIX) {
+                continue;
+            }
+            if let Some(value) = self.get_metadata(&key)? {
+                ops.push(JournalOp::PutMetadata { key, value });
+            }
+        }
+
+        Ok(ops)
+    }
+
+    /// Apply a journaled operation without journaling it again
+    pub fn apply_journal_op(&self, op: &JournalOp) -> Result<()> {
+        match op {
+            JournalOp::PutInode(inode) => {
+                self.put_inode(inode)?;
+                self.next_ino.fetch_max(inode.ino + 1, Ordering::SeqCst);
+            }
+            JournalOp::DeleteInode(ino) => self.remove_inode(*ino)?,
+            JournalOp::PutChunkRef {
+                chunk_id,
+                message_id,
+                ref_count,
+            } => self.put_chunk_ref(chunk_id, *message_id, *ref_count)?,
+            JournalOp::DeleteChunkRef(chunk_id) => {
+                self.chunks.remove(chunk_id.as_bytes())?;
+            }
+            JournalOp::PutMetadata { key, value } => self.put_metadata(key, value)?,
+        }
+        Ok(())
+    }
+
+    /// Check whether unbound ciphertexts are rejected
+    pub fn is_aad_bound(&self) -> bool {
+        self.aad_bound.load(Ordering::SeqCst)
+    }
+
+    /// Re-seal every inode and metadata entry bound to its identity
+    ///
+    /// Entries written before AAD binding are rewritten in place. The store
+    /// only starts rejecting unbound ciphertexts once every entry succeeded;
+    /// the caller records that in `encryption.require_bound_metadata`.
+    pub fn bind_aad(&self) -> Result<MigrationStats> {
+        let mut stats = MigrationStats::default();
+
+        for item in self.inodes.iter() {
+            let (key, value) = item?;
+            if key.len() < 8 {
+                continue;
+            }
+            let ino = u64::from_be_bytes(key[..8].try_into().unwrap());
+
+            match self.decrypt_inode(ino, &value) {
+                Ok(inode) => {
+                    self.inodes.insert(key, self.encrypt_inode(&inode)?)?;
+                    stats.entries_migrated += 1;
+                    stats.bytes_processed += value.len() as u64;
+                }
+                Err(e) => {
+                    warn!("Failed to bind inode {}: {}", ino, e);
+                    stats.entries_failed += 1;
+                }
+            }
+        }
+
+        for item in self.metadata.iter() {
+            let (key, value) = item?;
+            let aad = self.metadata_aad(&key);
+
+            let rebound = EncryptedData::from_bytes(&value)
+                .and_then(|encrypted| decrypt_bound(&self.key, &encrypted, &aad, self.unbound_aad()))
+                .and_then(|plaintext| self.seal(&plaintext, &aad));
+            match rebound {
+                Ok(encrypted) => {
+                    self.metadata.insert(&key, encrypted.to_bytes())?;
+                    stats.entries_migrated += 1;
+                    stats.bytes_processed += value.len() as u64;
+                }
+                Err(e) => {
+                    warn!("Failed to bind metadata entry {}: {}", String::from_utf8_lossy(&key), e);
+                    stats.entries_failed += 1;
+                }
+            }
+        }
+
+        self.clear_cache();
+        if stats.entries_failed == 0 {
+            self.aad_bound.store(true, Ordering::SeqCst);
+        }
+        self.flush()?;
+
+        info!(
+            "AAD binding complete: {} entries bound, {} failed",
+            stats.entries_migrated, stats.entries_failed
+        );
+        Ok(stats)
+    }
+
+    /// Rebuild the parent index and directory entries from the inodes
+    ///
+    /// Migrates stores whose index holds plaintext names or whose directories
+    /// keep their children inside the inode. Everything is read before any
+    /// write; a crash midway leaves the migration flags unset and it runs
+    /// again on open. Inodes that cannot be read are skipped and left for
+    /// fsck to report, unless none can be read (most likely a wrong key).
+    pub fn rebuild_index(&self) -> Result<usize> {
+        let mut index = sled::Batch::default();
+        let mut dirents = sled::Batch::default();
+        let mut inodes = sled::Batch::default();
+        for key in self.parent_index.iter().keys() {
+            index.remove(key?);
+        }
+        for key in self.dirents.iter().keys() {
+            dirents.remove(key?);
+        }
+
+        let mut entries = 0;
+        let mut skipped = Vec::new();
+        let mut readable = 0;
+        for ino in self.inode_numbers()? {
+            let mut inode = match self.get_inode_required(ino) {
+                Ok(inode) => inode,
+                Err(e) => {
+                    warn!("Skipping unreadable inode {} while rebuilding the index: {}", ino, e);
+                    skipped.push(ino);
+                    continue;
+                }
+            };
+            readable += 1;
+            if !inode.legacy_children.is_empty() {
+                inode.legacy_children.clear();
+                inodes.insert(&Self::inode_key(ino)[..], self.encrypt_inode(&inode)?);
+            }
+            if inode.ino == inode.parent {
+                continue;
+            }
+
+            let cookie = self.db.generate_id()? + FIRST_DIRENT_COOKIE;
+            let dirent_key = Self::dirent_key(inode.parent, cookie);
+            index.insert(
+                self.parent_name_key(inode.parent, &inode.name),
+                &Self::index_value(ino, cookie)[..],
+            );
+            dirents.insert(
+                &dirent_key[..],
+                self.encrypt_dirent(&dirent_key, &DirEntry::for_inode(&inode))?,
+            );
+            entries += 1;
+        }
+        if readable == 0 && !skipped.is_empty() {
+            return Err(Error::Internal(format!(
+                "cannot rebuild the directory index: none of {} inodes could be read",
+                skipped.len()
+            )));
+        }
+
+        self.parent_index.apply_batch(index)?;
+        self.dirents.apply_batch(dirents)?;
+        self.inodes.apply_batch(inodes)?;
+        self.clear_cache();
+        self.put_metadata(INDEX_HASHED_KEY, &[1])?;
+        self.put_metadata(DIRENTS_KEY, &[1])?;
+        self.flush()?;
+        info!("Directory index rebuilt ({} entries)", entries);
+        if !skipped.is_empty() {
+            warn!(
+                "{} unreadable inode(s) left out of the index; run `tgcryptfs fsck` to review them",
+                skipped.len()
+            );
+        }
+        Ok(entries)
+    }
+
     /// Get filesystem statistics
     pub fn get_stats(&self) -> Result<FsStats> {
         let inode_count = self.inodes.len() as u64;
@@ -400,9 +1385,20 @@ impl MetadataStore {
         })
     }
 
+    /// Limit the inode cache to `bytes` and `negative_entries` failed lookups
+    pub fn with_cache_limits(self, bytes: u64, negative_entries: usize) -> Self {
+        self.cache.lock().set_limits(bytes, negative_entries);
+        self
+    }
+
+    /// Inode cache counters and occupancy
+    pub fn cache_stats(&self) -> InodeCacheStats {
+        self.cache.lock().stats()
+    }
+
     /// Clear the cache
     pub fn clear_cache(&self) {
-        self.cache.write().clear();
+        self.cache.lock().clear();
     }
 
     /// Flush to disk
@@ -432,6 +1428,8 @@ pub struct FsStats {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::chunk::{ChunkManifest, ChunkRef};
+    use crate::crypto::encrypt;
     use rand::RngCore;
 
     fn test_key() -> [u8; KEY_SIZE] {
@@ -440,6 +1438,20 @@ mod tests {
         key
     }
 
+    /// Open a store on disk, waiting for sled's background threads to
+    /// release the lock held by one just dropped
+    fn open_on_disk(path: &std::path::Path, key: [u8; KEY_SIZE], config: &EncryptionConfig) -> MetadataStore {
+        for _ in 0..50 {
+            match MetadataStore::open(path, key, config) {
+                Err(Error::Database(sled::Error::Io(e))) if e.kind() == std::io::ErrorKind::Other => {
+                    std::thread::sleep(std::time::Duration::from_millis(20));
+                }
+                result => return result.unwrap(),
+            }
+        }
+        MetadataStore::open(path, key, config).unwrap()
+    }
+
     #[test]
     fn test_create_store() {
         let key = test_key();
@@ -463,6 +1475,67 @@ mod tests {
         assert!(retrieved.is_file());
     }
 
+    #[test]
+    fn test_inode_numbers_not_reused_after_reopen() {
+        let key = test_key();
+        let temp = tempfile::TempDir::new().unwrap();
+        let path = temp.path().join("metadata.db");
+
+        let last = {
+            let store = open_on_disk(&path, key, &EncryptionConfig::default());
+            assert_eq!(store.generation(), 1);
+            let ino = store.alloc_ino().unwrap();
+            let file = Inode::new_file(ino, 1, "gone".to_string(), 0, 0, 0o644)
+                .with_generation(store.generation());
+            store.save_inode(&file).unwrap();
+            store.delete_inode(ino).unwrap();
+            ino
+        };
+
+        // The highest inode was deleted, but its number stays reserved
+        let store = open_on_disk(&path, key, &EncryptionConfig::default());
+        assert!(store.alloc_ino().unwrap() > last);
+        assert_eq!(store.generation(), 2);
+    }
+
+    #[test]
+    fn test_inode_without_generation_decodes() {
+        let key = test_key();
+        let store = MetadataStore::in_memory(key).unwrap();
+
+        // An inode serialized before the generation field was appended
+        let file = Inode::new_file(2, 1, "old.txt".to_string(), 0, 0, 0o644).with_generation(7);
+        let mut data = bincode::serialize(&file).unwrap();
+        data.truncate(data.len() - 8);
+        let encrypted = encrypt(&store.key, &data, &store.inode_aad(2, 0)).unwrap();
+        store.inodes.insert(MetadataStore::inode_key(2), encrypted.to_bytes()).unwrap();
+
+        let decoded = store.get_inode(2).unwrap().unwrap();
+        assert_eq!(decoded.name, "old.txt");
+        assert_eq!(decoded.generation, 0);
+    }
+
+    #[test]
+    fn test_configured_envelope() {
+        let config = EncryptionConfig {
+            aead_suite: AeadSuite::XChaCha20Poly1305,
+            ..Default::default()
+        };
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store = MetadataStore::from_db(db, test_key(), None, &config).unwrap();
+        store
+            .save_inode(&Inode::new_file(2, 1, "a".to_string(), 0, 0, 0o644))
+            .unwrap();
+
+        // The root, written while opening, uses the configured suite too
+        for ino in [1, 2] {
+            let raw = store.inodes.get(MetadataStore::inode_key(ino)).unwrap().unwrap();
+            let header = EncryptedData::from_bytes(&raw).unwrap().header.unwrap();
+            assert_eq!(header.suite, AeadSuite::XChaCha20Poly1305);
+        }
+        assert_eq!(store.get_inode(2).unwrap().unwrap().name, "a");
+    }
+
     #[test]
     fn test_lookup() {
         let key = test_key();
@@ -478,6 +1551,41 @@ mod tests {
         assert!(not_found.is_none());
     }
 
+    #[test]
+    fn test_negative_lookup_invalidated_on_create() {
+        let key = test_key();
+        let store = MetadataStore::in_memory(key).unwrap();
+
+        assert!(store.lookup(1, "later.txt").unwrap().is_none());
+        assert!(store.lookup(1, "later.txt").unwrap().is_none());
+        assert_eq!(store.cache_stats().negative_hits, 1);
+
+        let file = Inode::new_file(2, 1, "later.txt".to_string(), 1000, 1000, 0o644);
+        store.save_inode(&file).unwrap();
+        assert_eq!(store.lookup(1, "later.txt").unwrap().unwrap().ino, 2);
+        assert_eq!(store.cache_stats().negative_entries, 0);
+    }
+
+    #[test]
+    fn test_cache_limits_bound_memory() {
+        let key = test_key();
+        let size = Inode::new_file(2, 1, "file2".to_string(), 1000, 1000, 0o644).memory_size();
+        let store = MetadataStore::in_memory(key)
+            .unwrap()
+            .with_cache_limits(4 * size as u64, 0);
+
+        for i in 2..50 {
+            let file = Inode::new_file(i, 1, format!("file{}", i), 1000, 1000, 0o644);
+            store.save_inode(&file).unwrap();
+        }
+        let stats = store.cache_stats();
+        assert!(stats.bytes <= stats.capacity);
+        assert!(stats.evictions > 0);
+
+        // Evicted inodes are still read from the database
+        assert_eq!(store.get_inode(2).unwrap().unwrap().name, "file2");
+    }
+
     #[test]
     fn test_get_children() {
         let key = test_key();
@@ -527,6 +1635,50 @@ mod tests {
         assert!(store.get_chunk_ref("chunk1").unwrap().is_none());
     }
 
+    #[test]
+    fn test_resolve_pending_chunk() {
+        let store = MetadataStore::in_memory(test_key()).unwrap();
+        let pending = |ino: u64, chunk_id: &str| {
+            let mut inode = Inode::new_file(ino, 1, format!("f{}", ino), 0, 0, 0o644);
+            let mut manifest = ChunkManifest::new(1);
+            manifest.chunks.push(ChunkRef {
+                id: chunk_id.to_string(),
+                size: 4,
+                message_id: PENDING_MESSAGE_ID,
+                offset: 0,
+                original_size: 4,
+                compressed: false,
+            });
+            inode.manifest = Some(manifest);
+            inode
+        };
+        store.save_inode(&pending(2, "queued")).unwrap();
+        // Rewritten meanwhile: no longer references the queued chunk
+        store.save_inode(&pending(3, "other")).unwrap();
+        store.save_chunk_ref("queued", PENDING_MESSAGE_ID).unwrap();
+
+        let mut txn = store.transaction();
+        txn.save_chunk_ref("queued", 42)
+            .decrement_chunk_ref("queued")
+            .resolve_pending_chunk(2, "queued", 42)
+            .resolve_pending_chunk(3, "queued", 42);
+        assert!(txn.commit().unwrap().is_empty());
+        assert_eq!(store.chunk_refs().unwrap(), [("queued".to_string(), 42, 1)]);
+        let chunk = |ino| store.get_inode(ino).unwrap().unwrap().manifest.unwrap().chunks[0].clone();
+        assert_eq!(chunk(2).message_id, 42);
+        assert_eq!((chunk(3).id.as_str(), chunk(3).message_id), ("other", PENDING_MESSAGE_ID));
+
+        // A late reference staged while offline keeps the uploaded message
+        store.save_chunk_ref("queued", PENDING_MESSAGE_ID).unwrap();
+        assert_eq!(store.chunk_refs().unwrap(), [("queued".to_string(), 42, 2)]);
+
+        // An unreferenced chunk comes back as orphaned
+        let mut txn = store.transaction();
+        txn.save_chunk_ref("missing", 44).decrement_chunk_ref("missing");
+        assert_eq!(txn.commit().unwrap(), [("missing".to_string(), 44)]);
+        assert!(store.get_chunk_ref("missing").unwrap().is_none());
+    }
+
     #[test]
     fn test_metadata() {
         let key = test_key();
@@ -537,4 +1689,239 @@ mod tests {
         let value = store.get_metadata("test_key").unwrap().unwrap();
         assert_eq!(value, b"test_value");
     }
+
+    #[test]
+    fn test_swapped_inode_fails() {
+        let key = test_key();
+        let store = MetadataStore::in_memory(key).unwrap();
+
+        store.save_inode(&Inode::new_file(2, 1, "a.txt".to_string(), 1000, 1000, 0o644)).unwrap();
+        store.save_inode(&Inode::new_file(3, 1, "b.txt".to_string(), 1000, 1000, 0o644)).unwrap();
+
+        // Put inode 3's ciphertext under inode 2's key
+        let blob = store.inodes.get(MetadataStore::inode_key(3)).unwrap().unwrap();
+        store.inodes.insert(MetadataStore::inode_key(2), blob).unwrap();
+        store.clear_cache();
+
+        assert!(store.get_inode(2).is_err());
+        assert!(store.get_inode(3).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_bind_aad_migrates_unbound_entries() {
+        let key = test_key();
+        let store = MetadataStore::in_memory(key).unwrap();
+
+        // Simulate a store written before AAD binding
+        let file = Inode::new_file(2, 1, "old.txt".to_string(), 1000, 1000, 0o644);
+        let unbound = encrypt(&key, &bincode::serialize(&file).unwrap(), &[]).unwrap();
+        store.inodes.insert(MetadataStore::inode_key(2), unbound.to_bytes()).unwrap();
+        let unbound = encrypt(&key, b"old_value", &[]).unwrap();
+        store.metadata.insert("old_key", unbound.to_bytes()).unwrap();
+        store.aad_bound.store(false, Ordering::SeqCst);
+        store.clear_cache();
+
+        assert_eq!(store.get_inode(2).unwrap().unwrap().name, "old.txt");
+
+        let stats = store.bind_aad().unwrap();
+        assert_eq!(stats.entries_failed, 0);
+        assert!(store.is_aad_bound());
+
+        assert_eq!(store.get_inode(2).unwrap().unwrap().name, "old.txt");
+        assert_eq!(store.get_metadata("old_key").unwrap().unwrap(), b"old_value");
+
+        // Unbound blobs are rejected once migrated
+        let unbound = encrypt(&key, b"injected", &[]).unwrap();
+        store.metadata.insert("old_key", unbound.to_bytes()).unwrap();
+        assert!(store.get_metadata("old_key").is_err());
+    }
+
+    #[test]
+    fn test_binding_required_by_config_after_reopen() {
+        let key = test_key();
+        let temp = tempfile::TempDir::new().unwrap();
+        let path = temp.path().join("metadata.db");
+        {
+            let store = open_on_disk(&path, key, &EncryptionConfig::default());
+            let unbound = encrypt(&key, b"old_value", &[]).unwrap();
+            store.metadata.insert("old_key", unbound.to_bytes()).unwrap();
+            store.flush().unwrap();
+        }
+
+        let legacy = EncryptionConfig {
+            require_bound_metadata: false,
+            ..Default::default()
+        };
+        let store = open_on_disk(&path, key, &legacy);
+        assert!(!store.is_aad_bound());
+        assert_eq!(store.get_metadata("old_key").unwrap().unwrap(), b"old_value");
+        drop(store);
+
+        let store = open_on_disk(&path, key, &EncryptionConfig::default());
+        assert!(store.is_aad_bound());
+        assert!(store.get_metadata("old_key").is_err());
+    }
+
+    #[test]
+    fn test_rebuild_on_open_rejects_unbound_inodes() {
+        let key = test_key();
+        let temp = tempfile::TempDir::new().unwrap();
+        let path = temp.path().join("metadata.db");
+        {
+            let store = open_on_disk(&path, key, &EncryptionConfig::default());
+            store
+                .save_inode(&Inode::new_file(2, 1, "bound".to_string(), 0, 0, 0o644))
+                .unwrap();
+            let file = Inode::new_file(3, 1, "unbound".to_string(), 0, 0, 0o644);
+            let unbound = encrypt(&key, &bincode::serialize(&file).unwrap(), &[]).unwrap();
+            store.inodes.insert(MetadataStore::inode_key(3), unbound.to_bytes()).unwrap();
+            // Deleting the marker forces a rebuild on the next open
+            store.metadata.remove(DIRENTS_KEY).unwrap();
+            store.flush().unwrap();
+        }
+
+        let store = open_on_disk(&path, key, &EncryptionConfig::default());
+        assert_eq!(store.lookup(1, "bound").unwrap().unwrap().ino, 2);
+        assert!(store.lookup(1, "unbound").unwrap().is_none());
+        assert!(store.get_inode(3).is_err());
+    }
+
+    #[test]
+    fn test_rebuild_index_migrates_legacy_layout() {
+        let key = test_key();
+        let store = MetadataStore::in_memory(key).unwrap();
+
+        // Simulate a plaintext-name index and children kept in the inode
+        let file = Inode::new_file(2, 1, "secret.txt".to_string(), 1000, 1000, 0o644);
+        store.save_inode(&file).unwrap();
+        let mut root = store.get_inode_required(1).unwrap();
+        root.legacy_children = vec![2];
+        store.inodes.insert(MetadataStore::inode_key(1), store.encrypt_inode(&root).unwrap()).unwrap();
+        store.parent_index.clear().unwrap();
+        store.dirents.clear().unwrap();
+        let mut plain_key = 1u64.to_be_bytes().to_vec();
+        plain_key.extend_from_slice(b"secret.txt");
+        store.parent_index.insert(plain_key, &MetadataStore::inode_key(2)[..]).unwrap();
+        store.metadata.remove(INDEX_HASHED_KEY).unwrap();
+        store.metadata.remove(DIRENTS_KEY).unwrap();
+        store.clear_cache();
+
+        assert_eq!(store.rebuild_index().unwrap(), 1);
+        assert!(store.get_metadata(INDEX_HASHED_KEY).unwrap().is_some());
+        assert!(store.get_metadata(DIRENTS_KEY).unwrap().is_some());
+        assert_eq!(store.lookup(1, "secret.txt").unwrap().unwrap().ino, 2);
+        assert_eq!(store.get_children(1).unwrap().len(), 1);
+        assert!(store.get_inode_required(1).unwrap().legacy_children.is_empty());
+
+        for key in store.parent_index.iter().keys() {
+            let key = key.unwrap();
+            assert!(!key.windows(6).any(|w| w == b"secret"));
+        }
+    }
+
+    #[test]
+    fn test_rebuild_index_skips_unreadable_inode() {
+        let store = MetadataStore::in_memory(test_key()).unwrap();
+        store
+            .save_inode(&Inode::new_file(2, 1, "good".to_string(), 0, 0, 0o644))
+            .unwrap();
+        store
+            .inodes
+            .insert(MetadataStore::inode_key(3), &b"not an inode"[..])
+            .unwrap();
+        store.clear_cache();
+
+        assert_eq!(store.rebuild_index().unwrap(), 1);
+        assert_eq!(store.lookup(1, "good").unwrap().unwrap().ino, 2);
+        let report = crate::metadata::fsck::check(&store, None).unwrap();
+        assert!(matches!(
+            report.problems.as_slice(),
+            [crate::metadata::FsckProblem::UnreadableInode { ino: 3, .. }]
+        ));
+
+        // A store nothing can be read from keeps its index
+        let wrong_key = MetadataStore::in_memory([9u8; KEY_SIZE]).unwrap();
+        wrong_key.inodes.clear().unwrap();
+        wrong_key
+            .inodes
+            .insert(MetadataStore::inode_key(3), &b"not an inode"[..])
+            .unwrap();
+        wrong_key.clear_cache();
+        assert!(wrong_key.rebuild_index().is_err());
+    }
+
+    #[test]
+    fn test_transaction_commits_atomically() {
+        let store = MetadataStore::in_memory(test_key()).unwrap();
+        store.enable_journal(0).unwrap();
+        store.save_chunk_ref("c1", 7).unwrap();
+
+        let mut root = store.get_inode_required(1).unwrap();
+        let file = Inode::new_file(2, 1, "a.txt".to_string(), 1000, 1000, 0o644);
+        root.attrs.nlink = 3;
+
+        let mut txn = store.transaction();
+        txn.save_inode(&file)
+            .save_inode(&root)
+            .save_chunk_ref("c2", 8)
+            .decrement_chunk_ref("c1");
+        let orphaned = txn.commit().unwrap();
+
+        assert_eq!(orphaned, vec![("c1".to_string(), 7)]);
+        assert_eq!(store.lookup(1, "a.txt").unwrap().unwrap().ino, 2);
+        assert_eq!(store.get_inode_required(1).unwrap().attrs.nlink, 3);
+        assert_eq!(store.read_dir(1, 0, 10).unwrap()[0].1.name, "a.txt");
+        assert_eq!(store.get_chunk_ref("c2").unwrap(), Some(8));
+        assert!(store.get_chunk_ref("c1").unwrap().is_none());
+
+        let seqs: Vec<u64> = store.pending_journal(100).unwrap().into_iter().map(|(seq, _)| seq).collect();
+        assert_eq!(seqs, vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_transaction_rolls_back_on_error() {
+        let store = MetadataStore::in_memory(test_key()).unwrap();
+
+        // An undecryptable inode makes the delete abort the transaction
+        store.inodes.insert(MetadataStore::inode_key(50), b"garbage".to_vec()).unwrap();
+
+        let file = Inode::new_file(2, 1, "a.txt".to_string(), 1000, 1000, 0o644);
+        let mut txn = store.transaction();
+        txn.save_inode(&file).save_chunk_ref("c1", 7).delete_inode(50);
+        assert!(txn.commit().is_err());
+
+        assert!(store.get_inode(2).unwrap().is_none());
+        assert!(store.lookup(1, "a.txt").unwrap().is_none());
+        assert!(store.get_chunk_ref("c1").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_read_dir_pages_by_cookie() {
+        let store = MetadataStore::in_memory(test_key()).unwrap();
+        let dir = Inode::new_directory(2, 1, "dir".to_string(), 1000, 1000, 0o755);
+        store.save_inode(&dir).unwrap();
+        for i in 0..5u64 {
+            let file = Inode::new_file(10 + i, 2, format!("f{}", i), 1000, 1000, 0o644);
+            store.save_inode(&file).unwrap();
+        }
+
+        let first = store.read_dir(2, 0, 2).unwrap();
+        assert_eq!(first.len(), 2);
+        assert!(first[0].0 >= FIRST_DIRENT_COOKIE);
+        let rest = store.read_dir(2, first[1].0, 10).unwrap();
+        let names: Vec<_> = first.iter().chain(&rest).map(|(_, e)| e.name.clone()).collect();
+        assert_eq!(names, vec!["f0", "f1", "f2", "f3", "f4"]);
+
+        // Renaming out of the directory moves the entry; deleting drops it
+        let mut moved = store.get_inode_required(10).unwrap();
+        moved.parent = 1;
+        store.save_inode(&moved).unwrap();
+        store.delete_inode(11).unwrap();
+
+        assert_eq!(store.read_dir(2, 0, 10).unwrap().len(), 3);
+        assert_eq!(store.read_dir(1, 0, 10).unwrap().len(), 2);
+        assert!(store.lookup(2, "f1").unwrap().is_none());
+        assert!(store.has_children(2).unwrap());
+        assert!(!store.has_children(10).unwrap());
+    }
 }
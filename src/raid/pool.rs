@@ -10,6 +10,7 @@ use tracing::{debug, error, info, warn};
 
 use crate::chunk::StripeInfo;
 use crate::config::TelegramConfig;
+use crate::crypto::KEY_SIZE;
 use crate::error::{Error, Result};
 use crate::telegram::TelegramBackend;
 
@@ -29,7 +30,19 @@ pub struct AccountPool {
 
 impl AccountPool {
     /// Create a new account pool (does not connect)
+    ///
+    /// Account sessions are stored in plaintext; use
+    /// [`AccountPool::with_session_key`] to keep them encrypted.
     pub fn new(config: PoolConfig) -> Result<Self> {
+        Self::open(config, None)
+    }
+
+    /// Create an account pool whose sessions are encrypted under `session_key`
+    pub fn with_session_key(config: PoolConfig, session_key: [u8; KEY_SIZE]) -> Result<Self> {
+        Self::open(config, Some(session_key))
+    }
+
+    fn open(config: PoolConfig, session_key: Option<[u8; KEY_SIZE]>) -> Result<Self> {
         // Validate configuration
         config.validate()?;
 
@@ -49,8 +62,7 @@ impl AccountPool {
         // Create backends from enabled account configs
         let mut backends = Vec::with_capacity(enabled_accounts.len());
         for account in &enabled_accounts {
-            let telegram_config = Self::account_to_telegram_config(account);
-            let backend = TelegramBackend::new(telegram_config);
+            let backend = Self::account_to_backend(account, session_key);
             backends.push(Arc::new(backend));
         }
 
@@ -71,6 +83,15 @@ impl AccountPool {
         })
     }
 
+    /// Create the backend of an account, encrypting its session if keyed
+    fn account_to_backend(account: &AccountConfig, session_key: Option<[u8; KEY_SIZE]>) -> TelegramBackend {
+        let backend = TelegramBackend::new(Self::account_to_telegram_config(account));
+        match session_key {
+            Some(key) => backend.with_session_key(key),
+            None => backend,
+        }
+    }
+
     /// Convert AccountConfig to TelegramConfig
     fn account_to_telegram_config(account: &AccountConfig) -> TelegramConfig {
         TelegramConfig {
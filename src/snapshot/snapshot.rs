@@ -4,13 +4,18 @@
 //! Since chunk data is immutable and content-addressed, snapshots
 //! only need to store inode metadata.
 
-use crate::crypto::{decrypt, encrypt, EncryptedData, KEY_SIZE};
+use crate::crypto::{decrypt_bound, encrypt, object_aad, EncryptedData, ObjectKind, KEY_SIZE};
 use crate::error::{Error, Result};
-use crate::metadata::Inode;
+use crate::metadata::{decode_inodes, Inode, InodeLayout};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
+/// Object version of exported snapshot indexes in the current inode layout
+///
+/// Older exports hold inodes in the layout from before generations.
+const INDEX_LAYOUT_VERSION: u64 = 1;
+
 /// A filesystem snapshot
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Snapshot {
@@ -157,18 +162,39 @@ impl SnapshotManager {
         }
     }
 
+    /// AAD binding the exported snapshot index
+    fn index_aad(version: u64) -> Vec<u8> {
+        object_aad(ObjectKind::SnapshotIndex, None, b"snapshots", version)
+    }
+
     /// Encrypt and serialize all snapshots for storage
     pub fn export(&self) -> Result<Vec<u8>> {
         let data = bincode::serialize(&self.snapshots)?;
-        let encrypted = encrypt(&self.key, &data, b"snapshots")?;
+        let encrypted = encrypt(&self.key, &data, &Self::index_aad(INDEX_LAYOUT_VERSION))?;
         Ok(encrypted.to_bytes())
     }
 
     /// Import snapshots from encrypted data
+    ///
+    /// Inodes of older exports are converted to the current layout.
     pub fn import(&mut self, data: &[u8]) -> Result<()> {
         let encrypted = EncryptedData::from_bytes(data)?;
-        let decrypted = decrypt(&self.key, &encrypted, b"snapshots")?;
-        self.snapshots = bincode::deserialize(&decrypted)?;
+        let current = Self::index_aad(INDEX_LAYOUT_VERSION);
+        if let Ok(decrypted) = decrypt_bound(&self.key, &encrypted, &current, None) {
+            self.snapshots = bincode::deserialize(&decrypted)?;
+            return Ok(());
+        }
+
+        // Exports written before AAD binding used a fixed "snapshots" AAD
+        let decrypted = decrypt_bound(&self.key, &encrypted, &Self::index_aad(0), Some(b"snapshots"))?;
+        let mut snapshots: Vec<Snapshot> = bincode::deserialize(&decrypted)?;
+        for snapshot in &mut snapshots {
+            for data in snapshot.inodes.values_mut() {
+                let inode: Inode = decode_inodes(data, InodeLayout::Legacy)?;
+                *data = bincode::serialize(&inode)?;
+            }
+        }
+        self.snapshots = snapshots;
         Ok(())
     }
 
@@ -278,4 +304,23 @@ mod tests {
         assert_eq!(manager2.list().len(), 1);
         assert_eq!(manager2.list()[0].name, "test");
     }
+
+    #[test]
+    fn test_import_converts_legacy_inodes() {
+        let key = test_key();
+        let mut snapshot = Snapshot::new("old".to_string(), None);
+        let file = test_inode(2, "file.txt").with_generation(7);
+        // Written before inodes had a generation (the last field)
+        let mut data = bincode::serialize(&file).unwrap();
+        data.truncate(data.len() - 8);
+        snapshot.inodes.insert(2, data);
+
+        let index = bincode::serialize(&vec![snapshot]).unwrap();
+        let exported = encrypt(&key, &index, &SnapshotManager::index_aad(0)).unwrap();
+
+        let mut manager = SnapshotManager::new(key, 10);
+        manager.import(&exported.to_bytes()).unwrap();
+        let inode = manager.list()[0].get_inode(2).unwrap().unwrap();
+        assert_eq!((inode.name.as_str(), inode.generation), ("file.txt", 0));
+    }
 }
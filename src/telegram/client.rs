@@ -4,14 +4,17 @@
 //! All data is uploaded to "Saved Messages" for private storage.
 
 use crate::config::TelegramConfig;
+use crate::crypto::KEY_SIZE;
 use crate::error::{Error, Result};
 use crate::telegram::rate_limit::{ExponentialBackoff, RateLimiter};
+use crate::telegram::session::EncryptedSession;
 use crate::telegram::{CHUNK_FILE_PREFIX, METADATA_FILE_PREFIX};
 
 use grammers_client::{Client, InputMessage, SignInError};
 use grammers_mtsender::{SenderPool, SenderPoolHandle};
 use grammers_session::storages::SqliteSession;
 use grammers_session::defs::PeerRef;
+use grammers_session::Session;
 
 use std::io::{BufRead, Cursor, Write};
 use std::path::PathBuf;
@@ -19,6 +22,7 @@ use std::sync::Arc;
 use tokio::sync::RwLock;
 use tokio::task::JoinHandle;
 use tracing::{debug, error, info, warn};
+use zeroize::Zeroizing;
 
 /// Represents a message stored in Telegram
 #[derive(Debug, Clone)]
@@ -54,7 +58,7 @@ impl PasswordToken {
 struct ClientState {
     client: Client,
     #[allow(dead_code)]
-    session: Arc<SqliteSession>,
+    session: Arc<dyn Session>,
     pool_handle: SenderPoolHandle,
     _pool_task: JoinHandle<()>,
 }
@@ -71,6 +75,8 @@ pub struct TelegramBackend {
     download_limiter: RateLimiter,
     /// Client state (when connected)
     client_state: Arc<RwLock<Option<ClientState>>>,
+    /// Key for the encrypted session file (plaintext SQLite if unset)
+    session_key: Option<Zeroizing<[u8; KEY_SIZE]>>,
 }
 
 impl TelegramBackend {
@@ -90,9 +96,19 @@ impl TelegramBackend {
             upload_limiter,
             download_limiter,
             client_state: Arc::new(RwLock::new(None)),
+            session_key: None,
         }
     }
 
+    /// Store the session encrypted under the given key
+    ///
+    /// A plaintext session file found at the configured path is migrated
+    /// on the next connect.
+    pub fn with_session_key(mut self, key: [u8; KEY_SIZE]) -> Self {
+        self.session_key = Some(Zeroizing::new(key));
+        self
+    }
+
     /// Check if connected
     pub fn is_connected(&self) -> bool {
         if let Ok(guard) = self.client_state.try_read() {
@@ -103,7 +119,7 @@ impl TelegramBackend {
     }
 
     /// Get the session file path
-    fn session_path(&self) -> PathBuf {
+    pub fn session_path(&self) -> PathBuf {
         let path = &self.config.session_file;
         if path.extension().is_none() {
             path.with_extension("session")
@@ -123,28 +139,39 @@ impl TelegramBackend {
             })?;
         }
 
-        let session = Arc::new(
-            SqliteSession::open(&session_path).map_err(|e| {
-                Error::TelegramClient(format!("Failed to open session: {}", e))
-            })?
-        );
+        let state = match &self.session_key {
+            Some(key) => {
+                let session = EncryptedSession::open(&session_path, **key)?;
+                self.start_client(Arc::new(session))
+            }
+            None => {
+                warn!("Telegram session at {:?} is stored unencrypted", session_path);
+                let session = SqliteSession::open(&session_path).map_err(|e| {
+                    Error::TelegramClient(format!("Failed to open session: {}", e))
+                })?;
+                self.start_client(Arc::new(session))
+            }
+        };
+
+        *self.client_state.write().await = Some(state);
+        info!("Connected to Telegram");
+        Ok(())
+    }
 
+    /// Start the sender pool and client on top of a session
+    fn start_client<S: Session + 'static>(&self, session: Arc<S>) -> ClientState {
         let pool = SenderPool::new(Arc::clone(&session), self.config.api_id);
         let client = Client::new(&pool);
         let SenderPool { runner, handle, .. } = pool;
 
         let pool_task = tokio::spawn(runner.run());
 
-        let state = ClientState {
+        ClientState {
             client,
             session,
             pool_handle: handle,
             _pool_task: pool_task,
-        };
-
-        *self.client_state.write().await = Some(state);
-        info!("Connected to Telegram");
-        Ok(())
+        }
     }
 
     /// Check if authorized
@@ -304,9 +331,24 @@ impl TelegramBackend {
 
     /// Download a chunk by message ID
     pub async fn download_chunk(&self, message_id: i32) -> Result<Vec<u8>> {
-        let _permit = self.download_limiter.acquire().await;
-
         debug!("Downloading chunk from message {}", message_id);
+        self.download_with_retry(message_id, None).await
+    }
+
+    /// Download `len` bytes of a chunk starting at `offset`
+    ///
+    /// The result is shorter if the chunk ends before the range does.
+    pub async fn download_chunk_range(&self, message_id: i32, offset: u64, len: u64) -> Result<Vec<u8>> {
+        debug!(
+            "Downloading {} bytes at {} from message {}",
+            len, offset, message_id
+        );
+        self.download_with_retry(message_id, Some((offset, len))).await
+    }
+
+    /// Download a whole chunk or a range of it, retrying with backoff
+    async fn download_with_retry(&self, message_id: i32, range: Option<(u64, u64)>) -> Result<Vec<u8>> {
+        let _permit = self.download_limiter.acquire().await;
 
         let mut backoff = ExponentialBackoff::new(
             self.config.retry_base_delay_ms,
@@ -314,7 +356,7 @@ impl TelegramBackend {
         );
 
         loop {
-            match self.do_download(message_id).await {
+            match self.do_download(message_id, range).await {
                 Ok(data) => {
                     debug!("Downloaded {} bytes from message {}", data.len(), message_id);
                     return Ok(data);
@@ -333,7 +375,7 @@ impl TelegramBackend {
     }
 
     /// Internal download implementation
-    async fn do_download(&self, message_id: i32) -> Result<Vec<u8>> {
+    async fn do_download(&self, message_id: i32, range: Option<(u64, u64)>) -> Result<Vec<u8>> {
         let state = self.client_state.read().await;
         let client_state = state.as_ref().ok_or_else(|| {
             Error::TelegramClient("Not connected".to_string())
@@ -358,17 +400,44 @@ impl TelegramBackend {
             Error::TelegramDownload(format!("Message {} has no media", message_id))
         })?;
 
-        // Download to memory
-        let mut data = Vec::new();
-        let mut download = client_state.client.iter_download(&media);
+        let Some((offset, len)) = range else {
+            // Download to memory
+            let mut data = Vec::new();
+            let mut download = client_state.client.iter_download(&media);
 
-        while let Some(chunk) = download.next().await.map_err(|e| {
-            Error::TelegramDownload(format!("Failed to download chunk: {}", e))
-        })? {
-            data.extend_from_slice(&chunk);
+            while let Some(chunk) = download.next().await.map_err(|e| {
+                Error::TelegramDownload(format!("Failed to download chunk: {}", e))
+            })? {
+                data.extend_from_slice(&chunk);
+            }
+
+            return Ok(data);
+        };
+
+        // Requests must be aligned to their size, so fetch the aligned
+        // blocks covering the range and trim them
+        let block = range_block_size(len);
+        let start = offset - offset % block;
+        let end = offset.saturating_add(len);
+        let mut data = Vec::new();
+        let mut download = client_state
+            .client
+            .iter_download(&media)
+            .chunk_size(block as i32)
+            .skip_chunks((start / block) as i32);
+
+        while start + (data.len() as u64) < end {
+            match download.next().await.map_err(|e| {
+                Error::TelegramDownload(format!("Failed to download chunk range: {}", e))
+            })? {
+                Some(chunk) => data.extend_from_slice(&chunk),
+                None => break,
+            }
         }
 
-        Ok(data)
+        let skip = ((offset - start) as usize).min(data.len());
+        data.truncate(((end - start) as usize).min(data.len()));
+        Ok(data.split_off(skip))
     }
 
     /// Delete a message by ID
@@ -394,6 +463,16 @@ impl TelegramBackend {
 
     /// List all chunk messages in Saved Messages
     pub async fn list_chunks(&self) -> Result<Vec<TelegramMessage>> {
+        self.list_documents(&[CHUNK_FILE_PREFIX, METADATA_FILE_PREFIX]).await
+    }
+
+    /// List all metadata messages in Saved Messages
+    pub async fn list_metadata(&self) -> Result<Vec<TelegramMessage>> {
+        self.list_documents(&[METADATA_FILE_PREFIX]).await
+    }
+
+    /// List documents whose file name starts with one of the prefixes
+    async fn list_documents(&self, prefixes: &[&str]) -> Result<Vec<TelegramMessage>> {
         let state = self.client_state.read().await;
         let client_state = state.as_ref().ok_or_else(|| {
             Error::TelegramClient("Not connected".to_string())
@@ -414,7 +493,7 @@ impl TelegramBackend {
                 // Check if it's a document with our prefix
                 if let grammers_client::types::Media::Document(doc) = media {
                     let name = doc.name();
-                    if name.starts_with(CHUNK_FILE_PREFIX) || name.starts_with(METADATA_FILE_PREFIX) {
+                    if prefixes.iter().any(|prefix| name.starts_with(prefix)) {
                         messages.push(TelegramMessage {
                             id: msg.id(),
                             filename: Some(name.to_string()),
@@ -435,6 +514,11 @@ impl TelegramBackend {
         self.do_upload(&filename, data).await
     }
 
+    /// Download metadata by message ID
+    pub async fn download_metadata(&self, message_id: i32) -> Result<Vec<u8>> {
+        self.download_chunk(message_id).await
+    }
+
     /// Disconnect from Telegram
     pub async fn disconnect(&self) {
         let mut state = self.client_state.write().await;
@@ -445,6 +529,20 @@ impl TelegramBackend {
     }
 }
 
+/// Smallest ranged download block size Telegram accepts
+const MIN_RANGE_BLOCK: u64 = 4 * 1024;
+
+/// Largest ranged download block size Telegram accepts
+const MAX_RANGE_BLOCK: u64 = 512 * 1024;
+
+/// Block size used to download a range of `len` bytes
+///
+/// Telegram requires a power of two between 4 KB and 512 KB, with requests
+/// at offsets that are multiples of it.
+fn range_block_size(len: u64) -> u64 {
+    len.clamp(MIN_RANGE_BLOCK, MAX_RANGE_BLOCK).next_power_of_two()
+}
+
 /// Prompt for input (used during interactive auth)
 #[allow(dead_code)]
 pub fn prompt(message: &str) -> std::io::Result<String> {
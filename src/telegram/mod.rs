@@ -8,9 +8,11 @@
 
 mod client;
 mod rate_limit;
+mod session;
 
 pub use client::{TelegramBackend, TelegramMessage};
 pub use rate_limit::RateLimiter;
+pub use session::EncryptedSession;
 
 /// Maximum file size for Telegram (2GB for premium, 1.5GB for regular)
 pub const MAX_FILE_SIZE: u64 = 2 * 1024 * 1024 * 1024; // 2GB
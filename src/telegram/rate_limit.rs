@@ -55,17 +55,13 @@ impl RateLimiter {
         // Enforce minimum delay
         let min_delay = Duration::from_micros(self.min_delay_us.load(Ordering::Relaxed));
         if !min_delay.is_zero() {
-            let mut last_op = self.last_op.lock();
-            let elapsed = last_op.elapsed();
-
+            // The guard must not live across the sleep (it is not Send)
+            let elapsed = self.last_op.lock().elapsed();
             if elapsed < min_delay {
-                let wait_time = min_delay - elapsed;
-                drop(last_op); // Release lock while sleeping
-                sleep(wait_time).await;
-                last_op = self.last_op.lock();
+                sleep(min_delay - elapsed).await;
             }
 
-            *last_op = Instant::now();
+            *self.last_op.lock() = Instant::now();
         }
 
         RateLimitGuard { _permit: permit }
@@ -0,0 +1,604 @@
+//! Encrypted Telegram session storage
+//!
+//! The grammers session holds the account's authorization keys, so anyone
+//! who copies it can act as the account. `EncryptedSession` keeps the session
+//! in memory and persists it only as an encrypted envelope, sealed with a key
+//! derived from the volume key.
+//!
+//! Plaintext `SqliteSession` files left by older versions are imported and
+//! replaced by [`EncryptedSession::open`].
+//!
+//! Auth keys and the home DC are written as soon as they change. Update
+//! state and cached peers change constantly, so those writes are batched
+//! to one per [`PERSIST_INTERVAL`] and flushed on drop.
+
+use crate::crypto::{decrypt_bound, encrypt, object_aad, EncryptedData, ObjectKind, KEY_SIZE};
+use crate::error::{Error, Result};
+use grammers_session::defs::{
+    ChannelKind, ChannelState, DcOption, PeerAuth, PeerId, PeerInfo, PeerKind, UpdateState,
+    UpdatesState,
+};
+use grammers_session::storages::SqliteSession;
+use grammers_session::{Session, SessionData};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+use zeroize::Zeroizing;
+
+/// Header of a plaintext SQLite database
+const SQLITE_MAGIC: &[u8] = b"SQLite format 3\0";
+
+/// Identity the session blob is bound to
+const SESSION_AAD_ID: &[u8] = b"telegram-session";
+
+/// Minimum time between writes of frequently changing state
+pub const PERSIST_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Telegram session persisted only in encrypted form
+pub struct EncryptedSession {
+    /// Path of the encrypted session file
+    path: PathBuf,
+    /// Session encryption key
+    key: Zeroizing<[u8; KEY_SIZE]>,
+    /// Live session state
+    data: Mutex<SessionData>,
+    /// Hash of the last persisted state, to skip redundant writes
+    persisted: Mutex<Option<[u8; 32]>>,
+    /// When the session file was last written
+    last_persist: Mutex<Instant>,
+}
+
+impl EncryptedSession {
+    /// Open an encrypted session, importing a plaintext one if found
+    pub fn open<P: AsRef<Path>>(path: P, key: [u8; KEY_SIZE]) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let key = Zeroizing::new(key);
+
+        let (data, plaintext) = match std::fs::read(&path) {
+            Ok(bytes) if bytes.starts_with(SQLITE_MAGIC) => {
+                info!("Migrating plaintext session {:?} to encrypted storage", path);
+                (Self::import_plaintext(&path)?, true)
+            }
+            Ok(bytes) => (Self::decrypt_state(&key, &bytes)?.into_session_data(), false),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (SessionData::default(), false),
+            Err(e) => return Err(e.into()),
+        };
+
+        let session = EncryptedSession {
+            path,
+            key,
+            data: Mutex::new(data),
+            persisted: Mutex::new(None),
+            last_persist: Mutex::new(Instant::now()),
+        };
+        if plaintext {
+            // The plaintext is only scrubbed once its encrypted copy is durable
+            session.write(|| Self::scrub_plaintext(&session.path))?;
+        } else {
+            session.persist()?;
+        }
+        Ok(session)
+    }
+
+    /// Check whether a session file is still stored in plaintext
+    pub fn is_plaintext<P: AsRef<Path>>(path: P) -> bool {
+        std::fs::read(path)
+            .map(|bytes| bytes.starts_with(SQLITE_MAGIC))
+            .unwrap_or(false)
+    }
+
+    /// Read a plaintext SQLite session
+    fn import_plaintext(path: &Path) -> Result<SessionData> {
+        let sqlite = SqliteSession::open(path).map_err(|e| {
+            Error::TelegramClient(format!("Failed to open plaintext session: {}", e))
+        })?;
+        Ok(SessionData::from(sqlite))
+    }
+
+    /// Overwrite a plaintext session and remove its SQLite sidecar files
+    ///
+    /// The encrypted replacement is renamed over the same path afterwards.
+    fn scrub_plaintext(path: &Path) -> Result<()> {
+        let len = std::fs::metadata(path)?.len() as usize;
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(path)
+            .and_then(|mut file| {
+                file.write_all(&vec![0u8; len])?;
+                file.sync_all()
+            })?;
+        for suffix in ["-journal", "-wal", "-shm"] {
+            let mut sidecar = path.as_os_str().to_owned();
+            sidecar.push(suffix);
+            let _ = std::fs::remove_file(PathBuf::from(sidecar));
+        }
+        Ok(())
+    }
+
+    /// Decrypt a persisted session blob
+    fn decrypt_state(key: &[u8; KEY_SIZE], bytes: &[u8]) -> Result<StoredSession> {
+        let encrypted = EncryptedData::from_bytes(bytes)?;
+        let aad = object_aad(ObjectKind::Session, None, SESSION_AAD_ID, 0);
+        let plaintext = Zeroizing::new(decrypt_bound(key, &encrypted, &aad, None)?);
+        Ok(bincode::deserialize(&plaintext)?)
+    }
+
+    /// Encrypt the current state and atomically replace the session file
+    pub fn persist(&self) -> Result<()> {
+        self.write(|| Ok(()))
+    }
+
+    /// Encrypt the current state and replace the session file
+    ///
+    /// `before_replace` runs once the encrypted copy is synced to disk but
+    /// before it is renamed over the session file.
+    fn write(&self, before_replace: impl FnOnce() -> Result<()>) -> Result<()> {
+        let plaintext = {
+            let data = self.data.lock();
+            Zeroizing::new(bincode::serialize(&StoredSession::from_session_data(&data))?)
+        };
+
+        let hash = *blake3::hash(&plaintext).as_bytes();
+        let mut persisted = self.persisted.lock();
+        *self.last_persist.lock() = Instant::now();
+        if *persisted == Some(hash) {
+            return Ok(());
+        }
+
+        let aad = object_aad(ObjectKind::Session, None, SESSION_AAD_ID, 0);
+        let encrypted = encrypt(&self.key, &plaintext, &aad)?;
+
+        let tmp_path = self.tmp_path();
+        let replaced = (|| -> Result<()> {
+            let mut options = std::fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.mode(0o600);
+            }
+            let mut file = options.open(&tmp_path)?;
+            file.write_all(&encrypted.to_bytes())?;
+            file.sync_all()?;
+            before_replace()?;
+            std::fs::rename(&tmp_path, &self.path)?;
+            Ok(())
+        })();
+        if let Err(e) = replaced {
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+        if let Some(parent) = self.path.parent() {
+            std::fs::File::open(parent)?.sync_all()?;
+        }
+
+        *persisted = Some(hash);
+        Ok(())
+    }
+
+    /// Temporary file next to the session file, unique to this write
+    ///
+    /// Other processes may be writing the same session (e.g. a mount and
+    /// a CLI command), so the name carries the pid and a sequence number.
+    fn tmp_path(&self) -> PathBuf {
+        static TMP_SEQ: AtomicU64 = AtomicU64::new(0);
+        let mut name = self.path.file_name().unwrap_or_default().to_owned();
+        name.push(format!(
+            ".{}-{}.tmp",
+            std::process::id(),
+            TMP_SEQ.fetch_add(1, Ordering::Relaxed)
+        ));
+        self.path.with_file_name(name)
+    }
+
+    /// Re-encrypt the session file under a new key
+    pub fn rekey(&mut self, key: [u8; KEY_SIZE]) -> Result<()> {
+        self.key = Zeroizing::new(key);
+        *self.persisted.lock() = None;
+        self.persist()
+    }
+
+    /// Persist after a mutation, logging failures the trait cannot return
+    fn persist_or_log(&self) {
+        if let Err(e) = self.persist() {
+            error!("Failed to persist encrypted session: {}", e);
+        }
+    }
+
+    /// Persist a frequent mutation unless the file was written recently
+    ///
+    /// Skipped changes are written by a later mutation or on drop.
+    fn persist_soon(&self) {
+        if self.last_persist.lock().elapsed() >= PERSIST_INTERVAL {
+            self.persist_or_log();
+        }
+    }
+}
+
+impl Session for EncryptedSession {
+    fn home_dc_id(&self) -> i32 {
+        self.data.lock().home_dc
+    }
+
+    fn set_home_dc_id(&self, dc_id: i32) {
+        self.data.lock().home_dc = dc_id;
+        self.persist_or_log();
+    }
+
+    fn dc_option(&self, dc_id: i32) -> Option<DcOption> {
+        self.data.lock().dc_options.get(&dc_id).cloned()
+    }
+
+    fn set_dc_option(&self, dc_option: &DcOption) {
+        self.data
+            .lock()
+            .dc_options
+            .insert(dc_option.id, dc_option.clone());
+        self.persist_or_log();
+    }
+
+    fn peer(&self, peer: PeerId) -> Option<PeerInfo> {
+        let data = self.data.lock();
+        if peer.kind() == PeerKind::UserSelf {
+            data.peer_infos
+                .values()
+                .find(|info| matches!(info, PeerInfo::User { is_self: Some(true), .. }))
+                .cloned()
+        } else {
+            data.peer_infos.get(&peer).cloned()
+        }
+    }
+
+    fn cache_peer(&self, peer: &PeerInfo) {
+        let previous = self
+            .data
+            .lock()
+            .peer_infos
+            .insert(peer.id(), peer.clone());
+        if previous.as_ref() != Some(peer) {
+            self.persist_soon();
+        }
+    }
+
+    fn updates_state(&self) -> UpdatesState {
+        self.data.lock().updates_state.clone()
+    }
+
+    fn set_update_state(&self, update: UpdateState) {
+        {
+            let mut data = self.data.lock();
+            match update {
+                UpdateState::All(updates_state) => {
+                    data.updates_state = updates_state;
+                }
+                UpdateState::Primary { pts, date, seq } => {
+                    data.updates_state.pts = pts;
+                    data.updates_state.date = date;
+                    data.updates_state.seq = seq;
+                }
+                UpdateState::Secondary { qts } => {
+                    data.updates_state.qts = qts;
+                }
+                UpdateState::Channel { id, pts } => {
+                    data.updates_state.channels.retain(|c| c.id != id);
+                    data.updates_state.channels.push(ChannelState { id, pts });
+                }
+            }
+        }
+        self.persist_soon();
+    }
+}
+
+impl Drop for EncryptedSession {
+    fn drop(&mut self) {
+        if let Err(e) = self.persist() {
+            warn!("Failed to persist encrypted session on close: {}", e);
+        }
+    }
+}
+
+/// Serializable mirror of the grammers session state
+#[derive(Serialize, Deserialize)]
+struct StoredSession {
+    home_dc: i32,
+    dc_options: Vec<StoredDcOption>,
+    peers: Vec<StoredPeer>,
+    pts: i32,
+    qts: i32,
+    date: i32,
+    seq: i32,
+    channels: Vec<(i64, i32)>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredDcOption {
+    id: i32,
+    ipv4: String,
+    ipv6: String,
+    auth_key: Option<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize)]
+enum StoredPeer {
+    User {
+        id: i64,
+        hash: Option<i64>,
+        bot: Option<bool>,
+        is_self: Option<bool>,
+    },
+    Chat {
+        id: i64,
+    },
+    Channel {
+        id: i64,
+        hash: Option<i64>,
+        kind: Option<u8>,
+    },
+}
+
+impl StoredSession {
+    fn from_session_data(data: &SessionData) -> Self {
+        StoredSession {
+            home_dc: data.home_dc,
+            dc_options: data
+                .dc_options
+                .values()
+                .map(|dc| StoredDcOption {
+                    id: dc.id,
+                    ipv4: dc.ipv4.to_string(),
+                    ipv6: dc.ipv6.to_string(),
+                    auth_key: dc.auth_key.map(|key| key.to_vec()),
+                })
+                .collect(),
+            peers: data.peer_infos.values().map(StoredPeer::from_info).collect(),
+            pts: data.updates_state.pts,
+            qts: data.updates_state.qts,
+            date: data.updates_state.date,
+            seq: data.updates_state.seq,
+            channels: data
+                .updates_state
+                .channels
+                .iter()
+                .map(|c| (c.id, c.pts))
+                .collect(),
+        }
+    }
+
+    fn into_session_data(self) -> SessionData {
+        let mut data = SessionData {
+            home_dc: self.home_dc,
+            ..SessionData::default()
+        };
+
+        for dc in self.dc_options {
+            let (Ok(ipv4), Ok(ipv6)) = (dc.ipv4.parse(), dc.ipv6.parse()) else {
+                warn!("Skipping malformed DC option {} in session", dc.id);
+                continue;
+            };
+            let auth_key = dc.auth_key.and_then(|key| key.try_into().ok());
+            data.dc_options.insert(
+                dc.id,
+                DcOption {
+                    id: dc.id,
+                    ipv4,
+                    ipv6,
+                    auth_key,
+                },
+            );
+        }
+
+        for peer in self.peers {
+            let info = peer.into_info();
+            data.peer_infos.insert(info.id(), info);
+        }
+
+        data.updates_state = UpdatesState {
+            pts: self.pts,
+            qts: self.qts,
+            date: self.date,
+            seq: self.seq,
+            channels: self
+                .channels
+                .into_iter()
+                .map(|(id, pts)| ChannelState { id, pts })
+                .collect(),
+        };
+        data
+    }
+}
+
+impl StoredPeer {
+    fn from_info(info: &PeerInfo) -> Self {
+        match info {
+            PeerInfo::User {
+                id,
+                auth,
+                bot,
+                is_self,
+            } => StoredPeer::User {
+                id: *id,
+                hash: auth.map(|a| a.hash()),
+                bot: *bot,
+                is_self: *is_self,
+            },
+            PeerInfo::Chat { id } => StoredPeer::Chat { id: *id },
+            PeerInfo::Channel { id, auth, kind } => StoredPeer::Channel {
+                id: *id,
+                hash: auth.map(|a| a.hash()),
+                kind: kind.map(|k| match k {
+                    ChannelKind::Megagroup => 0,
+                    ChannelKind::Broadcast => 1,
+                    ChannelKind::Gigagroup => 2,
+                }),
+            },
+        }
+    }
+
+    fn into_info(self) -> PeerInfo {
+        match self {
+            StoredPeer::User {
+                id,
+                hash,
+                bot,
+                is_self,
+            } => PeerInfo::User {
+                id,
+                auth: hash.map(PeerAuth::from_hash),
+                bot,
+                is_self,
+            },
+            StoredPeer::Chat { id } => PeerInfo::Chat { id },
+            StoredPeer::Channel { id, hash, kind } => PeerInfo::Channel {
+                id,
+                auth: hash.map(PeerAuth::from_hash),
+                kind: kind.and_then(|k| match k {
+                    0 => Some(ChannelKind::Megagroup),
+                    1 => Some(ChannelKind::Broadcast),
+                    2 => Some(ChannelKind::Gigagroup),
+                    _ => None,
+                }),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn self_peer() -> PeerInfo {
+        PeerInfo::User {
+            id: 12345,
+            auth: Some(PeerAuth::from_hash(678)),
+            bot: Some(false),
+            is_self: Some(true),
+        }
+    }
+
+    #[test]
+    fn test_session_roundtrip_encrypted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.session");
+        let key = [5u8; KEY_SIZE];
+
+        {
+            let session = EncryptedSession::open(&path, key).unwrap();
+            session.set_home_dc_id(4);
+            let mut dc = session.dc_option(4).unwrap();
+            dc.auth_key = Some([9u8; 256]);
+            session.set_dc_option(&dc);
+            session.cache_peer(&self_peer());
+        }
+
+        // Nothing readable on disk
+        let bytes = std::fs::read(&path).unwrap();
+        assert!(!bytes.starts_with(SQLITE_MAGIC));
+        assert!(!bytes.windows(256).any(|w| w == [9u8; 256]));
+
+        let session = EncryptedSession::open(&path, key).unwrap();
+        assert_eq!(session.home_dc_id(), 4);
+        assert_eq!(session.dc_option(4).unwrap().auth_key, Some([9u8; 256]));
+        assert_eq!(session.peer(PeerId::self_user()), Some(self_peer()));
+    }
+
+    #[test]
+    fn test_session_wrong_key_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.session");
+
+        drop(EncryptedSession::open(&path, [1u8; KEY_SIZE]).unwrap());
+        assert!(EncryptedSession::open(&path, [2u8; KEY_SIZE]).is_err());
+    }
+
+    #[test]
+    fn test_session_rekey() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.session");
+
+        {
+            let mut session = EncryptedSession::open(&path, [1u8; KEY_SIZE]).unwrap();
+            session.set_home_dc_id(5);
+            session.rekey([2u8; KEY_SIZE]).unwrap();
+        }
+
+        assert!(EncryptedSession::open(&path, [1u8; KEY_SIZE]).is_err());
+        let session = EncryptedSession::open(&path, [2u8; KEY_SIZE]).unwrap();
+        assert_eq!(session.home_dc_id(), 5);
+    }
+
+    #[test]
+    fn test_concurrent_writers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.session");
+        let key = [3u8; KEY_SIZE];
+        let sessions = [
+            EncryptedSession::open(&path, key).unwrap(),
+            EncryptedSession::open(&path, key).unwrap(),
+        ];
+
+        // Each write goes through its own temporary file
+        std::thread::scope(|scope| {
+            for (dc, session) in sessions.iter().enumerate() {
+                scope.spawn(move || {
+                    for round in 0..20 {
+                        session.set_home_dc_id((dc * 100 + round) as i32);
+                        session.persist().unwrap();
+                    }
+                });
+            }
+        });
+
+        let names: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, ["test.session"]);
+        assert!(EncryptedSession::open(&path, key).is_ok());
+    }
+
+    #[test]
+    fn test_frequent_updates_batched() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.session");
+        let key = [3u8; KEY_SIZE];
+        let on_disk = || EncryptedSession::decrypt_state(&key, &std::fs::read(&path).unwrap()).unwrap();
+
+        {
+            let session = EncryptedSession::open(&path, key).unwrap();
+            session.set_update_state(UpdateState::Secondary { qts: 7 });
+            assert_eq!(on_disk().qts, 0);
+
+            // Auth changes are written right away, with pending updates
+            session.set_home_dc_id(2);
+            assert_eq!((on_disk().home_dc, on_disk().qts), (2, 7));
+
+            session.set_update_state(UpdateState::Secondary { qts: 8 });
+        }
+        assert_eq!(on_disk().qts, 8);
+    }
+
+    #[test]
+    fn test_plaintext_session_migrated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.session");
+
+        {
+            let sqlite = SqliteSession::open(&path).unwrap();
+            sqlite.set_home_dc_id(3);
+            sqlite.cache_peer(&self_peer());
+        }
+        assert!(EncryptedSession::is_plaintext(&path));
+
+        let key = [7u8; KEY_SIZE];
+        drop(EncryptedSession::open(&path, key).unwrap());
+        assert!(!EncryptedSession::is_plaintext(&path));
+
+        let session = EncryptedSession::open(&path, key).unwrap();
+        assert_eq!(session.home_dc_id(), 3);
+        assert_eq!(session.peer(PeerId::self_user()), Some(self_peer()));
+    }
+}